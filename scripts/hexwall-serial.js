@@ -0,0 +1,352 @@
+/*
+    A small library for driving the wall from a host machine over
+    the binary serial protocol (see src/protocol.rs for the frame
+    layout). Hand it anything which behaves like a duplex stream,
+    for example a port from the `serialport` package:
+
+        const { SerialPort } = require('serialport');
+        const { HexwallClient } = require('./hexwall-serial');
+
+        const port = new SerialPort({ path: '/dev/ttyUSB0', baudRate: 115200 });
+        const wall = new HexwallClient(port);
+        await wall.setBrightness(64);
+        console.log(await wall.telemetry());
+
+    Commands are sent one at a time. Each one is retransmitted
+    until the wall acks it, or gives up after a few tries.
+
+    It also works from the command line:
+
+        node scripts/hexwall-serial.js /dev/ttyUSB0 telemetry
+        node scripts/hexwall-serial.js /dev/ttyUSB0 shader Medbay
+*/
+const SYNC = [0xA5, 0x5A];
+const HEADER_SIZE = 6;
+const CRC_SIZE = 4;
+const MAX_PAYLOAD = 512;
+const MAX_ARGS = 10;
+const COMMAND_SIZE = 4 + MAX_ARGS * 4 + 8;
+const UNSET_ARG = 0x7FFFFFFF;
+
+const KIND_COMMAND = 1;
+const KIND_ACK = 2;
+const KIND_NACK = 3;
+const STATUS_OK = 0;
+
+const NACK_REASONS = { 1: 'bad crc', 2: 'too long', 3: 'malformed', 4: 'bad checksum' };
+const WIFI_STATES = ['resetting', 'joining', 'connected', 'got_ip', 'fetching', 'idle', 'failed'];
+
+const CRC_TABLE = Array.from({ length: 256 }, (_, byte) => {
+    let crc = byte;
+    for (let bit = 0; bit < 8; bit++) {
+        crc = crc & 1 ? (crc >>> 1) ^ 0xEDB88320 : crc >>> 1;
+    }
+    return crc >>> 0;
+});
+
+// CRC-32 (IEEE 802.3), the same as src/crc.rs
+function crc32(bytes) {
+    let crc = 0xFFFFFFFF;
+    for (const byte of bytes) {
+        crc = CRC_TABLE[(crc ^ byte) & 0xFF] ^ (crc >>> 8);
+    }
+    return (~crc) >>> 0;
+}
+
+// A SystemCommand: the mnemonic, ten arguments and a checksum
+function encodeCommand(command, args = []) {
+    if (args.length > MAX_ARGS) {
+        throw new Error(`at most ${MAX_ARGS} arguments`);
+    }
+
+    const result = Buffer.alloc(COMMAND_SIZE);
+    result.write(command, 0, 4, 'latin1');
+    for (let idx = 0; idx < MAX_ARGS; idx++) {
+        result.writeInt32LE(idx < args.length ? args[idx] : UNSET_ARG, 4 + idx * 4);
+    }
+    result.writeBigUInt64LE(BigInt(crc32(result.subarray(0, COMMAND_SIZE - 8))), COMMAND_SIZE - 8);
+    return result;
+}
+
+function encodeFrame(kind, seq, payload) {
+    const header = Buffer.from([kind, seq, payload.length & 0xFF, payload.length >> 8]);
+    const crc = Buffer.alloc(CRC_SIZE);
+    crc.writeUInt32LE(crc32(Buffer.concat([header, payload])));
+    return Buffer.concat([Buffer.from(SYNC), header, payload, crc]);
+}
+
+// Picks frames out of everything the port says. Console text
+// and debug output in between is skipped.
+class FrameParser {
+    constructor() {
+        this.buffer = Buffer.alloc(0);
+    }
+
+    push(chunk) {
+        this.buffer = Buffer.concat([this.buffer, chunk]);
+        const frames = [];
+
+        while (true) {
+            const start = this.buffer.indexOf(Buffer.from(SYNC));
+            if (start < 0) {
+                // Keep a trailing first sync byte, the second may follow
+                const last = this.buffer[this.buffer.length - 1];
+                this.buffer = last === SYNC[0] ? Buffer.from([last]) : Buffer.alloc(0);
+                return frames;
+            }
+
+            this.buffer = this.buffer.subarray(start);
+            if (this.buffer.length < HEADER_SIZE) {
+                return frames;
+            }
+
+            const length = this.buffer.readUInt16LE(4);
+            if (length > MAX_PAYLOAD) {
+                this.buffer = this.buffer.subarray(1);
+                continue;
+            }
+
+            const end = HEADER_SIZE + length;
+            if (this.buffer.length < end + CRC_SIZE) {
+                return frames;
+            }
+
+            if (crc32(this.buffer.subarray(2, end)) === this.buffer.readUInt32LE(end)) {
+                frames.push({
+                    kind: this.buffer[2],
+                    seq: this.buffer[3],
+                    payload: Buffer.from(this.buffer.subarray(HEADER_SIZE, end)),
+                });
+                this.buffer = this.buffer.subarray(end + CRC_SIZE);
+            } else {
+                this.buffer = this.buffer.subarray(1);
+            }
+        }
+    }
+}
+
+class HexwallClient {
+    constructor(port, options = {}) {
+        this.port = port;
+        this.timeout = options.timeout ?? 500;
+        this.retries = options.retries ?? 5;
+        // The wall ignores a command with the same number as the
+        // one before, so don't start where the last run left off
+        this.seq = options.seq ?? Math.floor(Math.random() * 256);
+        this.parser = new FrameParser();
+        this.waiting = null;
+        this.queue = Promise.resolve();
+
+        port.on('data', (chunk) => {
+            for (const frame of this.parser.push(Buffer.from(chunk))) {
+                if (this.waiting !== null && frame.seq === this.waiting.seq) {
+                    this.waiting.resolve(frame);
+                }
+            }
+        });
+    }
+
+    // Send a command and return what the ack carried. Failed
+    // commands throw with the wall's message.
+    send(command, args = [], data = Buffer.alloc(0)) {
+        const result = this.queue.then(() => this._transmit(command, args, Buffer.from(data)));
+        this.queue = result.catch(() => {});
+        return result;
+    }
+
+    async _transmit(command, args, data) {
+        this.seq = (this.seq + 1) & 0xFF;
+        const seq = this.seq;
+        const frame = encodeFrame(KIND_COMMAND, seq, Buffer.concat([encodeCommand(command, args), data]));
+        let problem = 'no answer';
+
+        for (let attempt = 0; attempt <= this.retries; attempt++) {
+            const reply = await this._exchange(seq, frame);
+            if (reply === null) {
+                problem = 'no answer';
+            } else if (reply.kind === KIND_NACK) {
+                problem = NACK_REASONS[reply.payload[0]] ?? 'nack';
+            } else if (reply.kind === KIND_ACK) {
+                if (reply.payload[0] !== STATUS_OK) {
+                    throw new Error(`${command}: ${reply.payload.subarray(1).toString('latin1')}`);
+                }
+                return reply.payload.subarray(1);
+            }
+        }
+        throw new Error(`${command}: ${problem} after ${this.retries + 1} tries`);
+    }
+
+    _exchange(seq, frame) {
+        return new Promise((resolve) => {
+            const timer = setTimeout(() => {
+                this.waiting = null;
+                resolve(null);
+            }, this.timeout);
+
+            this.waiting = {
+                seq: seq,
+                resolve: (reply) => {
+                    clearTimeout(timer);
+                    this.waiting = null;
+                    resolve(reply);
+                },
+            };
+            this.port.write(frame);
+        });
+    }
+
+    async _text(command, args = []) {
+        return (await this.send(command, args)).toString('latin1');
+    }
+
+    status() {
+        return this._text('STAT');
+    }
+
+    configs() {
+        return this._text('CONF');
+    }
+
+    selfTest() {
+        return this._text('TEST');
+    }
+
+    reconnect() {
+        return this._text('WIFI');
+    }
+
+    reboot() {
+        return this._text('BOOT');
+    }
+
+    // [{ index, name, current }] in the order the wall numbers them
+    async shaders() {
+        return parseNames(await this._text('SHLS'));
+    }
+
+    async effects() {
+        return parseNames(await this._text('EFLS'));
+    }
+
+    async setShader(shader) {
+        return this._text('SHAD', [await this._find(shader, () => this.shaders())]);
+    }
+
+    async setEffect(effect) {
+        return this._text('EFCT', [await this._find(effect, () => this.effects())]);
+    }
+
+    setBrightness(brightness) {
+        return this._text('BRIT', [brightness]);
+    }
+
+    // Defaults to the host's clock
+    setTime(epochSeconds = Math.floor(Date.now() / 1000)) {
+        return this._text('TIME', [epochSeconds]);
+    }
+
+    // Hours behind UTC, 0 - 23
+    setUtcOffset(hours) {
+        return this._text('UTCO', [hours]);
+    }
+
+    // World seconds per real second for the next 10 minutes, 0 stops
+    timeWarp(multiplier) {
+        return this._text('WARP', [multiplier]);
+    }
+
+    async telemetry() {
+        const bytes = await this.send('TELE');
+        const temperature = bytes.readInt32LE(4);
+        const shader = bytes.readInt16LE(16);
+        const effect = bytes.readInt16LE(18);
+        return {
+            version: bytes[0],
+            power: bytes[1] === 1,
+            brightness: bytes[2],
+            wifi: bytes[3] === 0xFF ? null : WIFI_STATES[bytes[3]] ?? bytes[3],
+            temperature: temperature === UNSET_ARG ? null : temperature / 100,
+            uptime: bytes.readUInt32LE(8),
+            worldTime: bytes.readUInt32LE(12),
+            shader: shader < 0 ? null : shader,
+            effect: effect < 0 ? null : effect,
+            rssi: bytes.readInt8(20) === 0 ? null : bytes.readInt8(20),
+            streaming: bytes[21] === 1,
+        };
+    }
+
+    // Show a color for every led, given as [r, g, b] triples. The
+    // wall fades back to the schedule when frames stop coming.
+    async showFrame(colors) {
+        const bytes = Buffer.from(colors.flat());
+        const chunk = Math.floor((MAX_PAYLOAD - COMMAND_SIZE) / 3) * 3;
+        for (let offset = 0; offset < bytes.length; offset += chunk) {
+            const last = offset + chunk >= bytes.length;
+            await this.send('FRAM', [offset / 3, last ? 1 : 0], bytes.subarray(offset, offset + chunk));
+        }
+    }
+
+    endFrames() {
+        return this._text('FEND');
+    }
+
+    async _find(target, list) {
+        if (typeof target === 'number') {
+            return target;
+        }
+
+        const found = (await list()).find((entry) => entry.name.toLowerCase() === `${target}`.toLowerCase());
+        if (found === undefined) {
+            throw new Error(`unknown name ${target}`);
+        }
+        return found.index;
+    }
+}
+
+function parseNames(text) {
+    return text.split('\r\n').filter((line) => line.length > 0).map((line) => {
+        const match = /^(\*| ) *(\d+) (.*)$/.exec(line);
+        return { index: parseInt(match[2]), name: match[3], current: match[1] === '*' };
+    });
+}
+
+exports.crc32 = crc32;
+exports.encodeCommand = encodeCommand;
+exports.encodeFrame = encodeFrame;
+exports.FrameParser = FrameParser;
+exports.HexwallClient = HexwallClient;
+
+if (require.main === module) {
+    const [path, name, ...args] = process.argv.slice(2);
+    const commands = {
+        status: (wall) => wall.status(),
+        telemetry: (wall) => wall.telemetry(),
+        shaders: (wall) => wall.shaders(),
+        effects: (wall) => wall.effects(),
+        shader: (wall, shader) => wall.setShader(/^\d+$/.test(shader) ? parseInt(shader) : shader),
+        effect: (wall, effect) => wall.setEffect(/^\d+$/.test(effect) ? parseInt(effect) : effect),
+        brightness: (wall, value) => wall.setBrightness(parseInt(value)),
+        time: (wall, value) => wall.setTime(value === undefined ? undefined : parseInt(value)),
+        utc: (wall, value) => wall.setUtcOffset(parseInt(value)),
+        warp: (wall, value) => wall.timeWarp(parseInt(value)),
+        configs: (wall) => wall.configs(),
+        selftest: (wall) => wall.selfTest(),
+        reconnect: (wall) => wall.reconnect(),
+        reboot: (wall) => wall.reboot(),
+    };
+
+    if (path === undefined || commands[name] === undefined) {
+        console.error(`usage: hexwall-serial.js <port> <${Object.keys(commands).join('|')}> [argument]`);
+        process.exit(1);
+    }
+
+    const { SerialPort } = require('serialport');
+    const port = new SerialPort({ path: path, baudRate: 115200 });
+    commands[name](new HexwallClient(port), ...args)
+        .then((result) => console.log(result))
+        .catch((err) => {
+            console.error(err.message);
+            process.exitCode = 1;
+        })
+        .finally(() => port.close());
+}
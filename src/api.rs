@@ -0,0 +1,761 @@
+//! A small REST api for controlling the wall over the
+//! network. Responses are JSON. Commands which change what is
+//! being shown are queued through `control` and carried out by
+//! the pixel task.
+//!
+//! ```text
+//! GET  /api/status
+//! POST /api/shader?name=<shader>
+//! POST /api/effect?name=<effect>
+//! POST /api/brightness?value=<0-255>
+//! POST /api/transition_delay?seconds=<seconds>
+//! POST /api/randomize
+//! POST /api/timewarp?multiplier=<n>[&start=<epoch>][&duration=<seconds>]
+//! POST /api/timewarp/stop
+//! POST /api/config_url?url=<http url>
+//! POST /api/network?ip=<address>&gateway=<address>&netmask=<address>[&dns=<address>]
+//! POST /api/mqtt?url=<mqtt url>
+//! POST /api/stream?universe=<n>[&channel=<1-512>][&mode=units|leds][&protocol=dmx|ddp|off]
+//! POST /api/thermal?warning=<celsius>&critical=<celsius>[&hysteresis=<celsius>]
+//! ```
+//!
+//! Parameters can be given in the query string or as a form
+//! encoded body. The WLED compatible routes are in `wled`.
+
+use teensycore::*;
+use teensycore::clock::uNano;
+use teensycore::math::{int_to_hex, itoa};
+use teensycore::system::str::*;
+use crate::control::*;
+use crate::dmx::*;
+use crate::drivers::one_wire::rom_hex;
+use crate::hal::Clock;
+use crate::http::models::*;
+use crate::http::server::*;
+use crate::http::url::Url;
+use crate::mqtt::client::Broker;
+use crate::network::*;
+use crate::strings::*;
+use crate::thermal::ThermalConfig;
+use crate::wled;
+
+pub const API_PORT: u32 = 80;
+pub const JSON_CONTENT_TYPE: &[u8] = b"application/json";
+
+/// Every endpoint of the api. Requests which match none of
+/// them get a 404.
+pub fn api_routes<C: Clock>() -> [Route<C>; 21] {
+    return [
+        Route { method: HttpMethod::Get, path: b"/api/status", handler: show_status::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/shader", handler: force_shader::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/effect", handler: force_effect::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/brightness", handler: brightness::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/transition_delay", handler: transition_delay::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/randomize", handler: randomize::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/timewarp", handler: time_warp::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/timewarp/stop", handler: stop_time_warp::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/config_url", handler: config_url::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/network", handler: network::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/mqtt", handler: mqtt::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/stream", handler: stream::<C> },
+        Route { method: HttpMethod::Post, path: b"/api/thermal", handler: thermal::<C> },
+        Route { method: HttpMethod::Get, path: b"/json", handler: wled::show_all::<C> },
+        Route { method: HttpMethod::Post, path: b"/json", handler: wled::update_state::<C> },
+        Route { method: HttpMethod::Get, path: b"/json/si", handler: wled::show_state_info::<C> },
+        Route { method: HttpMethod::Get, path: b"/json/state", handler: wled::show_state::<C> },
+        Route { method: HttpMethod::Post, path: b"/json/state", handler: wled::update_state::<C> },
+        Route { method: HttpMethod::Get, path: b"/json/info", handler: wled::show_info::<C> },
+        Route { method: HttpMethod::Get, path: b"/json/eff", handler: wled::show_effects::<C> },
+        Route { method: HttpMethod::Get, path: b"/json/pal", handler: wled::show_palettes::<C> },
+    ];
+}
+
+fn show_status<C: Clock>(clock: &mut C, _request: &IncomingRequest) -> ServerResponse {
+    return json(HttpStatus::Ok, render_status(clock));
+}
+
+fn force_shader<C: Clock>(_clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    return match lookup(request, find_shader_name) {
+        Err(status) => error(status, b"unknown shader"),
+        Ok(name) => {
+            queue_command(ControlCommand::ForceShader(name));
+            accepted()
+        },
+    };
+}
+
+fn force_effect<C: Clock>(_clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    return match lookup(request, find_effect_name) {
+        Err(status) => error(status, b"unknown effect"),
+        Ok(name) => {
+            queue_command(ControlCommand::ForceEffect(name));
+            accepted()
+        },
+    };
+}
+
+fn brightness<C: Clock>(_clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    return match number_param(request, b"value") {
+        Some(value) if value <= 255 => {
+            crate::set_brightness(value as u8);
+            accepted()
+        },
+        _ => error(HttpStatus::BadRequest, b"value must be 0-255"),
+    };
+}
+
+fn transition_delay<C: Clock>(_clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    return match number_param(request, b"seconds") {
+        Some(seconds) if seconds > 0 && seconds <= 7 * 24 * 60 * 60 => {
+            crate::set_transition_delay(seconds as uNano * S_TO_NANO);
+            accepted()
+        },
+        _ => error(HttpStatus::BadRequest, b"seconds must be 1-604800"),
+    };
+}
+
+fn randomize<C: Clock>(_clock: &mut C, _request: &IncomingRequest) -> ServerResponse {
+    queue_command(ControlCommand::Randomize);
+    return accepted();
+}
+
+fn time_warp<C: Clock>(clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    let multiplier = number_param(request, b"multiplier");
+    let start = optional_number_param(request, b"start");
+    let duration = optional_number_param(request, b"duration");
+    return match (multiplier, start, duration) {
+        (Some(multiplier), Ok(start), Ok(duration)) if multiplier > 0 => {
+            crate::start_time_warp(clock, start.map(|val| val as uNano), multiplier as uNano, duration.unwrap_or(0) as uNano);
+            accepted()
+        },
+        _ => error(HttpStatus::BadRequest, b"invalid time warp"),
+    };
+}
+
+fn stop_time_warp<C: Clock>(_clock: &mut C, _request: &IncomingRequest) -> ServerResponse {
+    crate::stop_time_warp();
+    return accepted();
+}
+
+/// Change where the config is downloaded from. It takes effect
+/// the next time the config is downloaded. An empty url goes
+/// back to the default.
+fn config_url<C: Clock>(_clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    let url = match request.param(b"url") {
+        None => return error(HttpStatus::BadRequest, b"url is required"),
+        Some(url) => url,
+    };
+
+    if url.len() == 0 {
+        crate::clear_config_url();
+        return accepted();
+    }
+
+    return match Url::parse(&url) {
+        None => error(HttpStatus::BadRequest, b"url must start with http://"),
+        Some(mut parsed) => {
+            parsed.drop();
+            crate::set_config_url(&url);
+            accepted()
+        }
+    };
+}
+
+/// Give the wall a static address. It takes effect the next
+/// time a network is joined. An empty ip goes back to dhcp.
+fn network<C: Clock>(_clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    let ip = match request.param(b"ip") {
+        None => return error(HttpStatus::BadRequest, b"ip is required"),
+        Some(ip) => ip,
+    };
+
+    if ip.len() == 0 {
+        crate::set_network_config(NetworkConfig::Dhcp);
+        return accepted();
+    }
+
+    let address = |name: &[u8]| request.param(name).map(|value| Ipv4::parse(&value));
+    return match (Ipv4::parse(&ip), address(b"gateway"), address(b"netmask"), address(b"dns")) {
+        (Some(ip), Some(Some(gateway)), Some(Some(netmask)), dns) if dns != Some(None) => {
+            crate::set_network_config(NetworkConfig::Static {
+                ip: ip,
+                gateway: gateway,
+                netmask: netmask,
+                dns: dns.flatten(),
+            });
+            accepted()
+        },
+        _ => error(HttpStatus::BadRequest, b"ip, gateway and netmask must be addresses like 192.168.1.50"),
+    };
+}
+
+/// Point the wall at an mqtt broker. It connects the next time
+/// it is online. An empty url stops using mqtt.
+fn mqtt<C: Clock>(_clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    let url = match request.param(b"url") {
+        None => return error(HttpStatus::BadRequest, b"url is required"),
+        Some(url) => url,
+    };
+
+    if url.len() == 0 {
+        crate::clear_mqtt_url();
+        return accepted();
+    }
+
+    return match Broker::parse(&url) {
+        None => error(HttpStatus::BadRequest, b"url must look like mqtt://host:1883"),
+        Some(mut broker) => {
+            broker.drop();
+            crate::set_mqtt_url(&url);
+            accepted()
+        }
+    };
+}
+
+/// Listen for E1.31 and Art-Net frames on a universe, or for
+/// DDP. It takes effect the next time the server starts. An
+/// empty universe or a protocol of off stops listening.
+fn stream<C: Clock>(_clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    let protocol = match request.param(b"protocol") {
+        None => StreamProtocol::Dmx,
+        Some(name) if equals_ignore_case(&name, b"off") => {
+            crate::set_stream_config(None);
+            return accepted();
+        },
+        Some(name) => match StreamProtocol::parse(&name) {
+            None => return error(HttpStatus::BadRequest, b"protocol must be dmx, ddp or off"),
+            Some(protocol) => protocol,
+        },
+    };
+
+    let universe = match (request.param(b"universe"), protocol) {
+        (Some(universe), _) if universe.len() == 0 => {
+            crate::set_stream_config(None);
+            return accepted();
+        },
+        (Some(universe), _) => parse_decimal(&universe),
+        // DDP has no universes
+        (None, StreamProtocol::Ddp) => Some(0),
+        (None, StreamProtocol::Dmx) => return error(HttpStatus::BadRequest, b"universe is required"),
+    };
+
+    let mapping = match request.param(b"mode") {
+        None => Some(DmxMapping::Units),
+        Some(mode) => DmxMapping::parse(&mode),
+    };
+    return match (universe, optional_number_param(request, b"channel"), mapping) {
+        (Some(universe), Ok(channel), Some(mapping)) if universe <= 63999 && channel.unwrap_or(1) <= 512 => {
+            let config = StreamConfig {
+                protocol: protocol,
+                universe: universe as u16,
+                start_channel: channel.unwrap_or(1) as u16,
+                mapping: mapping,
+            };
+            if !config.is_valid() {
+                return error(HttpStatus::BadRequest, b"the wall does not fit in the universe from that channel on");
+            }
+            crate::set_stream_config(Some(config));
+            accepted()
+        },
+        _ => error(HttpStatus::BadRequest, b"universe must be 0-63999, channel 1-512 and mode units or leds"),
+    };
+}
+
+/// Set the temperatures, in whole degrees, at which the wall
+/// is dimmed and at which it only shows the safe pattern. The
+/// hysteresis is kept if it isn't given.
+fn thermal<C: Clock>(_clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    let hysteresis = crate::get_thermal_config().hysteresis;
+    return match (number_param(request, b"warning"), number_param(request, b"critical"), optional_number_param(request, b"hysteresis")) {
+        (Some(warning), Some(critical), Ok(degrees)) => {
+            let config = ThermalConfig {
+                warning: warning.min(1000) as i32 * 100,
+                critical: critical.min(1000) as i32 * 100,
+                hysteresis: degrees.map_or(hysteresis, |degrees| degrees.min(1000) as i32 * 100),
+            };
+            if !config.is_valid() {
+                return error(HttpStatus::BadRequest, b"warning must be below critical, critical at most 125 and hysteresis at most 10");
+            }
+            crate::set_thermal_config(config);
+            accepted()
+        },
+        _ => error(HttpStatus::BadRequest, b"warning and critical are required"),
+    };
+}
+
+/// Describe what the wall is doing right now.
+pub fn render_status<C: Clock>(clock: &C) -> Str {
+    let status = get_status();
+    let mut result = Str::new();
+
+    result.append(b"{\"shader\":");
+    append_json_name(&mut result, status.shader);
+    result.append(b",\"effect\":");
+    append_json_name(&mut result, status.effect);
+    result.append(b",\"uptime_s\":");
+    result.join(&itoa((clock.nanos() / S_TO_NANO) as u64));
+    result.append(b",\"temperature_c\":");
+    match status.temperature {
+        None => {
+            result.append(b"null");
+        },
+        Some(centi) => {
+            append_centi(&mut result, centi);
+        }
+    }
+    result.append(b",\"sensors\":[");
+    for (idx, reading) in status.sensors.iter().flatten().enumerate() {
+        if idx > 0 {
+            result.append(b",");
+        }
+        result.append(b"{\"rom\":\"");
+        result.append(&rom_hex(reading.rom));
+        result.append(b"\",\"temperature_c\":");
+        match reading.temperature {
+            None => {
+                result.append(b"null");
+            },
+            Some(centi) => {
+                append_centi(&mut result, centi);
+            }
+        }
+        result.append(b"}");
+    }
+    result.append(b"]");
+    let thermal_config = crate::get_thermal_config();
+    result.append(b",\"thermal\":{\"state\":\"");
+    result.append(status.thermal.state.name());
+    result.append(b"\",\"brightness_scale\":");
+    result.join(&itoa(status.thermal.scale as u64));
+    result.append(b",\"warning_c\":");
+    append_centi(&mut result, thermal_config.warning);
+    result.append(b",\"critical_c\":");
+    append_centi(&mut result, thermal_config.critical);
+    result.append(b",\"hysteresis_c\":");
+    append_centi(&mut result, thermal_config.hysteresis);
+    result.append(b",\"events\":[");
+    for (idx, event) in get_thermal_events().iter().flatten().enumerate() {
+        if idx > 0 {
+            result.append(b",");
+        }
+        result.append(b"{\"uptime_s\":");
+        result.join(&itoa((event.at / S_TO_NANO) as u64));
+        result.append(b",\"state\":\"");
+        result.append(event.derating.state.name());
+        result.append(b"\",\"brightness_scale\":");
+        result.join(&itoa(event.derating.scale as u64));
+        result.append(b",\"temperature_c\":");
+        match event.temperature {
+            None => {
+                result.append(b"null");
+            },
+            Some(centi) => {
+                append_centi(&mut result, centi);
+            }
+        }
+        result.append(b"}");
+    }
+    result.append(b"]}");
+    result.append(b",\"brightness\":");
+    result.join(&itoa(crate::get_brightness() as u64));
+    result.append(b",\"transition_delay_s\":");
+    result.join(&itoa((crate::get_tranasition_delay() / S_TO_NANO) as u64));
+    result.append(b",\"world_time\":");
+    result.join(&itoa(crate::get_world_time(clock) as u64));
+    let world_clock = crate::get_world_clock();
+    result.append(b",\"time_synced\":");
+    result.append(if world_clock.synced() { b"true" } else { b"false" });
+    result.append(b",\"clock_drift_ppm\":");
+    append_centi(&mut result, (world_clock.drift_ppb / 10) as i32);
+    result.append(b",\"time_warp\":");
+    match crate::get_time_warp(clock) {
+        None => {
+            result.append(b"null");
+        },
+        Some(warp) => {
+            result.append(b"{\"multiplier\":");
+            result.join(&itoa(warp.multiplier as u64));
+            result.append(b",\"remaining_s\":");
+            result.join(&itoa(warp.remaining_s(clock.nanos()) as u64));
+            result.append(b"}");
+        }
+    }
+    result.append(b",\"wifi\":");
+    match status.wifi {
+        None => {
+            result.append(b"null");
+        },
+        Some(wifi) => {
+            result.append(b"{\"state\":\"");
+            result.append(wifi.state.name());
+            result.append(b"\",\"state_s\":");
+            result.join(&itoa((clock.nanos().saturating_sub(wifi.since) / S_TO_NANO) as u64));
+            result.append(b",\"resets\":");
+            result.join(&itoa(wifi.resets as u64));
+            result.append(b",\"disconnects\":");
+            result.join(&itoa(wifi.disconnects as u64));
+            result.append(b",\"probe_failures\":");
+            result.join(&itoa(wifi.probe_failures as u64));
+            result.append(b",\"download_failures\":");
+            result.join(&itoa(wifi.download_failures as u64));
+            result.append(b",\"rssi\":");
+            match wifi.rssi {
+                None => {
+                    result.append(b"null");
+                },
+                Some(rssi) => {
+                    if rssi < 0 {
+                        result.append(b"-");
+                    }
+                    result.join(&itoa(rssi.unsigned_abs() as u64));
+                },
+            }
+            result.append(b",\"ip\":");
+            match wifi.ip {
+                None => {
+                    result.append(b"null");
+                },
+                Some(ip) => {
+                    let mut address = ip.to_str();
+                    result.append(b"\"");
+                    result.join(&address);
+                    result.append(b"\"");
+                    address.drop();
+                },
+            }
+            result.append(b",\"mac\":");
+            match wifi.mac {
+                None => {
+                    result.append(b"null");
+                },
+                Some(mac) => {
+                    result.append(b"\"");
+                    for (idx, byte) in mac.iter().enumerate() {
+                        if idx > 0 {
+                            result.append(b":");
+                        }
+                        result.append(&[int_to_hex(byte >> 4), int_to_hex(byte & 0xF)]);
+                    }
+                    result.append(b"\"");
+                },
+            }
+            result.append(b"}");
+        }
+    }
+    result.append(b"}");
+    return result;
+}
+
+fn lookup(request: &IncomingRequest, finder: fn(&Str) -> Option<&'static [u8]>) -> Result<&'static [u8], HttpStatus> {
+    let name = request.param(b"name").ok_or(HttpStatus::BadRequest)?;
+    return finder(&name).ok_or(HttpStatus::NotFound);
+}
+
+fn number_param(request: &IncomingRequest, name: &[u8]) -> Option<u64> {
+    return parse_decimal(&request.param(name)?);
+}
+
+/// Ok(None) if the parameter is absent, Err if it is malformed.
+fn optional_number_param(request: &IncomingRequest, name: &[u8]) -> Result<Option<u64>, ()> {
+    return match request.param(name) {
+        None => Ok(None),
+        Some(value) => parse_decimal(&value).map(Some).ok_or(()),
+    };
+}
+
+fn json(status: HttpStatus, body: Str) -> ServerResponse {
+    return ServerResponse::new(status, JSON_CONTENT_TYPE, body);
+}
+
+fn accepted() -> ServerResponse {
+    return json(HttpStatus::Ok, Str::with_content(b"{\"ok\":true}"));
+}
+
+fn error(status: HttpStatus, message: &[u8]) -> ServerResponse {
+    let mut body = Str::new();
+    body.append(b"{\"ok\":false,\"error\":\"");
+    body.append(message);
+    body.append(b"\"}");
+    return json(status, body);
+}
+
+/// Write a name as a JSON string, or null without one.
+pub fn append_json_name(target: &mut Str, name: Option<&'static [u8]>) {
+    match name {
+        None => {
+            target.append(b"null");
+        },
+        Some(name) => {
+            target.append(b"\"");
+            for char in name {
+                match char {
+                    b'"' | b'\\' => target.append(&[b'\\', *char]),
+                    _ => target.append(&[*char]),
+                };
+            }
+            target.append(b"\"");
+        }
+    }
+}
+
+/// Find a value in a json object by its key. Only the object's
+/// own keys count, not the ones of objects nested in it.
+/// Strings come back without their quotes, anything else as
+/// written, so nested objects and arrays come back whole.
+/// Escapes are left alone, since no name needs them. The value
+/// is a copy, so drop it.
+pub fn json_value(payload: &Str, key: &[u8]) -> Option<Str> {
+    let mut depth = 0;
+    let mut idx = 0;
+    loop {
+        match byte_at(payload, idx)? {
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth -= 1,
+            b'"' => {
+                let end = json_string_end(payload, idx)?;
+                let mut next = end + 1;
+                json_skip_space(payload, &mut next);
+                let is_key = end - idx - 1 == key.len()
+                    && key.iter().enumerate().all(|(offset, char)| byte_at(payload, idx + 1 + offset) == Some(*char));
+                if depth == 1 && is_key && byte_at(payload, next) == Some(b':') {
+                    next += 1;
+                    json_skip_space(payload, &mut next);
+                    return json_value_at(payload, next);
+                }
+                idx = end;
+            },
+            _ => {},
+        }
+        idx += 1;
+    }
+}
+
+/// The first entry of a json array.
+pub fn json_first(array: &Str) -> Option<Str> {
+    if byte_at(array, 0)? != b'[' {
+        return None;
+    }
+    let mut idx = 1;
+    json_skip_space(array, &mut idx);
+    return match byte_at(array, idx)? {
+        b']' => None,
+        _ => json_value_at(array, idx),
+    };
+}
+
+/// The value which starts at `idx`.
+fn json_value_at(payload: &Str, idx: usize) -> Option<Str> {
+    let end = match byte_at(payload, idx)? {
+        b'"' => {
+            let end = json_string_end(payload, idx)?;
+            return Some(substring(payload, idx + 1, end - idx - 1));
+        },
+        b'{' | b'[' => {
+            let mut depth = 0;
+            let mut end = idx;
+            loop {
+                match byte_at(payload, end)? {
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => depth -= 1,
+                    b'"' => end = json_string_end(payload, end)?,
+                    _ => {},
+                }
+                end += 1;
+                if depth == 0 {
+                    break end;
+                }
+            }
+        },
+        _ => {
+            let mut end = idx;
+            while byte_at(payload, end).map_or(false, |char| char != b',' && char != b'}' && char != b']' && !char.is_ascii_whitespace()) {
+                end += 1;
+            }
+            end
+        },
+    };
+    return Some(substring(payload, idx, end - idx));
+}
+
+/// Where the string which starts at `idx` ends, at its closing
+/// quote.
+fn json_string_end(payload: &Str, idx: usize) -> Option<usize> {
+    let mut end = idx + 1;
+    loop {
+        match byte_at(payload, end)? {
+            b'\\' => end += 2,
+            b'"' => return Some(end),
+            _ => end += 1,
+        }
+    }
+}
+
+fn json_skip_space(payload: &Str, idx: &mut usize) {
+    while byte_at(payload, *idx).map_or(false, |char| char.is_ascii_whitespace()) {
+        *idx += 1;
+    }
+}
+
+/// Write hundredths as a decimal number, e.g. -512 as -5.12
+pub fn append_centi(target: &mut Str, centi: i32) {
+    if centi < 0 {
+        target.append(b"-");
+    }
+
+    let magnitude = centi.unsigned_abs();
+    target.join(&itoa((magnitude / 100) as u64));
+    target.append(b".");
+    let fraction = magnitude % 100;
+    if fraction < 10 {
+        target.append(b"0");
+    }
+    target.join(&itoa(fraction as u64));
+}
+
+#[cfg(test)]
+pub mod test_api {
+    use super::*;
+    use std::prelude::rust_2021::*;
+    use crate::hal::mock::*;
+    use crate::http::parser::parse_request;
+    use crate::pixel_engine::golden::RENDER_LOCK;
+    use crate::thermal::*;
+
+    fn call(clock: &MockClock, raw: &[u8]) -> (HttpStatus, String) {
+        let mut request = parse_request(0, &Str::with_content(raw)).unwrap().unwrap();
+        let mut clock = clock;
+        let mut response = dispatch(&api_routes(), &mut clock, &request);
+        let bytes: Vec<u8> = chars(&response.body).collect();
+        let result = (response.status, String::from_utf8(bytes).unwrap());
+        response.drop();
+        request.drop();
+        return result;
+    }
+
+    #[test]
+    fn test_api_commands() {
+        // Commands go through the same queue the pixel task reads
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        while next_command().is_some() { }
+
+        let clock = MockClock::new();
+        register_shader(b"Medbay");
+        register_effect(b"Randomized");
+
+        let (status, body) = call(&clock, b"POST /api/shader?name=medbay HTTP/1.1\r\n\r\n");
+        assert_eq!(status, HttpStatus::Ok);
+        assert_eq!(body, "{\"ok\":true}");
+        assert!(next_command() == Some(ControlCommand::ForceShader(b"Medbay")));
+
+        assert_eq!(call(&clock, b"POST /api/shader?name=Nope HTTP/1.1\r\n\r\n").0, HttpStatus::NotFound);
+        assert_eq!(call(&clock, b"POST /api/shader HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+
+        let (status, _) = call(&clock, b"POST /api/effect HTTP/1.1\r\nContent-Length: 15\r\n\r\nname=Randomized");
+        assert_eq!(status, HttpStatus::Ok);
+        assert!(next_command() == Some(ControlCommand::ForceEffect(b"Randomized")));
+
+        call(&clock, b"POST /api/randomize HTTP/1.1\r\n\r\n");
+        assert!(next_command() == Some(ControlCommand::Randomize));
+        assert!(next_command().is_none());
+    }
+
+    #[test]
+    fn test_api_routing() {
+        let clock = MockClock::new();
+        assert_eq!(call(&clock, b"GET / HTTP/1.1\r\n\r\n").0, HttpStatus::NotFound);
+        assert_eq!(call(&clock, b"GET /api/nope HTTP/1.1\r\n\r\n").0, HttpStatus::NotFound);
+        assert_eq!(call(&clock, b"GET /api/shader HTTP/1.1\r\n\r\n").0, HttpStatus::MethodNotAllowed);
+
+        // Values are validated before anything changes
+        assert_eq!(call(&clock, b"POST /api/brightness?value=256 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/brightness?value=-1 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/transition_delay?seconds=0 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/timewarp?multiplier=60&start=soon HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/timewarp HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/config_url?url=https%3A%2F%2Fconfig.example HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/config_url HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/network HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/network?ip=192.168.1.50 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/network?ip=192.168.1.50&gateway=192.168.1.1&netmask=255.255.255 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/network?ip=192.168.1.50&gateway=192.168.1.1&netmask=255.255.255.0&dns=google HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/mqtt?url=http%3A%2F%2Fbroker HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/mqtt HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/stream HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/stream?universe=1&mode=pixels HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/stream?universe=1&channel=0 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/stream?universe=1&channel=500 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/stream?universe=1&channel=200&mode=leds HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/stream?universe=70000 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/stream?protocol=udp HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/thermal?warning=45 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/thermal?warning=60&critical=45 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/thermal?warning=45&critical=130 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/thermal?warning=45&critical=60&hysteresis=20 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /api/thermal?warning=45&critical=60&hysteresis=-1 HTTP/1.1\r\n\r\n").0, HttpStatus::BadRequest);
+    }
+
+    #[test]
+    fn test_json_value() {
+        let payload = str!(b"{\"state\": \"ON\" ,\"brightness\":128, \"effect\":\"Medbay\"}");
+        assert!(json_value(&payload, b"state").unwrap() == str!(b"ON"));
+        assert!(json_value(&payload, b"brightness").unwrap() == str!(b"128"));
+        assert!(json_value(&payload, b"effect").unwrap() == str!(b"Medbay"));
+        assert!(json_value(&payload, b"color").is_none());
+        assert!(json_value(&str!(b"{\"state\"}"), b"state").is_none());
+        assert!(json_value(&str!(b"{\"state\":\"ON"), b"state").is_none());
+
+        // Nested keys belong to someone else
+        let payload = str!(b"{\"seg\":[{\"on\":false,\"fx\":2}, {}],\"note\":\"\\\"on\\\":1\",\"on\":true}");
+        assert!(json_value(&payload, b"on").unwrap() == str!(b"true"));
+        assert!(json_value(&payload, b"fx").is_none());
+        let segments = json_value(&payload, b"seg").unwrap();
+        assert!(segments == str!(b"[{\"on\":false,\"fx\":2}, {}]"));
+        let segment = json_first(&segments).unwrap();
+        assert!(json_value(&segment, b"fx").unwrap() == str!(b"2"));
+        assert!(json_value(&segment, b"on").unwrap() == str!(b"false"));
+        assert!(json_first(&str!(b"[]")).is_none());
+        assert!(json_value(&str!(b"{\"seg\":{\"on\":true"), b"seg").is_none());
+    }
+
+    #[test]
+    fn test_status_json() {
+        // The thermal task publishes sensors and derating too
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let clock = MockClock::new();
+        clock.set(90 * S_TO_NANO);
+        set_temperature(Some(-512));
+
+        let (status, json) = call(&clock, b"GET /api/status HTTP/1.1\r\n\r\n");
+        assert_eq!(status, HttpStatus::Ok);
+        assert!(json.starts_with("{\"shader\":"));
+        assert!(json.contains(",\"uptime_s\":90,"));
+        assert!(json.contains(",\"temperature_c\":-5.12,"));
+        assert!(json.contains(",\"sensors\":[],"));
+
+        let mut sensors = [None; MAX_SENSORS];
+        sensors[0] = Some(SensorReading { rom: 0xA2000000_01B81C02, temperature: Some(2506) });
+        sensors[1] = Some(SensorReading { rom: 0x28, temperature: None });
+        set_sensor_readings(sensors);
+        let (_, json) = call(&clock, b"GET /api/status HTTP/1.1\r\n\r\n");
+        set_sensor_readings([None; MAX_SENSORS]);
+        assert!(json.contains(",\"sensors\":[{\"rom\":\"021cb801000000a2\",\"temperature_c\":25.06},{\"rom\":\"2800000000000000\",\"temperature_c\":null}],"));
+        assert!(json.contains(",\"thermal\":{\"state\":\"normal\",\"brightness_scale\":255,\"warning_c\":"));
+
+        record_thermal_event(ThermalEvent {
+            at: 80 * S_TO_NANO,
+            temperature: Some(4720),
+            derating: Derating { state: ThermalState::Warning, scale: 208 },
+        });
+        let (_, json) = call(&clock, b"GET /api/status HTTP/1.1\r\n\r\n");
+        clear_thermal_events();
+        assert!(json.contains("\"state\":\"warning\",\"brightness_scale\":208,"));
+        assert!(json.contains(",\"events\":[{\"uptime_s\":80,\"state\":\"warning\",\"brightness_scale\":208,\"temperature_c\":47.20}]},"));
+        assert!(json.contains(",\"clock_drift_ppm\":"));
+        assert!(json.contains(",\"wifi\":"));
+        assert!(json.ends_with("}"));
+
+        let mut centi = Str::new();
+        append_centi(&mut centi, 2106);
+        assert!(centi == Str::with_content(b"21.06"));
+    }
+}
@@ -0,0 +1,734 @@
+//! An interactive console on the usb serial port, which is
+//! otherwise only used for debug output. Every line typed is
+//! turned into a `SystemCommand` and carried out by `execute`,
+//! which answers in plain text. Type `help` for the commands.
+//! Programs can send the same commands as binary frames instead,
+//! see `protocol`.
+
+use teensycore::*;
+use teensycore::clock::uNano;
+use teensycore::math::itoa;
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+use crate::api::append_centi;
+use crate::control::*;
+use crate::drivers::one_wire::rom_hex;
+use crate::hal::*;
+use crate::models::*;
+use crate::protocol::*;
+use crate::strings::*;
+use crate::time_warp::MAX_TIME_WARP_MULTIPLIER;
+use crate::wifi_task::supervisor::WifiState;
+
+/// Anything typed past this is dropped
+const MAX_LINE: usize = 96;
+const PROMPT: &[u8] = b"> ";
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+/// Where the help text starts, so the descriptions line up
+const HELP_COLUMN: usize = 26;
+
+pub const CMD_HELP: [u8; 4] = *b"HELP";
+pub const CMD_STATUS: [u8; 4] = *b"STAT";
+pub const CMD_SHADERS: [u8; 4] = *b"SHLS";
+pub const CMD_EFFECTS: [u8; 4] = *b"EFLS";
+pub const CMD_SHADER: [u8; 4] = *b"SHAD";
+pub const CMD_EFFECT: [u8; 4] = *b"EFCT";
+pub const CMD_TIME: [u8; 4] = *b"TIME";
+pub const CMD_UTC_OFFSET: [u8; 4] = *b"UTCO";
+pub const CMD_BRIGHTNESS: [u8; 4] = *b"BRIT";
+pub const CMD_CONFIGS: [u8; 4] = *b"CONF";
+pub const CMD_RECONNECT: [u8; 4] = *b"WIFI";
+pub const CMD_REBOOT: [u8; 4] = *b"BOOT";
+pub const CMD_SELF_TEST: [u8; 4] = *b"TEST";
+pub const CMD_TIME_WARP: [u8; 4] = *b"WARP";
+
+/// What a command takes after its name.
+#[derive(Copy, Clone, PartialEq)]
+enum ArgKind {
+    None,
+    /// A shader by name, or by its number in `shaders`
+    Shader,
+    /// An effect by name, or by its number in `effects`
+    Effect,
+    Number,
+}
+
+struct CommandInfo {
+    name: &'static [u8],
+    code: [u8; 4],
+    arg: ArgKind,
+    usage: &'static [u8],
+    help: &'static [u8],
+}
+
+const COMMANDS: [CommandInfo; 14] = [
+    CommandInfo { name: b"help", code: CMD_HELP, arg: ArgKind::None, usage: b"help", help: b"Show this list" },
+    CommandInfo { name: b"status", code: CMD_STATUS, arg: ArgKind::None, usage: b"status", help: b"What the wall is doing right now" },
+    CommandInfo { name: b"shaders", code: CMD_SHADERS, arg: ArgKind::None, usage: b"shaders", help: b"List the shaders" },
+    CommandInfo { name: b"effects", code: CMD_EFFECTS, arg: ArgKind::None, usage: b"effects", help: b"List the effects" },
+    CommandInfo { name: b"shader", code: CMD_SHADER, arg: ArgKind::Shader, usage: b"shader <name|number>", help: b"Transition to a shader right away" },
+    CommandInfo { name: b"effect", code: CMD_EFFECT, arg: ArgKind::Effect, usage: b"effect <name|number>", help: b"Replay the current shader with an effect" },
+    CommandInfo { name: b"time", code: CMD_TIME, arg: ArgKind::Number, usage: b"time <epoch seconds>", help: b"Set the world time" },
+    CommandInfo { name: b"warp", code: CMD_TIME_WARP, arg: ArgKind::Number, usage: b"warp <multiplier>", help: b"Run world time faster for 10 minutes, 0 goes back" },
+    CommandInfo { name: b"utc", code: CMD_UTC_OFFSET, arg: ArgKind::Number, usage: b"utc <hours>", help: b"Set how many hours the wall is behind utc (0 - 23)" },
+    CommandInfo { name: b"brightness", code: CMD_BRIGHTNESS, arg: ArgKind::Number, usage: b"brightness <0-255>", help: b"Set the brightness" },
+    CommandInfo { name: b"configs", code: CMD_CONFIGS, arg: ArgKind::None, usage: b"configs", help: b"Show the shader rules from the config" },
+    CommandInfo { name: b"reconnect", code: CMD_RECONNECT, arg: ArgKind::None, usage: b"reconnect", help: b"Rejoin the wifi network" },
+    CommandInfo { name: b"reboot", code: CMD_REBOOT, arg: ArgKind::None, usage: b"reboot", help: b"Save the settings and restart the wall" },
+    CommandInfo { name: b"selftest", code: CMD_SELF_TEST, arg: ArgKind::None, usage: b"selftest", help: b"Check the sensor, shaders, config, clock and wifi" },
+];
+
+/// Why a line couldn't be turned into a command.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ParseError {
+    pub message: &'static [u8],
+    /// How the command is used, once it is known
+    pub usage: Option<&'static [u8]>,
+}
+
+/// Reads lines from a serial port and answers them.
+pub struct ConsoleTask<S: SerialPort, C: Clock> {
+    serial: S,
+    clock: C,
+    line: Str,
+    /// A line ended with \r, so a \n right after is part of it
+    after_cr: bool,
+    reader: FrameReader,
+    server: CommandServer,
+}
+
+impl<S: SerialPort, C: Clock> ConsoleTask<S, C> {
+    pub fn new(serial: S, clock: C) -> Self {
+        return ConsoleTask {
+            serial: serial,
+            clock: clock,
+            line: Str::new(),
+            after_cr: false,
+            reader: FrameReader::new(),
+            server: CommandServer::new(),
+        };
+    }
+
+    pub fn init(&mut self) {
+        self.serial.init();
+        self.serial.write(b"\r\nhexwall console, type help for the commands\r\n");
+        self.serial.write(PROMPT);
+    }
+
+    pub fn system_loop(&mut self) {
+        let input = self.serial.read();
+        if input.len() == 0 {
+            return;
+        }
+
+        let mut received = Str::from_str(input);
+        input.clear();
+        for char in chars(&received) {
+            self.receive_char(char);
+        }
+        received.drop();
+    }
+
+    fn receive_char(&mut self, char: u8) {
+        if self.reader.busy() || char == SYNC[0] {
+            match self.reader.push(char, self.clock.nanos()) {
+                ReadResult::Pending => {},
+                ReadResult::Complete => {
+                    self.server.handle(&self.reader.frame(), &self.clock, &mut self.serial);
+                    self.reader.reset();
+                },
+                ReadResult::Corrupt(seq, reason) => {
+                    self.server.reject(seq, reason, &mut self.serial);
+                }
+            }
+            return;
+        }
+
+        let after_cr = self.after_cr;
+        self.after_cr = char == b'\r';
+
+        match char {
+            b'\n' if after_cr => {},
+            b'\r' | b'\n' => {
+                self.serial.write(b"\r\n");
+                self.run_line();
+                self.line.clear();
+                self.serial.write(PROMPT);
+            },
+            BACKSPACE | DELETE => {
+                if self.line.len() > 0 {
+                    let last = self.line.len() - 1;
+                    remove(&mut self.line, last, 1);
+                    self.serial.write(b"\x08 \x08");
+                }
+            },
+            0x20 ..= 0x7E => {
+                if self.line.len() < MAX_LINE {
+                    self.line.append(&[char]);
+                    self.serial.write(&[char]);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn run_line(&mut self) {
+        match parse_line(&self.line) {
+            Ok(None) => {},
+            Ok(Some(command)) => {
+                match execute(&command, &self.clock, &mut self.serial) {
+                    Ok(()) => {},
+                    Err(message) => {
+                        write_error(&mut self.serial, message);
+                    }
+                }
+            },
+            Err(error) => {
+                write_error(&mut self.serial, error.message);
+                match error.usage {
+                    None => {},
+                    Some(usage) => {
+                        self.serial.write(b"usage: ");
+                        self.serial.write(usage);
+                        self.serial.write(b"\r\n");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Turn a line like `brightness 128` into a command. Names of
+/// shaders and effects become their number in the lists, which
+/// is how commands refer to them. Returns None for a blank line.
+pub fn parse_line(line: &Str) -> Result<Option<SystemCommand>, ParseError> {
+    let mut words = split(line, b' ');
+    let result = parse_words(&words);
+    for mut word in words.into_iter() {
+        word.drop();
+    }
+    words.free();
+    return result;
+}
+
+fn parse_words(words: &Vector<Str>) -> Result<Option<SystemCommand>, ParseError> {
+    let mut words = words.into_iter().filter(|word| word.len() > 0);
+    let name = match words.next() {
+        None => {
+            return Ok(None);
+        },
+        Some(name) => name,
+    };
+
+    let info = match COMMANDS.iter().find(|info| equals_ignore_case(&name, info.name)) {
+        None => {
+            return Err(ParseError { message: b"unknown command, try help", usage: None });
+        },
+        Some(info) => info,
+    };
+    let fail = |message: &'static [u8]| ParseError { message: message, usage: Some(info.usage) };
+
+    let mut command = SystemCommand::with_command(info.code);
+    let arg = words.next();
+    if words.next().is_some() || (info.arg == ArgKind::None && arg.is_some()) {
+        return Err(fail(b"too many arguments"));
+    }
+
+    match (info.arg, arg) {
+        (ArgKind::None, _) => {},
+        (_, None) => {
+            return Err(fail(b"missing argument"));
+        },
+        (ArgKind::Number, Some(arg)) => {
+            command.args[0] = parse_arg(&arg).ok_or(fail(b"expected a number"))?;
+        },
+        (ArgKind::Shader, Some(arg)) => {
+            command.args[0] = parse_arg(&arg)
+                .or(position(&get_shader_names(), &arg))
+                .ok_or(fail(b"no such shader, try shaders"))?;
+        },
+        (ArgKind::Effect, Some(arg)) => {
+            command.args[0] = parse_arg(&arg)
+                .or(position(&get_effect_names(), &arg))
+                .ok_or(fail(b"no such effect, try effects"))?;
+        },
+    }
+    return Ok(Some(command));
+}
+
+fn parse_arg(arg: &Str) -> Option<i32> {
+    return match parse_decimal(arg) {
+        Some(value) if value < UNSET_ARG as u64 => Some(value as i32),
+        _ => None,
+    };
+}
+
+fn position(names: &Vector<&'static [u8]>, name: &Str) -> Option<i32> {
+    return names.into_iter().position(|candidate| equals_ignore_case(name, candidate)).map(|idx| idx as i32);
+}
+
+/// Carry out a command and describe what happened. The
+/// arguments are checked here, since commands don't only come
+/// from the console.
+pub fn execute<C: Clock, O: ByteSink>(command: &SystemCommand, clock: &C, out: &mut O) -> Result<(), &'static [u8]> {
+    match command.command {
+        CMD_HELP => {
+            for info in COMMANDS.iter() {
+                out.write(b"  ");
+                out.write(info.usage);
+                for _ in info.usage.len() .. HELP_COLUMN {
+                    out.write(b" ");
+                }
+                out.write(info.help);
+                out.write(b"\r\n");
+            }
+        },
+        CMD_STATUS => {
+            write_status(clock, out);
+        },
+        CMD_SHADERS => {
+            write_names(out, &get_shader_names(), get_status().shader);
+        },
+        CMD_EFFECTS => {
+            write_names(out, &get_effect_names(), get_status().effect);
+        },
+        CMD_SHADER => {
+            let name = lookup(&get_shader_names(), command.arg(0)).ok_or(b"no such shader" as &[u8])?;
+            queue_command(ControlCommand::ForceShader(name));
+            write_line(out, b"showing ", name);
+        },
+        CMD_EFFECT => {
+            let name = lookup(&get_effect_names(), command.arg(0)).ok_or(b"no such effect" as &[u8])?;
+            queue_command(ControlCommand::ForceEffect(name));
+            write_line(out, b"replaying with ", name);
+        },
+        CMD_TIME => {
+            let time = match command.arg(0) {
+                Some(time) if time >= 0 => time,
+                _ => {
+                    return Err(b"time must be seconds since 1970");
+                }
+            };
+            crate::set_world_time(clock, time as uNano);
+            out.write(b"world time set\r\n");
+        },
+        CMD_TIME_WARP => {
+            let multiplier = match command.arg(0) {
+                Some(multiplier) if multiplier >= 0 && multiplier as uNano <= MAX_TIME_WARP_MULTIPLIER => multiplier,
+                _ => {
+                    return Err(b"multiplier must be 0 - 86400");
+                }
+            };
+            if multiplier == 0 {
+                crate::stop_time_warp();
+                out.write(b"back to real time\r\n");
+            } else {
+                crate::start_time_warp(clock, None, multiplier as uNano, 0);
+                out.write(b"time warp started\r\n");
+            }
+        },
+        CMD_UTC_OFFSET => {
+            let offset = match command.arg(0) {
+                Some(offset) if offset >= 0 && offset < 24 => offset,
+                _ => {
+                    return Err(b"offset must be 0 - 23 hours");
+                }
+            };
+            crate::set_utc_offset(offset as uNano);
+            out.write(b"utc offset set\r\n");
+        },
+        CMD_BRIGHTNESS => {
+            let brightness = match command.arg(0) {
+                Some(brightness) if brightness >= 0 && brightness <= 255 => brightness,
+                _ => {
+                    return Err(b"brightness must be 0 - 255");
+                }
+            };
+            crate::set_brightness(brightness as u8);
+            out.write(b"brightness set\r\n");
+        },
+        CMD_CONFIGS => {
+            write_configs(out);
+        },
+        CMD_RECONNECT => {
+            if get_status().wifi.is_none() {
+                return Err(b"wifi is disabled");
+            }
+            request_wifi_reconnect();
+            out.write(b"reconnecting\r\n");
+        },
+        CMD_REBOOT => {
+            request_reboot();
+            out.write(b"rebooting\r\n");
+        },
+        CMD_SELF_TEST => {
+            self_test(out);
+        },
+        _ => {
+            return Err(b"unknown command");
+        }
+    }
+    return Ok(());
+}
+
+fn lookup(names: &Vector<&'static [u8]>, idx: Option<i32>) -> Option<&'static [u8]> {
+    return match idx {
+        Some(idx) if idx >= 0 => names.get(idx as usize),
+        _ => None,
+    };
+}
+
+fn write_error<O: ByteSink>(out: &mut O, message: &[u8]) {
+    write_line(out, b"error: ", message);
+}
+
+fn write_line<O: ByteSink>(out: &mut O, label: &[u8], value: &[u8]) {
+    out.write(label);
+    out.write(value);
+    out.write(b"\r\n");
+}
+
+fn write_number<O: ByteSink>(out: &mut O, value: u64) {
+    let mut digits = itoa(value);
+    out.write_str(&digits);
+    digits.drop();
+}
+
+fn write_names<O: ByteSink>(out: &mut O, names: &Vector<&'static [u8]>, current: Option<&'static [u8]>) {
+    for (idx, name) in names.into_iter().enumerate() {
+        out.write(if current == Some(name) { b"* " } else { b"  " });
+        if idx < 10 {
+            out.write(b" ");
+        }
+        write_number(out, idx as u64);
+        out.write(b" ");
+        out.write(name);
+        out.write(b"\r\n");
+    }
+}
+
+fn write_status<C: Clock, O: ByteSink>(clock: &C, out: &mut O) {
+    let status = get_status();
+    write_line(out, b"shader: ", status.shader.unwrap_or(b"none"));
+    write_line(out, b"effect: ", status.effect.unwrap_or(b"none"));
+    write_line(out, b"power: ", if crate::get_power() { b"on" } else { b"off" });
+    out.write(b"brightness: ");
+    write_number(out, crate::get_brightness() as u64);
+    out.write(b"\r\ntemperature: ");
+    match status.temperature {
+        None => {
+            out.write(b"unknown");
+        },
+        Some(centi) => {
+            write_centi(out, centi);
+            out.write(b" C");
+        }
+    }
+    for reading in status.sensors.iter().flatten() {
+        out.write(b"\r\n  sensor ");
+        out.write(&rom_hex(reading.rom));
+        out.write(b": ");
+        match reading.temperature {
+            None => {
+                out.write(b"no answer");
+            },
+            Some(centi) => {
+                write_centi(out, centi);
+                out.write(b" C");
+            }
+        }
+    }
+    let thermal_config = crate::get_thermal_config();
+    out.write(b"\r\nthermal: ");
+    out.write(status.thermal.state.name());
+    out.write(b", brightness scale ");
+    write_number(out, status.thermal.scale as u64);
+    out.write(b" (warning ");
+    write_centi(out, thermal_config.warning);
+    out.write(b" C, critical ");
+    write_centi(out, thermal_config.critical);
+    out.write(b" C)");
+    for event in get_thermal_events().iter().flatten() {
+        out.write(b"\r\n  ");
+        write_number(out, (event.at / S_TO_NANO) as u64);
+        out.write(b" s: ");
+        out.write(event.derating.state.name());
+        match event.temperature {
+            None => {
+                out.write(b", no reading");
+            },
+            Some(centi) => {
+                out.write(b" at ");
+                write_centi(out, centi);
+                out.write(b" C");
+            }
+        }
+    }
+    out.write(b"\r\nuptime: ");
+    write_number(out, (clock.nanos() / S_TO_NANO) as u64);
+    out.write(b" s\r\nworld time: ");
+    write_number(out, crate::get_world_time(clock) as u64);
+    out.write(if crate::get_world_clock().synced() { b" (synced)" } else { b" (not synced)" });
+    match crate::get_time_warp(clock) {
+        None => {},
+        Some(warp) => {
+            out.write(b", warped x");
+            write_number(out, warp.multiplier as u64);
+            out.write(b" for ");
+            write_number(out, warp.remaining_s(clock.nanos()) as u64);
+            out.write(b" s");
+        }
+    }
+    out.write(b"\r\nutc offset: ");
+    write_number(out, crate::get_utc_offset() as u64);
+    out.write(b"\r\nwifi: ");
+    match status.wifi {
+        None => {
+            out.write(b"disabled");
+        },
+        Some(wifi) => {
+            out.write(wifi.state.name());
+            match wifi.ip {
+                None => {},
+                Some(ip) => {
+                    let mut address = ip.to_str();
+                    out.write(b" ");
+                    out.write_str(&address);
+                    address.drop();
+                }
+            }
+        }
+    }
+    out.write(b"\r\n");
+}
+
+fn write_centi<O: ByteSink>(out: &mut O, centi: i32) {
+    let mut value = Str::new();
+    append_centi(&mut value, centi);
+    out.write_str(&value);
+    value.drop();
+}
+
+fn write_configs<O: ByteSink>(out: &mut O) {
+    let configs = crate::get_shader_configs();
+    if configs.size() == 0 {
+        out.write(b"no configs loaded\r\n");
+        return;
+    }
+
+    out.write(b"start - end, probability, shader\r\n");
+    for config in configs.configs.into_iter() {
+        write_number(out, config.time_range_start as u64);
+        out.write(b" - ");
+        write_number(out, config.time_range_end as u64);
+        out.write(b", ");
+        write_number(out, config.probability);
+        out.write(b", ");
+        out.write_str(&config.shader);
+        out.write(b"\r\n");
+    }
+}
+
+/// Check what can be checked without help, one line each.
+fn self_test<O: ByteSink>(out: &mut O) {
+    let status = get_status();
+    let mut failures = 0;
+
+    match status.temperature {
+        None => {
+            failures += check(out, false, b"temperature");
+            out.write(b"no reading from the sensor\r\n");
+        },
+        Some(centi) => {
+            // The sensor goes up to 125 C, but the leds give up long before
+            failures += check(out, centi < 8500, b"temperature");
+            write_centi(out, centi);
+            out.write(b" C\r\n");
+        }
+    }
+
+    let shaders = get_shader_names().size();
+    failures += check(out, shaders > 0, b"shaders");
+    write_number(out, shaders as u64);
+    out.write(b" registered\r\n");
+    let effects = get_effect_names().size();
+    failures += check(out, effects > 0, b"effects");
+    write_number(out, effects as u64);
+    out.write(b" registered\r\n");
+
+    let configs = crate::get_shader_configs();
+    let unknown = configs.configs.into_iter().find(|config| find_shader_name(&config.shader).is_none());
+    match unknown {
+        _ if configs.size() == 0 => {
+            failures += check(out, false, b"configs");
+            out.write(b"none loaded\r\n");
+        },
+        None => {
+            failures += check(out, true, b"configs");
+            write_number(out, configs.size() as u64);
+            out.write(b" rules\r\n");
+        },
+        Some(config) => {
+            failures += check(out, false, b"configs");
+            out.write(b"unknown shader ");
+            out.write_str(&config.shader);
+            out.write(b"\r\n");
+        }
+    }
+
+    let synced = crate::get_world_clock().synced();
+    failures += check(out, synced, b"clock");
+    out.write(if synced { b"synced\r\n" } else { b"never synced\r\n" });
+
+    match status.wifi {
+        None => {
+            failures += check(out, true, b"wifi");
+            out.write(b"disabled\r\n");
+        },
+        Some(wifi) => {
+            let connected = wifi.state == WifiState::Idle || wifi.state == WifiState::Fetching;
+            failures += check(out, connected, b"wifi");
+            write_line(out, wifi.state.name(), b"");
+        }
+    }
+
+    if failures == 0 {
+        out.write(b"all passed\r\n");
+    } else {
+        write_number(out, failures);
+        out.write(b" failed\r\n");
+    }
+}
+
+/// Start a line of the self test, which the caller finishes
+/// with the details. Returns 1 on failure, for counting.
+fn check<O: ByteSink>(out: &mut O, passed: bool, name: &[u8]) -> u64 {
+    out.write(if passed { b"ok   " } else { b"FAIL " });
+    out.write(name);
+    out.write(b": ");
+    return if passed { 0 } else { 1 };
+}
+
+#[cfg(test)]
+pub mod test_console {
+    use super::*;
+    use std::prelude::rust_2021::*;
+    use crate::hal::mock::*;
+    use crate::pixel_engine::golden::RENDER_LOCK;
+
+    fn run(console: &mut ConsoleTask<&mut MockSerial, &MockClock>, serial: &MockSerial, input: &[u8]) -> String {
+        serial.receive(input);
+        console.system_loop();
+        return serial.take_written();
+    }
+
+    #[test]
+    fn test_console_parse() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        register_shader(b"Medbay");
+        let parse = |line: &[u8]| parse_line(&Str::with_content(line));
+
+        assert_eq!(parse(b"   "), Ok(None));
+        let command = parse(b"  Brightness   128 ").unwrap().unwrap();
+        assert_eq!(command.command, CMD_BRIGHTNESS);
+        assert_eq!(command.arg(0), Some(128));
+        assert_eq!(command.arg_count(), 1);
+        assert_eq!(parse(b"status").unwrap().unwrap().arg_count(), 0);
+
+        let fx = get_shader_names().into_iter().position(|name| name == b"Medbay").unwrap() as i32;
+        assert_eq!(parse(b"shader medbay").unwrap().unwrap().arg(0), Some(fx));
+        assert_eq!(parse(b"shader 3").unwrap().unwrap().arg(0), Some(3));
+
+        assert_eq!(parse(b"dance").unwrap_err().usage, None);
+        assert_eq!(parse(b"brightness").unwrap_err().message, b"missing argument");
+        assert_eq!(parse(b"brightness high").unwrap_err().message, b"expected a number");
+        assert_eq!(parse(b"brightness 1 2").unwrap_err().message, b"too many arguments");
+        assert_eq!(parse(b"status now").unwrap_err().usage, Some(b"status" as &[u8]));
+        assert_eq!(parse(b"time 2147483647").unwrap_err().message, b"expected a number");
+        assert!(parse(b"shader Medbay2").is_err());
+    }
+
+    #[test]
+    fn test_console_lines() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let mut serial = MockSerial::new();
+        let clock = MockClock::new();
+        let handle = serial.clone();
+        let mut console = ConsoleTask::new(&mut serial, &clock);
+        console.init();
+        assert!(handle.initialized());
+        assert!(handle.take_written().ends_with("> "));
+
+        // Typing is echoed, and backspace takes characters back
+        assert_eq!(run(&mut console, &handle, b"helq\x7Fp"), "helq\x08 \x08p");
+        let help = run(&mut console, &handle, b"\r\n");
+        assert!(help.starts_with("\r\n  help  "));
+        assert!(help.contains("  brightness <0-255>        Set the brightness\r\n"));
+        assert!(help.ends_with("\r\n> "));
+
+        crate::set_brightness(10);
+        assert_eq!(run(&mut console, &handle, b"brightness 64\n"), "brightness 64\r\nbrightness set\r\n> ");
+        assert_eq!(crate::get_brightness(), 64);
+        let error = run(&mut console, &handle, b"brightness 300\r");
+        assert!(error.contains("error: brightness must be 0 - 255\r\n"));
+        let error = run(&mut console, &handle, b"brightness\r");
+        assert!(error.contains("error: missing argument\r\nusage: brightness <0-255>\r\n"));
+        assert_eq!(crate::get_brightness(), 64);
+
+        let status = run(&mut console, &handle, b"status\r");
+        assert!(status.contains("\r\nbrightness: 64\r\n"));
+        assert!(status.contains("\r\npower: on\r\n"));
+        assert!(status.contains("\r\nthermal: normal, brightness scale 255 (warning 45.00 C, critical 60.00 C)\r\n"));
+
+        crate::set_brightness(crate::DEFAULT_BRIGHTNESS);
+    }
+
+    #[test]
+    fn test_console_commands() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let clock = MockClock::new();
+        register_shader(b"Medbay");
+        register_effect(b"Randomized");
+        while next_command().is_some() {}
+
+        let mut out = MockSerial::new();
+        let mut call = |command: [u8; 4], arg: Option<i32>| {
+            let mut system_command = SystemCommand::with_command(command);
+            system_command.args[0] = arg.unwrap_or(UNSET_ARG);
+            let result = execute(&system_command, &clock, &mut out);
+            return (result, out.take_written());
+        };
+
+        let fx = get_shader_names().into_iter().position(|name| name == b"Medbay").unwrap() as i32;
+        let (_, shaders) = call(CMD_SHADERS, None);
+        assert!(shaders.contains(&format!(" {} Medbay\r\n", fx)));
+        assert_eq!(call(CMD_SHADER, Some(fx)), (Ok(()), String::from("showing Medbay\r\n")));
+        assert!(next_command() == Some(ControlCommand::ForceShader(b"Medbay")));
+        assert_eq!(call(CMD_SHADER, Some(9999)).0, Err(b"no such shader" as &[u8]));
+        assert_eq!(call(CMD_EFFECT, Some(-1)).0, Err(b"no such effect" as &[u8]));
+        assert!(next_command().is_none());
+
+        let offset = crate::get_utc_offset();
+        assert_eq!(call(CMD_UTC_OFFSET, Some(7)).0, Ok(()));
+        assert_eq!(crate::get_utc_offset(), 7);
+        assert!(call(CMD_UTC_OFFSET, Some(24)).0.is_err());
+        assert!(call(CMD_UTC_OFFSET, None).0.is_err());
+        crate::set_utc_offset(offset);
+        assert!(call(CMD_TIME, Some(-5)).0.is_err());
+        assert_eq!(call(CMD_TIME_WARP, Some(-1)).0, Err(b"multiplier must be 0 - 86400" as &[u8]));
+        assert!(call(CMD_TIME_WARP, Some(100000)).0.is_err());
+        assert!(call(CMD_TIME_WARP, None).0.is_err());
+        assert_eq!(call(CMD_TIME_WARP, Some(0)), (Ok(()), String::from("back to real time\r\n")));
+        assert!(crate::get_time_warp(&clock).is_none());
+        assert!(call(*b"NOPE", None).0.is_err());
+
+        let (_, report) = call(CMD_SELF_TEST, None);
+        assert!(report.contains("ok   shaders: "));
+        assert!(report.contains(": none loaded\r\n") || report.contains(" rules\r\n"));
+        assert!(report.ends_with(" failed\r\n") || report.ends_with("all passed\r\n"));
+
+        assert_eq!(call(CMD_REBOOT, None), (Ok(()), String::from("rebooting\r\n")));
+        assert!(reboot_requested());
+    }
+}
@@ -0,0 +1,311 @@
+//! Shared state between the pixel task and anything which
+//! controls the wall remotely. Remote interfaces queue up
+//! commands here, and the pixel task carries them out on its
+//! next loop. The pixel task publishes what it is currently
+//! showing in return.
+
+use teensycore::clock::uNano;
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+use crate::pixel_engine::color::Color;
+use crate::pixel_task::LEDS;
+use crate::strings::*;
+use crate::thermal::{Derating, ThermalEvent};
+use crate::wifi_task::supervisor::WifiStats;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ControlCommand {
+    /// Transition to the named shader right away
+    ForceShader(&'static [u8]),
+    /// Replay the current shader through the named effect
+    ForceEffect(&'static [u8]),
+    /// Pick a new shader and effect, as if the transition
+    /// delay had elapsed
+    Randomize,
+}
+
+/// The most temperature sensors which are tracked
+pub const MAX_SENSORS: usize = 8;
+
+/// How many thermal events are kept. Older ones are dropped.
+pub const MAX_THERMAL_EVENTS: usize = 8;
+
+/// The latest reading of one temperature sensor.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SensorReading {
+    pub rom: u64,
+    /// Hundredths of a degree celsius, or None if the sensor
+    /// didn't answer
+    pub temperature: Option<i32>,
+}
+
+/// What the wall is currently showing.
+#[derive(Copy, Clone)]
+pub struct ControlStatus {
+    pub shader: Option<&'static [u8]>,
+    pub effect: Option<&'static [u8]>,
+    /// Hundredths of a degree celsius, from the hottest sensor
+    pub temperature: Option<i32>,
+    /// Every sensor found on the bus
+    pub sensors: [Option<SensorReading>; MAX_SENSORS],
+    /// How much the heat holds the wall back
+    pub thermal: Derating,
+    /// None when wifi is disabled
+    pub wifi: Option<WifiStats>,
+}
+
+/// The latest frame from lighting software, which is shown
+/// instead of the schedule while it is fresh.
+#[derive(Copy, Clone)]
+pub struct StreamFrame {
+    pub colors: [Color; LEDS],
+    pub received_at: uNano,
+    /// The sender stopped, so the wall can fade back right away
+    pub terminated: bool,
+}
+
+static mut COMMANDS: Vector<ControlCommand> = Vector { head: None, size: 0 };
+static mut SHADER_NAMES: Vector<&'static [u8]> = Vector { head: None, size: 0 };
+static mut EFFECT_NAMES: Vector<&'static [u8]> = Vector { head: None, size: 0 };
+static mut STREAM: Option<StreamFrame> = None;
+static mut THERMAL_EVENTS: [Option<ThermalEvent>; MAX_THERMAL_EVENTS] = [None; MAX_THERMAL_EVENTS];
+static mut WIFI_RECONNECT: bool = false;
+static mut REBOOT: bool = false;
+static mut STATUS: ControlStatus = ControlStatus {
+    shader: None,
+    effect: None,
+    temperature: None,
+    sensors: [None; MAX_SENSORS],
+    thermal: Derating::none(),
+    wifi: None,
+};
+
+pub fn queue_command(command: ControlCommand) {
+    unsafe {
+        let mut commands = COMMANDS;
+        commands.enqueue(command);
+        COMMANDS = commands;
+    }
+}
+
+/// Returns the oldest command which has not been handled yet.
+pub fn next_command() -> Option<ControlCommand> {
+    unsafe {
+        let mut commands = COMMANDS;
+        let result = commands.dequeue();
+        COMMANDS = commands;
+        return result;
+    }
+}
+
+/// Make a shader available to remote interfaces.
+pub fn register_shader(name: &'static [u8]) {
+    let mut names = get_shader_names();
+    if find_name(&names, name.iter().copied()).is_none() {
+        names.push(name);
+        unsafe {
+            SHADER_NAMES = names;
+        }
+    }
+}
+
+/// Make an effect available to remote interfaces.
+pub fn register_effect(name: &'static [u8]) {
+    let mut names = get_effect_names();
+    if find_name(&names, name.iter().copied()).is_none() {
+        names.push(name);
+        unsafe {
+            EFFECT_NAMES = names;
+        }
+    }
+}
+
+pub fn get_shader_names() -> Vector<&'static [u8]> {
+    return unsafe { SHADER_NAMES };
+}
+
+pub fn get_effect_names() -> Vector<&'static [u8]> {
+    return unsafe { EFFECT_NAMES };
+}
+
+/// Look up a registered shader, ignoring case.
+pub fn find_shader_name(name: &Str) -> Option<&'static [u8]> {
+    return find_name(&get_shader_names(), chars(name));
+}
+
+/// Look up a registered effect, ignoring case.
+pub fn find_effect_name(name: &Str) -> Option<&'static [u8]> {
+    return find_name(&get_effect_names(), chars(name));
+}
+
+fn find_name<I: Iterator<Item = u8> + Clone>(names: &Vector<&'static [u8]>, name: I) -> Option<&'static [u8]> {
+    for candidate in names.into_iter() {
+        let mut other = name.clone();
+        let matched = candidate.iter().all(|char| {
+            other.next().map_or(false, |next| next.eq_ignore_ascii_case(char))
+        });
+
+        if matched && other.next().is_none() {
+            return Some(candidate);
+        }
+    }
+    return None;
+}
+
+pub fn get_status() -> ControlStatus {
+    return unsafe { STATUS };
+}
+
+pub fn set_current_shader(name: &'static [u8]) {
+    unsafe {
+        STATUS.shader = Some(name);
+    }
+}
+
+pub fn set_current_effect(name: &'static [u8]) {
+    unsafe {
+        STATUS.effect = Some(name);
+    }
+}
+
+/// Publish the latest temperature reading, in hundredths of a
+/// degree celsius.
+pub fn set_temperature(centi_celsius: Option<i32>) {
+    unsafe {
+        STATUS.temperature = centi_celsius;
+    }
+}
+
+/// Publish the latest reading of every sensor.
+pub fn set_sensor_readings(readings: [Option<SensorReading>; MAX_SENSORS]) {
+    unsafe {
+        STATUS.sensors = readings;
+    }
+}
+
+/// Publish a change in derating, and remember it along with
+/// the last few before it.
+pub fn record_thermal_event(event: ThermalEvent) {
+    unsafe {
+        STATUS.thermal = event.derating;
+        let mut events = THERMAL_EVENTS;
+        match events.iter().position(|event| event.is_none()) {
+            None => {
+                events.rotate_left(1);
+                events[MAX_THERMAL_EVENTS - 1] = Some(event);
+            },
+            Some(idx) => {
+                events[idx] = Some(event);
+            }
+        }
+        THERMAL_EVENTS = events;
+    }
+}
+
+/// The most recent thermal events, oldest first.
+pub fn get_thermal_events() -> [Option<ThermalEvent>; MAX_THERMAL_EVENTS] {
+    return unsafe { THERMAL_EVENTS };
+}
+
+/// Forget every thermal event and stop derating.
+pub fn clear_thermal_events() {
+    unsafe {
+        STATUS.thermal = Derating::none();
+        THERMAL_EVENTS = [None; MAX_THERMAL_EVENTS];
+    }
+}
+
+/// Publish how the connection to the network is doing.
+pub fn set_wifi_status(stats: Option<WifiStats>) {
+    unsafe {
+        STATUS.wifi = stats;
+    }
+}
+
+/// Show these colors instead of the schedule.
+pub fn set_stream_frame(colors: [Color; LEDS], now: uNano) {
+    unsafe {
+        STREAM = Some(StreamFrame {
+            colors: colors,
+            received_at: now,
+            terminated: false,
+        });
+    }
+}
+
+/// The sender is done with the wall, so go back to the schedule.
+pub fn end_stream(now: uNano) {
+    unsafe {
+        match STREAM {
+            None => {},
+            Some(mut frame) => {
+                frame.received_at = now;
+                frame.terminated = true;
+                STREAM = Some(frame);
+            }
+        }
+    }
+}
+
+/// Forget the last frame, as if nothing was ever streamed.
+pub fn clear_stream() {
+    unsafe {
+        STREAM = None;
+    }
+}
+
+pub fn get_stream_frame() -> Option<StreamFrame> {
+    return unsafe { STREAM };
+}
+
+/// Ask the wifi task to rejoin the network from scratch.
+pub fn request_wifi_reconnect() {
+    unsafe {
+        WIFI_RECONNECT = true;
+    }
+}
+
+/// Returns true once for every reconnect which was asked for.
+pub fn take_wifi_reconnect() -> bool {
+    unsafe {
+        let result = WIFI_RECONNECT;
+        WIFI_RECONNECT = false;
+        return result;
+    }
+}
+
+/// Ask for the wall to restart once the settings are saved.
+pub fn request_reboot() {
+    unsafe {
+        REBOOT = true;
+    }
+}
+
+pub fn reboot_requested() -> bool {
+    return unsafe { REBOOT };
+}
+
+#[cfg(test)]
+pub mod test_control {
+    use super::*;
+    use teensycore::*;
+    use crate::pixel_engine::golden::RENDER_LOCK;
+
+    #[test]
+    fn test_find_names() {
+        // The pixel task registers names too
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        register_shader(b"Medbay");
+        register_shader(b"Medbay");
+        register_effect(b"Randomized");
+
+        assert_eq!(find_shader_name(&str!(b"medBAY")), Some(b"Medbay" as &[u8]));
+        assert_eq!(find_shader_name(&str!(b"Med")), None);
+        assert_eq!(find_shader_name(&str!(b"Medbay2")), None);
+        assert_eq!(find_effect_name(&str!(b"randomized")), Some(b"Randomized" as &[u8]));
+        assert_eq!(find_effect_name(&str!(b"Medbay")), None);
+
+        let copies = get_shader_names().into_iter().filter(|name| *name == b"Medbay").count();
+        assert_eq!(copies, 1);
+    }
+}
@@ -0,0 +1,96 @@
+//! Checksums used to protect data which lives outside of
+//! the firmware image.
+
+/// Feed more bytes into a running CRC-32 (IEEE 802.3). Start
+/// with `0xFFFF_FFFF` and invert the result when finished, or
+/// just use `crc32` if the data is in one piece.
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = crc;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0 .. 8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    return crc;
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    return !crc32_update(0xFFFF_FFFF, bytes);
+}
+
+/// The Dallas/Maxim CRC-8 (x^8 + x^5 + x^4 + 1) which 1-Wire
+/// devices append to their ROM codes and scratchpads. Running
+/// it over the data and the crc which came with it gives 0.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= *byte;
+        for _ in 0 .. 8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0x8C;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    return crc;
+}
+
+/// The CRC-16 (x^16 + x^15 + x^2 + 1) which 1-Wire devices use
+/// for longer transfers. They send it inverted, least
+/// significant byte first.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= *byte as u16;
+        for _ in 0 .. 8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    return crc;
+}
+
+#[cfg(test)]
+pub mod test_crc {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let partial = crc32_update(0xFFFF_FFFF, b"12345");
+        assert_eq!(!crc32_update(partial, b"6789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_crc8() {
+        assert_eq!(crc8(b""), 0);
+        assert_eq!(crc8(b"123456789"), 0xA1);
+        // A ROM code from the DS18B20 datasheet ends in its crc
+        let rom = [0x02, 0x1C, 0xB8, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(crc8(&rom), 0xA2);
+        assert_eq!(crc8(&[0x02, 0x1C, 0xB8, 0x01, 0x00, 0x00, 0x00, 0xA2]), 0);
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b""), 0);
+        assert_eq!(crc16(b"123456789"), 0xBB3D);
+        // Data followed by its inverted crc always leaves the same
+        let inverted = !crc16(b"123456789");
+        let mut sent = [0; 11];
+        sent[0 .. 9].copy_from_slice(b"123456789");
+        sent[9 ..].copy_from_slice(&inverted.to_le_bytes());
+        assert_eq!(crc16(&sent), 0xB001);
+    }
+}
@@ -1,5 +1,8 @@
 use crate::{get_world_time, get_utc_offset};
+use crate::hal::Clock;
+use crate::strings::chars;
 use teensycore::clock::uNano;
+use teensycore::system::str::*;
 
 /// Contrary to what you might think,
 /// This is an extremely limited interpreation of
@@ -15,8 +18,8 @@ pub struct DateTime {
 }
 
 impl DateTime {
-    pub fn now() -> Self {
-        let unix = get_world_time();
+    pub fn now<C: Clock>(clock: &C) -> Self {
+        let unix = get_world_time(clock);
         let days = unix / 3600;
         let hour = (days - (60 * get_utc_offset())) % 24;
         return DateTime {
@@ -24,4 +27,128 @@ impl DateTime {
             hour: hour,
         };
     }
-}
\ No newline at end of file
+}
+const MONTHS: [&[u8]; 12] = [
+    b"jan", b"feb", b"mar", b"apr", b"may", b"jun",
+    b"jul", b"aug", b"sep", b"oct", b"nov", b"dec",
+];
+
+#[derive(Default)]
+struct DateFields {
+    year: Option<uNano>,
+    month: Option<uNano>,
+    day: Option<uNano>,
+    seconds: Option<uNano>,
+}
+
+/// Parse a date the way http headers and the ESP8266's sntp
+/// client write them, either `Tue, 08 Mar 2022 21:53:41 GMT` or
+/// `Thu Aug 04 14:48:05 2016`. The time is taken to be UTC.
+/// Returns unix epoch seconds.
+pub fn parse_date(content: &Str) -> Option<uNano> {
+    let mut fields = DateFields::default();
+    let mut token = [0u8; 12];
+    let mut len = 0;
+
+    for char in chars(content).chain(core::iter::once(b' ')) {
+        if char == b' ' || char == b',' {
+            if len > 0 {
+                read_date_token(&token[0 .. len], &mut fields)?;
+            }
+            len = 0;
+        } else if len < token.len() {
+            token[len] = char;
+            len += 1;
+        } else {
+            return None;
+        }
+    }
+
+    let year = fields.year?;
+    let month = fields.month?;
+    let day = fields.day?;
+    if year < 1970 || day < 1 || day > 31 {
+        return None;
+    }
+
+    return Some(days_from_civil(year, month, day) * 24 * 60 * 60 + fields.seconds?);
+}
+
+fn read_date_token(token: &[u8], fields: &mut DateFields) -> Option<()> {
+    if token.contains(&b':') {
+        let mut parts = token.split(|char| *char == b':');
+        let hours = read_number(parts.next()?)?;
+        let minutes = read_number(parts.next()?)?;
+        let seconds = read_number(parts.next()?)?;
+        if parts.next().is_some() || hours > 23 || minutes > 59 || seconds > 60 {
+            return None;
+        }
+        fields.seconds = Some(hours * 3600 + minutes * 60 + seconds);
+    } else if token[0].is_ascii_digit() {
+        match token.len() {
+            4 => fields.year = Some(read_number(token)?),
+            1 | 2 => fields.day = Some(read_number(token)?),
+            _ => return None,
+        }
+    } else if token.len() == 3 {
+        // Day names and the timezone don't matter
+        match MONTHS.iter().position(|month| month.eq_ignore_ascii_case(token)) {
+            None => {},
+            Some(idx) => fields.month = Some(idx as uNano + 1),
+        }
+    }
+    return Some(());
+}
+
+fn read_number(digits: &[u8]) -> Option<uNano> {
+    if digits.len() == 0 || digits.len() > 4 {
+        return None;
+    }
+
+    let mut result = 0;
+    for char in digits {
+        if !char.is_ascii_digit() {
+            return None;
+        }
+        result = result * 10 + (char - b'0') as uNano;
+    }
+    return Some(result);
+}
+
+/// Days since the unix epoch for a date in the gregorian
+/// calendar. Only works from 1970 onwards.
+fn days_from_civil(year: uNano, month: uNano, day: uNano) -> uNano {
+    // Count years from March, so the leap day comes last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    return era * 146097 + day_of_era - 719468;
+}
+
+#[cfg(test)]
+pub mod test_date_time {
+    use super::*;
+    use teensycore::*;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date(&str!(b"Tue, 08 Mar 2022 21:53:41 GMT")), Some(1646776421));
+        assert_eq!(parse_date(&str!(b"Thu Aug 04 14:48:05 2016")), Some(1470322085));
+        assert_eq!(parse_date(&str!(b"Thu Aug  4 14:48:05 2016")), Some(1470322085));
+        assert_eq!(parse_date(&str!(b"Thu, 29 Feb 2024 23:59:59 GMT")), Some(1709251199));
+        assert_eq!(parse_date(&str!(b"Thu Jan 01 00:00:00 1970")), Some(0));
+    }
+
+    #[test]
+    fn test_parse_bad_date() {
+        assert_eq!(parse_date(&str!(b"")), None);
+        assert_eq!(parse_date(&str!(b"Tue, 08 Mar 2022")), None);
+        assert_eq!(parse_date(&str!(b"Tue, 08 Foo 2022 21:53:41 GMT")), None);
+        assert_eq!(parse_date(&str!(b"Tue, 08 Mar 2022 25:53:41 GMT")), None);
+        assert_eq!(parse_date(&str!(b"Tue, 08 Mar 2022 21:53 GMT")), None);
+        assert_eq!(parse_date(&str!(b"Tue, 08 Mar 22 21:53:41 GMT")), None);
+        assert_eq!(parse_date(&str!(b"Tuesday, 08-Mar-22 21:53:41 GMT")), None);
+    }
+}
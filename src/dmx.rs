@@ -0,0 +1,279 @@
+//! Lets lighting software like xLights or QLC+ drive the wall
+//! directly. Frames arrive over udp as E1.31 (sACN) or Art-Net,
+//! and a range of channels in one universe is mapped onto the
+//! hex units or onto every led. DDP, which has no universes,
+//! maps its pixels the same way. While frames keep coming, the
+//! pixel task shows them instead of the schedule.
+
+use teensycore::system::str::*;
+use crate::pixel_engine::color::*;
+use crate::pixel_task::{LEDS, LEDS_PER_UNIT};
+use crate::strings::*;
+
+pub mod artnet;
+pub mod ddp;
+pub mod e131;
+
+pub const E131_PORT: u32 = 5568;
+pub const ARTNET_PORT: u32 = 6454;
+pub const CHANNELS: usize = 512;
+/// E1.31 senders restart their sequence numbers, so anything
+/// further back than this counts as new
+const SEQUENCE_WINDOW: i8 = -20;
+
+/// What three channels in a row control.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DmxMapping {
+    /// Every led of a hex unit gets the same color
+    Units,
+    Leds,
+}
+
+impl DmxMapping {
+    pub fn parse(name: &Str) -> Option<DmxMapping> {
+        if equals_ignore_case(name, b"units") {
+            return Some(DmxMapping::Units);
+        } else if equals_ignore_case(name, b"leds") {
+            return Some(DmxMapping::Leds);
+        }
+        return None;
+    }
+
+    /// How many channels the whole wall takes.
+    pub fn channels(&self) -> usize {
+        return match self {
+            DmxMapping::Units => crate::HEX_UNITS * 3,
+            DmxMapping::Leds => LEDS * 3,
+        };
+    }
+}
+
+/// Which protocols the wall listens for. There aren't enough
+/// links on the module for all three.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StreamProtocol {
+    /// E1.31 and Art-Net
+    Dmx,
+    Ddp,
+}
+
+impl StreamProtocol {
+    pub fn parse(name: &Str) -> Option<StreamProtocol> {
+        if equals_ignore_case(name, b"dmx") {
+            return Some(StreamProtocol::Dmx);
+        } else if equals_ignore_case(name, b"ddp") {
+            return Some(StreamProtocol::Ddp);
+        }
+        return None;
+    }
+}
+
+/// Where on the wire the wall listens. The universe is numbered
+/// the way each protocol numbers it, so E1.31 starts at 1 and
+/// Art-Net at 0. DDP ignores the universe and start channel.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct StreamConfig {
+    pub protocol: StreamProtocol,
+    pub universe: u16,
+    /// The first channel, counting from 1
+    pub start_channel: u16,
+    pub mapping: DmxMapping,
+}
+
+impl StreamConfig {
+    /// Returns false if the wall doesn't fit in the universe
+    /// from the start channel on.
+    pub fn is_valid(&self) -> bool {
+        if self.protocol == StreamProtocol::Ddp {
+            return true;
+        }
+        return self.start_channel >= 1
+            && self.start_channel as usize - 1 + self.mapping.channels() <= CHANNELS;
+    }
+}
+
+/// The channels of one universe, pointing into the packet they
+/// came in.
+pub struct DmxPacket<'a> {
+    pub universe: u16,
+    /// None if the sender doesn't number its packets
+    pub sequence: Option<u8>,
+    /// The sender is done and the wall can go back to the
+    /// schedule
+    pub terminated: bool,
+    packet: &'a Str,
+    start: usize,
+    length: usize,
+}
+
+impl<'a> DmxPacket<'a> {
+    /// The value of a channel, counting from 0. Channels the
+    /// packet doesn't have are 0.
+    pub fn channel(&self, idx: usize) -> u8 {
+        if idx >= self.length {
+            return 0;
+        }
+        return byte_at(self.packet, self.start + idx).unwrap_or(0);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.length;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DmxProtocol {
+    E131,
+    ArtNet,
+}
+
+pub enum StreamUpdate {
+    /// A color for every led
+    Frame([Color; LEDS]),
+    /// The sender stopped on purpose
+    Terminated,
+}
+
+/// Turns the packets arriving on one port into frames for the
+/// pixel task.
+pub struct DmxReceiver {
+    protocol: DmxProtocol,
+    last_sequence: Option<u8>,
+}
+
+impl DmxReceiver {
+    pub fn new(protocol: DmxProtocol) -> Self {
+        return DmxReceiver {
+            protocol: protocol,
+            last_sequence: None,
+        };
+    }
+
+    pub fn port(&self) -> u32 {
+        return match self.protocol {
+            DmxProtocol::E131 => E131_PORT,
+            DmxProtocol::ArtNet => ARTNET_PORT,
+        };
+    }
+
+    /// Read a datagram. Returns None for anything which isn't
+    /// for the configured universe, or arrived out of order.
+    pub fn receive(&mut self, datagram: &Str, config: &StreamConfig) -> Option<StreamUpdate> {
+        let packet = match self.protocol {
+            DmxProtocol::E131 => e131::parse(datagram)?,
+            DmxProtocol::ArtNet => artnet::parse(datagram)?,
+        };
+
+        if packet.universe != config.universe {
+            return None;
+        }
+
+        match (packet.sequence, self.last_sequence) {
+            (Some(sequence), Some(last)) => {
+                let age = sequence.wrapping_sub(last) as i8;
+                if age <= 0 && age > SEQUENCE_WINDOW {
+                    return None;
+                }
+            },
+            _ => {},
+        }
+        self.last_sequence = packet.sequence;
+
+        if packet.terminated {
+            self.last_sequence = None;
+            return Some(StreamUpdate::Terminated);
+        }
+        return Some(StreamUpdate::Frame(map_colors(&packet, config)));
+    }
+}
+
+/// Pick the colors for every led out of the channels.
+pub fn map_colors(packet: &DmxPacket, config: &StreamConfig) -> [Color; LEDS] {
+    let mut result = [Color::blank(); LEDS];
+    let start = config.start_channel.max(1) as usize - 1;
+    for led in 0 .. LEDS {
+        let target = match config.mapping {
+            DmxMapping::Units => led / LEDS_PER_UNIT,
+            DmxMapping::Leds => led,
+        };
+        let channel = start + target * 3;
+        result[led] = rgb(packet.channel(channel), packet.channel(channel + 1), packet.channel(channel + 2));
+    }
+    return result;
+}
+
+#[cfg(test)]
+pub mod test_dmx {
+    use super::*;
+    use std::prelude::rust_2021::*;
+
+    /// An Art-Net packet carrying these channels
+    pub fn artnet_packet(universe: u16, sequence: u8, channels: &[u8]) -> Vec<u8> {
+        let mut packet = b"Art-Net\x00\x00\x50\x00\x0e".to_vec();
+        packet.extend_from_slice(&[sequence, 0, (universe & 0xFF) as u8, (universe >> 8) as u8]);
+        packet.extend_from_slice(&(channels.len() as u16).to_be_bytes());
+        packet.extend_from_slice(channels);
+        return packet;
+    }
+
+    #[test]
+    fn test_stream_config() {
+        let units = StreamConfig { protocol: StreamProtocol::Dmx, universe: 1, start_channel: 1, mapping: DmxMapping::Units };
+        assert!(units.is_valid());
+        assert!(StreamConfig { start_channel: 402, ..units }.is_valid());
+        assert!(!StreamConfig { start_channel: 403, ..units }.is_valid());
+        assert!(!StreamConfig { start_channel: 0, ..units }.is_valid());
+        assert!(!StreamConfig { start_channel: 200, mapping: DmxMapping::Leds, ..units }.is_valid());
+        assert!(StreamConfig { protocol: StreamProtocol::Ddp, start_channel: 0, ..units }.is_valid());
+        assert_eq!(StreamProtocol::parse(&Str::with_content(b"DDP")), Some(StreamProtocol::Ddp));
+        assert_eq!(DmxMapping::parse(&Str::with_content(b"LEDs")), Some(DmxMapping::Leds));
+        assert_eq!(DmxMapping::parse(&Str::with_content(b"pixels")), None);
+    }
+
+    #[test]
+    fn test_map_colors() {
+        let mut channels = vec![0u8; 9];
+        channels.extend_from_slice(&[10, 20, 30, 40, 50, 60]);
+        let datagram = Str::with_content(&artnet_packet(0, 0, &channels));
+        let packet = artnet::parse(&datagram).unwrap();
+
+        // The wall starts at channel 10
+        let config = StreamConfig { protocol: StreamProtocol::Dmx, universe: 0, start_channel: 10, mapping: DmxMapping::Units };
+        let colors = map_colors(&packet, &config);
+        for led in 0 .. LEDS_PER_UNIT {
+            assert_eq!(colors[led].as_hex(), rgb(10, 20, 30).as_hex());
+            assert_eq!(colors[LEDS_PER_UNIT + led].as_hex(), rgb(40, 50, 60).as_hex());
+        }
+        // Channels past the end of the packet are dark
+        assert_eq!(colors[2 * LEDS_PER_UNIT].as_hex(), 0);
+
+        let config = StreamConfig { mapping: DmxMapping::Leds, ..config };
+        let colors = map_colors(&packet, &config);
+        assert_eq!(colors[0].as_hex(), rgb(10, 20, 30).as_hex());
+        assert_eq!(colors[1].as_hex(), rgb(40, 50, 60).as_hex());
+        assert_eq!(colors[2].as_hex(), 0);
+    }
+
+    #[test]
+    fn test_receiver_sequence() {
+        let config = StreamConfig { protocol: StreamProtocol::Dmx, universe: 3, start_channel: 1, mapping: DmxMapping::Units };
+        let mut receiver = DmxReceiver::new(DmxProtocol::ArtNet);
+        assert_eq!(receiver.port(), ARTNET_PORT);
+        let mut receive = |universe: u16, sequence: u8| {
+            let datagram = Str::with_content(&artnet_packet(universe, sequence, &[255, 0, 0]));
+            return receiver.receive(&datagram, &config).is_some();
+        };
+
+        assert!(receive(3, 200));
+        assert!(!receive(4, 201));
+        assert!(receive(3, 201));
+        // Late packets are dropped, and ones from long ago mean the sender restarted
+        assert!(!receive(3, 199));
+        assert!(!receive(3, 201));
+        assert!(receive(3, 1));
+        assert!(receive(3, 150));
+        // Zero means the sender doesn't count
+        assert!(receive(3, 0));
+        assert!(receive(3, 0));
+    }
+}
@@ -0,0 +1,68 @@
+//! Reading ArtDmx packets, which is how Art-Net carries
+//! channels. Everything else Art-Net sends, like polls, is
+//! ignored.
+
+use teensycore::system::str::*;
+use crate::dmx::*;
+
+const ID: &[u8] = b"Art-Net\x00";
+/// OpDmx, sent low byte first
+const OP_DMX: u16 = 0x5000;
+const HEADER_SIZE: usize = 18;
+
+pub fn parse(datagram: &Str) -> Option<DmxPacket<'_>> {
+    if datagram.len() < HEADER_SIZE || !starts_with(datagram, ID) {
+        return None;
+    }
+
+    let byte = |idx: usize| byte_at(datagram, idx).unwrap_or(0);
+    let opcode = u16::from_le_bytes([byte(8), byte(9)]);
+    if opcode != OP_DMX {
+        return None;
+    }
+
+    let length = u16::from_be_bytes([byte(16), byte(17)]) as usize;
+    let sequence = byte(12);
+    return Some(DmxPacket {
+        // 15 bits of net, sub-net and universe
+        universe: ((byte(15) as u16 & 0x7F) << 8) | byte(14) as u16,
+        sequence: match sequence {
+            0 => None,
+            sequence => Some(sequence),
+        },
+        terminated: false,
+        packet: datagram,
+        start: HEADER_SIZE,
+        length: length.min(CHANNELS).min(datagram.len() - HEADER_SIZE),
+    });
+}
+
+#[cfg(test)]
+pub mod test_artnet {
+    use super::*;
+    use crate::dmx::test_dmx::artnet_packet;
+
+    #[test]
+    fn test_parse_artnet() {
+        let datagram = Str::with_content(&artnet_packet(0x0123, 7, &[1, 2, 3, 4]));
+        let packet = parse(&datagram).unwrap();
+        assert_eq!(packet.universe, 0x0123);
+        assert_eq!(packet.sequence, Some(7));
+        assert!(!packet.terminated);
+        assert_eq!(packet.len(), 4);
+        assert_eq!(packet.channel(0), 1);
+        assert_eq!(packet.channel(3), 4);
+        assert_eq!(packet.channel(4), 0);
+
+        // The length can't go past the end of the datagram
+        let mut short = artnet_packet(0, 0, &[9, 9]);
+        short[17] = 200;
+        let datagram = Str::with_content(&short);
+        assert_eq!(parse(&datagram).unwrap().len(), 2);
+
+        // ArtPoll
+        assert!(parse(&Str::with_content(b"Art-Net\x00\x00\x20\x00\x0e\x00\x00\x00\x00\x00\x00\x00\x00")).is_none());
+        assert!(parse(&Str::with_content(b"Art-Net\x00\x00\x50")).is_none());
+        assert!(parse(&Str::with_content(b"ASC-E1.17\x00\x00\x00\x00\x00\x00\x00\x00\x00")).is_none());
+    }
+}
@@ -0,0 +1,170 @@
+//! Reading DDP (Distributed Display Protocol) pixel data, which
+//! is what WLED and most pixel mapping software send. Unlike
+//! E1.31 and Art-Net there are no universes: each packet writes
+//! a run of pixels at an offset, and the last packet of a frame
+//! asks for it to be shown.
+
+use teensycore::system::str::*;
+use crate::dmx::*;
+
+pub const DDP_PORT: u32 = 4048;
+const HEADER_SIZE: usize = 10;
+// Flags in the first byte
+const VERSION_MASK: u8 = 0xC0;
+const VERSION_1: u8 = 0x40;
+const TIMECODE: u8 = 0x10;
+const QUERY: u8 = 0x02;
+const PUSH: u8 = 0x01;
+/// Type 3 in bits 3-5 means rgbw
+const DATA_TYPE_RGBW: u8 = 0x03;
+// Where the data goes
+const ID_DISPLAY: u8 = 1;
+const ID_ALL: u8 = 255;
+
+/// Builds up a frame from the packets which carry it.
+pub struct DdpReceiver {
+    frame: [Color; LEDS],
+}
+
+impl DdpReceiver {
+    pub fn new() -> Self {
+        return DdpReceiver {
+            frame: [rgb(0, 0, 0); LEDS],
+        };
+    }
+
+    pub fn port(&self) -> u32 {
+        return DDP_PORT;
+    }
+
+    /// Read a datagram. Returns the whole frame once a packet
+    /// asks for it to be shown, and None until then.
+    pub fn receive(&mut self, datagram: &Str, mapping: DmxMapping) -> Option<StreamUpdate> {
+        let byte = |idx: usize| byte_at(datagram, idx).unwrap_or(0);
+        let flags = byte(0);
+        if datagram.len() < HEADER_SIZE || flags & VERSION_MASK != VERSION_1 || flags & QUERY != 0 {
+            return None;
+        }
+
+        let id = byte(3);
+        if id != ID_DISPLAY && id != ID_ALL {
+            return None;
+        }
+
+        let pixel_size = match (byte(2) >> 3) & 0x07 {
+            DATA_TYPE_RGBW => 4,
+            _ => 3,
+        };
+        let offset = u32::from_be_bytes([byte(4), byte(5), byte(6), byte(7)]) as usize;
+        let length = u16::from_be_bytes([byte(8), byte(9)]) as usize;
+        let start = match flags & TIMECODE {
+            0 => HEADER_SIZE,
+            _ => HEADER_SIZE + 4,
+        };
+        let length = length.min(datagram.len().saturating_sub(start));
+
+        // Offsets count bytes, so a pixel can start in one
+        // packet and end in the next. They come straight off the
+        // network, so one near the top can't be trusted to add up.
+        for idx in 0 .. length {
+            let position = match offset.checked_add(idx) {
+                None => break,
+                Some(position) => position,
+            };
+            let pixel = position / pixel_size;
+            let targets = match mapping {
+                DmxMapping::Units if pixel < crate::HEX_UNITS => pixel * LEDS_PER_UNIT .. (pixel + 1) * LEDS_PER_UNIT,
+                DmxMapping::Leds if pixel < LEDS => pixel .. pixel + 1,
+                _ => break,
+            };
+
+            let value = byte(start + idx);
+            for led in targets {
+                let color = &mut self.frame[led];
+                match position % pixel_size {
+                    0 => color.r = value,
+                    1 => color.g = value,
+                    2 => color.b = value,
+                    _ => {},
+                }
+            }
+        }
+
+        if flags & PUSH == 0 {
+            return None;
+        }
+        return Some(StreamUpdate::Frame(self.frame));
+    }
+}
+
+#[cfg(test)]
+pub mod test_ddp {
+    use super::*;
+    use std::prelude::rust_2021::*;
+
+    /// A DDP packet writing these bytes at an offset
+    pub fn ddp_packet(push: bool, offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![VERSION_1 | if push { PUSH } else { 0 }, 1, 0x0B, ID_DISPLAY];
+        packet.extend_from_slice(&offset.to_be_bytes());
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+        return packet;
+    }
+
+    fn frame(update: Option<StreamUpdate>) -> [Color; LEDS] {
+        return match update {
+            Some(StreamUpdate::Frame(colors)) => colors,
+            _ => std::panic!("expected a frame"),
+        };
+    }
+
+    #[test]
+    fn test_ddp_frames() {
+        let mut receiver = DdpReceiver::new();
+        assert_eq!(receiver.port(), DDP_PORT);
+
+        // The frame only shows once it is pushed, and the second
+        // pixel is split between the packets
+        let first = Str::with_content(&ddp_packet(false, 0, &[255, 0, 0, 0]));
+        assert!(receiver.receive(&first, DmxMapping::Leds).is_none());
+        let second = Str::with_content(&ddp_packet(true, 4, &[255, 0, 0, 0, 255]));
+        let colors = frame(receiver.receive(&second, DmxMapping::Leds));
+        assert_eq!(colors[0].as_hex(), rgb(255, 0, 0).as_hex());
+        assert_eq!(colors[1].as_hex(), rgb(0, 255, 0).as_hex());
+        assert_eq!(colors[2].as_hex(), rgb(0, 0, 255).as_hex());
+        assert_eq!(colors[3].as_hex(), 0);
+
+        // Every led of a unit shares its pixel
+        let mut receiver = DdpReceiver::new();
+        let packet = Str::with_content(&ddp_packet(true, 3, &[1, 2, 3]));
+        let colors = frame(receiver.receive(&packet, DmxMapping::Units));
+        assert_eq!(colors[LEDS_PER_UNIT - 1].as_hex(), 0);
+        for led in LEDS_PER_UNIT .. 2 * LEDS_PER_UNIT {
+            assert_eq!(colors[led].as_hex(), rgb(1, 2, 3).as_hex());
+        }
+
+        // Pixels past the end of the wall are dropped
+        let packet = Str::with_content(&ddp_packet(true, (LEDS * 3) as u32, &[9, 9, 9]));
+        assert!(receiver.receive(&packet, DmxMapping::Leds).is_some());
+        let packet = Str::with_content(&ddp_packet(true, u32::MAX - 1, &[9, 9, 9]));
+        let colors = frame(receiver.receive(&packet, DmxMapping::Leds));
+        assert!(colors.iter().all(|color| color.as_hex() != rgb(9, 9, 9).as_hex()));
+    }
+
+    #[test]
+    fn test_ddp_ignored() {
+        let mut receiver = DdpReceiver::new();
+        let mut query = ddp_packet(true, 0, &[]);
+        query[0] |= QUERY;
+        assert!(receiver.receive(&Str::with_content(&query), DmxMapping::Leds).is_none());
+
+        let mut config = ddp_packet(true, 0, b"{}");
+        config[3] = 250;
+        assert!(receiver.receive(&Str::with_content(&config), DmxMapping::Leds).is_none());
+
+        let mut version_2 = ddp_packet(true, 0, &[1, 2, 3]);
+        version_2[0] = 0x80 | PUSH;
+        assert!(receiver.receive(&Str::with_content(&version_2), DmxMapping::Leds).is_none());
+        assert!(receiver.receive(&Str::with_content(b"\x41\x01"), DmxMapping::Leds).is_none());
+    }
+}
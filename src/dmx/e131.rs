@@ -0,0 +1,105 @@
+//! Reading E1.31 (streaming ACN) data packets. The wall only
+//! listens, so discovery and synchronization packets are
+//! ignored.
+
+use teensycore::system::str::*;
+use crate::dmx::*;
+
+const PACKET_ID: &[u8] = b"\x00\x10\x00\x00ASC-E1.17\x00\x00\x00";
+const VECTOR_ROOT_DATA: u32 = 0x04;
+const VECTOR_FRAMING_DATA: u32 = 0x02;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+// Framing options
+const PREVIEW_DATA: u8 = 0x80;
+const STREAM_TERMINATED: u8 = 0x40;
+/// Where the start code is. The channels follow it.
+const START_CODE: usize = 125;
+
+pub fn parse(datagram: &Str) -> Option<DmxPacket<'_>> {
+    if datagram.len() <= START_CODE || !starts_with(datagram, PACKET_ID) {
+        return None;
+    }
+
+    let byte = |idx: usize| byte_at(datagram, idx).unwrap_or(0);
+    let vector = |idx: usize| u32::from_be_bytes([byte(idx), byte(idx + 1), byte(idx + 2), byte(idx + 3)]);
+    if vector(18) != VECTOR_ROOT_DATA || vector(40) != VECTOR_FRAMING_DATA || byte(117) != VECTOR_DMP_SET_PROPERTY {
+        return None;
+    }
+
+    // Meant for visualizers, not for the lights themselves
+    let options = byte(112);
+    if options & PREVIEW_DATA != 0 {
+        return None;
+    }
+
+    // Other start codes carry things like per channel priorities
+    if byte(START_CODE) != 0 {
+        return None;
+    }
+
+    // The count includes the start code
+    let count = u16::from_be_bytes([byte(123), byte(124)]) as usize;
+    return Some(DmxPacket {
+        universe: u16::from_be_bytes([byte(113), byte(114)]),
+        sequence: Some(byte(111)),
+        terminated: options & STREAM_TERMINATED != 0,
+        packet: datagram,
+        start: START_CODE + 1,
+        length: count.saturating_sub(1).min(CHANNELS).min(datagram.len() - START_CODE - 1),
+    });
+}
+
+#[cfg(test)]
+pub mod test_e131 {
+    use super::*;
+    use std::prelude::rust_2021::*;
+
+    /// An E1.31 data packet carrying these channels
+    pub fn e131_packet(universe: u16, sequence: u8, options: u8, channels: &[u8]) -> Vec<u8> {
+        let mut packet = PACKET_ID.to_vec();
+        let flags_length = |length: usize| (0x7000 | length as u16).to_be_bytes();
+        packet.extend_from_slice(&flags_length(110 + channels.len()));
+        packet.extend_from_slice(&VECTOR_ROOT_DATA.to_be_bytes());
+        packet.extend_from_slice(&[0xAB; 16]);
+        packet.extend_from_slice(&flags_length(88 + channels.len()));
+        packet.extend_from_slice(&VECTOR_FRAMING_DATA.to_be_bytes());
+        let mut source = b"xLights".to_vec();
+        source.resize(64, 0);
+        packet.extend_from_slice(&source);
+        packet.extend_from_slice(&[100, 0, 0, sequence, options]);
+        packet.extend_from_slice(&universe.to_be_bytes());
+        packet.extend_from_slice(&flags_length(11 + channels.len()));
+        packet.extend_from_slice(&[VECTOR_DMP_SET_PROPERTY, 0xA1, 0, 0, 0, 1]);
+        packet.extend_from_slice(&(channels.len() as u16 + 1).to_be_bytes());
+        packet.push(0);
+        packet.extend_from_slice(channels);
+        return packet;
+    }
+
+    #[test]
+    fn test_parse_e131() {
+        let datagram = Str::with_content(&e131_packet(1, 42, 0, &[255, 128, 0]));
+        let packet = parse(&datagram).unwrap();
+        assert_eq!(packet.universe, 1);
+        assert_eq!(packet.sequence, Some(42));
+        assert!(!packet.terminated);
+        assert_eq!(packet.len(), 3);
+        assert_eq!(packet.channel(1), 128);
+
+        let datagram = Str::with_content(&e131_packet(1, 43, STREAM_TERMINATED, &[0]));
+        assert!(parse(&datagram).unwrap().terminated);
+        let datagram = Str::with_content(&e131_packet(1, 44, PREVIEW_DATA, &[0]));
+        assert!(parse(&datagram).is_none());
+
+        // Per channel priorities
+        let mut priorities = e131_packet(1, 45, 0, &[100]);
+        priorities[START_CODE] = 0xDD;
+        assert!(parse(&Str::with_content(&priorities)).is_none());
+
+        // Universe discovery
+        let mut discovery = e131_packet(1, 46, 0, &[0]);
+        discovery[43] = 0x08;
+        assert!(parse(&Str::with_content(&discovery)).is_none());
+        assert!(parse(&Str::with_content(&e131_packet(1, 47, 0, &[])[0 .. 100])).is_none());
+    }
+}
@@ -1,3 +1,4 @@
 pub mod ws2812;
+pub mod one_wire;
 pub mod max31820;
 pub mod esp8266;
\ No newline at end of file
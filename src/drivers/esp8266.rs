@@ -1,12 +1,20 @@
 //! This module is a driver for the ESP8266 WiFi
 //! peripheral. It includes wrappers for most
-//! of the AT+ instruction set.
+//! of the AT+ instruction set. The wrappers only write
+//! the command, so they work just as well on a serial
+//! port as on an `AtCommand` waiting in the queue.
 
-use teensycore::*;
-use teensycore::serio::*;
-use teensycore::clock::*;
 use teensycore::math::*;
 use teensycore::system::str::*;
+use crate::hal::*;
+use crate::network::Ipv4;
+use crate::strings::chars;
+
+pub mod parser;
+pub mod queue;
+
+#[cfg(feature = "testing")]
+pub mod emulator;
 
 pub enum WifiMode {
     Client = 0x1,
@@ -16,152 +24,194 @@ pub enum WifiMode {
 
 /// The AT command checks whether the system is
 /// in a healthy state.
-pub fn esp8266_at(device: SerioDevice) {
-    esp8266_raw(device, b"AT");
+pub fn esp8266_at<S: ByteSink>(serial: &mut S) {
+    esp8266_raw(serial, b"AT");
 }
 
 /// Sends the AT+RST reset command, causing
 /// the system to do a software-level
 /// reboot.
-pub fn esp8266_reset(device: SerioDevice) {
-    esp8266_raw(device, b"AT+RST");
+pub fn esp8266_reset<S: ByteSink>(serial: &mut S) {
+    esp8266_raw(serial, b"AT+RST");
 }
 
 /// Configures the ESP8266 to either send back
 /// received commands, or not.
-pub fn esp8266_configure_echo(device: SerioDevice, enabled: bool) {
+pub fn esp8266_configure_echo<S: ByteSink>(serial: &mut S, enabled: bool) {
     match enabled {
         true => {
-            esp8266_raw(device, b"ATE1");
+            esp8266_raw(serial, b"ATE1");
         },
         false => {
-            esp8266_raw(device, b"ATE0");
+            esp8266_raw(serial, b"ATE0");
         }
     }
 }
 
-pub fn esp8266_dhcp_mode(device: SerioDevice, mode: WifiMode, dhcp_enabled: bool) {
-    // serial_write(device, b"AT+CWDHCP=1");
-    serial_write_str(device, &itoa((mode as u64) - 1));
-    serial_write(device, b",");
+/// Turn dhcp on or off for the station, the soft access
+/// point or both. Setting a static address turns it off by
+/// itself.
+pub fn esp8266_dhcp_mode<S: ByteSink>(serial: &mut S, mode: WifiMode, dhcp_enabled: bool) {
+    serial.write(b"AT+CWDHCP=");
+    match mode {
+        WifiMode::Host => serial.write(b"0,"),
+        WifiMode::Client => serial.write(b"1,"),
+        WifiMode::All => serial.write(b"2,"),
+    }
     match dhcp_enabled {
         true => {
-            serial_write(device, b"0\r\n");
+            serial.write(b"1\r\n");
         },
         false => {
-            serial_write(device, b"1\r\n");
+            serial.write(b"0\r\n");
         }
     }
 }
 
 /// Configure the ESP8266 to either be a client,
 /// a host, or both.
-pub fn esp8266_wifi_mode(device: SerioDevice, mode: WifiMode) {
-    serial_write(device, b"AT+CWMODE=");
-    serial_write(device, &[int_to_hex(mode as u8)]);
-    serial_write(device, b"\r\n");
+pub fn esp8266_wifi_mode<S: ByteSink>(serial: &mut S, mode: WifiMode) {
+    serial.write(b"AT+CWMODE=");
+    serial.write(&[int_to_hex(mode as u8)]);
+    serial.write(b"\r\n");
 }
 
 /// Connect to a wifi access point.
-pub fn esp8266_connect_to_wifi(device: SerioDevice, ssid: &Str, pwd: &Str) {
-    serial_write(device, b"AT+CWJAP=\"");
-    serial_write_str(device, &ssid);
-    serial_write(device, b"\",\"");
-    serial_write_str(device, &pwd);
-    serial_write(device, b"\"\r\n");
+pub fn esp8266_connect_to_wifi<S: ByteSink>(serial: &mut S, ssid: &Str, pwd: &Str) {
+    serial.write(b"AT+CWJAP=\"");
+    write_escaped(serial, &ssid);
+    serial.write(b"\",\"");
+    write_escaped(serial, &pwd);
+    serial.write(b"\"\r\n");
+}
+
+/// Write text which goes between quotes. The module expects
+/// quotes, commas and backslashes in it to be escaped.
+fn write_escaped<S: ByteSink>(serial: &mut S, text: &Str) {
+    for char in chars(text) {
+        match char {
+            b'"' | b',' | b'\\' => serial.write(&[b'\\', char]),
+            _ => serial.write(&[char]),
+        }
+    }
 }
 
 /// Disconnect from any currently active access point
-pub fn esp8266_disconnect_from_wifi(device: SerioDevice) {
-    esp8266_raw(device, b"AT+CWQAP");
+pub fn esp8266_disconnect_from_wifi<S: ByteSink>(serial: &mut S) {
+    esp8266_raw(serial, b"AT+CWQAP");
 }
 
 /// Given a domain, this command will return the ip address
-pub fn esp8266_dns_lookup(device: SerioDevice, domain: &Str) {
-    serial_write(device, b"AT+CIPDOMAIN=\"");
-    serial_write_str(device, &domain);
-    serial_write(device, b"\"\r\n");
+pub fn esp8266_dns_lookup<S: ByteSink>(serial: &mut S, domain: &Str) {
+    serial.write(b"AT+CIPDOMAIN=\"");
+    serial.write_str(&domain);
+    serial.write(b"\"\r\n");
 }
 
 /// Esetablish a TCP connection
-pub fn esp8266_open_tcp(device: SerioDevice, domain: &Str, port: u32, id: Option<u8>) {
+pub fn esp8266_open_tcp<S: ByteSink>(serial: &mut S, domain: &Str, port: u32, id: Option<u8>) {
     match id {
         None => {
-            serial_write(device, b"AT+CIPSTART=\"TCP\",\"");
+            serial.write(b"AT+CIPSTART=\"TCP\",\"");
         },
         Some(con_id) => {
-            serial_write(device, b"AT+CIPSTART=");
-            serial_write_str(device, &itoa(con_id as u64));
-            serial_write(device, b",\"");
+            serial.write(b"AT+CIPSTART=");
+            serial.write_str(&itoa(con_id as u64));
+            serial.write(b",\"TCP\",\"");
         }
     }
-    serial_write_str(device, &domain);
-    serial_write(device, b"\",");
-    serial_write_str(device, &itoa(port as u64));
-    serial_write(device, b"\r\n");
+    serial.write_str(&domain);
+    serial.write(b"\",");
+    serial.write_str(&itoa(port as u64));
+    serial.write(b"\r\n");
 }
 
-pub fn esp8266_version(device: SerioDevice) {
-    esp8266_raw(device, b"AT+GMR");
+/// Receive datagrams sent to the port from anywhere.
+pub fn esp8266_listen_udp<S: ByteSink>(serial: &mut S, id: u8, port: u32) {
+    serial.write(b"AT+CIPSTART=");
+    serial.write_str(&itoa(id as u64));
+    serial.write(b",\"UDP\",\"0.0.0.0\",");
+    serial.write_str(&itoa(port as u64));
+    serial.write(b",");
+    serial.write_str(&itoa(port as u64));
+    serial.write(b",2\r\n");
 }
 
-/// Write content over TCP/UDP
-pub fn esp8266_write(device: SerioDevice, content: &Str, id: Option<u8>) {
-    serial_write(device, b"AT+CIPSEND=");
+pub fn esp8266_version<S: ByteSink>(serial: &mut S) {
+    esp8266_raw(serial, b"AT+GMR");
+}
 
+/// Close active TCP connection
+pub fn esp8266_close_tcp<S: ByteSink>(serial: &mut S, id: Option<u8>) {
+    serial.write(b"AT+CIPCLOSE");
     match id {
         None => {
-
+            serial.write(b"\r\n");
         },
         Some(con_id) => {
-            serial_write_str(device, &itoa(con_id as u64));
-            serial_write(device, b",");
+            serial.write(b"=");
+            serial.write_str(&itoa(con_id as u64));
+            serial.write(b"\r\n");
         }
     }
+}
 
-    serial_write_str(device, &itoa(content.len() as u64));
-    serial_write(device, b"\r\n");
-    // TODO: would be great to not block at all...
-    esp8266_block_until(device, b"OK", S_TO_NANO);
-    serial_write_str(device, &content);
-    serial_write(device, b"\r\n");
+/// Get the devices ip address
+pub fn esp8266_read_ip<S: ByteSink>(serial: &mut S) {
+    esp8266_raw(serial, b"AT+CIFSR");
 }
 
-/// Close active TCP connection
-pub fn esp8266_close_tcp(device: SerioDevice, id: Option<u8>) {
-    serial_write(device, b"AT+CIPCLOSE");
-    match id {
-        None => {
-            serial_write(device, b"\r\n");
-        },
-        Some(con_id) => {
-            serial_write(device, b"=");
-            serial_write_str(device, &itoa(con_id as u64));
-            serial_write(device, b"\r\n");
+/// Set the address of the soft access point
+pub fn esp8266_set_ip<S: ByteSink>(serial: &mut S, ip: Str) {
+    serial.write(b"AT+CIPAP=\"");
+    serial.write_str(&ip);
+    serial.write(b"\"\r\n");
+}
+
+/// Give the station a static address
+pub fn esp8266_station_ip<S: ByteSink>(serial: &mut S, ip: Ipv4, gateway: Ipv4, netmask: Ipv4) {
+    serial.write(b"AT+CIPSTA=");
+    for (idx, address) in [ip, gateway, netmask].iter().enumerate() {
+        if idx > 0 {
+            serial.write(b",");
         }
+        write_address(serial, address);
     }
+    serial.write(b"\r\n");
 }
 
-/// Get the devices ip address
-pub fn esp8266_read_ip(device: SerioDevice) {
-    esp8266_raw(device, b"AT+CIFSR");
+/// Use this name server, or go back to the ones the module
+/// picks by itself
+pub fn esp8266_dns<S: ByteSink>(serial: &mut S, server: Option<Ipv4>) {
+    match server {
+        None => {
+            esp8266_raw(serial, b"AT+CIPDNS=0");
+        },
+        Some(server) => {
+            serial.write(b"AT+CIPDNS=1,");
+            write_address(serial, &server);
+            serial.write(b"\r\n");
+        }
+    }
 }
 
-pub fn esp8266_set_ip(device: SerioDevice, ip: Str) {
-    serial_write(device, b"AT+CIPAP=\"");
-    serial_write_str(device, &ip);
-    serial_write(device, b"\"\r\n");
+fn write_address<S: ByteSink>(serial: &mut S, address: &Ipv4) {
+    let mut text = address.to_str();
+    serial.write(b"\"");
+    serial.write_str(&text);
+    serial.write(b"\"");
+    text.drop();
 }
 
 /// Set whether the device will automatically attempt to reconnect
 /// to the AP on boot.
-pub fn esp8266_auto_connect(device: SerioDevice, auto_connect: bool) {
+pub fn esp8266_auto_connect<S: ByteSink>(serial: &mut S, auto_connect: bool) {
     match auto_connect {
         true => {
-            esp8266_raw(device, b"AT+CWAUTOCONN=1");
+            esp8266_raw(serial, b"AT+CWAUTOCONN=1");
         },
         false => {
-            esp8266_raw(device, b"AT+CWAUTOCONN=0");
+            esp8266_raw(serial, b"AT+CWAUTOCONN=0");
         },
     }
 }
@@ -169,54 +219,80 @@ pub fn esp8266_auto_connect(device: SerioDevice, auto_connect: bool) {
 /// This muxes the device to either allow or disallow multiple connections.
 /// If multiple connections are allowed, you'll need to be cognizant
 /// of that when interfacing with some of the other commands.
-pub fn esp8266_multiple_connections(device: SerioDevice, allow: bool) {
+pub fn esp8266_multiple_connections<S: ByteSink>(serial: &mut S, allow: bool) {
     match allow {
         true => {
-            esp8266_raw(device, b"AT+CIPMUX=1");
+            esp8266_raw(serial, b"AT+CIPMUX=1");
         },
         false => {
             // This may require a reboot to work as intended
-            esp8266_raw(device, b"AT+CIPMUX=0");
+            esp8266_raw(serial, b"AT+CIPMUX=0");
         }
     }
 }
 
-pub fn esp8266_create_server(device: SerioDevice, port: u32) {
-    serial_write(device, b"AT+CIPSERVER=1,");
-    serial_write_str(device, &itoa(port as u64));
-    serial_write(device, b"\r\n");
+pub fn esp8266_create_server<S: ByteSink>(serial: &mut S, port: u32) {
+    serial.write(b"AT+CIPSERVER=1,");
+    serial.write_str(&itoa(port as u64));
+    serial.write(b"\r\n");
 }
 
-/// Set the server timeout in seconds
-pub fn esp8266_set_server_timeout(device: SerioDevice, timeout: u32) {
-    serial_write(device, b"AT+CIPSTO=");
-    serial_write_str(device, &itoa(timeout as u64));
-    serial_write(device, b"\r\n");
+/// Limit how many clients the server accepts at once, which
+/// leaves the remaining connections free for outgoing requests.
+/// This has to happen before the server is created.
+pub fn esp8266_server_max_connections<S: ByteSink>(serial: &mut S, count: u8) {
+    serial.write(b"AT+CIPSERVERMAXCONN=");
+    serial.write_str(&itoa(count as u64));
+    serial.write(b"\r\n");
 }
 
-pub fn esp8266_list_wifi(device: SerioDevice) {
-    esp8266_raw(device, b"AT+CWLAP");
+/// Set the server timeout in seconds
+pub fn esp8266_set_server_timeout<S: ByteSink>(serial: &mut S, timeout: u32) {
+    serial.write(b"AT+CIPSTO=");
+    serial.write_str(&itoa(timeout as u64));
+    serial.write(b"\r\n");
 }
 
-pub fn esp8266_block_until(device: SerioDevice, command: &[u8], timeout: uNano) {
-    let threshold = nanos() + timeout;
-    let cmd = str!(command);
+/// Configure the soft access point. An empty password
+/// creates an open network.
+pub fn esp8266_configure_ap<S: ByteSink>(serial: &mut S, ssid: &Str, pwd: &Str, channel: u8) {
+    serial.write(b"AT+CWSAP=\"");
+    write_escaped(serial, &ssid);
+    serial.write(b"\",\"");
+    write_escaped(serial, &pwd);
+    serial.write(b"\",");
+    serial.write_str(&itoa(channel as u64));
+    match pwd.len() {
+        0 => serial.write(b",0\r\n"),
+        // WPA2_PSK
+        _ => serial.write(b",3\r\n"),
+    }
+}
 
-    loop {
-        let buf = serial_read(device);
-        if buf.contains(&cmd) {
-            buf.clear();
-            wait_ns(MS_TO_NANO * 14);
-            return;
-        } else if nanos() > threshold {
-            return;
-        }
-        wait_ns(MS_TO_NANO * 7);
+/// Turn on the module's sntp client with up to three servers.
+/// The timezone stays at UTC.
+pub fn esp8266_sntp_config<S: ByteSink>(serial: &mut S, servers: &[&[u8]]) {
+    serial.write(b"AT+CIPSNTPCFG=1,0");
+    for server in servers {
+        serial.write(b",\"");
+        serial.write(server);
+        serial.write(b"\"");
     }
+    serial.write(b"\r\n");
+}
+
+/// Ask for the time the module got over sntp. It answers with
+/// something like `+CIPSNTPTIME:Thu Aug 04 14:48:05 2016`
+pub fn esp8266_sntp_time<S: ByteSink>(serial: &mut S) {
+    esp8266_raw(serial, b"AT+CIPSNTPTIME?");
+}
+
+pub fn esp8266_list_wifi<S: ByteSink>(serial: &mut S) {
+    esp8266_raw(serial, b"AT+CWLAP");
 }
 
 /// Send a raw command to the esp8266
-pub fn esp8266_raw(device: SerioDevice, command: &[u8]) {
-    serial_write(device, command);
-    serial_write(device, b"\r\n");
-}
\ No newline at end of file
+pub fn esp8266_raw<S: ByteSink>(serial: &mut S, command: &[u8]) {
+    serial.write(command);
+    serial.write(b"\r\n");
+}
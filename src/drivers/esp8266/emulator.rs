@@ -0,0 +1,980 @@
+//! A pretend ESP8266 for host tests. It sits on the other end
+//! of the serial port and answers AT commands the way the module
+//! does: joining networks, resolving names, opening connections
+//! and carrying HTTP requests to sites which are served from
+//! memory or from files. This is only available with the
+//! `testing` feature.
+//!
+//! Tests script it with rules, which answer matching commands
+//! with canned or generated responses before the built in
+//! behavior gets a chance. On top of that it can break payloads
+//! into small `+IPD` frames, trickle its output out a few bytes
+//! at a time, cut responses short, drop connections and restart
+//! whenever the reset pin is pulsed.
+//!
+//! ```ignore
+//! let esp = EspEmulator::new().with_reset_pin(gpio.clone(), RST_PIN);
+//! esp.add_network("Hexnet", "hunter2", -60, 6);
+//! esp.serve_file("http://52.27.143.19/hexwall", "config.txt");
+//! esp.once("AT+CIPSTART", Reply::text(b"ERROR\r\n"));
+//! let mut task = WifiTask::new(esp.clone(), &clock, gpio, random);
+//! ```
+
+use core::cell::{RefCell, RefMut};
+use std::prelude::rust_2021::*;
+use std::boxed::Box;
+use std::format;
+use std::rc::Rc;
+use teensycore::system::str::*;
+use crate::hal::*;
+use crate::hal::mock::MockGpio;
+use crate::strings::chars;
+
+/// How many connections the module juggles at once
+const LINKS: usize = 5;
+/// The largest `+IPD` frame the module sends by default
+const FRAME_SIZE: usize = 1460;
+/// The address the module gets when it joins a network
+const STATION_IP: &str = "192.168.1.50";
+const STATION_MAC: &str = "a4:2b:b0:c1:d2:ff";
+
+/// How a rule answers a command.
+pub enum Reply {
+    /// Answer with these bytes
+    Canned(Vec<u8>),
+    /// Work out the answer from the command line
+    Generated(Box<dyn Fn(&str) -> Vec<u8>>),
+    /// Never answer, so the command times out
+    Silent,
+}
+
+impl Reply {
+    pub fn text(bytes: &[u8]) -> Reply {
+        return Reply::Canned(bytes.to_vec());
+    }
+
+    pub fn generated<F: Fn(&str) -> Vec<u8> + 'static>(generate: F) -> Reply {
+        return Reply::Generated(Box::new(generate));
+    }
+}
+
+struct Rule {
+    prefix: String,
+    reply: Reply,
+    /// How many more commands this rule answers, if limited
+    remaining: Option<usize>,
+}
+
+struct Network {
+    ssid: String,
+    pwd: String,
+    rssi: i32,
+    channel: u8,
+}
+
+struct Site {
+    host: String,
+    address: String,
+    port: u32,
+    path: String,
+    response: Vec<u8>,
+    /// A plain tcp listener, which only records what it is sent
+    raw: bool,
+}
+
+#[derive(Clone, Default)]
+struct Link {
+    open: bool,
+    /// Opened by the firmware with `AT+CIPSTART`, as opposed to
+    /// a client connecting to the server
+    outgoing: bool,
+    /// Connected to a plain tcp listener rather than a site
+    raw: bool,
+    address: String,
+    port: u32,
+    /// The request being sent on an outgoing connection
+    request: Vec<u8>,
+    /// Everything the firmware sent on this connection
+    sent: Vec<u8>,
+}
+
+struct EmulatorState {
+    /// Waiting to be read by the firmware
+    output: Vec<u8>,
+    /// Written by the firmware but not handled yet
+    input: Vec<u8>,
+    /// The link and how many bytes are still expected after
+    /// `AT+CIPSEND` was answered with a prompt
+    sending: Option<(usize, usize)>,
+    payload: Vec<u8>,
+
+    rules: Vec<Rule>,
+    networks: Vec<Network>,
+    sites: Vec<Site>,
+    links: Vec<Link>,
+    commands: Vec<String>,
+
+    echo: bool,
+    booting: bool,
+    boots: usize,
+    joined: Option<String>,
+    /// Handed out by dhcp unless set with `AT+CIPSTA`
+    station_ip: String,
+    mux: bool,
+    server_port: Option<u32>,
+    sntp_time: String,
+
+    chunk_size: Option<usize>,
+    frame_size: usize,
+    truncate_after: Option<usize>,
+    reset_pin: Option<(MockGpio, usize)>,
+    resets_seen: usize,
+    initialized: bool,
+    baud: u32,
+}
+
+/// The emulated module. Cloning it returns another handle to
+/// the same module, so a test can keep one while a task owns
+/// the other.
+pub struct EspEmulator {
+    state: Rc<RefCell<EmulatorState>>,
+    /// What `read` hands out, like the mock serial port. Whatever
+    /// is left unread goes back to the module when the handle is
+    /// dropped.
+    rx: Str,
+}
+
+impl EspEmulator {
+    pub fn new() -> Self {
+        return EspEmulator {
+            state: Rc::new(RefCell::new(EmulatorState {
+                output: Vec::new(),
+                input: Vec::new(),
+                sending: None,
+                payload: Vec::new(),
+                rules: Vec::new(),
+                networks: Vec::new(),
+                sites: Vec::new(),
+                links: vec![Link::default(); LINKS],
+                commands: Vec::new(),
+                echo: true,
+                // Until it is powered on
+                booting: true,
+                boots: 0,
+                joined: None,
+                station_ip: String::from(STATION_IP),
+                mux: false,
+                server_port: None,
+                sntp_time: String::from("Thu Jan 01 00:00:00 1970"),
+                chunk_size: None,
+                frame_size: FRAME_SIZE,
+                truncate_after: None,
+                reset_pin: None,
+                resets_seen: 0,
+                initialized: false,
+                baud: 0,
+            })),
+            rx: Str::new(),
+        };
+    }
+
+    /// Restart whenever the pin is pulled low, the way the
+    /// firmware power cycles the module. Without a reset pin,
+    /// the module starts up when the port is initialized.
+    pub fn with_reset_pin(self, gpio: MockGpio, pin: usize) -> Self {
+        self.state().reset_pin = Some((gpio, pin));
+        return self;
+    }
+
+    fn state(&self) -> RefMut<'_, EmulatorState> {
+        return self.state.borrow_mut();
+    }
+
+    /// Answer commands starting with the prefix from now on.
+    /// Rules replace the built in behavior entirely, and the
+    /// oldest matching rule wins.
+    pub fn on(&self, prefix: &str, reply: Reply) {
+        self.state().rules.push(Rule { prefix: String::from(prefix), reply: reply, remaining: None });
+    }
+
+    /// Answer the next command starting with the prefix.
+    pub fn once(&self, prefix: &str, reply: Reply) {
+        self.state().rules.push(Rule { prefix: String::from(prefix), reply: reply, remaining: Some(1) });
+    }
+
+    /// Forget every rule, going back to the built in behavior.
+    pub fn clear_rules(&self) {
+        self.state().rules.clear();
+    }
+
+    /// An access point the module can see. Joining only works
+    /// with the right password once any network was added.
+    pub fn add_network(&self, ssid: &str, pwd: &str, rssi: i32, channel: u8) {
+        self.state().networks.push(Network {
+            ssid: String::from(ssid),
+            pwd: String::from(pwd),
+            rssi: rssi,
+            channel: channel,
+        });
+    }
+
+    /// Answer requests for the url with this raw HTTP response.
+    /// Host names resolve to made up addresses.
+    pub fn serve(&self, url: &str, response: &[u8]) {
+        let rest = url.strip_prefix("http://").unwrap_or(url);
+        let (authority, path) = match rest.find('/') {
+            None => (rest, "/"),
+            Some(slash) => (&rest[.. slash], &rest[slash ..]),
+        };
+        let (host, port) = match authority.split_once(':') {
+            None => (authority, 80),
+            Some((host, port)) => (host, port.parse().expect("invalid port")),
+        };
+        self.state().add_site(host, port, path, response, false);
+    }
+
+    /// Accept tcp connections to the host, like an mqtt broker.
+    /// Nothing is answered by itself: see what arrived with
+    /// `take_sent_bytes` and answer with `request`.
+    pub fn listen(&self, host: &str, port: u32) {
+        self.state().add_site(host, port, "", b"", true);
+    }
+
+    /// Answer requests for the url with a 200 and this body.
+    pub fn serve_body(&self, url: &str, body: &[u8]) {
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        response.extend_from_slice(body);
+        self.serve(url, &response);
+    }
+
+    /// Answer requests for the url with the contents of a file.
+    /// Relative paths start at the root of the repository.
+    pub fn serve_file(&self, url: &str, path: &str) {
+        let path = match path.starts_with('/') {
+            true => String::from(path),
+            false => format!("{}/{}", env!("CARGO_MANIFEST_DIR"), path),
+        };
+        let body = std::fs::read(&path).unwrap_or_else(|err| std::panic!("can't serve {}: {}", path, err));
+        self.serve_body(url, &body);
+    }
+
+    /// What `AT+CIPSNTPTIME?` answers with, like
+    /// `Tue Mar 08 21:53:41 2022`
+    pub fn set_sntp_time(&self, time: &str) {
+        self.state().sntp_time = String::from(time);
+    }
+
+    /// Only let this many bytes through each time the port is
+    /// read, so lines and frames arrive in pieces.
+    pub fn set_chunk_size(&self, size: Option<usize>) {
+        self.state().chunk_size = size;
+    }
+
+    /// Split payloads into `+IPD` frames of at most this size.
+    pub fn set_frame_size(&self, size: usize) {
+        self.state().frame_size = size.max(1);
+    }
+
+    /// Close connections after this many bytes of a response,
+    /// as if the server went away halfway through.
+    pub fn truncate_responses(&self, bytes: Option<usize>) {
+        self.state().truncate_after = bytes;
+    }
+
+    /// Say something out of the blue, like `WIFI DISCONNECT` or
+    /// garbage.
+    pub fn inject(&self, bytes: &[u8]) {
+        self.state().output.extend_from_slice(bytes);
+    }
+
+    /// A client connects to the server.
+    pub fn connect(&self, link: u8) {
+        let mut state = self.state();
+        state.links[link as usize] = Link { open: true, ..Link::default() };
+        state.output.extend_from_slice(format!("{},CONNECT\r\n", link).as_bytes());
+    }
+
+    /// A client sends data to the server, connecting first if
+    /// necessary.
+    pub fn request(&self, link: u8, content: &[u8]) {
+        if !self.state().links[link as usize].open {
+            self.connect(link);
+        }
+        self.state().deliver(link as usize, content);
+    }
+
+    /// The other end closes a connection.
+    pub fn drop_connection(&self, link: u8) {
+        self.state().close(link as usize);
+    }
+
+    /// The access point goes away, taking every outgoing
+    /// connection with it.
+    pub fn drop_wifi(&self) {
+        let mut state = self.state();
+        if state.joined.take().is_some() {
+            state.output.extend_from_slice(b"WIFI DISCONNECT\r\n");
+        }
+        for link in 0 .. LINKS {
+            if state.links[link].outgoing {
+                state.close(link);
+            }
+        }
+    }
+
+    /// Every command received so far, without line breaks.
+    pub fn commands(&self) -> Vec<String> {
+        return self.state().commands.clone();
+    }
+
+    /// Returns the commands received since the last call.
+    pub fn take_commands(&self) -> Vec<String> {
+        return std::mem::take(&mut self.state().commands);
+    }
+
+    /// Returns what the firmware sent on a connection since the
+    /// last call.
+    pub fn take_sent(&self, link: u8) -> String {
+        let sent = std::mem::take(&mut self.state().links[link as usize].sent);
+        return String::from_utf8_lossy(&sent).into_owned();
+    }
+
+    /// Like `take_sent`, for binary protocols.
+    pub fn take_sent_bytes(&self, link: u8) -> Vec<u8> {
+        return std::mem::take(&mut self.state().links[link as usize].sent);
+    }
+
+    pub fn is_open(&self, link: u8) -> bool {
+        return self.state().links[link as usize].open;
+    }
+
+    /// The network the module is on, if any.
+    pub fn joined(&self) -> Option<String> {
+        return self.state().joined.clone();
+    }
+
+    pub fn server_port(&self) -> Option<u32> {
+        return self.state().server_port;
+    }
+
+    /// How many times the module started up.
+    pub fn boots(&self) -> usize {
+        return self.state().boots;
+    }
+
+    pub fn initialized(&self) -> bool {
+        return self.state().initialized;
+    }
+
+    pub fn baud(&self) -> u32 {
+        return self.state().baud;
+    }
+
+    /// Restart if the reset pin was pulsed since the last look.
+    fn check_reset(&self) {
+        let mut state = self.state();
+        let pulses = match &state.reset_pin {
+            None => return,
+            Some((gpio, pin)) => gpio.history().iter().filter(|(written, high)| written == pin && !high).count(),
+        };
+
+        if pulses > state.resets_seen {
+            state.resets_seen = pulses;
+            // Whatever was on its way is lost
+            state.output.clear();
+            state.input.clear();
+            state.boot();
+        }
+    }
+}
+
+impl EmulatorState {
+    fn boot(&mut self) {
+        for link in self.links.iter_mut() {
+            *link = Link::default();
+        }
+        self.sending = None;
+        self.payload.clear();
+        self.echo = true;
+        self.joined = None;
+        self.mux = false;
+        self.server_port = None;
+        self.booting = true;
+        self.boots += 1;
+
+        // The boot rom talks at a different baud rate first
+        self.output.extend_from_slice(b"\r\n\x02\x8c\x9e\xfe\r\n\r\nready\r\n");
+    }
+
+    /// Work through everything the firmware wrote.
+    fn process(&mut self) {
+        loop {
+            match self.sending {
+                Some((link, remaining)) => {
+                    let count = remaining.min(self.input.len());
+                    let bytes: Vec<u8> = self.input.drain(.. count).collect();
+                    self.payload.extend_from_slice(&bytes);
+                    if count < remaining {
+                        self.sending = Some((link, remaining - count));
+                        return;
+                    }
+
+                    self.sending = None;
+                    let payload = std::mem::take(&mut self.payload);
+                    self.output.extend_from_slice(format!("\r\nRecv {} bytes\r\n\r\nSEND OK\r\n", payload.len()).as_bytes());
+                    self.receive(link, &payload);
+                    continue;
+                },
+                None => {},
+            }
+
+            let end = match self.input.windows(2).position(|pair| pair == b"\r\n") {
+                None => return,
+                Some(end) => end,
+            };
+            let line: Vec<u8> = self.input.drain(.. end + 2).collect();
+            let line = String::from_utf8_lossy(&line[.. end]).into_owned();
+
+            // Nothing gets through until the module is up
+            if self.booting {
+                continue;
+            }
+
+            if self.echo {
+                self.output.extend_from_slice(line.as_bytes());
+                self.output.extend_from_slice(b"\r\n");
+            }
+            self.commands.push(line.clone());
+            self.answer(&line);
+        }
+    }
+
+    fn answer(&mut self, line: &str) {
+        let rule = self.rules.iter_mut().position(|rule| line.starts_with(&rule.prefix));
+        match rule {
+            None => {},
+            Some(idx) => {
+                let reply = match &self.rules[idx].reply {
+                    Reply::Canned(bytes) => Some(bytes.clone()),
+                    Reply::Generated(generate) => Some(generate(line)),
+                    Reply::Silent => None,
+                };
+
+                match self.rules[idx].remaining {
+                    Some(1) => { self.rules.remove(idx); },
+                    Some(remaining) => self.rules[idx].remaining = Some(remaining - 1),
+                    None => {},
+                }
+
+                match reply {
+                    None => {},
+                    Some(bytes) => self.output.extend_from_slice(&bytes),
+                }
+                return;
+            }
+        }
+
+        let (name, args) = match line.split_once('=') {
+            None => (line, Vec::new()),
+            Some((name, args)) => (name, arguments(args)),
+        };
+
+        match name {
+            "AT" | "AT+CWMODE" | "AT+CWAUTOCONN" | "AT+CIPDNS" | "AT+CIPAP"
+            | "AT+CWSAP" | "AT+CIPSERVERMAXCONN" | "AT+CIPSTO" | "AT+CIPSNTPCFG" => {
+                self.ok();
+            },
+            "AT+CWDHCP" => {
+                // The station is mode 1, both is mode 2
+                if args.len() == 2 && args[0] != "0" && args[1] == "1" {
+                    self.station_ip = String::from(STATION_IP);
+                }
+                self.ok();
+            },
+            "AT+CIPSTA" => {
+                match args.first() {
+                    Some(ip) if args.len() == 3 => {
+                        self.station_ip = ip.clone();
+                        self.ok();
+                    },
+                    _ => self.error(),
+                }
+            },
+            "ATE0" | "ATE1" => {
+                self.echo = name == "ATE1";
+                self.ok();
+            },
+            "AT+GMR" => {
+                self.output.extend_from_slice(b"AT version:1.7.4.0(May 11 2020 19:13:04)\r\nSDK version:3.0.4(9532ceb)\r\n\r\nOK\r\n");
+            },
+            "AT+RST" => {
+                self.ok();
+                self.boot();
+            },
+            "AT+CIPMUX" => {
+                self.mux = args.first().map(|arg| arg == "1").unwrap_or(false);
+                self.ok();
+            },
+            "AT+CWJAP" => self.join(&args),
+            "AT+CWQAP" => {
+                self.ok();
+                if self.joined.take().is_some() {
+                    self.output.extend_from_slice(b"WIFI DISCONNECT\r\n");
+                }
+            },
+            "AT+CWLAP" => {
+                for (idx, network) in self.networks.iter().enumerate() {
+                    let encryption = if network.pwd.is_empty() { 0 } else { 3 };
+                    let line = format!("+CWLAP:({},\"{}\",{},\"a4:2b:b0:c1:d2:{:02x}\",{},-12,0)\r\n", encryption, network.ssid, network.rssi, idx, network.channel);
+                    self.output.extend_from_slice(line.as_bytes());
+                }
+                self.output.extend_from_slice(b"\r\nOK\r\n");
+            },
+            "AT+CIFSR" => {
+                let ip = if self.joined.is_some() { self.station_ip.as_str() } else { "0.0.0.0" };
+                let lines = format!("+CIFSR:APIP,\"192.168.4.1\"\r\n+CIFSR:STAIP,\"{}\"\r\n+CIFSR:STAMAC,\"{}\"\r\n\r\nOK\r\n", ip, STATION_MAC);
+                self.output.extend_from_slice(lines.as_bytes());
+            },
+            "AT+CIPDOMAIN" => {
+                let host = args.first().cloned().unwrap_or_default();
+                let address = self.sites.iter().find(|site| site.host == host).map(|site| site.address.clone());
+                match address {
+                    Some(address) if self.joined.is_some() => {
+                        self.output.extend_from_slice(format!("+CIPDOMAIN:\"{}\"\r\n\r\nOK\r\n", address).as_bytes());
+                    },
+                    _ => self.output.extend_from_slice(b"DNS Fail\r\n\r\nERROR\r\n"),
+                }
+            },
+            "AT+CIPSTART" => self.open(&args),
+            "AT+CIPSEND" => {
+                let (link, len) = match self.link_argument(&args) {
+                    Some((link, rest)) => (link, rest.first().and_then(|len| len.parse::<usize>().ok())),
+                    None => (0, None),
+                };
+                match len {
+                    Some(len) if self.links[link].open => {
+                        self.sending = Some((link, len));
+                        self.output.extend_from_slice(b"\r\nOK\r\n> ");
+                    },
+                    _ => self.output.extend_from_slice(b"link is not valid\r\n\r\nERROR\r\n"),
+                }
+            },
+            "AT+CIPCLOSE" => {
+                let link = match self.link_argument(&args) {
+                    Some((link, _)) => link,
+                    None => 0,
+                };
+                if self.links[link].open {
+                    self.close(link);
+                    self.ok();
+                } else {
+                    self.output.extend_from_slice(b"UNLINK\r\n\r\nERROR\r\n");
+                }
+            },
+            "AT+CIPSERVER" => {
+                match (args.first().map(|arg| arg.as_str()), args.get(1)) {
+                    (Some("1"), port) if self.mux => {
+                        self.server_port = Some(port.and_then(|port| port.parse().ok()).unwrap_or(333));
+                        self.ok();
+                    },
+                    (Some("0"), _) => {
+                        self.server_port = None;
+                        self.ok();
+                    },
+                    _ => self.error(),
+                }
+            },
+            "AT+CIPSNTPTIME?" => {
+                let line = format!("+CIPSNTPTIME:{}\r\nOK\r\n", self.sntp_time);
+                self.output.extend_from_slice(line.as_bytes());
+            },
+            _ => self.error(),
+        }
+    }
+
+    fn ok(&mut self) {
+        self.output.extend_from_slice(b"\r\nOK\r\n");
+    }
+
+    fn error(&mut self) {
+        self.output.extend_from_slice(b"\r\nERROR\r\n");
+    }
+
+    /// Split off the link in front of the other arguments when
+    /// multiple connections are enabled.
+    fn link_argument<'a>(&self, args: &'a [String]) -> Option<(usize, &'a [String])> {
+        if !self.mux {
+            return Some((0, args));
+        }
+        return match args.first().and_then(|link| link.parse::<usize>().ok()) {
+            Some(link) if link < LINKS => Some((link, &args[1 ..])),
+            _ => None,
+        };
+    }
+
+    fn join(&mut self, args: &[String]) {
+        let (ssid, pwd) = match (args.get(0), args.get(1)) {
+            (Some(ssid), Some(pwd)) => (ssid.clone(), pwd.clone()),
+            _ => return self.error(),
+        };
+
+        if self.joined.take().is_some() {
+            self.output.extend_from_slice(b"WIFI DISCONNECT\r\n");
+        }
+
+        // 1: timeout, 2: wrong password, 3: no such network
+        let network = self.networks.iter().find(|network| network.ssid == ssid);
+        let reason = match network {
+            None if self.networks.is_empty() => None,
+            None => Some(3),
+            Some(network) if network.pwd != pwd => Some(2),
+            Some(_) => None,
+        };
+
+        match reason {
+            None => {
+                self.joined = Some(ssid);
+                self.output.extend_from_slice(b"WIFI CONNECTED\r\nWIFI GOT IP\r\n\r\nOK\r\n");
+            },
+            Some(reason) => {
+                self.output.extend_from_slice(format!("+CWJAP:{}\r\n\r\nFAIL\r\n", reason).as_bytes());
+            }
+        }
+    }
+
+    fn open(&mut self, args: &[String]) {
+        let (link, rest) = match self.link_argument(args) {
+            Some(found) => found,
+            None => return self.error(),
+        };
+        let (address, port) = match (rest.get(1), rest.get(2).and_then(|port| port.parse::<u32>().ok())) {
+            (Some(address), Some(port)) => (address.clone(), port),
+            _ => return self.error(),
+        };
+
+        if self.links[link].open {
+            self.output.extend_from_slice(b"ALREADY CONNECTED\r\n\r\nERROR\r\n");
+            return;
+        }
+
+        // Udp links listen for datagrams from anyone, which tests
+        // send with `request`
+        if rest.get(0).map_or(false, |kind| kind == "UDP") {
+            if self.joined.is_none() {
+                return self.error();
+            }
+            self.links[link] = Link { open: true, outgoing: true, raw: true, address: address, port: port, ..Link::default() };
+            self.output.extend_from_slice(self.notice(link, "CONNECT").as_bytes());
+            return self.ok();
+        }
+
+        let site = self.sites.iter().find(|site| {
+            return (site.address == address || site.host == address) && site.port == port;
+        });
+        let raw = match site {
+            Some(site) if self.joined.is_some() => site.raw,
+            _ => return self.error(),
+        };
+
+        self.links[link] = Link { open: true, outgoing: true, raw: raw, address: address, port: port, ..Link::default() };
+        self.output.extend_from_slice(self.notice(link, "CONNECT").as_bytes());
+        self.ok();
+    }
+
+    fn notice(&self, link: usize, notice: &str) -> String {
+        return match self.mux {
+            true => format!("{},{}\r\n", link, notice),
+            false => format!("{}\r\n", notice),
+        };
+    }
+
+    fn close(&mut self, link: usize) {
+        if !self.links[link].open {
+            return;
+        }
+        let sent = std::mem::take(&mut self.links[link].sent);
+        self.links[link] = Link { sent: sent, ..Link::default() };
+        self.output.extend_from_slice(self.notice(link, "CLOSED").as_bytes());
+    }
+
+    /// The firmware sent data on a connection. Requests on
+    /// outgoing connections are answered by the site.
+    fn receive(&mut self, link: usize, payload: &[u8]) {
+        self.links[link].sent.extend_from_slice(payload);
+        if !self.links[link].outgoing || self.links[link].raw {
+            return;
+        }
+
+        self.links[link].request.extend_from_slice(payload);
+        let request = &self.links[link].request;
+        let header_end = match request.windows(4).position(|window| window == b"\r\n\r\n") {
+            None => return,
+            Some(end) => end,
+        };
+
+        let head = String::from_utf8_lossy(&request[.. header_end]).into_owned();
+        let path = head.split(' ').nth(1).unwrap_or("/").to_string();
+        self.links[link].request.clear();
+
+        let (address, port) = (self.links[link].address.clone(), self.links[link].port);
+        let site = self.sites.iter().find(|site| {
+            return (site.address == address || site.host == address) && site.port == port && site.path == path;
+        });
+        let mut response = match site {
+            Some(site) => site.response.clone(),
+            None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        };
+
+        match self.truncate_after {
+            Some(bytes) if bytes < response.len() => response.truncate(bytes),
+            _ => {},
+        }
+
+        // The request asked for the connection to be closed
+        self.deliver(link, &response);
+        self.close(link);
+    }
+
+    /// Host names resolve to made up addresses.
+    fn add_site(&mut self, host: &str, port: u32, path: &str, response: &[u8], raw: bool) {
+        let address = if host.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            String::from(host)
+        } else {
+            match self.sites.iter().find(|site| site.host == host) {
+                Some(site) => site.address.clone(),
+                None => format!("10.0.0.{}", self.sites.len() + 1),
+            }
+        };
+
+        self.sites.retain(|site| !(site.host == host && site.port == port && site.path == path));
+        self.sites.push(Site {
+            host: String::from(host),
+            address: address,
+            port: port,
+            path: String::from(path),
+            response: response.to_vec(),
+            raw: raw,
+        });
+    }
+
+    /// Send data which arrived on a connection to the firmware.
+    fn deliver(&mut self, link: usize, content: &[u8]) {
+        for frame in content.chunks(self.frame_size) {
+            let header = match self.mux {
+                true => format!("\r\n+IPD,{},{}:", link, frame.len()),
+                false => format!("\r\n+IPD,{}:", frame.len()),
+            };
+            self.output.extend_from_slice(header.as_bytes());
+            self.output.extend_from_slice(frame);
+        }
+    }
+}
+
+/// Split the arguments of a command, taking the quotes off of
+/// strings. Commas inside quotes don't count, and a backslash
+/// takes the character after it as is.
+fn arguments(args: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = args.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' if quoted => current.extend(chars.next()),
+            '"' => quoted = !quoted,
+            ',' if !quoted => result.push(std::mem::take(&mut current)),
+            _ => current.push(char),
+        }
+    }
+    result.push(current);
+    return result;
+}
+
+impl Clone for EspEmulator {
+    fn clone(&self) -> Self {
+        return EspEmulator {
+            state: self.state.clone(),
+            rx: Str::new(),
+        };
+    }
+}
+
+impl Drop for EspEmulator {
+    fn drop(&mut self) {
+        let unread: Vec<u8> = chars(&self.rx).collect();
+        self.state().output.splice(0 .. 0, unread);
+        self.rx.drop();
+    }
+}
+
+impl SerialPort for EspEmulator {
+    fn init(&mut self) {
+        let mut state = self.state();
+        state.initialized = true;
+        if state.reset_pin.is_none() {
+            state.boot();
+        }
+    }
+
+    fn set_baud(&mut self, rate: u32) {
+        self.state().baud = rate;
+    }
+
+    fn read(&mut self) -> &mut Str {
+        self.check_reset();
+        let mut state = self.state();
+        let count = match state.chunk_size {
+            None => state.output.len(),
+            Some(size) => size.min(state.output.len()),
+        };
+        let bytes: Vec<u8> = state.output.drain(.. count).collect();
+
+        // The module is up once it said so
+        if state.output.is_empty() && state.boots > 0 {
+            state.booting = false;
+        }
+        drop(state);
+        self.rx.append(&bytes);
+        return &mut self.rx;
+    }
+}
+
+impl ByteSink for EspEmulator {
+    fn write(&mut self, bytes: &[u8]) {
+        self.check_reset();
+        let mut state = self.state();
+        state.input.extend_from_slice(bytes);
+        state.process();
+    }
+
+    fn write_str(&mut self, content: &Str) {
+        let bytes: Vec<u8> = chars(content).collect();
+        self.write(&bytes);
+    }
+}
+
+#[cfg(test)]
+pub mod test_emulator {
+    use super::*;
+    use crate::drivers::esp8266::parser::*;
+    use crate::drivers::esp8266::esp8266_connect_to_wifi;
+
+    /// Read everything the module said, as text.
+    fn said(esp: &mut EspEmulator) -> String {
+        let rx = esp.read();
+        let bytes: Vec<u8> = chars(rx).collect();
+        rx.clear();
+        return String::from_utf8_lossy(&bytes).into_owned();
+    }
+
+    #[test]
+    fn test_emulator_commands() {
+        let mut esp = EspEmulator::new();
+        esp.add_network("Hexnet", "hunter2", -48, 6);
+        esp.add_network("Guest", "", -80, 11);
+
+        // Nothing is answered before the module is up
+        esp.write(b"AT\r\n");
+        assert_eq!(said(&mut esp), "");
+        esp.init();
+        assert!(said(&mut esp).ends_with("ready\r\n"));
+
+        esp.write(b"ATE0\r\n");
+        assert_eq!(said(&mut esp), "ATE0\r\n\r\nOK\r\n");
+        esp.write(b"AT+CWLAP\r\n");
+        assert_eq!(said(&mut esp), "+CWLAP:(3,\"Hexnet\",-48,\"a4:2b:b0:c1:d2:00\",6,-12,0)\r\n+CWLAP:(0,\"Guest\",-80,\"a4:2b:b0:c1:d2:01\",11,-12,0)\r\n\r\nOK\r\n");
+        esp.write(b"AT+CWJAP=\"Hexnet\",\"wrong\"\r\n");
+        assert_eq!(said(&mut esp), "+CWJAP:2\r\n\r\nFAIL\r\n");
+        esp.write(b"AT+CWJAP=\"Hexnet\",\"hunter2\"\r\n");
+        assert!(said(&mut esp).ends_with("WIFI GOT IP\r\n\r\nOK\r\n"));
+        assert_eq!(esp.joined(), Some(String::from("Hexnet")));
+
+        // Credentials are escaped on the way out
+        esp.add_network("Hex, \"net\"", "pa\\ss\",word", -60, 1);
+        let mut ssid = teensycore::str!(b"Hex, \"net\"");
+        let mut pwd = teensycore::str!(b"pa\\ss\",word");
+        let mut command = Str::new();
+        esp8266_connect_to_wifi(&mut command, &ssid, &pwd);
+        assert_eq!(chars(&command).collect::<Vec<u8>>(), b"AT+CWJAP=\"Hex\\, \\\"net\\\"\",\"pa\\\\ss\\\"\\,word\"\r\n".to_vec());
+        esp.write_str(&command);
+        assert!(said(&mut esp).ends_with("WIFI GOT IP\r\n\r\nOK\r\n"));
+        assert_eq!(esp.joined(), Some(String::from("Hex, \"net\"")));
+        ssid.drop();
+        pwd.drop();
+        command.drop();
+
+        // Rules come first, and run out
+        esp.once("AT+CIFSR", Reply::text(b"busy p...\r\n"));
+        esp.on("AT+CIPSNTPTIME?", Reply::generated(|line| format!("{}\r\nERROR\r\n", line.len()).into_bytes()));
+        esp.write(b"AT+CIFSR\r\n");
+        assert_eq!(said(&mut esp), "busy p...\r\n");
+        esp.write(b"AT+CIFSR\r\n");
+        assert!(said(&mut esp).contains("+CIFSR:STAIP,\"192.168.1.50\""));
+        esp.write(b"AT+CIPSNTPTIME?\r\n");
+        assert_eq!(said(&mut esp), "15\r\nERROR\r\n");
+        esp.write(b"AT+NOPE\r\n");
+        assert_eq!(said(&mut esp), "\r\nERROR\r\n");
+
+        assert_eq!(esp.take_commands().len(), 9);
+    }
+
+    #[test]
+    fn test_emulator_http() {
+        let mut esp = EspEmulator::new();
+        esp.init();
+        said(&mut esp);
+        esp.serve_body("http://config.example:8080/wall", b"0123456789");
+        esp.set_frame_size(4);
+        esp.set_chunk_size(Some(5));
+
+        let mut commands = String::new();
+        commands.push_str("ATE0\r\nAT+CWJAP=\"any\",\"thing\"\r\nAT+CIPMUX=1\r\nAT+CIPDOMAIN=\"config.example\"\r\n");
+        commands.push_str("AT+CIPSTART=4,\"TCP\",\"10.0.0.1\",8080\r\nAT+CIPSEND=4,31\r\nGET /wall HTTP/1.1\r\nHost: x\r\n\r\n");
+        esp.write(commands.as_bytes());
+
+        // Everything arrives in pieces, and the payload in
+        // frames of four bytes
+        let mut buf = Str::new();
+        let mut body = Vec::new();
+        let mut closed = false;
+        for _ in 0 .. 200 {
+            let rx = esp.read();
+            buf.append(&chars(rx).collect::<Vec<u8>>());
+            rx.clear();
+            while let Some(mut event) = parse_event(&mut buf) {
+                match &event {
+                    AtEvent::Ipd { link: Some(4), len, data } => {
+                        assert!(*len <= 4);
+                        body.extend(chars(data));
+                    },
+                    AtEvent::Closed(Some(4)) => closed = true,
+                    _ => {},
+                }
+                event.drop();
+            }
+        }
+
+        let response = String::from_utf8(body).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n0123456789"));
+        assert!(closed);
+        assert_eq!(esp.take_sent(4), "GET /wall HTTP/1.1\r\nHost: x\r\n\r\n");
+
+        // Unknown paths are missing, and responses can be cut
+        // short
+        esp.set_chunk_size(None);
+        esp.truncate_responses(Some(12));
+        esp.write(b"AT+CIPSTART=4,\"TCP\",\"config.example\",8080\r\nAT+CIPSEND=4,19\r\nGET /x HTTP/1.1\r\n\r\n");
+        let output = said(&mut esp);
+        assert!(output.ends_with("+IPD,4,4:/1.1\r\n+IPD,4,4: 4044,CLOSED\r\n"));
+
+        // Clients connect to the server
+        esp.write(b"AT+CIPSERVER=1,80\r\n");
+        esp.request(0, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(said(&mut esp).starts_with("\r\nOK\r\n0,CONNECT\r\n\r\n+IPD,0,4:GET "));
+        esp.drop_connection(0);
+        assert_eq!(said(&mut esp), "0,CLOSED\r\n");
+        esp.write(b"AT+CIPCLOSE=0\r\n");
+        assert_eq!(said(&mut esp), "UNLINK\r\n\r\nERROR\r\n");
+    }
+}
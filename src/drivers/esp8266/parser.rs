@@ -0,0 +1,512 @@
+//! Reading what the ESP8266 says, one event at a time. The
+//! module answers commands with lines like `OK` or `ERROR`,
+//! reports on connections and the network by itself, and
+//! forwards data it receives as `+IPD` frames. Those frames
+//! are not lines at all, since the payload can contain
+//! anything, so they are read by their length instead.
+
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+use crate::network::Ipv4;
+use crate::strings::*;
+
+/// Frames with a longer header than this are garbled
+const MAX_IPD_HEADER: usize = 16;
+/// The module never forwards more than this in one frame, so a
+/// longer one is garbled too. Waiting for it would swallow
+/// everything which follows.
+const MAX_IPD_LENGTH: usize = 2920;
+
+pub enum AtEvent {
+    Ok,
+    Error,
+    /// Some commands, like joining a network, fail with this
+    /// instead of ERROR
+    Fail,
+    /// The module is still working on something else and
+    /// ignored the last command
+    Busy,
+    SendOk,
+    SendFail,
+    /// The module is waiting for the data of `AT+CIPSEND`
+    Prompt,
+    /// The module (re)started
+    Ready,
+    /// Data arrived on a connection. The link is only present
+    /// when multiple connections are enabled.
+    Ipd { link: Option<u8>, len: usize, data: Str },
+    Connect(Option<u8>),
+    Closed(Option<u8>),
+    WifiConnected,
+    GotIp,
+    Disconnected,
+    /// Anything else, like `+CIPDOMAIN:10.0.0.7` or an echoed
+    /// command
+    Line(Str),
+}
+
+impl AtEvent {
+    pub fn drop(&mut self) {
+        match self {
+            AtEvent::Ipd { data, .. } => data.drop(),
+            AtEvent::Line(line) => line.drop(),
+            _ => {},
+        }
+    }
+}
+
+/// Take the next event out of the buffer. Returns None until a
+/// whole event has arrived, in which case the buffer is left
+/// alone. Blank lines are skipped.
+pub fn parse_event(buf: &mut Str) -> Option<AtEvent> {
+    loop {
+        let mut blank = 0;
+        while let Some(b'\r') | Some(b'\n') = byte_at(buf, blank) {
+            blank += 1;
+        }
+        if blank > 0 {
+            consume(buf, blank);
+        }
+
+        match byte_at(buf, 0) {
+            None => return None,
+            Some(b'>') => {
+                // The prompt isn't followed by a line break
+                let len = if byte_at(buf, 1) == Some(b' ') { 2 } else { 1 };
+                consume(buf, len);
+                return Some(AtEvent::Prompt);
+            },
+            _ => {},
+        }
+
+        if starts_with(buf, b"+IPD,") {
+            match find(buf, b":", 0) {
+                Some(colon) if colon <= MAX_IPD_HEADER => {
+                    match ipd_header(buf, colon) {
+                        Some((link, len)) => match (colon + 1).checked_add(len) {
+                            Some(end) if buf.len() < end => return None,
+                            Some(end) => {
+                                let data = substring(buf, colon + 1, len);
+                                consume(buf, end);
+                                return Some(AtEvent::Ipd { link: link, len: len, data: data });
+                            },
+                            None => {},
+                        },
+                        None => {},
+                    }
+                },
+                None if buf.len() <= MAX_IPD_HEADER => return None,
+                _ => {},
+            }
+
+            // Something got garbled, skip it
+            consume(buf, 5);
+            continue;
+        }
+
+        let end = find(buf, b"\r\n", 0)?;
+        let line = substring(buf, 0, end);
+        consume(buf, end + 2);
+        return Some(classify(line));
+    }
+}
+
+/// Read the connection id and length out of either
+/// `+IPD,<len>:` or `+IPD,<id>,<len>:`
+fn ipd_header(buf: &Str, colon: usize) -> Option<(Option<u8>, usize)> {
+    let mut header = substring(buf, 5, colon - 5);
+    let mut fields = split(&header, b',');
+    let result = match fields.size() {
+        1 => parse_decimal(&fields.get(0).unwrap()).map(|len| (None, len)),
+        _ => match (parse_decimal(&fields.get(0).unwrap()), parse_decimal(&fields.get(1).unwrap())) {
+            (Some(link), Some(len)) if link < 10 => Some((Some(link as u8), len)),
+            _ => None,
+        },
+    };
+    let result = result.filter(|(_, len)| *len <= MAX_IPD_LENGTH as u64).map(|(link, len)| (link, len as usize));
+
+    for mut field in fields.into_iter() {
+        field.drop();
+    }
+    fields.free();
+    header.drop();
+    return result;
+}
+
+fn classify(mut line: Str) -> AtEvent {
+    let event = if equals(&line, b"OK") {
+        AtEvent::Ok
+    } else if equals(&line, b"ERROR") {
+        AtEvent::Error
+    } else if equals(&line, b"FAIL") {
+        AtEvent::Fail
+    } else if equals(&line, b"SEND OK") {
+        AtEvent::SendOk
+    } else if equals(&line, b"SEND FAIL") {
+        AtEvent::SendFail
+    } else if starts_with(&line, b"busy ") {
+        AtEvent::Busy
+    } else if equals(&line, b"ready") {
+        AtEvent::Ready
+    } else if equals(&line, b"WIFI CONNECTED") {
+        AtEvent::WifiConnected
+    } else if equals(&line, b"WIFI GOT IP") {
+        AtEvent::GotIp
+    } else if equals(&line, b"WIFI DISCONNECT") {
+        AtEvent::Disconnected
+    } else {
+        match link_notice(&line) {
+            Some(event) => event,
+            None => return AtEvent::Line(line),
+        }
+    };
+
+    line.drop();
+    return event;
+}
+
+/// With multiple connections enabled, `CONNECT` and `CLOSED`
+/// come with the link in front, like `0,CONNECT`
+fn link_notice(line: &Str) -> Option<AtEvent> {
+    let (link, start) = match (byte_at(line, 0), byte_at(line, 1)) {
+        (Some(digit), Some(b',')) if digit.is_ascii_digit() => (Some(digit - b'0'), 2),
+        _ => (None, 0),
+    };
+
+    let mut notice = substring(line, start, line.len());
+    let result = if equals(&notice, b"CONNECT") {
+        Some(AtEvent::Connect(link))
+    } else if equals(&notice, b"CLOSED") {
+        Some(AtEvent::Closed(link))
+    } else {
+        None
+    };
+    notice.drop();
+    return result;
+}
+
+/// How a network found by `AT+CWLAP` is secured
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Encryption {
+    Open,
+    Wep,
+    WpaPsk,
+    Wpa2Psk,
+    WpaWpa2Psk,
+    Wpa2Enterprise,
+    Wpa3Psk,
+    Wpa2Wpa3Psk,
+    /// Newer firmware knows a few more
+    Other,
+}
+
+impl Encryption {
+    fn from_ecn(ecn: u64) -> Encryption {
+        return match ecn {
+            0 => Encryption::Open,
+            1 => Encryption::Wep,
+            2 => Encryption::WpaPsk,
+            3 => Encryption::Wpa2Psk,
+            4 => Encryption::WpaWpa2Psk,
+            5 => Encryption::Wpa2Enterprise,
+            6 => Encryption::Wpa3Psk,
+            7 => Encryption::Wpa2Wpa3Psk,
+            _ => Encryption::Other,
+        };
+    }
+}
+
+/// A network found by `AT+CWLAP`
+#[derive(Copy, Clone)]
+pub struct AccessPoint {
+    pub ssid: Str,
+    /// Signal strength in dBm
+    pub rssi: i32,
+    pub channel: u8,
+    pub encryption: Encryption,
+}
+
+impl AccessPoint {
+    pub fn drop(&mut self) {
+        self.ssid.drop();
+    }
+}
+
+/// Read the networks out of the answer to `AT+CWLAP`, in the
+/// order the module listed them. Lines which don't make sense
+/// are skipped. Don't forget to drop each entry and free the
+/// vector.
+pub fn parse_access_points(buf: &Str) -> Vector<AccessPoint> {
+    let mut result = Vector::new();
+    let mut offset = 0;
+
+    while let Some(start) = find(buf, b"+CWLAP:(", offset) {
+        offset = start + 1;
+        let end = find(buf, b"\r\n", start).unwrap_or(buf.len());
+        let mut line = substring(buf, start + 8, end - start - 8);
+        match parse_access_point(&line) {
+            None => {},
+            Some(access_point) => result.push(access_point),
+        }
+        line.drop();
+    }
+
+    return result;
+}
+
+/// `<ecn>,"<ssid>",<rssi>,"<mac>",<channel>,...)`. The ssid
+/// ends at the first quote followed by a comma, so it may
+/// contain either by itself.
+fn parse_access_point(line: &Str) -> Option<AccessPoint> {
+    let open = find(line, b",\"", 0)?;
+    let mut ecn = substring(line, 0, open);
+    let encryption = parse_decimal(&ecn).map(Encryption::from_ecn);
+    ecn.drop();
+
+    let close = find(line, b"\",", open + 2)?;
+    let mut fields = substring(line, close + 2, line.len());
+    let parts = split(&fields, b',');
+    fields.drop();
+
+    let rssi = parts.get(0).and_then(|rssi| parse_signed(&rssi));
+    let channel = parts.get(2).and_then(|channel| parse_decimal(&channel));
+    for mut part in parts.into_iter() {
+        part.drop();
+    }
+    let mut parts = parts;
+    parts.free();
+
+    return match (encryption, rssi, channel) {
+        (Some(encryption), Some(rssi), Some(channel)) if channel <= u8::MAX as u64 => Some(AccessPoint {
+            ssid: substring(line, open + 2, close - open - 2),
+            rssi: rssi,
+            channel: channel as u8,
+            encryption: encryption,
+        }),
+        _ => None,
+    };
+}
+
+fn parse_signed(content: &Str) -> Option<i32> {
+    if byte_at(content, 0) == Some(b'-') {
+        let mut digits = substring(content, 1, content.len());
+        let result = parse_decimal(&digits);
+        digits.drop();
+        return result.filter(|value| *value <= i32::MAX as u64).map(|value| -(value as i32));
+    }
+    return parse_decimal(content).filter(|value| *value <= i32::MAX as u64).map(|value| value as i32);
+}
+
+/// What `AT+CIFSR` reports. Anything the module has no
+/// address for is None.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Addresses {
+    pub station_ip: Option<Ipv4>,
+    pub station_mac: Option<[u8; 6]>,
+    pub ap_ip: Option<Ipv4>,
+}
+
+/// Read the addresses out of the answer to `AT+CIFSR`, which
+/// has a line like `+CIFSR:STAIP,"192.168.1.50"` for each.
+pub fn parse_addresses(buf: &Str) -> Addresses {
+    let mut station_ip = quoted_value(buf, b"+CIFSR:STAIP,\"");
+    let mut station_mac = quoted_value(buf, b"+CIFSR:STAMAC,\"");
+    let mut ap_ip = quoted_value(buf, b"+CIFSR:APIP,\"");
+
+    let result = Addresses {
+        station_ip: station_ip.as_ref().and_then(Ipv4::parse).filter(|ip| !ip.is_unspecified()),
+        station_mac: station_mac.as_ref().and_then(parse_mac),
+        ap_ip: ap_ip.as_ref().and_then(Ipv4::parse).filter(|ip| !ip.is_unspecified()),
+    };
+
+    for value in [station_ip.as_mut(), station_mac.as_mut(), ap_ip.as_mut()] {
+        match value {
+            None => {},
+            Some(value) => value.drop(),
+        }
+    }
+    return result;
+}
+
+/// Whatever follows the prefix up to the next quote. Don't
+/// forget to drop the result.
+fn quoted_value(buf: &Str, prefix: &[u8]) -> Option<Str> {
+    let start = find(buf, prefix, 0)? + prefix.len();
+    let end = find(buf, b"\"", start)?;
+    return Some(substring(buf, start, end - start));
+}
+
+/// Six pairs of hex digits, like `a4:2b:b0:c1:d2:ff`
+fn parse_mac(content: &Str) -> Option<[u8; 6]> {
+    if content.len() != 17 {
+        return None;
+    }
+
+    let mut result = [0u8; 6];
+    for idx in 0 .. 6 {
+        let high = hex_digit(byte_at(content, idx * 3)?)?;
+        let low = hex_digit(byte_at(content, idx * 3 + 1)?)?;
+        if idx < 5 && byte_at(content, idx * 3 + 2) != Some(b':') {
+            return None;
+        }
+        result[idx] = (high << 4) | low;
+    }
+    return Some(result);
+}
+
+fn hex_digit(char: u8) -> Option<u8> {
+    return match char {
+        b'0' ..= b'9' => Some(char - b'0'),
+        b'a' ..= b'f' => Some(char - b'a' + 10),
+        b'A' ..= b'F' => Some(char - b'A' + 10),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+pub mod test_parser {
+    use super::*;
+    use teensycore::*;
+
+    #[test]
+    fn test_parse_lines() {
+        let mut buf = str!(b"AT+CWJAP=\"Hexnet\",\"x\"\r\nWIFI CONNECTED\r\nWIFI GOT IP\r\n\r\nOK\r\n0,CONNECT\r\nCLOSED\r\n0,CONNECT FAIL\r\nbusy p...\r\nSEND OK\r\nready\r\nWIFI DISCONNECT\r\nERROR\r\nSEND FAIL\r\nFAIL\r\n");
+        match parse_event(&mut buf) {
+            Some(AtEvent::Line(line)) => assert!(line == str!(b"AT+CWJAP=\"Hexnet\",\"x\"")),
+            _ => std::panic!("expected the echo"),
+        }
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::WifiConnected)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::GotIp)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Ok)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Connect(Some(0)))));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Closed(None))));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Line(_))));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Busy)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::SendOk)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Ready)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Disconnected)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Error)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::SendFail)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Fail)));
+        assert!(parse_event(&mut buf).is_none());
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_partial_lines() {
+        let mut buf = str!(b"\r\nO");
+        assert!(parse_event(&mut buf).is_none());
+        buf.append(b"K\r");
+        assert!(parse_event(&mut buf).is_none());
+        buf.append(b"\n> AT");
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Ok)));
+
+        // The prompt has no line break
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Prompt)));
+        assert!(parse_event(&mut buf).is_none());
+        assert!(buf == str!(b"AT"));
+    }
+
+    #[test]
+    fn test_parse_ipd() {
+        let mut buf = str!(b"0,CONNECT\r\n\r\n+IPD,0,5:hello+IPD,4:a\r\nc+IPD,1,10:parti");
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Connect(Some(0)))));
+
+        match parse_event(&mut buf) {
+            Some(AtEvent::Ipd { link, len, data }) => {
+                assert_eq!(link, Some(0));
+                assert_eq!(len, 5);
+                assert!(data == str!(b"hello"));
+            },
+            _ => std::panic!("expected a frame"),
+        }
+
+        // Line breaks in the payload are just data
+        match parse_event(&mut buf) {
+            Some(AtEvent::Ipd { link, data, .. }) => {
+                assert_eq!(link, None);
+                assert!(data == str!(b"a\r\nc"));
+            },
+            _ => std::panic!("expected a frame"),
+        }
+
+        // Waits for the rest of the frame
+        assert!(parse_event(&mut buf).is_none());
+        buf.append(b"al data\r\nOK\r\n");
+        match parse_event(&mut buf) {
+            Some(AtEvent::Ipd { link, data, .. }) => {
+                assert_eq!(link, Some(1));
+                assert!(data == str!(b"partial da"));
+            },
+            _ => std::panic!("expected a frame"),
+        }
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Line(_))));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Ok)));
+    }
+
+    #[test]
+    fn test_parse_garbled_ipd() {
+        // The header is cut off, so it is read as a line
+        let mut buf = str!(b"+IPD,0,whatever and more\r\nOK\r\n");
+        match parse_event(&mut buf) {
+            Some(AtEvent::Line(line)) => assert!(line == str!(b"0,whatever and more")),
+            _ => std::panic!("expected the rest of the line"),
+        }
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Ok)));
+
+        let mut buf = str!(b"+IPD,x,4:abcd\r\nOK\r\n");
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Line(_))));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Ok)));
+
+        // Lengths the module never sends don't hold up what follows
+        let mut buf = str!(b"+IPD,0,99999:abcd\r\nOK\r\n+IPD,4294967295:x\r\nready\r\n");
+        match parse_event(&mut buf) {
+            Some(AtEvent::Line(line)) => assert!(line == str!(b"0,99999:abcd")),
+            _ => std::panic!("expected the rest of the line"),
+        }
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Ok)));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Line(_))));
+        assert!(matches!(parse_event(&mut buf), Some(AtEvent::Ready)));
+        assert!(parse_event(&mut buf).is_none());
+    }
+
+    #[test]
+    fn test_parse_access_points() {
+        let buf = str!(b"AT+CWLAP\r\n+CWLAP:(3,\"Home\",-52,\"aa:bb:cc:dd:ee:ff\",1,-22,0)\r\n+CWLAP:(0,\"Cafe, \"free\"\",-70,\"aa:bb:cc:dd:ee:00\",11,-22,0)\r\n+CWLAP:(3,\"Broken\",strong,\"aa:bb:cc:dd:ee:01\",6)\r\n+CWLAP:(9,\"\",-90,\"aa:bb:cc:dd:ee:02\",6,-22,0)\r\n\r\nOK\r\n");
+        let access_points = parse_access_points(&buf);
+        assert_eq!(access_points.size(), 3);
+
+        let home = access_points.get(0).unwrap();
+        assert!(home.ssid == str!(b"Home"));
+        assert_eq!(home.rssi, -52);
+        assert_eq!(home.channel, 1);
+        assert_eq!(home.encryption, Encryption::Wpa2Psk);
+
+        let cafe = access_points.get(1).unwrap();
+        assert!(cafe.ssid == str!(b"Cafe, \"free\""));
+        assert_eq!(cafe.rssi, -70);
+        assert_eq!(cafe.channel, 11);
+        assert_eq!(cafe.encryption, Encryption::Open);
+
+        // Hidden networks have no name
+        let hidden = access_points.get(2).unwrap();
+        assert_eq!(hidden.ssid.len(), 0);
+        assert_eq!(hidden.encryption, Encryption::Other);
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        let buf = str!(b"AT+CIFSR\r\n+CIFSR:APIP,\"192.168.4.1\"\r\n+CIFSR:APMAC,\"a6:2b:b0:c1:d2:ff\"\r\n+CIFSR:STAIP,\"192.168.1.50\"\r\n+CIFSR:STAMAC,\"a4:2B:b0:c1:d2:ff\"\r\n\r\nOK\r\n");
+        let addresses = parse_addresses(&buf);
+        assert_eq!(addresses.station_ip, Some(Ipv4([192, 168, 1, 50])));
+        assert_eq!(addresses.station_mac, Some([0xa4, 0x2b, 0xb0, 0xc1, 0xd2, 0xff]));
+        assert_eq!(addresses.ap_ip, Some(Ipv4([192, 168, 4, 1])));
+
+        // Not joined yet
+        let buf = str!(b"+CIFSR:STAIP,\"0.0.0.0\"\r\n+CIFSR:STAMAC,\"a4:2b:b0\"\r\n\r\nOK\r\n");
+        let addresses = parse_addresses(&buf);
+        assert_eq!(addresses.station_ip, None);
+        assert_eq!(addresses.station_mac, None);
+        assert_eq!(addresses.ap_ip, None);
+    }
+}
@@ -0,0 +1,478 @@
+//! Sending AT commands without ever waiting on the module.
+//! Commands are queued up along with a callback, and the queue
+//! sends each one as soon as the one before it is answered.
+//! Everything is driven by `poll`, which looks at what the
+//! module has said so far and hands back whatever happened:
+//! either a command finished, or the module reported something
+//! by itself, like data arriving on a connection.
+//!
+//! ```ignore
+//! let mut command = AtCommand::new();
+//! esp8266_configure_echo(&mut command, false);
+//! queue.push(command, Self::echo_configured);
+//!
+//! while let Some(event) = queue.poll(&mut serial, &clock) {
+//!     match event {
+//!         QueueEvent::Done(callback, mut response) => {
+//!             callback(self, &response);
+//!             response.drop();
+//!         },
+//!         QueueEvent::Event(mut event) => event.drop(),
+//!     }
+//! }
+//! ```
+
+use teensycore::*;
+use teensycore::clock::uNano;
+use teensycore::math::itoa;
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+use crate::drivers::esp8266::parser::*;
+use crate::hal::*;
+use crate::strings::*;
+
+/// How long most commands get to answer
+pub const DEFAULT_TIMEOUT: uNano = S_TO_NANO;
+/// How soon to try again when the module was too busy for a
+/// command
+const BUSY_RETRY: uNano = 100 * MS_TO_NANO;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AtResult {
+    Ok,
+    Error,
+    /// FAIL or SEND FAIL
+    Fail,
+    /// The module never answered
+    Timeout,
+}
+
+/// How the module answered a command.
+pub struct AtResponse {
+    pub result: AtResult,
+    /// The other lines the module sent while the command was
+    /// running, each ending in a line break
+    pub text: Str,
+}
+
+impl AtResponse {
+    pub fn ok(&self) -> bool {
+        return self.result == AtResult::Ok;
+    }
+
+    /// Returns the rest of the first line which starts with the
+    /// prefix, like the address in `+CIPDOMAIN:10.0.0.7`. Don't
+    /// forget to drop it.
+    pub fn line(&self, prefix: &[u8]) -> Option<Str> {
+        let mut offset = 0;
+        while let Some(end) = find(&self.text, b"\r\n", offset) {
+            let mut line = substring(&self.text, offset, end - offset);
+            let matched = starts_with(&line, prefix);
+            line.drop();
+
+            if matched {
+                return Some(substring(&self.text, offset + prefix.len(), end - offset - prefix.len()));
+            }
+            offset = end + 2;
+        }
+        return None;
+    }
+
+    pub fn drop(&mut self) {
+        self.text.drop();
+    }
+}
+
+pub type AtCallback<T> = fn(&mut T, &AtResponse);
+
+/// A callback for commands whose answer doesn't matter.
+pub fn ignore_response<T>(_context: &mut T, _response: &AtResponse) {}
+
+/// A command waiting to be sent. The esp8266 functions can
+/// write straight into one.
+#[derive(Copy, Clone)]
+pub struct AtCommand {
+    text: Str,
+    /// Sent once the module asks for it
+    data: Option<Str>,
+    timeout: uNano,
+}
+
+impl AtCommand {
+    pub fn new() -> Self {
+        return AtCommand {
+            text: Str::new(),
+            data: None,
+            timeout: DEFAULT_TIMEOUT,
+        };
+    }
+
+    /// Send data over a connection with `AT+CIPSEND`. The
+    /// command takes ownership of the content, and is done once
+    /// the module reports `SEND OK`.
+    pub fn send(link: Option<u8>, content: Str) -> Self {
+        let mut command = AtCommand::new();
+        command.write(b"AT+CIPSEND=");
+        match link {
+            None => {},
+            Some(link) => {
+                command.write_str(&itoa(link as u64));
+                command.write(b",");
+            }
+        }
+        command.write_str(&itoa(content.len() as u64));
+        command.write(b"\r\n");
+        command.data = Some(content);
+        return command;
+    }
+
+    /// Give the module this long to answer, instead of the
+    /// default.
+    pub fn with_timeout(mut self, timeout: uNano) -> Self {
+        self.timeout = timeout;
+        return self;
+    }
+
+    pub fn drop(&mut self) {
+        self.text.drop();
+        match self.data.as_mut() {
+            None => {},
+            Some(data) => data.drop(),
+        }
+    }
+}
+
+impl ByteSink for AtCommand {
+    fn write(&mut self, bytes: &[u8]) {
+        self.text.append(bytes);
+    }
+
+    fn write_str(&mut self, content: &Str) {
+        self.text.join(content);
+    }
+}
+
+pub enum QueueEvent<T> {
+    /// A command was answered. Run the callback with the
+    /// response, then drop it.
+    Done(AtCallback<T>, AtResponse),
+    /// Something the module reported by itself. Don't forget
+    /// to drop it.
+    Event(AtEvent),
+}
+
+struct Entry<T> {
+    command: AtCommand,
+    callback: AtCallback<T>,
+}
+
+// Deriving these would require T to be Copy as well
+impl<T> Clone for Entry<T> {
+    fn clone(&self) -> Self {
+        return *self;
+    }
+}
+
+impl<T> Copy for Entry<T> {}
+
+#[derive(Copy, Clone, PartialEq)]
+enum SendState {
+    /// Not sent yet, or sent again after the module was busy
+    Unsent,
+    /// Waiting for the result
+    Sent,
+    /// Waiting for the module to ask for the data
+    Prompt,
+    /// The data went out, waiting for SEND OK
+    Data,
+}
+
+/// Commands waiting for their turn. Callbacks get a `T`, which
+/// is usually the task that owns the queue.
+pub struct CommandQueue<T> {
+    pending: Vector<Entry<T>>,
+    current: Option<Entry<T>>,
+    state: SendState,
+    /// When the current command gives up
+    deadline: uNano,
+    /// When the current command is sent again, if it is unsent
+    resend_at: uNano,
+    text: Str,
+}
+
+impl<T> CommandQueue<T> {
+    pub fn new() -> Self {
+        return CommandQueue {
+            pending: Vector::new(),
+            current: None,
+            state: SendState::Unsent,
+            deadline: 0,
+            resend_at: 0,
+            text: Str::new(),
+        };
+    }
+
+    /// Queue up a command. The callback runs once it has been
+    /// answered, or gave up waiting.
+    pub fn push(&mut self, command: AtCommand, callback: AtCallback<T>) {
+        self.pending.enqueue(Entry {
+            command: command,
+            callback: callback,
+        });
+    }
+
+    /// Returns true while any command is waiting or running.
+    pub fn busy(&self) -> bool {
+        return self.current.is_some() || self.pending.size() > 0;
+    }
+
+    /// Forget about every command without calling back, like
+    /// when the module restarted.
+    pub fn clear(&mut self) {
+        match self.current.take() {
+            None => {},
+            Some(mut entry) => entry.command.drop(),
+        }
+
+        while let Some(mut entry) = self.pending.dequeue() {
+            entry.command.drop();
+        }
+        self.text.clear();
+    }
+
+    /// Send whatever is due and read what the module said. Call
+    /// this until it returns None.
+    pub fn poll<S: SerialPort, C: Clock>(&mut self, serial: &mut S, clock: &C) -> Option<QueueEvent<T>> {
+        let now = clock.nanos();
+        if self.current.is_none() {
+            self.current = self.pending.dequeue();
+            self.state = SendState::Unsent;
+            self.deadline = now + self.current.map_or(0, |entry| entry.command.timeout);
+            self.resend_at = now;
+        }
+
+        match self.current {
+            Some(entry) if self.state == SendState::Unsent && now >= self.resend_at => {
+                serial.write_str(&entry.command.text);
+                self.state = match entry.command.data {
+                    None => SendState::Sent,
+                    Some(_) => SendState::Prompt,
+                };
+            },
+            _ => {},
+        }
+
+        while let Some(event) = parse_event(serial.read()) {
+            match self.handle(serial, now, event) {
+                None => {},
+                result => return result,
+            }
+        }
+
+        if self.current.is_some() && now > self.deadline {
+            return Some(self.finish(AtResult::Timeout));
+        }
+        return None;
+    }
+
+    pub fn drop(&mut self) {
+        self.clear();
+        self.pending.free();
+        self.text.drop();
+    }
+
+    /// Apply an event to the command that is running. Anything
+    /// which doesn't belong to it is passed on.
+    fn handle<S: SerialPort>(&mut self, serial: &mut S, now: uNano, event: AtEvent) -> Option<QueueEvent<T>> {
+        let command = match self.current {
+            Some(entry) if self.state != SendState::Unsent => entry.command,
+            _ => return Some(QueueEvent::Event(event)),
+        };
+
+        match event {
+            AtEvent::Ok if command.data.is_none() => return Some(self.finish(AtResult::Ok)),
+            // The prompt comes next
+            AtEvent::Ok => {},
+            AtEvent::SendOk if command.data.is_some() => return Some(self.finish(AtResult::Ok)),
+            AtEvent::Error => return Some(self.finish(AtResult::Error)),
+            AtEvent::Fail | AtEvent::SendFail => return Some(self.finish(AtResult::Fail)),
+            AtEvent::Busy => {
+                self.state = SendState::Unsent;
+                self.resend_at = now + BUSY_RETRY;
+                self.text.clear();
+            },
+            AtEvent::Prompt if self.state == SendState::Prompt => {
+                match command.data {
+                    None => {},
+                    Some(data) => serial.write_str(&data),
+                }
+                self.state = SendState::Data;
+            },
+            AtEvent::Line(mut line) => {
+                self.text.join(&line);
+                self.text.append(b"\r\n");
+                line.drop();
+            },
+            event => return Some(QueueEvent::Event(event)),
+        }
+        return None;
+    }
+
+    fn finish(&mut self, result: AtResult) -> QueueEvent<T> {
+        let mut entry = self.current.take().unwrap();
+        entry.command.drop();
+
+        let response = AtResponse {
+            result: result,
+            text: Str::from_str(&self.text),
+        };
+        self.text.clear();
+        return QueueEvent::Done(entry.callback, response);
+    }
+}
+
+#[cfg(test)]
+pub mod test_queue {
+    use super::*;
+    use crate::drivers::esp8266::*;
+    use crate::hal::mock::*;
+    use std::prelude::rust_2021::*;
+
+    struct Log {
+        results: Vec<(AtResult, String)>,
+        events: usize,
+    }
+
+    fn record(log: &mut Log, response: &AtResponse) {
+        let text: Vec<u8> = chars(&response.text).collect();
+        log.results.push((response.result, String::from_utf8(text).unwrap()));
+    }
+
+    fn run(queue: &mut CommandQueue<Log>, log: &mut Log, serial: &MockSerial, clock: &MockClock) {
+        while let Some(event) = queue.poll(&mut serial.clone(), clock) {
+            match event {
+                QueueEvent::Done(callback, mut response) => {
+                    callback(log, &response);
+                    response.drop();
+                },
+                QueueEvent::Event(mut event) => {
+                    log.events += 1;
+                    event.drop();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_queue_sends_in_order() {
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut log = Log { results: Vec::new(), events: 0 };
+        let mut queue = CommandQueue::new();
+
+        let mut command = AtCommand::new();
+        esp8266_configure_echo(&mut command, false);
+        queue.push(command, record);
+        let mut command = AtCommand::new();
+        esp8266_dns_lookup(&mut command, &str!(b"hexwall.example"));
+        queue.push(command, record);
+        assert!(queue.busy());
+
+        // One at a time
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(serial.take_written(), "ATE0\r\n");
+        serial.receive(b"ATE0\r\n\r\nOK\r\n");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(log.results, vec![(AtResult::Ok, String::from("ATE0\r\n"))]);
+        assert_eq!(serial.take_written(), "AT+CIPDOMAIN=\"hexwall.example\"\r\n");
+
+        // Notices in between are passed on
+        serial.receive(b"0,CONNECT\r\n+CIPDOMAIN:10.0.0.7\r\n+IPD,0,2:hi\r\nOK\r\n");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(log.events, 2);
+        assert_eq!(log.results[1].0, AtResult::Ok);
+        assert!(!queue.busy());
+        queue.drop();
+    }
+
+    #[test]
+    fn test_queue_failures() {
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut log = Log { results: Vec::new(), events: 0 };
+        let mut queue = CommandQueue::new();
+
+        let mut command = AtCommand::new();
+        esp8266_connect_to_wifi(&mut command, &str!(b"Hexnet"), &str!(b"hunter2"));
+        queue.push(command.with_timeout(20 * S_TO_NANO), record);
+        let mut command = AtCommand::new();
+        esp8266_at(&mut command);
+        queue.push(command, record);
+        let mut command = AtCommand::new();
+        esp8266_at(&mut command);
+        queue.push(command, record);
+
+        run(&mut queue, &mut log, &serial, &clock);
+        serial.receive(b"+CWJAP:1\r\n\r\nFAIL\r\n");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(log.results[0], (AtResult::Fail, String::from("+CWJAP:1\r\n")));
+
+        // Nobody answers
+        assert_eq!(serial.take_written(), "AT+CWJAP=\"Hexnet\",\"hunter2\"\r\nAT\r\n");
+        clock.advance(DEFAULT_TIMEOUT + 1);
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(log.results[1].0, AtResult::Timeout);
+        serial.receive(b"ERROR\r\n");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(log.results[2].0, AtResult::Error);
+
+        // Nothing is left to call back
+        let mut command = AtCommand::new();
+        esp8266_at(&mut command);
+        queue.push(command, record);
+        queue.clear();
+        serial.receive(b"OK\r\n");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(log.results.len(), 3);
+    }
+
+    #[test]
+    fn test_queue_sends_data() {
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut log = Log { results: Vec::new(), events: 0 };
+        let mut queue = CommandQueue::new();
+
+        queue.push(AtCommand::send(Some(3), str!(b"hello")), record);
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(serial.take_written(), "AT+CIPSEND=3,5\r\n");
+
+        // The data waits for the prompt
+        serial.receive(b"OK\r\n");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(serial.take_written(), "");
+        serial.receive(b"> ");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(serial.take_written(), "hello");
+        serial.receive(b"\r\nRecv 5 bytes\r\n\r\nSEND OK\r\n");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(log.results, vec![(AtResult::Ok, String::from("Recv 5 bytes\r\n"))]);
+
+        // A busy module gets asked again a little later
+        queue.push(AtCommand::send(Some(3), str!(b"again")), record);
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(serial.take_written(), "AT+CIPSEND=3,5\r\n");
+        serial.receive(b"busy s...\r\n");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(serial.take_written(), "");
+        clock.advance(BUSY_RETRY);
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(serial.take_written(), "AT+CIPSEND=3,5\r\n");
+        serial.receive(b"OK\r\n> ");
+        run(&mut queue, &mut log, &serial, &clock);
+        serial.receive(b"SEND FAIL\r\n");
+        run(&mut queue, &mut log, &serial, &clock);
+        assert_eq!(serial.take_written(), "again");
+        assert_eq!(log.results[1].0, AtResult::Fail);
+    }
+}
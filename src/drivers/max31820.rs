@@ -1,258 +1,374 @@
+//! Driver for the MAX31820 (and the DS18B20, which behaves the
+//! same) 1-Wire temperature sensor. Readings are hundredths of
+//! a degree celsius.
+//!
+//! The driver only speaks the sensor's function commands, and
+//! leaves the bus to `OneWireBus`. With a single sensor on the
+//! bus, it can be talked to without knowing its ROM code. With
+//! several, search the bus for their codes and address each one
+//! by its code.
+
 use teensycore::*;
 use teensycore::clock::*;
-use teensycore::debug::*;
-use teensycore::phys::pins::*;
+use crate::drivers::one_wire::*;
+use crate::hal::*;
 
 const fn micros(time: uNano) -> uNano {
     return MICRO_TO_NANO * time;
 }
 
-pub struct Max31820Driver {
-    pin: usize,
+// Function commands
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+const WRITE_SCRATCHPAD: u8 = 0x4E;
+
+pub const SCRATCHPAD_SIZE: usize = 9;
+/// Conversions get this much longer than the datasheet says
+const CONVERSION_MARGIN: uNano = 50 * MS_TO_NANO;
+/// How long to give a conversion started by `start_convert_all`
+pub const CONVERT_ALL_TIME: uNano = micros(750_000) + CONVERSION_MARGIN;
+
+/// Why the sensor couldn't be read.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SensorError {
+    /// Nothing answered the reset pulse
+    NoPresence,
+    /// The bus stayed low, so something is shorting it
+    BusShorted,
+    /// The data didn't match the crc which came with it
+    BadCrc,
+    /// The conversion didn't finish in time
+    Timeout,
 }
 
-impl Max31820Driver {
-    pub fn new(data_pin: usize) -> Self {
-        pin_mux_config(data_pin, Alt::Alt5);
-        return Max31820Driver {
-            pin: data_pin,
+impl From<BusError> for SensorError {
+    fn from(err: BusError) -> Self {
+        return match err {
+            BusError::NoPresence => SensorError::NoPresence,
+            BusError::BusShorted => SensorError::BusShorted,
+            BusError::BadCrc => SensorError::BadCrc,
         };
     }
+}
 
-    fn cmd_convert_t(&self) {
-        self.send_command(0x44); // Convert T command
-        // Read until receiving a 1
-        // TODO: Add an oh-shit detector
-        loop {
-            let bit = self.read_bit();
-            // Temperature conversino is done
-            if bit > 0 {
-                break;
-            }
-            wait_ns(micros(1));
-        }
-    }
-
-    fn cmd_read_scratchpad(&self) -> Option<u16> {
-        self.send_command(0xBE);
+/// How many bits the conversion produces. More bits take longer.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Resolution {
+    /// 0.5 degrees
+    Bits9,
+    /// 0.25 degrees
+    Bits10,
+    /// 0.125 degrees
+    Bits11,
+    /// 0.0625 degrees, what the sensor starts with
+    Bits12,
+}
 
-        // Read bytes
-        let byte_lsb = self.read_byte() as u16;
-        let byte_msb = self.read_byte() as u16; 
-        let result: u16 = (byte_msb << 8) | byte_lsb;
+impl Resolution {
+    /// The configuration register value
+    fn config(&self) -> u8 {
+        return match self {
+            Resolution::Bits9 => 0x1F,
+            Resolution::Bits10 => 0x3F,
+            Resolution::Bits11 => 0x5F,
+            Resolution::Bits12 => 0x7F,
+        };
+    }
 
-        // Take the first 10 bits
-        return Some(result);
+    fn from_config(config: u8) -> Resolution {
+        return match (config >> 5) & 0x03 {
+            0 => Resolution::Bits9,
+            1 => Resolution::Bits10,
+            2 => Resolution::Bits11,
+            _ => Resolution::Bits12,
+        };
     }
 
-    fn cmd_skip_rom(&self) -> Option<bool> {
-        if self.initialize() {
-            self.send_command(0xCC);
-            return Some(true);
-        }
+    /// The longest a conversion takes, per the datasheet
+    pub fn conversion_time(&self) -> uNano {
+        return match self {
+            Resolution::Bits9 => micros(93_750),
+            Resolution::Bits10 => micros(187_500),
+            Resolution::Bits11 => micros(375_000),
+            Resolution::Bits12 => micros(750_000),
+        };
+    }
 
-        return None;
+    /// The low bits of a reading which mean nothing at this
+    /// resolution
+    fn undefined_bits(&self) -> i16 {
+        return match self {
+            Resolution::Bits9 => 0x07,
+            Resolution::Bits10 => 0x03,
+            Resolution::Bits11 => 0x01,
+            Resolution::Bits12 => 0x00,
+        };
     }
+}
 
-    fn cmd_read_rom(&self) -> Option<u64> {
-        if self.initialize() {
-            self.send_command(0x33);
-            
-            // Rest period
-            wait_ns(micros(1));
-
-            // Read 64 bits     
-            let mut family_code = 0;
-            for bit in 0 .. 8 {
-                let bit_val = self.read_bit();
-                debug_u64(bit_val as u64, b"bit");
-                family_code |= (bit_val as u64) << bit;
-            }
+/// The sensor's memory, checked against its crc.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Scratchpad {
+    /// Sixteenths of a degree
+    pub raw: i16,
+    pub alarm_high: i8,
+    pub alarm_low: i8,
+    pub resolution: Resolution,
+}
 
-            debug_u64(family_code, b"family code");
+impl Scratchpad {
+    pub fn parse(bytes: &[u8; SCRATCHPAD_SIZE]) -> Result<Scratchpad, SensorError> {
+        // A bus held low reads as zeros, which pass the crc
+        if crc8(bytes) != 0 || bytes.iter().all(|byte| *byte == 0) {
+            return Err(SensorError::BadCrc);
+        }
 
-            let mut rom_code = 0;       
-            for bit in 0 .. 48 {
-                rom_code |= (self.read_bit() as u64) << bit;
-            }
+        let resolution = Resolution::from_config(bytes[4]);
+        return Ok(Scratchpad {
+            raw: i16::from_le_bytes([bytes[0], bytes[1]]) & !resolution.undefined_bits(),
+            alarm_high: bytes[2] as i8,
+            alarm_low: bytes[3] as i8,
+            resolution: resolution,
+        });
+    }
 
-            debug_u64(rom_code, b"rom code");
+    /// Hundredths of a degree celsius
+    pub fn temperature(&self) -> i32 {
+        return self.raw as i32 * 100 / 16;
+    }
+}
 
-            let mut crc = 0u64;
-            for bit in 0 .. 8 {
-                crc |= (self.read_bit() as u64) << bit;
-            }
+pub struct Max31820Driver<G: Gpio, C: Clock> {
+    bus: OneWireBus<G, C>,
+    /// What the sensor said last, which decides how long a
+    /// conversion may take
+    resolution: Resolution,
+}
 
-            debug_u64(crc, b"crc");
-            return Some(rom_code);
-        }
+impl<G: Gpio, C: Clock> Max31820Driver<G, C> {
+    pub fn new(bus: OneWireBus<G, C>) -> Self {
+        return Max31820Driver {
+            bus: bus,
+            resolution: Resolution::Bits12,
+        };
+    }
 
-        return None;
+    /// The bus the sensors are on, for searching it.
+    pub fn bus(&mut self) -> &mut OneWireBus<G, C> {
+        return &mut self.bus;
     }
 
-    #[allow(dead_code)]
-    fn cmd_match_rom(&self, rom: u64) {
-        // Tell the bus we're about to address a specific node
-        self.send_command(0x55);
-        let mut bit_index: usize = 0;
-        while bit_index < 64 {
-            let bit = rom & (0x1 << bit_index);
-            if bit > 0 {
-                self.write_1();
-            } else {
-                self.write_0();
+    /// Start a conversion and wait for it to finish. The sensor
+    /// reads as 0 until it is done.
+    fn cmd_convert_t(&mut self, resolution: Resolution) -> Result<(), SensorError> {
+        self.bus.write_byte(CONVERT_T);
+        let deadline = self.bus.clock().nanos() + resolution.conversion_time() + CONVERSION_MARGIN;
+        while !self.bus.read_bit() {
+            if self.bus.clock().nanos() > deadline {
+                return Err(SensorError::Timeout);
             }
-            bit_index += 1;
+            self.bus.clock().wait_ns(micros(1));
         }
+        return Ok(());
     }
 
-    pub fn read_rom(&self) -> Option<u64> {
-        return self.cmd_read_rom();
-    }
+    fn cmd_read_scratchpad(&mut self) -> Result<Scratchpad, SensorError> {
+        self.bus.write_byte(READ_SCRATCHPAD);
+        let mut bytes = [0; SCRATCHPAD_SIZE];
+        self.bus.read_bytes(&mut bytes);
 
-    pub fn read_temperature(&self) -> Option<u16> {
-        // Get ROM
-        if self.initialize() {
-            self.cmd_skip_rom();
-            self.cmd_convert_t();
-            self.cmd_skip_rom();
-            return self.cmd_read_scratchpad();
-        } else {
-            return None;
-        }
+        let scratchpad = Scratchpad::parse(&bytes)?;
+        self.resolution = scratchpad.resolution;
+        return Ok(scratchpad);
     }
 
-    fn as_input(&self) {
-        pin_mode(self.pin, Mode::Input);
-        pin_pad_config(self.pin, PadConfig { 
-            hysterisis: false, 
-            resistance: PullUpDown::PullDown100k, 
-            pull_keep: PullKeep::Pull, 
-            pull_keep_en: false, 
-            open_drain: true, 
-            speed: PinSpeed::Max200MHz, 
-            drive_strength: DriveStrength::Max, 
-            fast_slew_rate: false 
-        });
+    fn cmd_write_scratchpad(&mut self, alarm_high: i8, alarm_low: i8, resolution: Resolution) {
+        self.bus.write_byte(WRITE_SCRATCHPAD);
+        self.bus.write_bytes(&[alarm_high as u8, alarm_low as u8, resolution.config()]);
     }
 
-    fn as_output(&self) {
-        pin_mode(self.pin, Mode::Output);
+    /// Take a reading from the only sensor on the bus, in
+    /// hundredths of a degree celsius.
+    pub fn read_temperature(&mut self) -> Result<i32, SensorError> {
+        self.bus.skip_rom()?;
+        self.cmd_convert_t(self.resolution)?;
+        self.bus.skip_rom()?;
+        return Ok(self.cmd_read_scratchpad()?.temperature());
     }
 
-    fn pull_low(&self) {
-        self.as_output();
-        pin_out(self.pin, Power::Low);
+    /// Take a reading from one sensor, in hundredths of a degree
+    /// celsius.
+    pub fn read_temperature_of(&mut self, rom: u64) -> Result<i32, SensorError> {
+        // Its resolution isn't known yet, so allow for the slowest
+        self.bus.match_rom(rom)?;
+        self.cmd_convert_t(Resolution::Bits12)?;
+        return self.read_converted(Some(rom));
     }
 
+    /// Start a conversion on every sensor at once and wait for
+    /// the slowest one. Read each of them with `read_converted`.
+    pub fn convert_all(&mut self) -> Result<(), SensorError> {
+        self.bus.skip_rom()?;
+        return self.cmd_convert_t(Resolution::Bits12);
+    }
 
-    fn initialize(&self) -> bool {
-        for _ in 0 .. 125 {
-            if self.reset() {
-                return true;
-            }
-        }
+    /// Like `convert_all`, but without waiting. The readings are
+    /// ready once `CONVERT_ALL_TIME` has passed, and reading them
+    /// any sooner returns the previous conversion.
+    pub fn start_convert_all(&mut self) -> Result<(), SensorError> {
+        self.bus.skip_rom()?;
+        self.bus.write_byte(CONVERT_T);
+        return Ok(());
+    }
 
-        return false;
+    /// What one sensor, or the only one on the bus, measured in
+    /// the last conversion, in hundredths of a degree celsius.
+    pub fn read_converted(&mut self, rom: Option<u64>) -> Result<i32, SensorError> {
+        self.bus.select(rom)?;
+        return Ok(self.cmd_read_scratchpad()?.temperature());
     }
 
-    fn reset(&self) -> bool{
-        // Write low
-        self.pull_low();
-        wait_ns(micros(500));
-
-        // Allow float
-        self.as_input();
-        wait_ns(micros(70));
-
-        // Wait a while then sample
-        let target = nanos() + micros(240 - 70);
-        let mut result = 1;
-        while nanos() < target {
-            if pin_read(self.pin) == 0 {
-                result = 0;
-            }
-        }
-        
-        // Wait 410 micros
-        wait_ns(micros(240));
+    /// Trade precision for speed, for one sensor or the only one on
+    /// the bus. The setting is lost when the sensor loses power.
+    pub fn set_resolution(&mut self, rom: Option<u64>, resolution: Resolution) -> Result<(), SensorError> {
+        // Keep the alarm thresholds, which share the write
+        self.bus.select(rom)?;
+        let scratchpad = self.cmd_read_scratchpad()?;
+        return self.write_scratchpad(rom, scratchpad.alarm_high, scratchpad.alarm_low, resolution);
+    }
 
-        // If result is 0, that's an alive pulse.
-        return result == 0;
+    /// Set the whole degrees outside of which a sensor shows up in
+    /// an alarm search. The thresholds are lost when the sensor
+    /// loses power.
+    pub fn set_alarms(&mut self, rom: Option<u64>, alarm_high: i8, alarm_low: i8) -> Result<(), SensorError> {
+        self.bus.select(rom)?;
+        let scratchpad = self.cmd_read_scratchpad()?;
+        return self.write_scratchpad(rom, alarm_high, alarm_low, scratchpad.resolution);
     }
 
-    fn send_command(&self, command: u8) {
-        for bit in 0 .. 8 {
-            let signal = command & (0x1 << bit);
-            if signal > 0 {
-                self.write_1();
-            } else {
-                self.write_0();
-            }
-        }
+    /// Write the scratchpad and read it back to check.
+    fn write_scratchpad(&mut self, rom: Option<u64>, alarm_high: i8, alarm_low: i8, resolution: Resolution) -> Result<(), SensorError> {
+        self.bus.select(rom)?;
+        self.cmd_write_scratchpad(alarm_high, alarm_low, resolution);
 
-        self.as_input();
+        self.bus.select(rom)?;
+        let written = self.cmd_read_scratchpad()?;
+        if written.resolution != resolution || written.alarm_high != alarm_high || written.alarm_low != alarm_low {
+            return Err(SensorError::BadCrc);
+        }
+        return Ok(());
     }
 
-    fn write_1(&self) {
-        self.pull_low();
-        wait_ns(micros(10));
-        // Release
-        pin_mode(self.pin, Mode::Input);
-        wait_ns(micros(65));
-    } 
-
-    fn write_0(&self) {
-        self.pull_low();
-        wait_ns(micros( 65));
-
-        // Release
-        pin_mode(self.pin, Mode::Input);
-        wait_ns(micros(5));
+    pub fn resolution(&self) -> Resolution {
+        return self.resolution;
     }
+}
 
-    fn read_bit(&self) -> u8 {
-        // Initiate a read slot
-        self.pull_low();
-        wait_ns( micros(3));
-
-        self.as_input();
-        wait_ns(micros(12));
-
-        let mut sig_high = 0;
-        let mut sig_low = 0;
-        let duration = nanos() + micros(1);
+#[cfg(test)]
+pub mod test_max31820 {
+    use super::*;
+    use crate::crc::crc8;
+    use crate::drivers::one_wire::simulator::*;
+    use crate::drivers::one_wire::test_one_wire::rom;
+    use teensycore::system::vector::*;
+    use crate::hal::mock::*;
+
+    /// A scratchpad as the sensor sends it, crc included
+    pub fn scratchpad(raw: i16, config: u8) -> [u8; SCRATCHPAD_SIZE] {
+        let raw = raw.to_le_bytes();
+        let mut result = [raw[0], raw[1], 0x4B, 0x46, config, 0xFF, 0x0C, 0x10, 0];
+        result[8] = crc8(&result[0 .. 8]);
+        return result;
+    }
 
-        loop {
-            let signal = pin_read(self.pin);
-            if signal == 0 {
-                sig_low += 1;
-            } else {
-                sig_high += 1;
-            }
+    #[test]
+    fn test_scratchpad() {
+        // Examples from the datasheet
+        let reading = Scratchpad::parse(&scratchpad(0x0191, 0x7F)).unwrap();
+        assert_eq!(reading.temperature(), 2506);
+        assert_eq!(reading.resolution, Resolution::Bits12);
+        assert_eq!((reading.alarm_high, reading.alarm_low), (75, 70));
+        assert_eq!(Scratchpad::parse(&scratchpad(-162, 0x7F)).unwrap().temperature(), -1012);
+        assert_eq!(Scratchpad::parse(&scratchpad(0x07D0, 0x7F)).unwrap().temperature(), 12500);
+
+        // Bits below the resolution are ignored
+        let reading = Scratchpad::parse(&scratchpad(0x0197, 0x1F)).unwrap();
+        assert_eq!(reading.resolution, Resolution::Bits9);
+        assert_eq!(reading.temperature(), 2500);
+        assert!(Resolution::Bits9.conversion_time() < Resolution::Bits12.conversion_time());
+
+        let mut damaged = scratchpad(0x0191, 0x7F);
+        damaged[0] ^= 0x10;
+        assert_eq!(Scratchpad::parse(&damaged), Err(SensorError::BadCrc));
+        assert_eq!(Scratchpad::parse(&[0; SCRATCHPAD_SIZE]), Err(SensorError::BadCrc));
+    }
 
-            if nanos() > duration {
-                break;
-            }
-        }
-        
-        // Wait the remainder of the time slot
-        wait_ns(micros(53));
-
-        // Determine the result based on which bucket
-        // got more hits.
-        if sig_high > sig_low {
-            return 1;
-        } else {
-            return 0;
-        }
-    } 
+    #[test]
+    fn test_bus_faults() {
+        let clock = MockClock::new().with_auto_advance(MICRO_TO_NANO);
+        let wire = BusSimulator::new(&clock, 10);
+        let mut driver = Max31820Driver::new(OneWireBus::new(10, wire.clone(), &clock));
+
+        // Nothing pulls the bus low
+        assert_eq!(driver.read_temperature(), Err(SensorError::NoPresence));
+        assert_eq!(driver.bus().search().err(), Some(BusError::NoPresence));
+
+        // Nothing lets go of it
+        wire.add_sensor(rom(0x28, 1), 0x0191);
+        wire.short(true);
+        assert_eq!(driver.read_temperature(), Err(SensorError::BusShorted));
+        assert_eq!(driver.set_resolution(None, Resolution::Bits9), Err(SensorError::BusShorted));
+
+        wire.short(false);
+        wire.stick_conversions(rom(0x28, 1));
+        let started = clock.now();
+        assert_eq!(driver.read_temperature(), Err(SensorError::Timeout));
+        assert!(clock.now() - started >= Resolution::Bits12.conversion_time());
+    }
 
-    fn read_byte(&self) -> u8 {
-        let mut result = 0;
-        for bit in 0 .. 8 {
-            result |= self.read_bit() << bit;
-        }
-        return result;
+    #[test]
+    fn test_simulated_sensors() {
+        let clock = MockClock::new().with_auto_advance(MICRO_TO_NANO);
+        let wire = BusSimulator::new(&clock, 10);
+        let mut driver = Max31820Driver::new(OneWireBus::new(10, wire.clone(), &clock));
+        let (near, far) = (rom(0x28, 0x11), rom(0x28, 0x12));
+
+        // One sensor can be read without its code
+        wire.add_sensor(near, 0x0191);
+        assert_eq!(driver.read_temperature(), Ok(2506));
+        assert_eq!(driver.bus().read_rom(), Ok(near));
+
+        wire.add_sensor(far, -162);
+        let mut found = driver.bus().search().unwrap();
+        assert_eq!(found.size(), 2);
+        found.free();
+
+        assert_eq!(driver.convert_all(), Ok(()));
+        assert_eq!(driver.read_converted(Some(near)), Ok(2506));
+        assert_eq!(driver.read_converted(Some(far)), Ok(-1012));
+        wire.set_temperature(far, 0x0200);
+        assert_eq!(driver.read_temperature_of(far), Ok(3200));
+        assert_eq!(driver.read_converted(Some(near)), Ok(2506));
+
+        // Only the one above 30 degrees is in alarm
+        assert_eq!(driver.set_alarms(Some(near), 30, -10), Ok(()));
+        assert_eq!(driver.set_alarms(Some(far), 30, -10), Ok(()));
+        let mut alarms = driver.bus().alarm_search().unwrap();
+        assert_eq!(alarms.size(), 1);
+        assert_eq!(alarms.get(0), Some(far));
+        alarms.free();
+
+        // Starting a conversion doesn't wait for it
+        wire.set_temperature(near, 0x0200);
+        assert_eq!(driver.start_convert_all(), Ok(()));
+        assert_eq!(driver.read_converted(Some(near)), Ok(2506));
+        clock.advance(CONVERT_ALL_TIME);
+        assert_eq!(driver.read_converted(Some(near)), Ok(3200));
+
+        assert_eq!(driver.set_resolution(Some(near), Resolution::Bits9), Ok(()));
+        assert_eq!(wire.config(near), (30, -10, 0x1F));
+        assert_eq!(wire.config(far).2, 0x7F);
+        assert_eq!(driver.resolution(), Resolution::Bits9);
+        assert!(wire.timing_errors().is_empty(), "{:?}", wire.timing_errors());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,687 @@
+//! The 1-Wire bus: resets, time slots and the ROM commands which
+//! every device understands. Drivers for particular devices sit
+//! on top of it and only deal with their own function commands.
+//!
+//! Slots are timed by busy waiting, with the values recommended
+//! in Maxim's application note 126. Devices which support it can
+//! be switched to overdrive, which is about ten times faster.
+
+use teensycore::*;
+use teensycore::clock::*;
+use teensycore::phys::pins::*;
+use teensycore::system::vector::*;
+use crate::hal::*;
+
+pub use crate::crc::{crc8, crc16};
+
+#[cfg(feature = "testing")]
+pub mod simulator;
+
+// ROM commands
+pub const READ_ROM: u8 = 0x33;
+pub const MATCH_ROM: u8 = 0x55;
+pub const SKIP_ROM: u8 = 0xCC;
+pub const SEARCH_ROM: u8 = 0xF0;
+pub const ALARM_SEARCH: u8 = 0xEC;
+pub const OVERDRIVE_SKIP_ROM: u8 = 0x3C;
+pub const OVERDRIVE_MATCH_ROM: u8 = 0x69;
+
+/// How many times a reset is tried before giving up
+pub const RESET_ATTEMPTS: usize = 125;
+
+/// Why the bus couldn't be talked to.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BusError {
+    /// Nothing answered the reset pulse
+    NoPresence,
+    /// The bus stayed low, so something is shorting it
+    BusShorted,
+    /// The data didn't match the crc which came with it
+    BadCrc,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Speed {
+    Standard,
+    Overdrive,
+}
+
+/// How long each part of a reset or a time slot takes.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Timing {
+    pub reset_low: uNano,
+    /// When to start listening for presence pulses, counting
+    /// from the end of the reset pulse
+    pub presence_start: uNano,
+    /// When to stop listening
+    pub presence_end: uNano,
+    /// The rest of the reset, after listening
+    pub reset_recovery: uNano,
+    pub write_1_low: uNano,
+    pub write_1_recovery: uNano,
+    pub write_0_low: uNano,
+    pub write_0_recovery: uNano,
+    /// How long the master starts a read slot for
+    pub read_low: uNano,
+    /// How long after letting go the bus is sampled
+    pub read_wait: uNano,
+    /// How long it is sampled for
+    pub read_sample: uNano,
+    pub read_recovery: uNano,
+}
+
+impl Speed {
+    pub fn timing(&self) -> Timing {
+        return match self {
+            Speed::Standard => Timing {
+                reset_low: 500 * MICRO_TO_NANO,
+                presence_start: 70 * MICRO_TO_NANO,
+                presence_end: 240 * MICRO_TO_NANO,
+                reset_recovery: 240 * MICRO_TO_NANO,
+                write_1_low: 10 * MICRO_TO_NANO,
+                write_1_recovery: 65 * MICRO_TO_NANO,
+                write_0_low: 65 * MICRO_TO_NANO,
+                write_0_recovery: 5 * MICRO_TO_NANO,
+                read_low: 3 * MICRO_TO_NANO,
+                read_wait: 12 * MICRO_TO_NANO,
+                read_sample: 1 * MICRO_TO_NANO,
+                read_recovery: 53 * MICRO_TO_NANO,
+            },
+            Speed::Overdrive => Timing {
+                reset_low: 70 * MICRO_TO_NANO,
+                presence_start: 6 * MICRO_TO_NANO,
+                presence_end: 10 * MICRO_TO_NANO,
+                reset_recovery: 40 * MICRO_TO_NANO,
+                write_1_low: 1 * MICRO_TO_NANO,
+                write_1_recovery: 7_500,
+                write_0_low: 7_500,
+                write_0_recovery: 2_500,
+                read_low: 1 * MICRO_TO_NANO,
+                read_wait: 1 * MICRO_TO_NANO,
+                read_sample: 250,
+                read_recovery: 7 * MICRO_TO_NANO,
+            },
+        };
+    }
+}
+
+/// A ROM code in hex, in the order it goes over the wire, so
+/// the family code comes first.
+pub fn rom_hex(rom: u64) -> [u8; 16] {
+    let digits = b"0123456789abcdef";
+    let mut result = [0; 16];
+    for (idx, byte) in rom.to_le_bytes().iter().enumerate() {
+        result[idx * 2] = digits[(byte >> 4) as usize];
+        result[idx * 2 + 1] = digits[(byte & 0x0F) as usize];
+    }
+    return result;
+}
+
+/// Check data against the inverted crc16 a device sent after it.
+pub fn check_crc16(bytes: &[u8], sent: [u8; 2]) -> bool {
+    return !crc16(bytes) == u16::from_le_bytes(sent);
+}
+
+pub struct OneWireBus<G: Gpio, C: Clock> {
+    pin: usize,
+    gpio: G,
+    clock: C,
+    speed: Speed,
+}
+
+impl<G: Gpio, C: Clock> OneWireBus<G, C> {
+    pub fn new(pin: usize, mut gpio: G, clock: C) -> Self {
+        gpio.mux_config(pin, Alt::Alt5);
+        gpio.pad_config(pin, PadConfig {
+            hysterisis: false,
+            resistance: PullUpDown::PullDown100k,
+            pull_keep: PullKeep::Pull,
+            pull_keep_en: false,
+            open_drain: true,
+            speed: PinSpeed::Max200MHz,
+            drive_strength: DriveStrength::Max,
+            fast_slew_rate: false
+        });
+        gpio.mode(pin, Mode::Input);
+
+        return OneWireBus {
+            pin: pin,
+            gpio: gpio,
+            clock: clock,
+            speed: Speed::Standard,
+        };
+    }
+
+    pub fn clock(&self) -> &C {
+        return &self.clock;
+    }
+
+    pub fn speed(&self) -> Speed {
+        return self.speed;
+    }
+
+    /// Time slots from now on run at this speed. Devices only
+    /// follow after `overdrive_skip_rom` or `overdrive_match_rom`,
+    /// and go back to standard speed after a standard reset.
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+    }
+
+    /// Reset the bus until something answers.
+    pub fn initialize(&mut self) -> Result<(), BusError> {
+        let mut result = Err(BusError::NoPresence);
+        for _ in 0 .. RESET_ATTEMPTS {
+            result = self.reset();
+            if result.is_ok() {
+                return result;
+            }
+        }
+
+        return result;
+    }
+
+    /// Send a reset pulse and listen for devices answering it.
+    pub fn reset(&mut self) -> Result<(), BusError> {
+        let timing = self.speed.timing();
+        self.pull_low();
+        self.clock.wait_ns(timing.reset_low);
+
+        // Allow float, then listen for a while
+        self.release();
+        self.clock.wait_ns(timing.presence_start);
+        let target = self.clock.nanos() + timing.presence_end - timing.presence_start;
+        let mut present = false;
+        while self.clock.nanos() < target {
+            if self.gpio.read(self.pin) == 0 {
+                present = true;
+            }
+        }
+
+        // Devices let go of the bus long before now
+        self.clock.wait_ns(timing.reset_recovery);
+        if self.gpio.read(self.pin) == 0 {
+            return Err(BusError::BusShorted);
+        } else if !present {
+            return Err(BusError::NoPresence);
+        }
+        return Ok(());
+    }
+
+    pub fn write_bit(&mut self, bit: bool) {
+        let timing = self.speed.timing();
+        let (low, recovery) = match bit {
+            true => (timing.write_1_low, timing.write_1_recovery),
+            false => (timing.write_0_low, timing.write_0_recovery),
+        };
+
+        self.pull_low();
+        self.clock.wait_ns(low);
+        self.release();
+        self.clock.wait_ns(recovery);
+    }
+
+    pub fn read_bit(&mut self) -> bool {
+        let timing = self.speed.timing();
+
+        // Start a read slot, then see whether a device holds
+        // the bus low
+        self.pull_low();
+        self.clock.wait_ns(timing.read_low);
+        self.release();
+        self.clock.wait_ns(timing.read_wait);
+
+        let mut sig_high = 0;
+        let mut sig_low = 0;
+        let duration = self.clock.nanos() + timing.read_sample;
+        loop {
+            if self.gpio.read(self.pin) == 0 {
+                sig_low += 1;
+            } else {
+                sig_high += 1;
+            }
+
+            if self.clock.nanos() > duration {
+                break;
+            }
+        }
+
+        // Wait the remainder of the time slot
+        self.clock.wait_ns(timing.read_recovery);
+        return sig_high > sig_low;
+    }
+
+    /// Least significant bit first, like everything on the bus.
+    pub fn write_byte(&mut self, byte: u8) {
+        for bit in 0 .. 8 {
+            self.write_bit(byte & (0x1 << bit) > 0);
+        }
+    }
+
+    pub fn read_byte(&mut self) -> u8 {
+        let mut result = 0;
+        for bit in 0 .. 8 {
+            if self.read_bit() {
+                result |= 0x1 << bit;
+            }
+        }
+        return result;
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_byte(*byte);
+        }
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) {
+        for idx in 0 .. bytes.len() {
+            bytes[idx] = self.read_byte();
+        }
+    }
+
+    /// Address every device at once, which only makes sense for
+    /// commands nobody answers or with a single device.
+    pub fn skip_rom(&mut self) -> Result<(), BusError> {
+        self.initialize()?;
+        self.write_byte(SKIP_ROM);
+        return Ok(());
+    }
+
+    /// Only the device with this code listens to what follows.
+    pub fn match_rom(&mut self, rom: u64) -> Result<(), BusError> {
+        self.initialize()?;
+        self.write_byte(MATCH_ROM);
+        self.write_bytes(&rom.to_le_bytes());
+        return Ok(());
+    }
+
+    /// Address one device by its code, or the only one on the bus.
+    pub fn select(&mut self, rom: Option<u64>) -> Result<(), BusError> {
+        return match rom {
+            None => self.skip_rom(),
+            Some(rom) => self.match_rom(rom),
+        };
+    }
+
+    /// Read the ROM code of the only device on the bus. The
+    /// family code is the lowest byte and the crc the highest.
+    pub fn read_rom(&mut self) -> Result<u64, BusError> {
+        self.initialize()?;
+        self.write_byte(READ_ROM);
+
+        let mut bytes = [0; 8];
+        self.read_bytes(&mut bytes);
+        if crc8(&bytes) != 0 || bytes[0] == 0 {
+            return Err(BusError::BadCrc);
+        }
+        return Ok(u64::from_le_bytes(bytes));
+    }
+
+    /// Switch every device which supports it to overdrive and
+    /// address them all, like `skip_rom`.
+    pub fn overdrive_skip_rom(&mut self) -> Result<(), BusError> {
+        self.speed = Speed::Standard;
+        self.initialize()?;
+        self.write_byte(OVERDRIVE_SKIP_ROM);
+        self.speed = Speed::Overdrive;
+        return Ok(());
+    }
+
+    /// Switch one device to overdrive and address it. Its code
+    /// already goes out at overdrive speed.
+    pub fn overdrive_match_rom(&mut self, rom: u64) -> Result<(), BusError> {
+        self.speed = Speed::Standard;
+        self.initialize()?;
+        self.write_byte(OVERDRIVE_MATCH_ROM);
+        self.speed = Speed::Overdrive;
+        self.write_bytes(&rom.to_le_bytes());
+        return Ok(());
+    }
+
+    /// Find the ROM code of every device on the bus.
+    pub fn search(&mut self) -> Result<Vector<u64>, BusError> {
+        return self.run_search(false);
+    }
+
+    /// Find the devices which have an alarm condition, like
+    /// temperature sensors outside of their thresholds.
+    pub fn alarm_search(&mut self) -> Result<Vector<u64>, BusError> {
+        return self.run_search(true);
+    }
+
+    fn run_search(&mut self, alarm_only: bool) -> Result<Vector<u64>, BusError> {
+        let mut search = RomSearch::new(alarm_only);
+        let mut result = Vector::new();
+        loop {
+            match search.next(self) {
+                None => {
+                    return Ok(result);
+                },
+                Some(Ok(rom)) => {
+                    result.push(rom);
+                },
+                Some(Err(err)) => {
+                    result.free();
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    fn pull_low(&mut self) {
+        self.gpio.mode(self.pin, Mode::Output);
+        self.gpio.write(self.pin, Power::Low);
+    }
+
+    /// Let the pull-up take the bus high, unless a device
+    /// holds it low.
+    fn release(&mut self) {
+        self.gpio.mode(self.pin, Mode::Input);
+    }
+}
+
+/// What a ROM search needs from the bus.
+pub trait SearchBus {
+    /// Reset the bus and send the search command.
+    fn begin_search(&mut self, command: u8) -> Result<(), BusError>;
+    /// Every device still taking part sends the next bit of its
+    /// code, then its complement. The bus reads as the AND of them.
+    fn read_search_bit(&mut self) -> bool;
+    /// Devices whose bit is different drop out until the next reset.
+    fn write_search_bit(&mut self, bit: bool);
+}
+
+impl<G: Gpio, C: Clock> SearchBus for OneWireBus<G, C> {
+    fn begin_search(&mut self, command: u8) -> Result<(), BusError> {
+        self.initialize()?;
+        self.write_byte(command);
+        return Ok(());
+    }
+
+    fn read_search_bit(&mut self) -> bool {
+        return self.read_bit();
+    }
+
+    fn write_search_bit(&mut self, bit: bool) {
+        self.write_bit(bit);
+    }
+}
+
+/// The search algorithm from Maxim's application note 187. Each
+/// pass walks down the ROM codes a bit at a time. Where devices
+/// disagree, a pass takes the 0 branch first and the next pass
+/// comes back for the 1 branch, so every device is found once.
+pub struct RomSearch {
+    command: u8,
+    rom: u64,
+    /// The last bit (counting from 1) where a pass took the 0
+    /// branch, or 0 when every branch has been taken
+    last_discrepancy: usize,
+    done: bool,
+}
+
+impl RomSearch {
+    /// An alarm search only finds the devices with an alarm
+    /// condition.
+    pub fn new(alarm_only: bool) -> Self {
+        return RomSearch {
+            command: if alarm_only { ALARM_SEARCH } else { SEARCH_ROM },
+            rom: 0,
+            last_discrepancy: 0,
+            done: false,
+        };
+    }
+
+    /// Find the next device. Returns None once every one has been
+    /// found, and stops after the first error.
+    pub fn next<B: SearchBus>(&mut self, bus: &mut B) -> Option<Result<u64, BusError>> {
+        if self.done {
+            return None;
+        }
+
+        self.done = true;
+        match bus.begin_search(self.command) {
+            Err(err) => {
+                return Some(Err(err));
+            },
+            Ok(_) => {},
+        }
+
+        let mut last_zero = 0;
+        for bit in 1 ..= 64 {
+            let id_bit = bus.read_search_bit();
+            let complement = bus.read_search_bit();
+            let mask = 1 << (bit - 1);
+
+            let direction = if id_bit && complement {
+                // Nobody is left. Right at the start, that means no
+                // device is in alarm. Later on, one went missing.
+                if bit == 1 {
+                    return None;
+                }
+                return Some(Err(BusError::BadCrc));
+            } else if id_bit != complement {
+                id_bit
+            } else {
+                // Some devices have a 0 here and some a 1
+                let direction = if bit < self.last_discrepancy {
+                    self.rom & mask != 0
+                } else {
+                    bit == self.last_discrepancy
+                };
+                if !direction {
+                    last_zero = bit;
+                }
+                direction
+            };
+
+            if direction {
+                self.rom |= mask;
+            } else {
+                self.rom &= !mask;
+            }
+            bus.write_search_bit(direction);
+        }
+
+        // A bus held low reads as zeros, which pass the crc
+        let bytes = self.rom.to_le_bytes();
+        if crc8(&bytes) != 0 || bytes[0] == 0 {
+            return Some(Err(BusError::BadCrc));
+        }
+
+        self.last_discrepancy = last_zero;
+        self.done = last_zero == 0;
+        return Some(Ok(self.rom));
+    }
+}
+
+#[cfg(test)]
+pub mod test_one_wire {
+    use super::*;
+    use super::simulator::*;
+    use crate::hal::mock::*;
+
+    /// A ROM code with its crc
+    pub fn rom(family: u8, serial: u64) -> u64 {
+        let mut bytes = (serial << 8 | family as u64).to_le_bytes();
+        bytes[7] = crc8(&bytes[0 .. 7]);
+        return u64::from_le_bytes(bytes);
+    }
+
+    /// Devices answering a search the way the bus combines them
+    struct FakeBus {
+        roms: [u64; 4],
+        alarms: [bool; 4],
+        taking_part: [bool; 4],
+        bit: usize,
+        complement: bool,
+    }
+
+    impl FakeBus {
+        fn new(roms: [u64; 4], alarms: [bool; 4]) -> Self {
+            return FakeBus {
+                roms: roms,
+                alarms: alarms,
+                taking_part: [false; 4],
+                bit: 0,
+                complement: false,
+            };
+        }
+    }
+
+    impl SearchBus for FakeBus {
+        fn begin_search(&mut self, command: u8) -> Result<(), BusError> {
+            for idx in 0 .. self.roms.len() {
+                self.taking_part[idx] = self.roms[idx] != 0 && (command == SEARCH_ROM || self.alarms[idx]);
+            }
+            self.bit = 0;
+            self.complement = false;
+            return Ok(());
+        }
+
+        fn read_search_bit(&mut self) -> bool {
+            let mut result = true;
+            for idx in 0 .. self.roms.len() {
+                let bit = self.roms[idx] & (1 << self.bit) != 0;
+                if self.taking_part[idx] {
+                    result &= bit != self.complement;
+                }
+            }
+            self.complement = !self.complement;
+            return result;
+        }
+
+        fn write_search_bit(&mut self, bit: bool) {
+            for idx in 0 .. self.roms.len() {
+                if (self.roms[idx] & (1 << self.bit) != 0) != bit {
+                    self.taking_part[idx] = false;
+                }
+            }
+            self.bit += 1;
+        }
+    }
+
+    fn search_all(bus: &mut FakeBus, alarm_only: bool) -> ([u64; 4], usize, Option<BusError>) {
+        let mut search = RomSearch::new(alarm_only);
+        let mut found = [0; 4];
+        let mut count = 0;
+        loop {
+            match search.next(bus) {
+                None => {
+                    return (found, count, None);
+                },
+                Some(Ok(rom)) => {
+                    found[count] = rom;
+                    count += 1;
+                },
+                Some(Err(err)) => {
+                    return (found, count, Some(err));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rom_search() {
+        // The first two only differ in the last bit of the serial
+        let roms = [rom(0x28, 0x0A), rom(0x28, 0x0B), rom(0x3B, 0x0A), rom(0x28, 0xFF_0000_0000)];
+        let mut bus = FakeBus::new(roms, [false, true, false, true]);
+
+        let (found, count, err) = search_all(&mut bus, false);
+        assert_eq!((count, err), (4, None));
+        for rom in roms {
+            assert_eq!(found.iter().filter(|other| **other == rom).count(), 1);
+        }
+
+        let (found, count, err) = search_all(&mut bus, true);
+        assert_eq!((count, err), (2, None));
+        assert!(found[0 .. 2].contains(&roms[1]) && found[0 .. 2].contains(&roms[3]));
+
+        // Nobody in alarm is an empty search, not an error
+        let mut quiet = FakeBus::new(roms, [false; 4]);
+        assert_eq!(search_all(&mut quiet, true), ([0; 4], 0, None));
+
+        let mut single = FakeBus::new([roms[2], 0, 0, 0], [false; 4]);
+        assert_eq!(search_all(&mut single, false), ([roms[2], 0, 0, 0], 1, None));
+
+        let mut damaged = FakeBus::new([roms[0] ^ 0x100, 0, 0, 0], [false; 4]);
+        assert_eq!(search_all(&mut damaged, false).2, Some(BusError::BadCrc));
+    }
+
+    #[test]
+    fn test_simulated_timing() {
+        let clock = MockClock::new().with_auto_advance(MICRO_TO_NANO);
+        let wire = BusSimulator::new(&clock, 10);
+        let mut bus = OneWireBus::new(10, wire.clone(), &clock);
+        assert_eq!(bus.reset(), Err(BusError::NoPresence));
+
+        let roms = [rom(0x28, 0x0A), rom(0x28, 0x0B), rom(0x3B, 0x0A)];
+        for rom in roms {
+            wire.add_sensor(rom, 0x0191);
+        }
+        assert_eq!(bus.reset(), Ok(()));
+        assert_eq!(bus.read_rom(), Err(BusError::BadCrc));
+
+        let mut found = bus.search().unwrap();
+        assert_eq!(found.size(), 3);
+        for rom in roms {
+            assert!(found.into_iter().any(|other| other == rom));
+        }
+        found.free();
+        assert!(wire.timing_errors().is_empty(), "{:?}", wire.timing_errors());
+
+        // A slot which is neither a 1 nor a proper 0
+        let mut pin = wire.clone();
+        clock.advance(10 * MICRO_TO_NANO);
+        pin.mode(10, Mode::Output);
+        pin.write(10, Power::Low);
+        clock.advance(30 * MICRO_TO_NANO);
+        pin.mode(10, Mode::Input);
+        assert_eq!(wire.timing_errors().len(), 1);
+    }
+
+    #[test]
+    fn test_overdrive() {
+        let clock = MockClock::new().with_auto_advance(50);
+        let wire = BusSimulator::new(&clock, 10);
+        let mut bus = OneWireBus::new(10, wire.clone(), &clock);
+        let (first, second) = (rom(0x28, 0x0A), rom(0x28, 0x0B));
+        wire.add_sensor(first, 0x0191);
+        wire.add_sensor(second, -162);
+
+        // Devices at standard speed take an overdrive reset for a 0
+        bus.set_speed(Speed::Overdrive);
+        assert_eq!(bus.reset(), Err(BusError::NoPresence));
+
+        assert_eq!(bus.overdrive_match_rom(second), Ok(()));
+        assert!(wire.overdrive());
+        assert_eq!(bus.speed(), Speed::Overdrive);
+
+        // Read the scratchpad at overdrive speed
+        bus.write_byte(0xBE);
+        let mut scratchpad = [0; 9];
+        bus.read_bytes(&mut scratchpad);
+        assert_eq!(crc8(&scratchpad), 0);
+        assert_eq!(scratchpad[0 .. 2], [0x50, 0x05]);
+
+        assert_eq!(bus.reset(), Ok(()));
+        let mut found = bus.search().unwrap();
+        assert_eq!(found.size(), 2);
+        found.free();
+        assert!(wire.timing_errors().is_empty(), "{:?}", wire.timing_errors());
+
+        // A standard reset brings everyone back
+        bus.set_speed(Speed::Standard);
+        assert_eq!(bus.reset(), Ok(()));
+        assert!(!wire.overdrive());
+        assert!(wire.timing_errors().is_empty(), "{:?}", wire.timing_errors());
+    }
+
+    #[test]
+    fn test_crc_helpers() {
+        let inverted = !crc16(&[0xF0, 0x00, 0x00]);
+        assert!(check_crc16(&[0xF0, 0x00, 0x00], inverted.to_le_bytes()));
+        assert!(!check_crc16(&[0xF0, 0x00, 0x01], inverted.to_le_bytes()));
+        assert_eq!(&rom_hex(0x0000_0000_0000_0128), b"2801000000000000");
+    }
+}
@@ -0,0 +1,569 @@
+//! A pretend 1-Wire bus for host tests. It sits on the pin in
+//! place of the real wire, watches when the master pulls it low
+//! and lets go, and answers the way devices do: presence pulses,
+//! the ROM commands, searches and the function commands of the
+//! temperature sensors. This is only available with the `testing`
+//! feature.
+//!
+//! Slots are told apart by their timing, so a master which gets
+//! the timing wrong reads garbage, just like on the wall. Anything
+//! outside of what the datasheets allow is noted as well.
+//!
+//! ```ignore
+//! let wire = BusSimulator::new(&clock, PIN);
+//! wire.add_sensor(rom, 0x0191);
+//! let mut driver = Max31820Driver::new(OneWireBus::new(PIN, wire.clone(), &clock));
+//! assert!(wire.timing_errors().is_empty());
+//! ```
+
+use core::cell::{RefCell, RefMut};
+use std::prelude::rust_2021::*;
+use std::format;
+use std::rc::Rc;
+use teensycore::*;
+use teensycore::clock::*;
+use teensycore::phys::pins::*;
+use crate::hal::*;
+use crate::hal::mock::MockClock;
+use super::*;
+
+// Function commands of the temperature sensors
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+const WRITE_SCRATCHPAD: u8 = 0x4E;
+
+/// What a sensor reads before its first conversion, 85 C
+pub const POWER_ON_READING: i16 = 0x0550;
+
+/// What devices expect from the master, and how they answer.
+struct Limits {
+    /// The shortest pulse which resets devices
+    reset_low: uNano,
+    /// The longest a slot may hold the bus low
+    slot_low: uNano,
+    /// The shortest low which reliably writes a 0
+    write_0_low: uNano,
+    /// Devices sample written bits this long into the slot, so
+    /// anything shorter is a 1
+    sample_at: uNano,
+    /// How long a device holds the bus to send a 0
+    hold: uNano,
+    presence_wait: uNano,
+    presence_low: uNano,
+    /// How long the bus has to stay high after a reset
+    reset_high: uNano,
+    /// How long it has to stay high between slots
+    recovery: uNano,
+}
+
+fn limits(overdrive: bool) -> Limits {
+    return match overdrive {
+        false => Limits {
+            reset_low: 480 * MICRO_TO_NANO,
+            slot_low: 120 * MICRO_TO_NANO,
+            write_0_low: 60 * MICRO_TO_NANO,
+            sample_at: 15 * MICRO_TO_NANO,
+            hold: 30 * MICRO_TO_NANO,
+            presence_wait: 30 * MICRO_TO_NANO,
+            presence_low: 120 * MICRO_TO_NANO,
+            reset_high: 480 * MICRO_TO_NANO,
+            recovery: 1 * MICRO_TO_NANO,
+        },
+        true => Limits {
+            reset_low: 48 * MICRO_TO_NANO,
+            slot_low: 16 * MICRO_TO_NANO,
+            write_0_low: 6 * MICRO_TO_NANO,
+            sample_at: 2 * MICRO_TO_NANO,
+            hold: 3 * MICRO_TO_NANO,
+            presence_wait: 2 * MICRO_TO_NANO,
+            presence_low: 10 * MICRO_TO_NANO,
+            reset_high: 48 * MICRO_TO_NANO,
+            recovery: 1 * MICRO_TO_NANO,
+        },
+    };
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Phase {
+    /// Ignoring everything until the next reset
+    Idle,
+    RomCommand,
+    MatchRom,
+    /// Sending a bit of the code, its complement, then reading
+    /// which way the master went
+    Search(u8),
+    Function,
+    /// Sending `out` to the master
+    Transmit,
+    /// Receiving the scratchpad
+    Receive,
+    Converting,
+}
+
+struct Sensor {
+    rom: u64,
+    /// What the scratchpad says
+    reading: i16,
+    /// What the next conversion reads
+    actual: i16,
+    alarm_high: i8,
+    alarm_low: i8,
+    config: u8,
+    selected: bool,
+    converting_until: Option<uNano>,
+    /// Conversions never finish
+    stuck: bool,
+    out: [u8; 9],
+}
+
+impl Sensor {
+    fn bit(&self, index: usize) -> bool {
+        return self.rom & (1 << index) != 0;
+    }
+
+    fn in_alarm(&self) -> bool {
+        // Only the whole degrees are compared
+        let degrees = (self.reading >> 4) as i8;
+        return degrees >= self.alarm_high || degrees <= self.alarm_low;
+    }
+
+    fn scratchpad(&self) -> [u8; 9] {
+        let raw = self.reading.to_le_bytes();
+        let mut result = [raw[0], raw[1], self.alarm_high as u8, self.alarm_low as u8, self.config, 0xFF, 0x0C, 0x10, 0];
+        result[8] = crc8(&result[0 .. 8]);
+        return result;
+    }
+
+    fn conversion_time(&self) -> uNano {
+        return (93_750 * MICRO_TO_NANO) << ((self.config >> 5) & 0x03);
+    }
+}
+
+struct BusState {
+    pin: usize,
+    sensors: Vec<Sensor>,
+    phase: Phase,
+    overdrive: bool,
+    shorted: bool,
+    is_output: bool,
+    output_low: bool,
+    /// Whether the master holds the bus low
+    driving: bool,
+    fall: uNano,
+    rise: uNano,
+    after_reset: bool,
+    /// The devices sent this slot, so the master isn't writing
+    read_slot: bool,
+    /// A device holds the bus low in between
+    hold_from: uNano,
+    hold_until: uNano,
+    bits: u64,
+    bit_count: usize,
+    tx_index: usize,
+    search_bit: usize,
+    received: Vec<u8>,
+    resets: usize,
+    errors: Vec<String>,
+}
+
+/// Sensors on a simulated wire. Clones share the same wire, so
+/// a test can keep one while a driver owns the other.
+#[derive(Clone)]
+pub struct BusSimulator<'a> {
+    state: Rc<RefCell<BusState>>,
+    clock: &'a MockClock,
+}
+
+impl<'a> BusSimulator<'a> {
+    pub fn new(clock: &'a MockClock, pin: usize) -> Self {
+        return BusSimulator {
+            state: Rc::new(RefCell::new(BusState {
+                pin: pin,
+                sensors: Vec::new(),
+                phase: Phase::Idle,
+                overdrive: false,
+                shorted: false,
+                is_output: false,
+                output_low: false,
+                driving: false,
+                fall: 0,
+                rise: 0,
+                after_reset: false,
+                read_slot: false,
+                hold_from: 0,
+                hold_until: 0,
+                bits: 0,
+                bit_count: 0,
+                tx_index: 0,
+                search_bit: 0,
+                received: Vec::new(),
+                resets: 0,
+                errors: Vec::new(),
+            })),
+            clock: clock,
+        };
+    }
+
+    fn state(&self) -> RefMut<'_, BusState> {
+        return self.state.borrow_mut();
+    }
+
+    /// Put a sensor on the wire, which measures `raw` sixteenths
+    /// of a degree once asked to.
+    pub fn add_sensor(&self, rom: u64, raw: i16) {
+        self.state().sensors.push(Sensor {
+            rom: rom,
+            reading: POWER_ON_READING,
+            actual: raw,
+            alarm_high: 75,
+            alarm_low: 70,
+            config: 0x7F,
+            selected: false,
+            converting_until: None,
+            stuck: false,
+            out: [0xFF; 9],
+        });
+    }
+
+    /// Take a sensor off the wire, as if it came loose.
+    pub fn remove_sensor(&self, rom: u64) {
+        self.state().sensors.retain(|sensor| sensor.rom != rom);
+    }
+
+    pub fn set_temperature(&self, rom: u64, raw: i16) {
+        self.sensor(rom).actual = raw;
+    }
+
+    /// Conversions on this sensor never finish.
+    pub fn stick_conversions(&self, rom: u64) {
+        self.sensor(rom).stuck = true;
+    }
+
+    /// Hold the bus low, as if it was shorted to ground.
+    pub fn short(&self, shorted: bool) {
+        self.state().shorted = shorted;
+    }
+
+    /// What a sensor's alarm thresholds and configuration are set to.
+    pub fn config(&self, rom: u64) -> (i8, i8, u8) {
+        let sensor = self.sensor(rom);
+        return (sensor.alarm_high, sensor.alarm_low, sensor.config);
+    }
+
+    pub fn overdrive(&self) -> bool {
+        return self.state().overdrive;
+    }
+
+    pub fn resets(&self) -> usize {
+        return self.state().resets;
+    }
+
+    /// Everything the master did which the datasheets don't allow.
+    pub fn timing_errors(&self) -> Vec<String> {
+        return self.state().errors.clone();
+    }
+
+    fn sensor(&self, rom: u64) -> RefMut<'_, Sensor> {
+        return RefMut::map(self.state(), |state| state.sensors.iter_mut().find(|sensor| sensor.rom == rom).unwrap());
+    }
+
+    /// Pass a change of the pin on to the wire.
+    fn update(&self) {
+        let now = self.clock.now();
+        self.state().update(now);
+    }
+}
+
+impl BusState {
+    /// Finish the conversions which are done by now.
+    fn settle(&mut self, now: uNano) {
+        for sensor in self.sensors.iter_mut() {
+            match sensor.converting_until {
+                Some(until) if until <= now && !sensor.stuck => {
+                    sensor.reading = sensor.actual;
+                    sensor.converting_until = None;
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Notice the master pulling the bus low or letting go.
+    fn update(&mut self, now: uNano) {
+        self.settle(now);
+        let driving = self.is_output && self.output_low;
+        if driving != self.driving {
+            self.driving = driving;
+            if driving {
+                self.on_fall(now);
+            } else {
+                self.on_rise(now);
+            }
+        }
+    }
+
+    fn on_fall(&mut self, now: uNano) {
+        let limits = limits(self.overdrive);
+        let high = now - self.rise;
+        let after_reset = self.after_reset;
+        self.after_reset = false;
+        self.fall = now;
+
+        // Nobody listens before the first reset
+        if self.resets > 0 {
+            if after_reset && high < limits.reset_high {
+                self.error("reset recovery too short", high);
+            } else if high < limits.recovery {
+                self.error("no recovery between slots", high);
+            }
+        }
+
+        // Devices which are sending answer every slot
+        let sent = self.sending_bit(now);
+        self.read_slot = sent.is_some();
+        if sent == Some(false) {
+            self.hold_from = now;
+            self.hold_until = now + limits.hold;
+        }
+    }
+
+    fn on_rise(&mut self, now: uNano) {
+        let low = now - self.fall;
+        let overdrive = self.overdrive;
+        self.rise = now;
+
+        if low >= limits(false).reset_low {
+            self.reset(now, true);
+        } else if overdrive && low >= limits(true).reset_low {
+            self.reset(now, false);
+        } else if low > limits(overdrive).slot_low {
+            self.error("too long for a slot, too short for a reset", low);
+        } else if !self.read_slot && self.receiving() {
+            let bit = low < limits(overdrive).sample_at;
+            if !bit && low < limits(overdrive).write_0_low {
+                self.error("write 0 too short", low);
+            }
+            self.receive_bit(bit, now);
+        }
+    }
+
+    fn reset(&mut self, now: uNano, standard: bool) {
+        if standard {
+            self.overdrive = false;
+        }
+        self.resets += 1;
+        self.after_reset = true;
+        self.bits = 0;
+        self.bit_count = 0;
+        for sensor in self.sensors.iter_mut() {
+            sensor.selected = false;
+        }
+
+        if self.sensors.len() == 0 {
+            self.phase = Phase::Idle;
+            return;
+        }
+
+        let limits = limits(self.overdrive);
+        self.phase = Phase::RomCommand;
+        self.hold_from = now + limits.presence_wait;
+        self.hold_until = self.hold_from + limits.presence_low;
+    }
+
+    fn receiving(&mut self) -> bool {
+        return match self.phase {
+            Phase::RomCommand | Phase::MatchRom | Phase::Function | Phase::Receive | Phase::Search(2) => true,
+            _ => false,
+        };
+    }
+
+    /// What the devices put on the bus in a read slot, if they
+    /// are sending.
+    fn sending_bit(&mut self, now: uNano) -> Option<bool> {
+        let mut selected = self.sensors.iter().filter(|sensor| sensor.selected);
+        return match self.phase {
+            Phase::Transmit => {
+                let idx = self.tx_index;
+                self.tx_index += 1;
+                Some(selected.fold(true, |bus, sensor| {
+                    let byte = sensor.out.get(idx / 8).copied().unwrap_or(0xFF);
+                    bus && byte & (1 << (idx % 8)) != 0
+                }))
+            },
+            Phase::Search(0) => {
+                self.phase = Phase::Search(1);
+                Some(selected.fold(true, |bus, sensor| bus && sensor.bit(self.search_bit)))
+            },
+            Phase::Search(1) => {
+                self.phase = Phase::Search(2);
+                Some(selected.fold(true, |bus, sensor| bus && !sensor.bit(self.search_bit)))
+            },
+            Phase::Converting => {
+                let busy = selected.any(|sensor| {
+                    sensor.stuck || sensor.converting_until.map_or(false, |until| until > now)
+                });
+                Some(!busy)
+            },
+            _ => None,
+        };
+    }
+
+    fn receive_bit(&mut self, bit: bool, now: uNano) {
+        match self.phase {
+            Phase::Search(_) => {
+                let search_bit = self.search_bit;
+                for sensor in self.sensors.iter_mut() {
+                    if sensor.bit(search_bit) != bit {
+                        sensor.selected = false;
+                    }
+                }
+                self.search_bit += 1;
+                // The device which is left is addressed
+                self.phase = if self.search_bit == 64 { Phase::Function } else { Phase::Search(0) };
+                return;
+            },
+            _ => {},
+        }
+
+        if bit {
+            self.bits |= 1 << self.bit_count;
+        }
+        self.bit_count += 1;
+
+        if self.phase == Phase::MatchRom {
+            if self.bit_count == 64 {
+                let rom = self.bits;
+                for sensor in self.sensors.iter_mut() {
+                    sensor.selected = sensor.rom == rom;
+                }
+                self.phase = Phase::Function;
+                self.bits = 0;
+                self.bit_count = 0;
+            }
+        } else if self.bit_count == 8 {
+            let byte = self.bits as u8;
+            self.bits = 0;
+            self.bit_count = 0;
+            self.receive_byte(byte, now);
+        }
+    }
+
+    fn receive_byte(&mut self, byte: u8, now: uNano) {
+        match self.phase {
+            Phase::RomCommand => self.rom_command(byte),
+            Phase::Function => {
+                self.phase = match byte {
+                    CONVERT_T => {
+                        for sensor in self.sensors.iter_mut().filter(|sensor| sensor.selected) {
+                            sensor.converting_until = Some(now + sensor.conversion_time());
+                        }
+                        Phase::Converting
+                    },
+                    READ_SCRATCHPAD => {
+                        for sensor in self.sensors.iter_mut().filter(|sensor| sensor.selected) {
+                            sensor.out = sensor.scratchpad();
+                        }
+                        self.tx_index = 0;
+                        Phase::Transmit
+                    },
+                    WRITE_SCRATCHPAD => {
+                        self.received.clear();
+                        Phase::Receive
+                    },
+                    _ => Phase::Idle,
+                };
+            },
+            Phase::Receive => {
+                self.received.push(byte);
+                if self.received.len() == 3 {
+                    let received = [self.received[0], self.received[1], self.received[2]];
+                    for sensor in self.sensors.iter_mut().filter(|sensor| sensor.selected) {
+                        sensor.alarm_high = received[0] as i8;
+                        sensor.alarm_low = received[1] as i8;
+                        sensor.config = (received[2] & 0x60) | 0x1F;
+                    }
+                    self.phase = Phase::Idle;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn rom_command(&mut self, command: u8) {
+        let phase = match command {
+            READ_ROM => {
+                for sensor in self.sensors.iter_mut() {
+                    sensor.selected = true;
+                    sensor.out[0 .. 8].copy_from_slice(&sensor.rom.to_le_bytes());
+                    sensor.out[8] = 0xFF;
+                }
+                self.tx_index = 0;
+                Phase::Transmit
+            },
+            SKIP_ROM | OVERDRIVE_SKIP_ROM => {
+                for sensor in self.sensors.iter_mut() {
+                    sensor.selected = true;
+                }
+                Phase::Function
+            },
+            MATCH_ROM | OVERDRIVE_MATCH_ROM => Phase::MatchRom,
+            SEARCH_ROM | ALARM_SEARCH => {
+                for sensor in self.sensors.iter_mut() {
+                    sensor.selected = command == SEARCH_ROM || sensor.in_alarm();
+                }
+                self.search_bit = 0;
+                Phase::Search(0)
+            },
+            _ => Phase::Idle,
+        };
+
+        if command == OVERDRIVE_SKIP_ROM || command == OVERDRIVE_MATCH_ROM {
+            self.overdrive = true;
+        }
+        self.phase = phase;
+    }
+
+    fn error(&mut self, message: &str, duration: uNano) {
+        self.errors.push(format!("{} ({} ns)", message, duration));
+    }
+}
+
+impl<'a> Gpio for BusSimulator<'a> {
+    fn mode(&mut self, pin: usize, mode: Mode) {
+        if pin == self.state().pin {
+            self.state().is_output = match mode {
+                Mode::Output => true,
+                Mode::Input => false,
+            };
+            self.update();
+        }
+    }
+
+    fn pad_config(&mut self, _pin: usize, _config: PadConfig) { }
+
+    fn mux_config(&mut self, _pin: usize, _alt: Alt) { }
+
+    fn write(&mut self, pin: usize, power: Power) {
+        if pin == self.state().pin {
+            self.state().output_low = match power {
+                Power::High => false,
+                Power::Low => true,
+            };
+            self.update();
+        }
+    }
+
+    fn read(&mut self, pin: usize) -> u32 {
+        let now = self.clock.now();
+        let mut state = self.state();
+        state.settle(now);
+        if pin != state.pin {
+            return 1;
+        } else if state.shorted || state.driving {
+            return 0;
+        } else if now >= state.hold_from && now < state.hold_until {
+            return 0;
+        }
+        return 1;
+    }
+}
@@ -1,6 +1,7 @@
 use teensycore::phys::irq::{disable_interrupts, enable_interrupts};
 use teensycore::phys::pins::*;
 use teensycore::{wait_ns, wait_exact_ns, MICRO_TO_NANO, clock::uNano};
+use crate::hal::LedSink;
 
 // 800MHz
 const T0_H: uNano = 110; // ns
@@ -133,4 +134,17 @@ impl<const SIZE: usize> WS2812Driver<SIZE> {
         enable_interrupts();
         
     }
-}
\ No newline at end of file
+}
+impl<const SIZE: usize> LedSink for WS2812Driver<SIZE> {
+    fn init(&mut self) {
+        WS2812Driver::init(self);
+    }
+
+    fn set_color(&mut self, index: usize, rgb: u32) {
+        WS2812Driver::set_color(self, index, rgb);
+    }
+
+    fn flush(&mut self) {
+        WS2812Driver::flush(self);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::pixel_engine::effect::Effect;
 use teensycore::clock::uNano;
-use teensycore::{system::vector::*, vector, math::rand};
+use crate::pixel_engine::math::rand;
+use teensycore::{system::vector::*, vector};
 
 const TIME: uNano = 4200 * crate::WORLD_MUTIPLIER;
 
@@ -0,0 +1,154 @@
+//! This module is the hardware abstraction layer. Tasks
+//! and drivers talk to the clock, random number generator,
+//! serial ports, pins and LEDs through these traits instead
+//! of calling into teensycore directly. That way the entire
+//! firmware can be exercised on a host machine by swapping
+//! in the mock implementations.
+
+use teensycore::clock::uNano;
+use teensycore::phys::pins::*;
+use teensycore::system::str::*;
+
+pub mod teensy;
+
+#[cfg(feature = "testing")]
+pub mod mock;
+
+/// A monotonic source of time.
+pub trait Clock {
+    /// Nanoseconds since the system booted.
+    fn nanos(&self) -> uNano;
+    /// Block for the specified amount of nanoseconds.
+    fn wait_ns(&self, nanos: uNano);
+}
+
+/// A source of pseudo-random numbers.
+pub trait RandomSource {
+    fn rand(&mut self) -> u64;
+    fn seed(&mut self, seed: u64);
+}
+
+/// Something bytes can be written to. Besides serial ports,
+/// this lets commands be put together before they are sent.
+pub trait ByteSink {
+    fn write(&mut self, bytes: &[u8]);
+    fn write_str(&mut self, content: &Str);
+}
+
+/// A byte-oriented serial device.
+pub trait SerialPort: ByteSink {
+    fn init(&mut self);
+    fn set_baud(&mut self, rate: u32);
+    /// Returns everything received so far. The caller is
+    /// responsible for clearing the buffer once consumed.
+    fn read(&mut self) -> &mut Str;
+}
+
+/// Direct access to the physical pins.
+pub trait Gpio {
+    fn mode(&mut self, pin: usize, mode: Mode);
+    fn pad_config(&mut self, pin: usize, config: PadConfig);
+    fn mux_config(&mut self, pin: usize, alt: Alt);
+    fn write(&mut self, pin: usize, power: Power);
+    fn read(&mut self, pin: usize) -> u32;
+}
+
+/// Something which can display a strip of colors.
+pub trait LedSink {
+    fn init(&mut self);
+    /// Set the color of a single led, encoded as 0xRRGGBB.
+    fn set_color(&mut self, index: usize, rgb: u32);
+    /// Push the colors out to the leds.
+    fn flush(&mut self);
+}
+
+/// A region of non-volatile memory which behaves like NOR
+/// flash. Erasing a sector sets every byte to 0xFF and writing
+/// can only clear bits, so a byte must be erased before it can
+/// be written with a new value.
+pub trait Storage {
+    /// Size of the smallest erasable unit, in bytes.
+    fn sector_size(&self) -> usize;
+    /// How many sectors are available.
+    fn sectors(&self) -> usize;
+    /// Fill `buf` with the bytes starting at `offset`.
+    fn read(&mut self, offset: usize, buf: &mut [u8]);
+    /// Program bytes starting at `offset`. Returns false if the
+    /// device reported an error.
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> bool;
+    /// Erase an entire sector.
+    fn erase(&mut self, sector: usize) -> bool;
+}
+
+/// Text can be put together in memory before it is sent.
+impl ByteSink for Str {
+    fn write(&mut self, bytes: &[u8]) {
+        self.append(bytes);
+    }
+
+    fn write_str(&mut self, content: &Str) {
+        self.join(content);
+    }
+}
+
+// Allow borrowed implementations to be injected. This lets
+// a test keep ownership of a mock while a task drives it.
+impl<T: Clock> Clock for &T {
+    fn nanos(&self) -> uNano { return (**self).nanos(); }
+    fn wait_ns(&self, nanos: uNano) { (**self).wait_ns(nanos); }
+}
+
+impl<T: RandomSource> RandomSource for &mut T {
+    fn rand(&mut self) -> u64 { return (**self).rand(); }
+    fn seed(&mut self, seed: u64) { (**self).seed(seed); }
+}
+
+impl<T: ByteSink> ByteSink for &mut T {
+    fn write(&mut self, bytes: &[u8]) { (**self).write(bytes); }
+    fn write_str(&mut self, content: &Str) { (**self).write_str(content); }
+}
+
+impl<T: SerialPort> SerialPort for &mut T {
+    fn init(&mut self) { (**self).init(); }
+    fn set_baud(&mut self, rate: u32) { (**self).set_baud(rate); }
+    fn read(&mut self) -> &mut Str { return (**self).read(); }
+}
+
+impl<T: Gpio> Gpio for &mut T {
+    fn mode(&mut self, pin: usize, mode: Mode) { (**self).mode(pin, mode); }
+    fn pad_config(&mut self, pin: usize, config: PadConfig) { (**self).pad_config(pin, config); }
+    fn mux_config(&mut self, pin: usize, alt: Alt) { (**self).mux_config(pin, alt); }
+    fn write(&mut self, pin: usize, power: Power) { (**self).write(pin, power); }
+    fn read(&mut self, pin: usize) -> u32 { return (**self).read(pin); }
+}
+
+impl<T: LedSink> LedSink for &mut T {
+    fn init(&mut self) { (**self).init(); }
+    fn set_color(&mut self, index: usize, rgb: u32) { (**self).set_color(index, rgb); }
+    fn flush(&mut self) { (**self).flush(); }
+}
+
+impl<T: Storage> Storage for &mut T {
+    fn sector_size(&self) -> usize { return (**self).sector_size(); }
+    fn sectors(&self) -> usize { return (**self).sectors(); }
+    fn read(&mut self, offset: usize, buf: &mut [u8]) { (**self).read(offset, buf); }
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> bool { return (**self).write(offset, bytes); }
+    fn erase(&mut self, sector: usize) -> bool { return (**self).erase(sector); }
+}
+
+/// Write a debug message. On the device this goes out over
+/// the debug uart, on the host it is discarded.
+pub fn debug_str(message: &[u8]) {
+    #[cfg(not(feature = "testing"))]
+    teensycore::debug::debug_str(message);
+    #[cfg(feature = "testing")]
+    let _ = message;
+}
+
+/// Write a number and a debug message.
+pub fn debug_u64(val: u64, message: &[u8]) {
+    #[cfg(not(feature = "testing"))]
+    teensycore::debug::debug_u64(val, message);
+    #[cfg(feature = "testing")]
+    let _ = (val, message);
+}
@@ -0,0 +1,446 @@
+//! Mock implementations of the hardware abstraction layer.
+//! These are only available with the `testing` feature and
+//! let the firmware run on a host machine.
+
+use core::cell::{Cell, RefCell};
+use std::prelude::rust_2021::*;
+use std::rc::Rc;
+use std::vec;
+use teensycore::clock::uNano;
+use teensycore::phys::pins::*;
+use teensycore::system::str::*;
+use crate::hal::*;
+use crate::strings::chars;
+
+/// A clock which only moves when told to. Waiting advances
+/// the clock instantly, and every read can optionally advance
+/// it as well so busy-loops eventually terminate.
+pub struct MockClock {
+    now: Cell<uNano>,
+    auto_advance: Cell<uNano>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        return MockClock {
+            now: Cell::new(0),
+            auto_advance: Cell::new(0),
+        };
+    }
+
+    /// Advance the clock by this many nanoseconds on every read.
+    pub fn with_auto_advance(self, nanos: uNano) -> Self {
+        self.auto_advance.set(nanos);
+        return self;
+    }
+
+    pub fn set(&self, nanos: uNano) {
+        self.now.set(nanos);
+    }
+
+    pub fn advance(&self, nanos: uNano) {
+        self.now.set(self.now.get() + nanos);
+    }
+
+    /// The time, without advancing the clock like a read does.
+    pub fn now(&self) -> uNano {
+        return self.now.get();
+    }
+}
+
+impl Clock for MockClock {
+    fn nanos(&self) -> uNano {
+        let now = self.now.get();
+        self.now.set(now + self.auto_advance.get());
+        return now;
+    }
+
+    fn wait_ns(&self, nanos: uNano) {
+        self.advance(nanos);
+    }
+}
+
+/// A deterministic random source with its own state.
+pub struct MockRandom {
+    state: u64,
+}
+
+impl MockRandom {
+    pub fn new(seed: u64) -> Self {
+        let mut result = MockRandom { state: 0 };
+        result.seed(seed);
+        return result;
+    }
+}
+
+impl RandomSource for MockRandom {
+    fn rand(&mut self) -> u64 {
+        // splitmix64
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        return z ^ (z >> 31);
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+}
+
+struct SerialState {
+    /// Received, but not read by the firmware yet
+    incoming: Vec<u8>,
+    tx: Vec<u8>,
+    initialized: bool,
+    baud: u32,
+}
+
+/// A serial port backed by in-memory buffers. Cloning the
+/// port returns another handle to the same buffers, so a test
+/// can keep one while a task owns the other.
+pub struct MockSerial {
+    state: Rc<RefCell<SerialState>>,
+    /// What `read` hands out. Each handle has its own, since
+    /// the caller holds on to it after the state is released.
+    /// Whatever is left unread goes back when the handle is
+    /// dropped, so the next handle to read picks it up.
+    rx: Str,
+}
+
+impl MockSerial {
+    pub fn new() -> Self {
+        return MockSerial {
+            state: Rc::new(RefCell::new(SerialState {
+                incoming: Vec::new(),
+                tx: Vec::new(),
+                initialized: false,
+                baud: 0,
+            })),
+            rx: Str::new(),
+        };
+    }
+
+    /// Simulate bytes arriving from the other end.
+    pub fn receive(&self, bytes: &[u8]) {
+        self.state.borrow_mut().incoming.extend_from_slice(bytes);
+    }
+
+    /// Everything received but not yet read.
+    pub fn pending(&self) -> String {
+        return String::from_utf8_lossy(&self.state.borrow().incoming).into_owned();
+    }
+
+    /// Everything written to the port so far.
+    pub fn written(&self) -> String {
+        return String::from_utf8_lossy(&self.state.borrow().tx).into_owned();
+    }
+
+    /// Returns everything written since the last call and
+    /// clears the transmit log.
+    pub fn take_written(&self) -> String {
+        let result = self.written();
+        self.state.borrow_mut().tx.clear();
+        return result;
+    }
+
+    /// Like `take_written`, for binary protocols.
+    pub fn take_written_bytes(&self) -> Vec<u8> {
+        return std::mem::take(&mut self.state.borrow_mut().tx);
+    }
+
+    pub fn initialized(&self) -> bool {
+        return self.state.borrow().initialized;
+    }
+
+    pub fn baud(&self) -> u32 {
+        return self.state.borrow().baud;
+    }
+}
+
+impl Clone for MockSerial {
+    fn clone(&self) -> Self {
+        return MockSerial {
+            state: self.state.clone(),
+            rx: Str::new(),
+        };
+    }
+}
+
+impl Drop for MockSerial {
+    fn drop(&mut self) {
+        let unread: Vec<u8> = chars(&self.rx).collect();
+        self.state.borrow_mut().incoming.splice(0 .. 0, unread);
+        self.rx.drop();
+    }
+}
+
+impl SerialPort for MockSerial {
+    fn init(&mut self) {
+        self.state.borrow_mut().initialized = true;
+    }
+
+    fn set_baud(&mut self, rate: u32) {
+        self.state.borrow_mut().baud = rate;
+    }
+
+    fn read(&mut self) -> &mut Str {
+        let incoming = std::mem::take(&mut self.state.borrow_mut().incoming);
+        self.rx.append(&incoming);
+        return &mut self.rx;
+    }
+}
+
+impl ByteSink for MockSerial {
+    fn write(&mut self, bytes: &[u8]) {
+        self.state.borrow_mut().tx.extend_from_slice(bytes);
+    }
+
+    fn write_str(&mut self, content: &Str) {
+        self.state.borrow_mut().tx.extend(chars(content));
+    }
+}
+
+const MOCK_PINS: usize = 40;
+
+struct GpioState {
+    outputs: [Option<bool>; MOCK_PINS],
+    inputs: [u32; MOCK_PINS],
+    is_output: [bool; MOCK_PINS],
+    history: Vec<(usize, bool)>,
+}
+
+/// A set of pins which remembers what was written to them.
+/// Inputs float high unless told otherwise. Clones share
+/// the same pins.
+#[derive(Clone)]
+pub struct MockGpio {
+    state: Rc<RefCell<GpioState>>,
+}
+
+impl MockGpio {
+    pub fn new() -> Self {
+        return MockGpio {
+            state: Rc::new(RefCell::new(GpioState {
+                outputs: [None; MOCK_PINS],
+                inputs: [1; MOCK_PINS],
+                is_output: [false; MOCK_PINS],
+                history: Vec::new(),
+            })),
+        };
+    }
+
+    /// Set the level that will be read from a pin.
+    pub fn set_input(&self, pin: usize, level: u32) {
+        self.state.borrow_mut().inputs[pin] = level;
+    }
+
+    /// The last level written to a pin, if any.
+    pub fn output(&self, pin: usize) -> Option<bool> {
+        return self.state.borrow().outputs[pin];
+    }
+
+    pub fn is_output(&self, pin: usize) -> bool {
+        return self.state.borrow().is_output[pin];
+    }
+
+    /// Every write in order, as (pin, high)
+    pub fn history(&self) -> Vec<(usize, bool)> {
+        return self.state.borrow().history.clone();
+    }
+}
+
+impl Gpio for MockGpio {
+    fn mode(&mut self, pin: usize, mode: Mode) {
+        self.state.borrow_mut().is_output[pin] = match mode {
+            Mode::Output => true,
+            Mode::Input => false,
+        };
+    }
+
+    fn pad_config(&mut self, _pin: usize, _config: PadConfig) { }
+
+    fn mux_config(&mut self, _pin: usize, _alt: Alt) { }
+
+    fn write(&mut self, pin: usize, power: Power) {
+        let high = match power {
+            Power::High => true,
+            Power::Low => false,
+        };
+
+        let mut state = self.state.borrow_mut();
+        state.outputs[pin] = Some(high);
+        state.history.push((pin, high));
+    }
+
+    fn read(&mut self, pin: usize) -> u32 {
+        let state = self.state.borrow();
+
+        // A pin being driven low reads low
+        if state.is_output[pin] && state.outputs[pin] == Some(false) {
+            return 0;
+        }
+        return state.inputs[pin];
+    }
+}
+
+struct LedState {
+    colors: Vec<u32>,
+    frames: usize,
+    initialized: bool,
+}
+
+/// A strip of leds which records the last frame. Clones
+/// share the same strip.
+#[derive(Clone)]
+pub struct MockLeds {
+    state: Rc<RefCell<LedState>>,
+}
+
+impl MockLeds {
+    pub fn new(size: usize) -> Self {
+        return MockLeds {
+            state: Rc::new(RefCell::new(LedState {
+                colors: vec![0; size],
+                frames: 0,
+                initialized: false,
+            })),
+        };
+    }
+
+    /// The colors as of the last call to `set_color`.
+    pub fn colors(&self) -> Vec<u32> {
+        return self.state.borrow().colors.clone();
+    }
+
+    /// How many times the strip has been flushed.
+    pub fn frames(&self) -> usize {
+        return self.state.borrow().frames;
+    }
+
+    pub fn initialized(&self) -> bool {
+        return self.state.borrow().initialized;
+    }
+}
+
+impl LedSink for MockLeds {
+    fn init(&mut self) {
+        self.state.borrow_mut().initialized = true;
+    }
+
+    fn set_color(&mut self, index: usize, rgb: u32) {
+        let mut state = self.state.borrow_mut();
+        if index < state.colors.len() {
+            state.colors[index] = rgb;
+        }
+    }
+
+    fn flush(&mut self) {
+        self.state.borrow_mut().frames += 1;
+    }
+}
+
+struct FlashState {
+    bytes: Vec<u8>,
+    erase_counts: Vec<usize>,
+    sector_size: usize,
+    fail_writes: bool,
+    // Stop programming after this many more bytes, to
+    // simulate losing power partway through a write.
+    write_budget: Option<usize>,
+}
+
+/// NOR flash in memory. Writes can only clear bits, just like
+/// the real thing, and every erase is counted so tests can
+/// check how evenly the sectors wear. Clones share the same
+/// flash.
+#[derive(Clone)]
+pub struct MockFlash {
+    state: Rc<RefCell<FlashState>>,
+}
+
+impl MockFlash {
+    pub fn new(sector_size: usize, sectors: usize) -> Self {
+        return MockFlash {
+            state: Rc::new(RefCell::new(FlashState {
+                bytes: vec![0xFF; sector_size * sectors],
+                erase_counts: vec![0; sectors],
+                sector_size: sector_size,
+                fail_writes: false,
+                write_budget: None,
+            })),
+        };
+    }
+
+    /// How many times each sector has been erased.
+    pub fn erase_counts(&self) -> Vec<usize> {
+        return self.state.borrow().erase_counts.clone();
+    }
+
+    /// Flip the bits of a stored byte.
+    pub fn corrupt(&self, offset: usize) {
+        let mut state = self.state.borrow_mut();
+        state.bytes[offset] = !state.bytes[offset];
+    }
+
+    /// Make every write and erase report an error.
+    pub fn fail_writes(&self, fail: bool) {
+        self.state.borrow_mut().fail_writes = fail;
+    }
+
+    /// Only program this many more bytes, then silently drop
+    /// the rest.
+    pub fn cut_power_after(&self, bytes: usize) {
+        self.state.borrow_mut().write_budget = Some(bytes);
+    }
+
+    pub fn restore_power(&self) {
+        self.state.borrow_mut().write_budget = None;
+    }
+}
+
+impl Storage for MockFlash {
+    fn sector_size(&self) -> usize {
+        return self.state.borrow().sector_size;
+    }
+
+    fn sectors(&self) -> usize {
+        return self.state.borrow().erase_counts.len();
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) {
+        let state = self.state.borrow();
+        buf.copy_from_slice(&state.bytes[offset .. offset + buf.len()]);
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.fail_writes {
+            return false;
+        }
+
+        for idx in 0 .. bytes.len() {
+            match state.write_budget {
+                Some(0) => return true,
+                Some(budget) => state.write_budget = Some(budget - 1),
+                None => {},
+            }
+            state.bytes[offset + idx] &= bytes[idx];
+        }
+        return true;
+    }
+
+    fn erase(&mut self, sector: usize) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.fail_writes {
+            return false;
+        }
+
+        let size = state.sector_size;
+        for byte in state.bytes[sector * size .. (sector + 1) * size].iter_mut() {
+            *byte = 0xFF;
+        }
+        state.erase_counts[sector] += 1;
+        return true;
+    }
+}
@@ -0,0 +1,283 @@
+//! Implementations of the hardware abstraction layer
+//! backed by the teensycore kernel.
+
+use teensycore::clock::*;
+use teensycore::phys::pins::*;
+use teensycore::phys::irq::{disable_interrupts, enable_interrupts};
+use teensycore::serio::*;
+use teensycore::system::str::*;
+use crate::hal::*;
+use crate::pixel_engine::math::{rand, seed_rand};
+use crate::strings::chars;
+
+/// The periodic timer based system clock.
+#[derive(Copy, Clone)]
+pub struct TeensyClock { }
+
+impl TeensyClock {
+    pub const fn new() -> Self {
+        return TeensyClock { };
+    }
+}
+
+impl Clock for TeensyClock {
+    fn nanos(&self) -> uNano {
+        return nanos();
+    }
+
+    fn wait_ns(&self, nanos: uNano) {
+        teensycore::wait_ns(nanos);
+    }
+}
+
+/// The global XORSHIFT+ generator used by the pixel engine.
+#[derive(Copy, Clone)]
+pub struct TeensyRandom { }
+
+impl TeensyRandom {
+    pub const fn new() -> Self {
+        return TeensyRandom { };
+    }
+}
+
+impl RandomSource for TeensyRandom {
+    fn rand(&mut self) -> u64 {
+        return rand();
+    }
+
+    fn seed(&mut self, seed: u64) {
+        seed_rand(seed);
+    }
+}
+
+/// One of the hardware uarts.
+#[derive(Copy, Clone)]
+pub struct TeensySerial {
+    device: SerioDevice,
+}
+
+impl TeensySerial {
+    pub const fn new(device: SerioDevice) -> Self {
+        return TeensySerial {
+            device: device,
+        };
+    }
+}
+
+impl SerialPort for TeensySerial {
+    fn init(&mut self) {
+        serial_init(self.device);
+    }
+
+    fn set_baud(&mut self, rate: u32) {
+        serial_baud(self.device, rate);
+    }
+
+    fn read(&mut self) -> &mut Str {
+        return serial_read(self.device);
+    }
+}
+
+impl ByteSink for TeensySerial {
+    fn write(&mut self, bytes: &[u8]) {
+        serial_write(self.device, bytes);
+    }
+
+    fn write_str(&mut self, content: &Str) {
+        // serial_write_str relies on the kernel iterator, which
+        // misbehaves for anything longer than a single block.
+        for byte in chars(content) {
+            serial_write(self.device, &[byte]);
+        }
+    }
+}
+
+/// The teensy gpio pins.
+#[derive(Copy, Clone)]
+pub struct TeensyGpio { }
+
+impl TeensyGpio {
+    pub const fn new() -> Self {
+        return TeensyGpio { };
+    }
+}
+
+impl Gpio for TeensyGpio {
+    fn mode(&mut self, pin: usize, mode: Mode) {
+        pin_mode(pin, mode);
+    }
+
+    fn pad_config(&mut self, pin: usize, config: PadConfig) {
+        pin_pad_config(pin, config);
+    }
+
+    fn mux_config(&mut self, pin: usize, alt: Alt) {
+        pin_mux_config(pin, alt);
+    }
+
+    fn write(&mut self, pin: usize, power: Power) {
+        pin_out(pin, power);
+    }
+
+    fn read(&mut self, pin: usize) -> u32 {
+        return pin_read(pin);
+    }
+}
+
+// The last 64K of flash sit past the end of the firmware
+// image. The final sector holds the bootloader's restore
+// program, so it is left alone.
+const FLASH_BASE: usize = 0x6000_0000;
+const STORAGE_OFFSET: usize = 0x1F_0000;
+const STORAGE_SECTORS: usize = 15;
+const FLASH_SECTOR_SIZE: usize = 4096;
+const FLASH_PAGE_SIZE: usize = 256;
+
+// The i.MX RT1062 boot ROM exposes a FlexSPI NOR driver
+// through the bootloader API tree.
+const ROM_API_TREE: usize = 0x0020_001C;
+
+#[repr(C)]
+struct FlexspiNorDriver {
+    version: u32,
+    init: extern "C" fn(instance: u32, config: *mut u32) -> u32,
+    program: extern "C" fn(instance: u32, config: *mut u32, dst_addr: u32, src: *const u32) -> u32,
+    erase_all: extern "C" fn(instance: u32, config: *mut u32) -> u32,
+    erase: extern "C" fn(instance: u32, config: *mut u32, start: u32, length: u32) -> u32,
+}
+
+#[repr(C)]
+struct BootloaderApi {
+    version: u32,
+    copyright: *const u8,
+    run_bootloader: extern "C" fn(arg: *mut u8),
+    reserved: *const u32,
+    flexspi_nor_driver: *const FlexspiNorDriver,
+}
+
+extern "C" {
+    // The boot configuration block defined by teensycore
+    static FlexSPI_NOR_Config: [u32; 128];
+}
+
+const ROM_STATUS_SUCCESS: u32 = 0;
+
+// Invalidates the data cache line holding the address written
+// to it. The ROM changes flash behind the cache's back, so
+// whatever was cached before has to be thrown away.
+const SCB_DCIMVAC: usize = 0xE000_EF5C;
+const DCACHE_LINE_SIZE: usize = 32;
+
+fn invalidate_dcache(address: usize, length: usize) {
+    let mut line = address & !(DCACHE_LINE_SIZE - 1);
+    teensycore::dsb();
+    while line < address + length {
+        unsafe {
+            core::ptr::write_volatile(SCB_DCIMVAC as *mut u32, line as u32);
+        }
+        line += DCACHE_LINE_SIZE;
+    }
+    teensycore::dsb();
+    teensycore::isb();
+}
+
+/// The flash region after the firmware image, programmed
+/// through the boot ROM.
+pub struct TeensyFlash {
+    // The ROM driver reads the config while flash is busy,
+    // so it has to live in ram.
+    config: [u32; 128],
+}
+
+impl TeensyFlash {
+    pub fn new() -> Self {
+        return TeensyFlash {
+            config: unsafe { FlexSPI_NOR_Config },
+        };
+    }
+
+    fn driver(&self) -> &'static FlexspiNorDriver {
+        return unsafe {
+            let tree = *(ROM_API_TREE as *const *const BootloaderApi);
+            &*(*tree).flexspi_nor_driver
+        };
+    }
+}
+
+impl Storage for TeensyFlash {
+    fn sector_size(&self) -> usize {
+        return FLASH_SECTOR_SIZE;
+    }
+
+    fn sectors(&self) -> usize {
+        return STORAGE_SECTORS;
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) {
+        let base = FLASH_BASE + STORAGE_OFFSET + offset;
+        for idx in 0 .. buf.len() {
+            buf[idx] = unsafe { core::ptr::read_volatile((base + idx) as *const u8) };
+        }
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> bool {
+        // The ROM can only program whole pages. Bytes outside
+        // of the requested range are left at 0xFF, which does
+        // not change whatever is already stored there.
+        let driver = self.driver();
+        let mut written = 0;
+
+        while written < bytes.len() {
+            let address = offset + written;
+            let page_start = address - (address % FLASH_PAGE_SIZE);
+            let page_offset = address - page_start;
+            let count = (FLASH_PAGE_SIZE - page_offset).min(bytes.len() - written);
+            let mut page = [0xFFFF_FFFFu32; FLASH_PAGE_SIZE / 4];
+
+            {
+                let page_bytes = unsafe { core::slice::from_raw_parts_mut(page.as_mut_ptr() as *mut u8, FLASH_PAGE_SIZE) };
+                page_bytes[page_offset .. page_offset + count].copy_from_slice(&bytes[written .. written + count]);
+            }
+
+            disable_interrupts();
+            let status = (driver.program)(0, self.config.as_mut_ptr(), (STORAGE_OFFSET + page_start) as u32, page.as_ptr());
+            invalidate_dcache(FLASH_BASE + STORAGE_OFFSET + page_start, FLASH_PAGE_SIZE);
+            enable_interrupts();
+
+            if status != ROM_STATUS_SUCCESS {
+                return false;
+            }
+
+            written += count;
+        }
+
+        return true;
+    }
+
+    fn erase(&mut self, sector: usize) -> bool {
+        if sector >= STORAGE_SECTORS {
+            return false;
+        }
+
+        let driver = self.driver();
+        disable_interrupts();
+        let status = (driver.erase)(0, self.config.as_mut_ptr(), (STORAGE_OFFSET + sector * FLASH_SECTOR_SIZE) as u32, FLASH_SECTOR_SIZE as u32);
+        invalidate_dcache(FLASH_BASE + STORAGE_OFFSET + sector * FLASH_SECTOR_SIZE, FLASH_SECTOR_SIZE);
+        enable_interrupts();
+        return status == ROM_STATUS_SUCCESS;
+    }
+}
+
+// Writing the key with SYSRESETREQ to the application
+// interrupt and reset control register restarts the chip.
+const SCB_AIRCR: usize = 0xE000_ED0C;
+const AIRCR_SYSRESETREQ: u32 = 0x05FA_0004;
+
+/// Restart the whole board, as if it was power cycled.
+pub fn reboot() -> ! {
+    teensycore::dsb();
+    unsafe {
+        core::ptr::write_volatile(SCB_AIRCR as *mut u32, AIRCR_SYSRESETREQ);
+    }
+    loop {}
+}
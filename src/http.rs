@@ -2,5 +2,8 @@
 //! additional functionality for parsing and managing
 //! connections.
 
+pub mod client;
 pub mod parser;
-pub mod models;
\ No newline at end of file
+pub mod models;
+pub mod server;
+pub mod url;
\ No newline at end of file
@@ -0,0 +1,551 @@
+//! An http client which makes requests through the ESP8266.
+//! The client never waits for the module. Its commands go
+//! through the command queue, and each answer moves the
+//! request along:
+//!
+//! 1. Resolve the host with `AT+CIPDOMAIN` (skipped for ip addresses)
+//! 2. Open the tcp connection
+//! 3. Send the request and wait for `SEND OK`
+//! 4. Collect `+IPD` packets until the response is complete
+//!
+//! The packets and the notice that the server hung up have to
+//! be passed along with `handle_event`, and `poll` reports
+//! once the request is over. Any open connection is then
+//! closed with `AT+CIPCLOSE`.
+
+use teensycore::clock::uNano;
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+use crate::drivers::esp8266::*;
+use crate::drivers::esp8266::parser::*;
+use crate::drivers::esp8266::queue::*;
+use crate::hal::*;
+use crate::http::models::*;
+use crate::http::parser::*;
+use crate::http::url::*;
+use crate::strings::*;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ClientError {
+    /// The url could not be parsed, or isn't http
+    InvalidUrl,
+    /// The host name could not be resolved
+    DnsFailed,
+    ConnectFailed,
+    SendFailed,
+    /// The whole exchange took longer than allowed
+    Timeout,
+    /// The server answered with something unusable
+    Response(ResponseError),
+}
+
+pub enum ClientResult {
+    /// Still waiting on the module or the server. This is also
+    /// what an idle client returns.
+    Pending,
+    /// The complete response, with any status code. Don't
+    /// forget to drop it.
+    Done(HttpResponse),
+    Failed(ClientError),
+}
+
+/// Implemented by whatever owns both the client and the
+/// command queue it sends through, so the client's callbacks
+/// can find their way back to it.
+pub trait HttpContext: Sized {
+    fn http_client(&mut self) -> &mut HttpClient;
+    fn command_queue(&mut self) -> &mut CommandQueue<Self>;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum ClientStep {
+    Idle,
+    Resolve,
+    Connect,
+    Send,
+    Receive,
+}
+
+pub struct HttpClient {
+    /// The connection id to use when multiple connections are
+    /// enabled on the module
+    link: Option<u8>,
+    step: ClientStep,
+    request: Option<HttpRequest>,
+    address: Str,
+    port: u32,
+    received: Str,
+    /// Set once the server hung up
+    closed: bool,
+    /// Set once one of the commands failed
+    error: Option<ClientError>,
+    timeout: uNano,
+    deadline: uNano,
+}
+
+impl HttpClient {
+    pub fn new(link: Option<u8>) -> Self {
+        return HttpClient {
+            link: link,
+            step: ClientStep::Idle,
+            request: None,
+            address: Str::new(),
+            port: DEFAULT_HTTP_PORT,
+            received: Str::new(),
+            closed: false,
+            error: None,
+            timeout: 0,
+            deadline: 0,
+        };
+    }
+
+    /// Start a GET request. Anything already in flight is
+    /// abandoned.
+    pub fn get<T: HttpContext, C: Clock>(&mut self, queue: &mut CommandQueue<T>, clock: &C, url: &Str, timeout: uNano) -> Result<(), ClientError> {
+        return self.start(queue, clock, b"GET", url, None, timeout);
+    }
+
+    /// Start a POST request with the given body.
+    pub fn post<T: HttpContext, C: Clock>(&mut self, queue: &mut CommandQueue<T>, clock: &C, url: &Str, content_type: &[u8], body: &Str, timeout: uNano) -> Result<(), ClientError> {
+        self.start(queue, clock, b"POST", url, Some(Str::from_str(body)), timeout)?;
+        let mut value = Str::with_content(content_type);
+        self.add_header(b"Content-Type", &value);
+        value.drop();
+        return Ok(());
+    }
+
+    /// Add a header to the request which was just started.
+    pub fn add_header(&mut self, key: &[u8], value: &Str) {
+        match self.request.as_mut() {
+            None => {},
+            Some(request) => {
+                let mut headers = request.headers.unwrap_or(Vector::new());
+                headers.push(HttpHeader {
+                    key: Str::with_content(key),
+                    value: Str::from_str(value),
+                });
+                request.headers = Some(headers);
+            }
+        }
+    }
+
+    /// Returns true while a request is in flight.
+    pub fn busy(&self) -> bool {
+        return self.step != ClientStep::Idle;
+    }
+
+    /// Take in something the module reported. Returns true if
+    /// it was meant for the client. Anything on the client's
+    /// own link is, and without a link, anything that arrives
+    /// while a request is in flight.
+    pub fn handle_event(&mut self, event: &AtEvent) -> bool {
+        let link = match event {
+            AtEvent::Ipd { link, .. } | AtEvent::Closed(link) => *link,
+            _ => return false,
+        };
+
+        if link != self.link || (link.is_none() && !self.busy()) {
+            return false;
+        }
+
+        match event {
+            AtEvent::Ipd { data, .. } if self.busy() => {
+                self.received.join(data);
+            },
+            AtEvent::Closed(_) => {
+                self.closed = true;
+            },
+            _ => {},
+        }
+        return true;
+    }
+
+    pub fn poll<T: HttpContext, C: Clock>(&mut self, queue: &mut CommandQueue<T>, clock: &C) -> ClientResult {
+        if self.step == ClientStep::Idle {
+            return ClientResult::Pending;
+        }
+
+        match self.error {
+            Some(error) => return self.fail(queue, error),
+            None => {},
+        }
+
+        if clock.nanos() > self.deadline {
+            return self.fail(queue, ClientError::Timeout);
+        } else if self.step != ClientStep::Receive {
+            return ClientResult::Pending;
+        }
+
+        match parse_response(&self.received) {
+            Ok(mut response) => {
+                // Without a length, the body only ends when the connection does
+                if !self.closed && !has_length(&response) {
+                    response.drop();
+                    return ClientResult::Pending;
+                }
+                self.finish(queue);
+                return ClientResult::Done(response);
+            },
+            Err(ResponseError::Truncated) if !self.closed => {},
+            Err(err) => {
+                return self.fail(queue, ClientError::Response(err));
+            }
+        }
+
+        return ClientResult::Pending;
+    }
+
+    /// Forget about the request in flight without closing
+    /// anything, like when the module restarted.
+    pub fn cancel(&mut self) {
+        self.reset();
+    }
+
+    pub fn drop(&mut self) {
+        self.reset();
+        self.address.drop();
+        self.received.drop();
+    }
+
+    fn start<T: HttpContext, C: Clock>(&mut self, queue: &mut CommandQueue<T>, clock: &C, method: &[u8], url: &Str, content: Option<Str>, timeout: uNano) -> Result<(), ClientError> {
+        self.reset();
+        let mut url = match Url::parse(url) {
+            Some(url) => url,
+            None => {
+                match content {
+                    None => {},
+                    Some(mut content) => content.drop(),
+                }
+                return Err(ClientError::InvalidUrl);
+            }
+        };
+
+        // The connection is closed after every request, so
+        // tell the server not to wait around for another one.
+        let mut headers = Vector::new();
+        headers.push(HttpHeader {
+            key: Str::with_content(b"Connection"),
+            value: Str::with_content(b"close"),
+        });
+
+        self.request = Some(HttpRequest {
+            method: Str::with_content(method),
+            request_path: Str::from_str(&url.path),
+            host: url.host_header(),
+            headers: Some(headers),
+            content: content,
+        });
+        self.port = url.port;
+        self.timeout = timeout;
+        self.deadline = clock.nanos() + timeout;
+        self.address.join(&url.host);
+
+        if url.has_ip_address() {
+            queue.push(self.connect_command(), Self::connected::<T>);
+            self.step = ClientStep::Connect;
+        } else {
+            let mut command = AtCommand::new().with_timeout(timeout);
+            esp8266_dns_lookup(&mut command, &url.host);
+            queue.push(command, Self::resolved::<T>);
+            self.step = ClientStep::Resolve;
+        }
+
+        url.drop();
+        return Ok(());
+    }
+
+    fn connect_command(&self) -> AtCommand {
+        let mut command = AtCommand::new().with_timeout(self.timeout);
+        esp8266_open_tcp(&mut command, &self.address, self.port, self.link);
+        return command;
+    }
+
+    fn resolved<T: HttpContext>(context: &mut T, response: &AtResponse) {
+        let client = context.http_client();
+        if client.step != ClientStep::Resolve {
+            return;
+        }
+
+        // +CIPDOMAIN:"93.184.216.34" (older firmware leaves out the quotes)
+        let mut address = match response.line(b"+CIPDOMAIN:") {
+            Some(address) if response.ok() => address,
+            Some(mut address) => {
+                address.drop();
+                client.error = Some(ClientError::DnsFailed);
+                return;
+            },
+            None => {
+                client.error = Some(ClientError::DnsFailed);
+                return;
+            }
+        };
+
+        client.address.clear();
+        for char in chars(&address) {
+            if char != b'"' {
+                client.address.append(&[char]);
+            }
+        }
+        address.drop();
+
+        let command = client.connect_command();
+        client.step = ClientStep::Connect;
+        context.command_queue().push(command, Self::connected::<T>);
+    }
+
+    fn connected<T: HttpContext>(context: &mut T, response: &AtResponse) {
+        let client = context.http_client();
+        if client.step != ClientStep::Connect {
+            return;
+        } else if !response.ok() {
+            client.error = Some(ClientError::ConnectFailed);
+            return;
+        }
+
+        let payload = match client.request.as_ref() {
+            None => Str::new(),
+            Some(request) => request.to_str(),
+        };
+        let command = AtCommand::send(client.link, payload).with_timeout(client.timeout);
+        client.step = ClientStep::Send;
+        context.command_queue().push(command, Self::sent::<T>);
+    }
+
+    fn sent<T: HttpContext>(context: &mut T, response: &AtResponse) {
+        let client = context.http_client();
+        if client.step != ClientStep::Send {
+            return;
+        } else if !response.ok() {
+            client.error = Some(ClientError::SendFailed);
+            return;
+        }
+
+        // The response may already have arrived
+        client.step = ClientStep::Receive;
+    }
+
+    fn fail<T>(&mut self, queue: &mut CommandQueue<T>, error: ClientError) -> ClientResult {
+        self.finish(queue);
+        return ClientResult::Failed(error);
+    }
+
+    /// Close the connection if one was made, and forget about
+    /// the request.
+    fn finish<T>(&mut self, queue: &mut CommandQueue<T>) {
+        if self.step == ClientStep::Send || self.step == ClientStep::Receive {
+            let mut command = AtCommand::new();
+            esp8266_close_tcp(&mut command, self.link);
+            queue.push(command, ignore_response);
+        }
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        match self.request.as_mut() {
+            None => {},
+            Some(request) => request.drop(),
+        }
+        self.request = None;
+        self.address.clear();
+        self.received.clear();
+        self.closed = false;
+        self.error = None;
+        self.step = ClientStep::Idle;
+    }
+}
+
+fn has_length(response: &HttpResponse) -> bool {
+    return response.header(b"Content-Length").is_some()
+        || response.header(b"Transfer-Encoding").is_some()
+        || response.status == 204
+        || response.status == 304;
+}
+
+#[cfg(test)]
+pub mod test_client {
+    use super::*;
+    use teensycore::*;
+    use std::prelude::rust_2021::*;
+    use crate::hal::mock::*;
+
+    struct Context {
+        client: HttpClient,
+        queue: CommandQueue<Context>,
+    }
+
+    impl HttpContext for Context {
+        fn http_client(&mut self) -> &mut HttpClient {
+            return &mut self.client;
+        }
+
+        fn command_queue(&mut self) -> &mut CommandQueue<Self> {
+            return &mut self.queue;
+        }
+    }
+
+    fn context(link: Option<u8>) -> Context {
+        return Context {
+            client: HttpClient::new(link),
+            queue: CommandQueue::new(),
+        };
+    }
+
+    /// Run the queue over what the module said. Anything the
+    /// client doesn't take is left in `ignored`.
+    fn run(context: &mut Context, serial: &MockSerial, clock: &MockClock, ignored: &mut Vec<u8>) {
+        while let Some(event) = context.queue.poll(&mut serial.clone(), clock) {
+            match event {
+                QueueEvent::Done(callback, mut response) => {
+                    callback(context, &response);
+                    response.drop();
+                },
+                QueueEvent::Event(mut event) => {
+                    if !context.client.handle_event(&event) {
+                        match &event {
+                            AtEvent::Ipd { link, data, .. } => {
+                                ignored.extend_from_slice(link.unwrap_or(0).to_string().as_bytes());
+                                ignored.extend(chars(data));
+                            },
+                            _ => {},
+                        }
+                    }
+                    event.drop();
+                }
+            }
+        }
+    }
+
+    fn poll(context: &mut Context, serial: &MockSerial, clock: &MockClock, received: &[u8]) -> ClientResult {
+        serial.receive(received);
+        run(context, serial, clock, &mut Vec::new());
+        let result = context.client.poll(&mut context.queue, clock);
+        run(context, serial, clock, &mut Vec::new());
+        return result;
+    }
+
+    fn get(context: &mut Context, serial: &MockSerial, clock: &MockClock, url: &[u8], timeout: uNano) -> Result<(), ClientError> {
+        let result = context.client.get(&mut context.queue, clock, &str!(url), timeout);
+        run(context, serial, clock, &mut Vec::new());
+        return result;
+    }
+
+    #[test]
+    fn test_request_serialization() {
+        let mut headers = Vector::new();
+        headers.push(HttpHeader { key: str!(b"Accept"), value: str!(b"text/plain") });
+        let request = HttpRequest {
+            method: str!(b"POST"),
+            request_path: str!(b"/log"),
+            host: str!(b"config.example:8080"),
+            headers: Some(headers),
+            content: Some(str!(b"hi")),
+        };
+        assert!(request.to_str() == str!(b"POST /log HTTP/1.1\r\nHost: config.example:8080\r\nAccept: text/plain\r\nContent-Length: 2\r\n\r\nhi"));
+    }
+
+    #[test]
+    fn test_client_get() {
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut context = context(None);
+
+        get(&mut context, &serial, &clock, b"http://config.example:8080/wall?id=3", 10 * S_TO_NANO).ok().unwrap();
+        assert!(context.client.busy());
+        assert_eq!(serial.take_written(), "AT+CIPDOMAIN=\"config.example\"\r\n");
+
+        assert!(matches!(poll(&mut context, &serial, &clock, b"+CIPDOMAIN:\"10.0.0.7"), ClientResult::Pending));
+        assert!(matches!(poll(&mut context, &serial, &clock, b"\"\r\n\r\nOK\r\n"), ClientResult::Pending));
+        assert_eq!(serial.take_written(), "AT+CIPSTART=\"TCP\",\"10.0.0.7\",8080\r\n");
+
+        poll(&mut context, &serial, &clock, b"CONNECT\r\n\r\nOK\r\n");
+        assert!(serial.take_written().starts_with("AT+CIPSEND="));
+        poll(&mut context, &serial, &clock, b"\r\nOK\r\n> ");
+        assert!(serial.take_written().contains("GET /wall?id=3 HTTP/1.1\r\nHost: config.example:8080\r\nConnection: close\r\n\r\n"));
+
+        // The response follows right on the heels of SEND OK
+        poll(&mut context, &serial, &clock, b"Recv 80 bytes\r\n\r\nSEND OK\r\n\r\n+IPD,28:HTTP/1.1 200 OK\r\nETag: \"7\"\r\n");
+        assert!(matches!(poll(&mut context, &serial, &clock, b"\r\n+IPD,11:\r\nsome body"), ClientResult::Pending));
+        match poll(&mut context, &serial, &clock, b"CLOSED\r\n") {
+            ClientResult::Done(mut response) => {
+                assert_eq!(response.status, 200);
+                assert!(response.header(b"etag").unwrap() == str!(b"\"7\""));
+                assert!(response.body == str!(b"some body"));
+                response.drop();
+            },
+            _ => std::panic!("expected a response"),
+        }
+        assert_eq!(serial.take_written(), "AT+CIPCLOSE\r\n");
+        assert!(!context.client.busy());
+        context.client.drop();
+    }
+
+    #[test]
+    fn test_client_post() {
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut context = context(Some(4));
+
+        context.client.post(&mut context.queue, &clock, &str!(b"http://10.0.0.7/log"), b"text/plain", &str!(b"hello"), 10 * S_TO_NANO).ok().unwrap();
+        poll(&mut context, &serial, &clock, b"");
+        assert_eq!(serial.take_written(), "AT+CIPSTART=4,\"TCP\",\"10.0.0.7\",80\r\n");
+        poll(&mut context, &serial, &clock, b"4,CONNECT\r\n\r\nOK\r\n");
+        assert!(serial.take_written().starts_with("AT+CIPSEND=4,"));
+        poll(&mut context, &serial, &clock, b"OK\r\n> ");
+        assert!(serial.take_written().contains("POST /log HTTP/1.1\r\nHost: 10.0.0.7\r\nConnection: close\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello"));
+
+        // Requests to the server on other links are left alone
+        let mut ignored = Vec::new();
+        serial.receive(b"SEND OK\r\n+IPD,0,3:GET+IPD,4,38:HTTP/1.1 204 No Content\r\nServer: x\r\n\r\n");
+        run(&mut context, &serial, &clock, &mut ignored);
+        assert_eq!(ignored, b"0GET");
+        assert!(matches!(poll(&mut context, &serial, &clock, b""), ClientResult::Done(_)));
+        assert_eq!(serial.take_written(), "AT+CIPCLOSE=4\r\n");
+    }
+
+    #[test]
+    fn test_client_failures() {
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut context = context(None);
+
+        assert_eq!(get(&mut context, &serial, &clock, b"https://config.example/", S_TO_NANO).err(), Some(ClientError::InvalidUrl));
+        assert!(!context.client.busy());
+
+        get(&mut context, &serial, &clock, b"http://nowhere.example/", S_TO_NANO).ok().unwrap();
+        assert!(matches!(poll(&mut context, &serial, &clock, b"DNS Fail\r\nERROR\r\n"), ClientResult::Failed(ClientError::DnsFailed)));
+        assert_eq!(serial.take_written(), "AT+CIPDOMAIN=\"nowhere.example\"\r\n");
+
+        get(&mut context, &serial, &clock, b"http://10.0.0.7/", S_TO_NANO).ok().unwrap();
+        assert!(matches!(poll(&mut context, &serial, &clock, b"ERROR\r\nCLOSED\r\n"), ClientResult::Failed(ClientError::ConnectFailed)));
+        assert_eq!(serial.take_written(), "AT+CIPSTART=\"TCP\",\"10.0.0.7\",80\r\n");
+
+        // The server hangs up halfway through
+        get(&mut context, &serial, &clock, b"http://10.0.0.7/", 10 * S_TO_NANO).ok().unwrap();
+        poll(&mut context, &serial, &clock, b"CONNECT\r\n\r\nOK\r\n");
+        poll(&mut context, &serial, &clock, b"OK\r\n> ");
+        poll(&mut context, &serial, &clock, b"SEND OK\r\n");
+        serial.take_written();
+        let result = poll(&mut context, &serial, &clock, b"+IPD,41:HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabCLOSED\r\n");
+        assert!(matches!(result, ClientResult::Failed(ClientError::Response(ResponseError::Truncated))));
+        assert_eq!(serial.take_written(), "AT+CIPCLOSE\r\n");
+        poll(&mut context, &serial, &clock, b"ERROR\r\n");
+
+        // Or never answers at all
+        get(&mut context, &serial, &clock, b"http://10.0.0.7/", 10 * S_TO_NANO).ok().unwrap();
+        poll(&mut context, &serial, &clock, b"CONNECT\r\n\r\nOK\r\n");
+        poll(&mut context, &serial, &clock, b"OK\r\n> ");
+        poll(&mut context, &serial, &clock, b"SEND OK\r\n");
+        serial.take_written();
+        clock.advance(10 * S_TO_NANO + 1);
+        assert!(matches!(poll(&mut context, &serial, &clock, b""), ClientResult::Failed(ClientError::Timeout)));
+        assert_eq!(serial.take_written(), "AT+CIPCLOSE\r\n");
+        poll(&mut context, &serial, &clock, b"OK\r\n");
+
+        // A module that never answers the command counts as well
+        get(&mut context, &serial, &clock, b"http://10.0.0.7/", 10 * S_TO_NANO).ok().unwrap();
+        clock.advance(10 * S_TO_NANO + 1);
+        assert!(matches!(poll(&mut context, &serial, &clock, b""), ClientResult::Failed(ClientError::ConnectFailed)));
+        context.client.drop();
+        context.queue.drop();
+    }
+}
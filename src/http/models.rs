@@ -1,6 +1,7 @@
 use teensycore::math::itoa;
 use teensycore::system::str::*;
 use teensycore::system::vector::*;
+use crate::strings::*;
 
 #[derive(Copy, Clone)]
 pub struct HttpHeader {
@@ -27,10 +28,10 @@ impl HttpRequest {
         result.join(&self.method);
         result.append(b" ");
         result.join(&self.request_path);
-        result.append(b" HTTP/1.1\n");
-        result.append(b"HOST: ");
+        result.append(b" HTTP/1.1\r\n");
+        result.append(b"Host: ");
         result.join(&self.host);
-        result.append(b"\n");
+        result.append(b"\r\n");
 
         // headers
         match self.headers {
@@ -40,7 +41,7 @@ impl HttpRequest {
                     result.join(&header.key);
                     result.append(b": ");
                     result.join(&header.value);
-                    result.append(b"\n");
+                    result.append(b"\r\n");
                 }
             }
         }
@@ -48,12 +49,12 @@ impl HttpRequest {
         // Check for contenet
         match self.content {
             None => {
-                result.append(b"\n");
+                result.append(b"\r\n");
             },
             Some(content) => {
                 result.append(b"Content-Length: ");
                 result.join(&itoa(content.len() as u64));
-                result.append(b"\n\n");
+                result.append(b"\r\n\r\n");
                 result.join(&content);
             }
         }
@@ -75,6 +76,7 @@ impl HttpRequest {
                     header.key.drop();
                     header.value.drop();
                 }
+                headers.free();
             }
         }
 
@@ -85,4 +87,170 @@ impl HttpRequest {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HttpMethod {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Other,
+}
+
+impl HttpMethod {
+    pub fn from_bytes(method: &Str) -> Self {
+        for (name, method_type) in [
+            (b"GET" as &[u8], HttpMethod::Get),
+            (b"HEAD", HttpMethod::Head),
+            (b"POST", HttpMethod::Post),
+            (b"PUT", HttpMethod::Put),
+            (b"DELETE", HttpMethod::Delete),
+            (b"OPTIONS", HttpMethod::Options),
+        ] {
+            if equals(method, name) {
+                return method_type;
+            }
+        }
+        return HttpMethod::Other;
+    }
+}
+
+/// The status codes the firmware knows how to send.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HttpStatus {
+    Ok,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    InternalServerError,
+    NotImplemented,
+}
+
+impl HttpStatus {
+    /// The status line, without the protocol
+    pub fn line(&self) -> &'static [u8] {
+        return match self {
+            HttpStatus::Ok => b"200 OK",
+            HttpStatus::BadRequest => b"400 Bad Request",
+            HttpStatus::NotFound => b"404 Not Found",
+            HttpStatus::MethodNotAllowed => b"405 Method Not Allowed",
+            HttpStatus::PayloadTooLarge => b"413 Payload Too Large",
+            HttpStatus::InternalServerError => b"500 Internal Server Error",
+            HttpStatus::NotImplemented => b"501 Not Implemented",
+        };
+    }
+}
+
+/// A request which arrived at the server, already split
+/// into its parts. Query and form parameters are url-decoded.
+pub struct IncomingRequest {
+    /// The ESP8266 link the request arrived on
+    pub link: u8,
+    pub method: HttpMethod,
+    /// The path without the query string, like /api/status
+    pub path: Str,
+    pub query: Vector<HttpHeader>,
+    pub headers: Vector<HttpHeader>,
+    /// Parameters from a form encoded body
+    pub form: Vector<HttpHeader>,
+    pub body: Str,
+}
+
+impl IncomingRequest {
+    /// Returns the value of a query or form parameter. The
+    /// query string wins if both have it. The value still
+    /// belongs to the request, so don't drop it.
+    pub fn param(&self, name: &[u8]) -> Option<Str> {
+        return find_value(&self.query, name, false).or(find_value(&self.form, name, false));
+    }
+
+    /// Returns the value of a header, ignoring the case of the
+    /// name. The value still belongs to the request.
+    pub fn header(&self, name: &[u8]) -> Option<Str> {
+        return find_value(&self.headers, name, true);
+    }
+
+    pub fn path_is(&self, path: &[u8]) -> bool {
+        return equals(&self.path, path);
+    }
+
+    pub fn drop(&mut self) {
+        self.path.drop();
+        self.body.drop();
+        for list in [&mut self.query, &mut self.headers, &mut self.form] {
+            for mut entry in list.into_iter() {
+                entry.key.drop();
+                entry.value.drop();
+            }
+            list.free();
+        }
+    }
+}
+
+/// A response received from a server.
+pub struct HttpResponse {
+    /// The status code, like 200 or 404
+    pub status: u16,
+    pub headers: Vector<HttpHeader>,
+    /// The body, already joined together if it was chunked
+    pub body: Str,
+}
+
+impl HttpResponse {
+    /// Returns the value of a header, ignoring the case of the
+    /// name. The value still belongs to the response.
+    pub fn header(&self, name: &[u8]) -> Option<Str> {
+        return find_value(&self.headers, name, true);
+    }
+
+    pub fn drop(&mut self) {
+        self.body.drop();
+        for mut header in self.headers.into_iter() {
+            header.key.drop();
+            header.value.drop();
+        }
+        self.headers.free();
+    }
+}
+
+fn find_value(list: &Vector<HttpHeader>, name: &[u8], ignore_case: bool) -> Option<Str> {
+    for entry in list.into_iter() {
+        let matched = match ignore_case {
+            true => equals_ignore_case(&entry.key, name),
+            false => equals(&entry.key, name),
+        };
+
+        if matched {
+            return Some(entry.value);
+        }
+    }
+    return None;
+}
+
+/// What a route handler sends back.
+pub struct ServerResponse {
+    pub status: HttpStatus,
+    pub content_type: &'static [u8],
+    pub body: Str,
+}
+
+impl ServerResponse {
+    pub fn new(status: HttpStatus, content_type: &'static [u8], body: Str) -> Self {
+        return ServerResponse {
+            status: status,
+            content_type: content_type,
+            body: body,
+        };
+    }
+
+    pub fn text(status: HttpStatus, body: &[u8]) -> Self {
+        return ServerResponse::new(status, b"text/plain", Str::with_content(body));
+    }
+
+    pub fn drop(&mut self) {
+        self.body.drop();
+    }
+}
@@ -1,171 +1,500 @@
-use teensycore::*;
-use teensycore::debug::*;
-use teensycore::math::atoi;
 use teensycore::system::str::*;
 use teensycore::system::vector::*;
+use crate::http::models::*;
+use crate::strings::*;
 
-/// HttpResponse is a tuple containing two primary pieces
-/// of information. The first parameter is the parsed header
-/// and the third parameter is the parsed content.
-pub type HttpResponse = (Option<Str>, Option<Str>);
-
-enum ParserState {
-    LookForStart = 0x0,
-    LookForLength = 0x1,
-    LookForContent = 0x2,
-    ReadContent = 0x3,
-    Done = 0x4,
+/// The largest request the server is willing to buffer
+pub const MAX_REQUEST_SIZE: usize = 4096;
+const MAX_HEADERS: usize = 32;
+
+/// Why a response from a server could not be used.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResponseError {
+    /// The data ended before the body did. More may still be
+    /// on its way.
+    Truncated,
+    /// Not something a server would send
+    Malformed,
+    /// The server answered, but not with 200 OK
+    Status(u16),
 }
 
+/// Parse a response from a server. The body is decoded if it
+/// was sent in chunks. A body without a length runs until the
+/// end of the data, so only parse those once the connection
+/// has closed.
+pub fn parse_response(raw: &Str) -> Result<HttpResponse, ResponseError> {
+    let mut offset = 0;
+    loop {
+        let mut response = HttpResponse {
+            status: 0,
+            headers: Vector::new(),
+            body: Str::new(),
+        };
 
-pub fn parse_http_request(rx_buffer: &Str, header: &mut Str, content: &mut Str) -> bool {
-    // Ensure buffers are setup
-    header.clear();
-    content.clear();
-
-    debug_str(b"HELLO YES THIS IS DOG");
-    
-    let mut content_length_cmp = str!(b"Content-Length: ");
-    let mut ipd = str!(b"+IPD,");
-    let mut colon = str!(b":");
-    let mut crnl = str!(b"\r\n");
-    
-
-    let mut content_length: Option<u64> = None;
-    let mut state = ParserState::LookForStart;
-    let mut parsed_packet = parse_response_payload(&rx_buffer);
-
-    // Debug each line of parsed data.
-    // Search for the content length
-    // Then search for the content start
-    // Then read until content length
-    let mut lines = parsed_packet.split(b'\n');
-    for line in lines.into_iter() {
-
-        match state {
-            ParserState::LookForStart => {
-                if line.contains(&content_length_cmp) {
-                    let mut temp = line.slice(content_length_cmp.len(), line.len());
-                    content_length = Some(atoi(&temp));
-                    temp.drop();
-
-                    state = ParserState::LookForContent;
-                }
+        match parse_response_parts(raw, offset, &mut response) {
+            Ok(None) => return Ok(response),
+            Ok(Some(next)) => {
+                // An informational response, the real one follows
+                response.drop();
+                offset = next;
+            },
+            Err(err) => {
+                response.drop();
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Fill in the response which starts at the offset. For 1xx
+/// responses, returns where the next one starts.
+fn parse_response_parts(raw: &Str, start: usize, response: &mut HttpResponse) -> Result<Option<usize>, ResponseError> {
+    // HTTP/1.x SP 3DIGIT SP reason
+    let (line_end, mut offset) = next_line(raw, start).ok_or(ResponseError::Truncated)?;
+    let reason_ok = line_end == start + 12 || byte_at(raw, start + 12) == Some(b' ');
+    if find(raw, b"HTTP/1.", start) != Some(start) || byte_at(raw, start + 8) != Some(b' ') || line_end < start + 12 || !reason_ok {
+        return Err(ResponseError::Malformed);
+    }
+
+    for idx in start + 9 .. start + 12 {
+        match byte_at(raw, idx) {
+            Some(digit) if digit >= b'0' && digit <= b'9' => {
+                response.status = response.status * 10 + (digit - b'0') as u16;
+            },
+            _ => return Err(ResponseError::Malformed),
+        }
+    }
+
+    // Headers, up to the first empty line
+    loop {
+        let (header_end, next) = next_line(raw, offset).ok_or(ResponseError::Truncated)?;
+        if header_end == offset {
+            offset = next;
+            break;
+        }
+
+        let colon = find(raw, b":", offset).filter(|idx| *idx < header_end && *idx > offset).ok_or(ResponseError::Malformed)?;
+        if response.headers.size() >= MAX_HEADERS {
+            return Err(ResponseError::Malformed);
+        }
+
+        response.headers.push(HttpHeader {
+            key: trimmed(raw, offset, colon),
+            value: trimmed(raw, colon + 1, header_end),
+        });
+        offset = next;
+    }
+
+    if response.status >= 100 && response.status < 200 {
+        return Ok(Some(offset));
+    } else if response.status == 204 || response.status == 304 {
+        return Ok(None);
+    }
 
-                header.join(&line);
+    let chunked = match response.header(b"Transfer-Encoding") {
+        None => false,
+        Some(encoding) if equals_ignore_case(&encoding, b"chunked") => true,
+        Some(_) => return Err(ResponseError::Malformed),
+    };
 
+    if chunked {
+        response.body = decode_chunks(raw, offset)?;
+    } else {
+        match response.header(b"Content-Length") {
+            None => {
+                response.body = substring(raw, offset, raw.len());
             },
-            ParserState::LookForContent => {
-                // Look for the blank line
-                if line.len() == 1 {
-                    state = ParserState::ReadContent;
-                } else {
-                    header.join(&line);
+            Some(value) => {
+                let length = parse_decimal(&value).and_then(|length| usize::try_from(length).ok()).ok_or(ResponseError::Malformed)?;
+                if raw.len() < offset.checked_add(length).ok_or(ResponseError::Malformed)? {
+                    return Err(ResponseError::Truncated);
                 }
+                response.body = substring(raw, offset, length);
+            }
+        }
+    }
+
+    return Ok(None);
+}
+
+/// Join the chunks of a chunked body which starts at offset.
+fn decode_chunks(raw: &Str, offset: usize) -> Result<Str, ResponseError> {
+    let mut result = Str::new();
+    let mut offset = offset;
+
+    loop {
+        // The size in hex, optionally followed by extensions
+        let (line_end, next) = match next_line(raw, offset) {
+            None => {
+                result.drop();
+                return Err(ResponseError::Truncated);
             },
-            ParserState::ReadContent => {
-                content.join(&line);
-                content.append(b"\n");
+            Some(line) => line,
+        };
+
+        let mut size: usize = 0;
+        let mut digits = 0;
+        for idx in offset .. line_end {
+            let char = byte_at(raw, idx).unwrap();
+            match hex_value(char) {
+                Some(value) if digits < 8 => {
+                    size = (size << 4) | value as usize;
+                    digits += 1;
+                },
+                _ if char == b';' || char == b' ' => break,
+                _ => {
+                    result.drop();
+                    return Err(ResponseError::Malformed);
+                }
+            }
+        }
+
+        if digits == 0 {
+            result.drop();
+            return Err(ResponseError::Malformed);
+        }
 
-                if content.len() >= content_length.unwrap() as usize {
-                    state = ParserState::Done;
+        if size == 0 {
+            // Skip any trailers, up to the final empty line
+            let mut offset = next;
+            loop {
+                match next_line(raw, offset) {
+                    None => {
+                        result.drop();
+                        return Err(ResponseError::Truncated);
+                    },
+                    Some((line_end, _)) if line_end == offset => return Ok(result),
+                    Some((_, next)) => offset = next,
                 }
+            }
+        }
+
+        // The data is followed by a line break of its own. A size
+        // which doesn't even add up can never arrive.
+        let end = match next.checked_add(size).and_then(|end| end.checked_add(2)) {
+            None => {
+                result.drop();
+                return Err(ResponseError::Malformed);
             },
-            _ => {
+            Some(end) => end,
+        };
+
+        if raw.len() < end {
+            result.drop();
+            return Err(ResponseError::Truncated);
+        }
 
+        let mut chunk = substring(raw, next, size);
+        result.join(&chunk);
+        chunk.drop();
+
+        match next_line(raw, next + size) {
+            Some((line_end, after)) if line_end == next + size => offset = after,
+            _ => {
+                result.drop();
+                return Err(ResponseError::Malformed);
             }
         }
     }
+}
 
-    lines.free();
-    ipd.drop();
-    colon.drop();
-    crnl.drop();
-    content_length_cmp.drop();
-    parsed_packet.drop();
+/// Find the end of the line starting at offset. Returns where
+/// the line ends (without the line break) and where the next
+/// line starts. Bare `\n` line breaks are accepted too.
+fn next_line(raw: &Str, offset: usize) -> Option<(usize, usize)> {
+    let newline = find(raw, b"\n", offset)?;
+    if newline > offset && byte_at(raw, newline - 1) == Some(b'\r') {
+        return Some((newline - 1, newline + 1));
+    }
+    return Some((newline, newline + 1));
+}
 
-    // Checksum validation
-    if state as usize == ParserState::Done as usize {
-        return true;
-    } else {
-        header.clear();
-        content.clear();
-        return false;
+/// Parse a request sent to the server. Returns `Ok(None)` while
+/// the request is still arriving, and the status to reply with
+/// if it can never be parsed.
+pub fn parse_request(link: u8, raw: &Str) -> Result<Option<IncomingRequest>, HttpStatus> {
+    let header_end = match find(raw, b"\r\n\r\n", 0) {
+        None if raw.len() > MAX_REQUEST_SIZE => return Err(HttpStatus::PayloadTooLarge),
+        None => return Ok(None),
+        Some(header_end) => header_end,
+    };
+
+    let mut request = IncomingRequest {
+        link: link,
+        method: HttpMethod::Other,
+        path: Str::new(),
+        query: Vector::new(),
+        headers: Vector::new(),
+        form: Vector::new(),
+        body: Str::new(),
+    };
+
+    let result = parse_request_parts(raw, header_end, &mut request);
+    match result {
+        Ok(true) => return Ok(Some(request)),
+        Ok(false) => {
+            request.drop();
+            return Ok(None);
+        },
+        Err(status) => {
+            request.drop();
+            return Err(status);
+        }
     }
 }
 
-/// This method takes a serial blob of data and returns
-/// All content after IPD+,[content_length]: and is smart
-/// enough to handle chunking.
-pub fn parse_response_payload(buf: &Str) -> Str {
-    let mut result = Str::new();
-    let mut ipd = str!(b"+IPD,");
-    let mut colon = str!(b":");
-
-
-    let mut state: ParserState = ParserState::LookForStart;
-    let mut temp = Str::new();
-    let mut ipd_buf = Vector::<u8>::new();
-    let mut packet_length: Option<usize> = None;
-
-    // Scan until ipd is found. At which point,
-    // read until packet_length is known at which point
-    // aggregate packet details. Repeat until end of stream.
-    for char in buf.into_iter() {
-        ipd_buf.enqueue(char);
-        if ipd_buf.size() > 5 {
-            ipd_buf.dequeue();
+/// Fill in the request. Returns false if the body is incomplete.
+fn parse_request_parts(raw: &Str, header_end: usize, request: &mut IncomingRequest) -> Result<bool, HttpStatus> {
+    // METHOD SP target SP HTTP/1.x
+    let line_end = find(raw, b"\r\n", 0).unwrap_or(header_end);
+    let method_end = find(raw, b" ", 0).filter(|idx| *idx < line_end).ok_or(HttpStatus::BadRequest)?;
+    let target_end = find(raw, b" ", method_end + 1).filter(|idx| *idx < line_end).ok_or(HttpStatus::BadRequest)?;
+    if byte_at(raw, method_end + 1) != Some(b'/') || find(raw, b"HTTP/1.", target_end) != Some(target_end + 1) {
+        return Err(HttpStatus::BadRequest);
+    }
+
+    let mut method = substring(raw, 0, method_end);
+    request.method = HttpMethod::from_bytes(&method);
+    method.drop();
+
+    match find(raw, b"?", method_end).filter(|idx| *idx < target_end) {
+        None => {
+            request.path = substring(raw, method_end + 1, target_end - method_end - 1);
+        },
+        Some(query_start) => {
+            request.path = substring(raw, method_end + 1, query_start - method_end - 1);
+            request.query = parse_params(raw, query_start + 1, target_end);
         }
+    }
 
-        match state {
-            ParserState::LookForLength => {
-                if char == b':' {
-                    packet_length = Some(atoi(&temp) as usize);
-                    state = ParserState::ReadContent;
-                    temp.clear();
-                } else {
-                    temp.append(&[char]);
-                }
+    // Key: value
+    let mut offset = line_end + 2;
+    while offset < header_end {
+        let next = find(raw, b"\r\n", offset).unwrap_or(header_end);
+        let colon = find(raw, b":", offset).filter(|idx| *idx < next && *idx > offset).ok_or(HttpStatus::BadRequest)?;
+        if request.headers.size() >= MAX_HEADERS {
+            return Err(HttpStatus::BadRequest);
+        }
+
+        request.headers.push(HttpHeader {
+            key: trimmed(raw, offset, colon),
+            value: trimmed(raw, colon + 1, next),
+        });
+        offset = next + 2;
+    }
+
+    if request.header(b"Transfer-Encoding").is_some() {
+        return Err(HttpStatus::NotImplemented);
+    }
+
+    let content_length = match request.header(b"Content-Length") {
+        None => 0,
+        Some(value) => parse_decimal(&value).ok_or(HttpStatus::BadRequest)? as usize,
+    };
+
+    if content_length > MAX_REQUEST_SIZE {
+        return Err(HttpStatus::PayloadTooLarge);
+    } else if raw.len() < header_end + 4 + content_length {
+        return Ok(false);
+    }
+
+    request.body = substring(raw, header_end + 4, content_length);
+
+    // Browsers send forms url-encoded. Simple clients often
+    // leave the content type out entirely.
+    let is_form = match request.header(b"Content-Type") {
+        None => true,
+        Some(content_type) => find(&content_type, b"application/x-www-form-urlencoded", 0) == Some(0),
+    };
+
+    if is_form {
+        request.form = parse_params(&request.body, 0, request.body.len());
+    }
+
+    return Ok(true);
+}
+
+/// Copy the range without surrounding whitespace.
+fn trimmed(raw: &Str, start: usize, end: usize) -> Str {
+    let mut start = start;
+    let mut end = end;
+    while start < end && matches!(byte_at(raw, start), Some(b' ') | Some(b'\t')) {
+        start += 1;
+    }
+    while end > start && matches!(byte_at(raw, end - 1), Some(b' ') | Some(b'\t')) {
+        end -= 1;
+    }
+    return substring(raw, start, end - start);
+}
+
+/// Split `key=value&key=value` between start and end. Both
+/// sides are url-decoded.
+pub fn parse_params(raw: &Str, start: usize, end: usize) -> Vector<HttpHeader> {
+    let mut result = Vector::new();
+    let mut offset = start;
+
+    while offset < end {
+        let field_end = find(raw, b"&", offset).filter(|idx| *idx < end).unwrap_or(end);
+        let (key_end, value_start) = match find(raw, b"=", offset).filter(|idx| *idx < field_end) {
+            None => (field_end, field_end),
+            Some(equals) => (equals, equals + 1),
+        };
+
+        if key_end > offset {
+            result.push(HttpHeader {
+                key: url_decode(raw, offset, key_end),
+                value: url_decode(raw, value_start, field_end),
+            });
+        }
+        offset = field_end + 1;
+    }
+
+    return result;
+}
+
+/// Decode `+` and `%XX` escapes between start and end.
+pub fn url_decode(raw: &Str, start: usize, end: usize) -> Str {
+    let mut result = Str::new();
+    let mut idx = start;
+    while idx < end {
+        let char = byte_at(raw, idx).unwrap();
+        match char {
+            b'+' => {
+                result.append(b" ");
             },
-            ParserState::ReadContent => {
-                temp.append(&[char]);
-                if packet_length.is_some() && temp.len() >= packet_length.unwrap() {
-                    result.join(&temp);
-                    temp.clear();
-                    state = ParserState::LookForStart;
+            b'%' if idx + 2 < end => {
+                let high = byte_at(raw, idx + 1).and_then(hex_value);
+                let low = byte_at(raw, idx + 2).and_then(hex_value);
+                match (high, low) {
+                    (Some(high), Some(low)) => {
+                        result.append(&[(high << 4) | low]);
+                        idx += 2;
+                    },
+                    _ => {
+                        result.append(b"%");
+                    }
                 }
             },
             _ => {
-
+                result.append(&[char]);
             }
         }
+        idx += 1;
+    }
+    return result;
+}
 
-        // Check for ipd
-        if arr_contains(ipd_buf, &ipd) {
-            state = ParserState::LookForLength;
-            temp.clear();
-        }
+fn hex_value(char: u8) -> Option<u8> {
+    return match char {
+        b'0' ..= b'9' => Some(char - b'0'),
+        b'a' ..= b'f' => Some(char - b'a' + 10),
+        b'A' ..= b'F' => Some(char - b'A' + 10),
+        _ => None,
+    };
+}
 
+#[cfg(test)]
+pub mod test_parser {
+    use super::*;
+    use teensycore::*;
+
+    fn parse(raw: &[u8]) -> Result<Option<IncomingRequest>, HttpStatus> {
+        return parse_request(2, &Str::with_content(raw));
     }
 
-    ipd_buf.free();
-    temp.drop();
-    ipd.drop();
-    colon.drop();
-    return result;
-}
+    #[test]
+    fn test_parse_request() {
+        let mut request = parse(b"POST /save?mode=full&flag HTTP/1.1\r\nHost: 192.168.4.1\r\ncontent-length:  33 \r\n\r\nssid=My+Net%21&password=p%26ss%3D").unwrap().unwrap();
+        assert_eq!(request.link, 2);
+        assert_eq!(request.method, HttpMethod::Post);
+        assert!(request.path_is(b"/save"));
+        assert!(request.param(b"mode").unwrap() == str!(b"full"));
+        assert_eq!(request.param(b"flag").unwrap().len(), 0);
+        assert!(request.param(b"ssid").unwrap() == str!(b"My Net!"));
+        assert!(request.param(b"password").unwrap() == str!(b"p&ss="));
+        assert!(request.param(b"Host").is_none());
+        assert!(request.header(b"HOST").unwrap() == str!(b"192.168.4.1"));
+        assert!(request.header(b"Content-Length").unwrap() == str!(b"33"));
+        request.drop();
+
+        let mut request = parse(b"GET /api/status HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.method, HttpMethod::Get);
+        assert!(request.path_is(b"/api/status"));
+        assert_eq!(request.body.len(), 0);
+        request.drop();
 
-fn arr_contains(arr: Vector<u8>, target: &Str) -> bool {
-    if arr.size() < target.len() {
-        return false;
+        // JSON bodies are left alone
+        let mut request = parse(b"PUT /x HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 7\r\n\r\n{\"a\":1}").unwrap().unwrap();
+        assert!(request.body == str!(b"{\"a\":1}"));
+        assert_eq!(request.form.size(), 0);
+        request.drop();
     }
 
-    for idx in 0 .. target.len() {
-        let c = arr.get(idx);
-        if c.is_some() && c.unwrap() != target.char_at(idx).unwrap() {
-            return false;
-        }
+    #[test]
+    fn test_parse_incomplete_request() {
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: x\r\n").unwrap().is_none());
+        assert!(parse(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").unwrap().is_none());
     }
 
-    return true;
-}
\ No newline at end of file
+    #[test]
+    fn test_parse_bad_request() {
+        assert_eq!(parse(b"GET\r\n\r\n").err(), Some(HttpStatus::BadRequest));
+        assert_eq!(parse(b"GET index.html HTTP/1.1\r\n\r\n").err(), Some(HttpStatus::BadRequest));
+        assert_eq!(parse(b"GET / SPDY/3\r\n\r\n").err(), Some(HttpStatus::BadRequest));
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nNo colon here\r\n\r\n").err(), Some(HttpStatus::BadRequest));
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n").err(), Some(HttpStatus::BadRequest));
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nContent-Length: 99999\r\n\r\n").err(), Some(HttpStatus::PayloadTooLarge));
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").err(), Some(HttpStatus::NotImplemented));
+    }
+
+    fn response(raw: &[u8]) -> Result<HttpResponse, ResponseError> {
+        return parse_response(&Str::with_content(raw));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut ok = response(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\ncontent-length: 5\r\n\r\nhello, and then some").unwrap();
+        assert_eq!(ok.status, 200);
+        assert!(ok.header(b"Content-Type").unwrap() == str!(b"text/plain"));
+        assert!(ok.body == str!(b"hello"));
+        ok.drop();
+
+        // Error pages are parsed, not mistaken for content
+        let mut not_found = response(b"HTTP/1.0 404 Not Found\n\n<h1>Not Found</h1>").unwrap();
+        assert_eq!(not_found.status, 404);
+        assert!(not_found.body == str!(b"<h1>Not Found</h1>"));
+        not_found.drop();
+
+        // The real response follows an informational one
+        let mut continued = response(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        assert_eq!(continued.status, 204);
+        continued.drop();
+    }
+
+    #[test]
+    fn test_parse_chunked_response() {
+        let mut chunked = response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nrule;\r\n19;ext=1\r\n1641024000;7952371200;Med\r\n0\r\nX-Trailer: yes\r\n\r\n").unwrap();
+        assert!(chunked.body == str!(b"rule;1641024000;7952371200;Med"));
+        chunked.drop();
+
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nrule;\r\n").err(), Some(ResponseError::Truncated));
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nru").err(), Some(ResponseError::Truncated));
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n").err(), Some(ResponseError::Truncated));
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").err(), Some(ResponseError::Malformed));
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n0\r\n\r\n").err(), Some(ResponseError::Malformed));
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n").err(), Some(ResponseError::Malformed));
+    }
+
+    #[test]
+    fn test_parse_bad_response() {
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort").err(), Some(ResponseError::Truncated));
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n").err(), Some(ResponseError::Truncated));
+        assert_eq!(response(b"HTTP/1.1 200").err(), Some(ResponseError::Truncated));
+        assert_eq!(response(b"HTTP/1.1 2x0 OK\r\n\r\n").err(), Some(ResponseError::Malformed));
+        assert_eq!(response(b"HTTP/1.1 2000 OK\r\n\r\n").err(), Some(ResponseError::Malformed));
+        assert_eq!(response(b"ICY 200 OK\r\n\r\n").err(), Some(ResponseError::Malformed));
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nContent-Length: lots\r\n\r\n").err(), Some(ResponseError::Malformed));
+        assert_eq!(response(b"HTTP/1.1 200 OK\r\nbroken header\r\n\r\n").err(), Some(ResponseError::Malformed));
+    }
+}
@@ -0,0 +1,211 @@
+//! Serving http requests which arrive through the ESP8266
+//! while it is acting as a tcp server. Each connection shows
+//! up as a numbered link, and requests arrive as one or more
+//! `+IPD,<link>,<len>:` frames.
+
+use teensycore::math::itoa;
+use teensycore::system::str::*;
+use crate::http::models::*;
+use crate::http::parser::*;
+
+/// The ESP8266 supports up to five connections at once
+pub const MAX_LINKS: usize = 5;
+
+pub type RouteHandler<T> = fn(&mut T, &IncomingRequest) -> ServerResponse;
+
+/// Send requests for this method and path to the handler.
+pub struct Route<T> {
+    pub method: HttpMethod,
+    pub path: &'static [u8],
+    pub handler: RouteHandler<T>,
+}
+
+/// Run the handler that matches the request. Unknown paths get
+/// a 404, and known paths requested with the wrong method get
+/// a 405.
+pub fn dispatch<T>(routes: &[Route<T>], context: &mut T, request: &IncomingRequest) -> ServerResponse {
+    let mut path_matched = false;
+    for route in routes {
+        if request.path_is(route.path) {
+            if request.method == route.method {
+                return (route.handler)(context, request);
+            }
+            path_matched = true;
+        }
+    }
+
+    return match path_matched {
+        true => ServerResponse::text(HttpStatus::MethodNotAllowed, b"Method not allowed"),
+        false => ServerResponse::text(HttpStatus::NotFound, b"Not found"),
+    };
+}
+
+/// Wrap a body in an http response. The connection is always
+/// closed afterwards, so no keep-alive handling is needed.
+pub fn http_response(response: &ServerResponse) -> Str {
+    let mut result = Str::new();
+    result.append(b"HTTP/1.1 ");
+    result.append(response.status.line());
+    result.append(b"\r\nContent-Type: ");
+    result.append(response.content_type);
+    result.append(b"\r\nContent-Length: ");
+    result.join(&itoa(response.body.len() as u64));
+    result.append(b"\r\nConnection: close\r\n\r\n");
+    result.join(&response.body);
+    return result;
+}
+
+/// Collects the frames of each connection until they add up
+/// to a whole request.
+pub struct RequestReader {
+    buffers: [Str; MAX_LINKS],
+}
+
+impl RequestReader {
+    pub fn new() -> Self {
+        return RequestReader {
+            buffers: [Str::new(), Str::new(), Str::new(), Str::new(), Str::new()],
+        };
+    }
+
+    /// Add a frame which arrived on a connection.
+    pub fn receive(&mut self, link: u8, payload: &Str) {
+        match self.buffers.get_mut(link as usize) {
+            None => {},
+            Some(buffer) => {
+                buffer.join(payload);
+            }
+        }
+    }
+
+    /// Returns the next request which has fully arrived, or the
+    /// status to reply with if it could not be understood.
+    pub fn next_request(&mut self) -> Option<(u8, Result<IncomingRequest, HttpStatus>)> {
+        for link in 0 .. MAX_LINKS {
+            if self.buffers[link].len() == 0 {
+                continue;
+            }
+
+            match parse_request(link as u8, &self.buffers[link]) {
+                Ok(None) => {},
+                Ok(Some(request)) => {
+                    self.buffers[link].clear();
+                    return Some((link as u8, Ok(request)));
+                },
+                Err(status) => {
+                    self.buffers[link].clear();
+                    return Some((link as u8, Err(status)));
+                }
+            }
+        }
+        return None;
+    }
+
+    /// Forget anything received on a link, like when the
+    /// connection closed.
+    pub fn reset(&mut self, link: u8) {
+        match self.buffers.get_mut(link as usize) {
+            None => {},
+            Some(buffer) => buffer.clear(),
+        }
+    }
+
+    pub fn drop(&mut self) {
+        for buffer in self.buffers.iter_mut() {
+            buffer.drop();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test_server {
+    use super::*;
+    use teensycore::*;
+    use crate::drivers::esp8266::parser::*;
+
+    /// Hand the frames in the buffer to the reader, the way the
+    /// wifi task does.
+    fn receive(reader: &mut RequestReader, buf: &mut Str) {
+        while let Some(mut event) = parse_event(buf) {
+            match &event {
+                AtEvent::Ipd { link: Some(link), data, .. } => reader.receive(*link, data),
+                AtEvent::Closed(Some(link)) => reader.reset(*link),
+                _ => {},
+            }
+            event.drop();
+        }
+    }
+
+    fn reply_with_path(calls: &mut usize, request: &IncomingRequest) -> ServerResponse {
+        *calls += 1;
+        return ServerResponse::new(HttpStatus::Ok, b"text/plain", Str::from_str(&request.path));
+    }
+
+    #[test]
+    fn test_http_response() {
+        let response = ServerResponse::new(HttpStatus::Ok, b"application/json", str!(b"{}"));
+        assert!(http_response(&response) == str!(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}"));
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let routes = [
+            Route { method: HttpMethod::Get, path: b"/a", handler: reply_with_path },
+            Route { method: HttpMethod::Post, path: b"/b", handler: reply_with_path },
+        ];
+
+        let mut calls = 0;
+        let mut reader = RequestReader::new();
+        receive(&mut reader, &mut str!(b"+IPD,0,19:GET /a HTTP/1.1\r\n\r\n+IPD,1,19:GET /b HTTP/1.1\r\n\r\n+IPD,2,19:GET /c HTTP/1.1\r\n\r\n"));
+
+        let (_, request) = reader.next_request().unwrap();
+        let response = dispatch(&routes, &mut calls, &request.ok().unwrap());
+        assert_eq!(response.status, HttpStatus::Ok);
+        assert!(response.body == str!(b"/a"));
+
+        let (_, request) = reader.next_request().unwrap();
+        assert_eq!(dispatch(&routes, &mut calls, &request.ok().unwrap()).status, HttpStatus::MethodNotAllowed);
+        let (_, request) = reader.next_request().unwrap();
+        assert_eq!(dispatch(&routes, &mut calls, &request.ok().unwrap()).status, HttpStatus::NotFound);
+        assert!(reader.next_request().is_none());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_request_reader() {
+        let mut reader = RequestReader::new();
+
+        // Two connections interleave their frames
+        let mut buf = str!(b"0,CONNECT\r\n1,CONNECT\r\n+IPD,0,16:POST /save HTTP/");
+        receive(&mut reader, &mut buf);
+        buf.append(b"+IPD,1,16:GET / HTTP/1.1\r\n+IPD,0,32:1.1\r\nContent-Length: 6\r\n\r\nssid=a+IPD,1,2:\r\n");
+        receive(&mut reader, &mut buf);
+        assert_eq!(buf.len(), 0);
+
+        let (link, request) = reader.next_request().unwrap();
+        let mut request = request.ok().unwrap();
+        assert_eq!(link, 0);
+        assert!(request.param(b"ssid").unwrap() == str!(b"a"));
+        request.drop();
+
+        let (link, request) = reader.next_request().unwrap();
+        assert_eq!(link, 1);
+        assert!(request.ok().unwrap().path_is(b"/"));
+        assert!(reader.next_request().is_none());
+
+        // A connection which goes away leaves nothing behind
+        let mut buf = str!(b"+IPD,3,11:GET /nope\r\n3,CLOSED\r\n+IPD,3,19:GET /x HTTP/1.1\r\n\r\n");
+        receive(&mut reader, &mut buf);
+        let (link, request) = reader.next_request().unwrap();
+        assert_eq!(link, 3);
+        assert!(request.ok().unwrap().path_is(b"/x"));
+
+        // As does one that cannot be understood
+        let mut buf = str!(b"+IPD,4,11:garbage\r\n\r\n");
+        receive(&mut reader, &mut buf);
+        let (_, request) = reader.next_request().unwrap();
+        assert_eq!(request.err(), Some(HttpStatus::BadRequest));
+        assert!(reader.next_request().is_none());
+        reader.drop();
+    }
+}
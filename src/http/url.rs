@@ -0,0 +1,151 @@
+//! Splitting urls into the parts needed to make a request.
+//! Only plain `http://` urls are supported, because the
+//! firmware has no way of doing tls.
+
+use teensycore::math::itoa;
+use teensycore::system::str::*;
+use crate::strings::*;
+
+pub const DEFAULT_HTTP_PORT: u32 = 80;
+
+pub struct Url {
+    /// A host name or an ipv4 address
+    pub host: Str,
+    pub port: u32,
+    /// The path including any query string, like /hexwall?id=3
+    pub path: Str,
+}
+
+impl Url {
+    /// Parse a url like `http://host:8080/path?query`. The port
+    /// defaults to 80 and the path to `/`. Returns None for
+    /// anything else, including `https://` urls.
+    pub fn parse(url: &Str) -> Option<Url> {
+        let scheme = b"http://";
+        let mut prefix = substring(url, 0, scheme.len());
+        let is_http = equals_ignore_case(&prefix, scheme);
+        prefix.drop();
+        if !is_http {
+            return None;
+        }
+
+        let host_start = scheme.len();
+        let mut host_end = host_start;
+        while let Some(char) = byte_at(url, host_end) {
+            if char == b'/' || char == b'?' {
+                break;
+            }
+            host_end += 1;
+        }
+
+        // Split off the port, if there is one
+        let (name_end, port) = match find(url, b":", host_start) {
+            Some(colon) if colon < host_end => {
+                let mut digits = substring(url, colon + 1, host_end - colon - 1);
+                let port = parse_decimal(&digits);
+                digits.drop();
+                match port {
+                    Some(port) if port > 0 && port <= 65535 => (colon, port as u32),
+                    _ => return None,
+                }
+            },
+            _ => (host_end, DEFAULT_HTTP_PORT),
+        };
+
+        if name_end == host_start {
+            return None;
+        }
+
+        for idx in host_start .. name_end {
+            let char = byte_at(url, idx).unwrap();
+            if !(char.is_ascii_alphanumeric() || char == b'-' || char == b'.') {
+                return None;
+            }
+        }
+
+        let mut path = Str::new();
+        if byte_at(url, host_end) != Some(b'/') {
+            path.append(b"/");
+        }
+        let mut rest = substring(url, host_end, url.len());
+        path.join(&rest);
+        rest.drop();
+
+        return Some(Url {
+            host: substring(url, host_start, name_end - host_start),
+            port: port,
+            path: path,
+        });
+    }
+
+    /// The value for the Host header. The port is only
+    /// included if it isn't the default one.
+    pub fn host_header(&self) -> Str {
+        let mut result = Str::from_str(&self.host);
+        if self.port != DEFAULT_HTTP_PORT {
+            result.append(b":");
+            result.join(&itoa(self.port as u64));
+        }
+        return result;
+    }
+
+    /// Returns true if the host is an ipv4 address, which
+    /// doesn't need to be resolved.
+    pub fn has_ip_address(&self) -> bool {
+        let mut parts = split(&self.host, b'.');
+        let mut result = parts.size() == 4;
+        for mut part in parts.into_iter() {
+            result = result && part.len() <= 3 && parse_decimal(&part).map_or(false, |value| value <= 255);
+            part.drop();
+        }
+        parts.free();
+        return result;
+    }
+
+    pub fn drop(&mut self) {
+        self.host.drop();
+        self.path.drop();
+    }
+}
+
+#[cfg(test)]
+pub mod test_url {
+    use super::*;
+    use teensycore::*;
+
+    #[test]
+    fn test_parse_url() {
+        let url = Url::parse(&str!(b"http://52.27.143.19/hexwall")).unwrap();
+        assert!(url.host == str!(b"52.27.143.19"));
+        assert_eq!(url.port, 80);
+        assert!(url.path == str!(b"/hexwall"));
+        assert!(url.has_ip_address());
+        assert!(url.host_header() == str!(b"52.27.143.19"));
+
+        let url = Url::parse(&str!(b"HTTP://config.example:8080/wall?id=3")).unwrap();
+        assert!(url.host == str!(b"config.example"));
+        assert_eq!(url.port, 8080);
+        assert!(url.path == str!(b"/wall?id=3"));
+        assert!(!url.has_ip_address());
+        assert!(url.host_header() == str!(b"config.example:8080"));
+
+        let url = Url::parse(&str!(b"http://hexwall")).unwrap();
+        assert!(url.path == str!(b"/"));
+        let url = Url::parse(&str!(b"http://hexwall?id=3")).unwrap();
+        assert!(url.path == str!(b"/?id=3"));
+        assert!(!Url::parse(&str!(b"http://1.2.3.256/")).unwrap().has_ip_address());
+    }
+
+    #[test]
+    fn test_parse_bad_url() {
+        assert!(Url::parse(&str!(b"https://config.example/")).is_none());
+        assert!(Url::parse(&str!(b"config.example/hexwall")).is_none());
+        assert!(Url::parse(&str!(b"http://")).is_none());
+        assert!(Url::parse(&str!(b"http://:80/")).is_none());
+        assert!(Url::parse(&str!(b"http://host:0/")).is_none());
+        assert!(Url::parse(&str!(b"http://host:99999/")).is_none());
+        assert!(Url::parse(&str!(b"http://host:port/")).is_none());
+        assert!(Url::parse(&str!(b"http://user@host/")).is_none());
+        assert!(Url::parse(&str!(b"http://ho st/")).is_none());
+    }
+}
@@ -1,3 +1,16 @@
+use crate::crc::crc32;
+
+/// Arguments which were not given hold this value
+pub const UNSET_ARG: i32 = i32::MAX;
+pub const MAX_ARGS: usize = 10;
+/// Bytes a command takes on the wire: the mnemonic, every
+/// argument and then the checksum, all little endian
+pub const COMMAND_SIZE: usize = 4 + MAX_ARGS * 4 + 8;
+
+/// A command for the wall, named by a four letter mnemonic
+/// like `BRIT`. Whatever reads commands, the console or a
+/// host talking binary, turns them into one of these.
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub struct SystemCommand {
     pub command: [u8;4],
     pub args: [i32; 10],
@@ -8,8 +21,96 @@ impl SystemCommand {
     pub fn new() -> Self {
         return SystemCommand {
             command: [0; 4],
-            args: [i32::MAX; 10],
+            args: [UNSET_ARG; MAX_ARGS],
             checksum: 0,
         };
     }
-}
\ No newline at end of file
+
+    pub fn with_command(command: [u8; 4]) -> Self {
+        let mut result = SystemCommand::new();
+        result.command = command;
+        return result;
+    }
+
+    /// Returns the argument, or None if it was not given.
+    pub fn arg(&self, idx: usize) -> Option<i32> {
+        return match self.args.get(idx) {
+            Some(&UNSET_ARG) | None => None,
+            Some(value) => Some(*value),
+        };
+    }
+
+    /// How many arguments were given, counting from the first.
+    pub fn arg_count(&self) -> usize {
+        return self.args.iter().take_while(|arg| **arg != UNSET_ARG).count();
+    }
+
+    /// The crc32 of the mnemonic and the arguments.
+    pub fn compute_checksum(&self) -> u64 {
+        let bytes = self.encode();
+        return crc32(&bytes[0 .. COMMAND_SIZE - 8]) as u64;
+    }
+
+    /// Fill in the checksum, once the command is complete.
+    pub fn seal(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    pub fn encode(&self) -> [u8; COMMAND_SIZE] {
+        let mut result = [0; COMMAND_SIZE];
+        result[0 .. 4].copy_from_slice(&self.command);
+        for (idx, arg) in self.args.iter().enumerate() {
+            result[4 + idx * 4 .. 8 + idx * 4].copy_from_slice(&arg.to_le_bytes());
+        }
+        result[COMMAND_SIZE - 8 ..].copy_from_slice(&self.checksum.to_le_bytes());
+        return result;
+    }
+
+    /// Read a command from the start of the bytes. The checksum
+    /// is read, but not checked.
+    pub fn decode(bytes: &[u8]) -> Option<SystemCommand> {
+        if bytes.len() < COMMAND_SIZE {
+            return None;
+        }
+
+        let mut result = SystemCommand::new();
+        result.command.copy_from_slice(&bytes[0 .. 4]);
+        for idx in 0 .. MAX_ARGS {
+            let mut arg = [0; 4];
+            arg.copy_from_slice(&bytes[4 + idx * 4 .. 8 + idx * 4]);
+            result.args[idx] = i32::from_le_bytes(arg);
+        }
+        let mut checksum = [0; 8];
+        checksum.copy_from_slice(&bytes[COMMAND_SIZE - 8 .. COMMAND_SIZE]);
+        result.checksum = u64::from_le_bytes(checksum);
+        return Some(result);
+    }
+}
+
+#[cfg(test)]
+pub mod test_models {
+    use super::*;
+
+    #[test]
+    fn test_command_encoding() {
+        let mut command = SystemCommand::with_command(*b"BRIT");
+        command.args[0] = 128;
+        command.args[1] = -2;
+        command.seal();
+
+        let bytes = command.encode();
+        assert_eq!(&bytes[0 .. 12], b"BRIT\x80\x00\x00\x00\xFE\xFF\xFF\xFF");
+        assert_eq!(&bytes[12 .. 16], &UNSET_ARG.to_le_bytes());
+        let decoded = SystemCommand::decode(&bytes).unwrap();
+        assert_eq!(decoded, command);
+        assert_eq!(decoded.checksum, decoded.compute_checksum());
+        assert_eq!(decoded.arg_count(), 2);
+        assert_eq!(decoded.arg(2), None);
+
+        let mut damaged = bytes;
+        damaged[5] ^= 1;
+        let damaged = SystemCommand::decode(&damaged).unwrap();
+        assert_ne!(damaged.checksum, damaged.compute_checksum());
+        assert!(SystemCommand::decode(&bytes[1 ..]).is_none());
+    }
+}
@@ -0,0 +1,7 @@
+//! This module lets the wall join an MQTT broker, so it can be
+//! switched and dimmed from home automation. Home Assistant
+//! finds it on its own through discovery.
+
+pub mod client;
+pub mod home_assistant;
+pub mod packet;
@@ -0,0 +1,444 @@
+//! Home Assistant discovery. The wall shows up as a light whose
+//! effects are the shaders, plus a select for the transition
+//! effects. Everything lives under `hexwall/<node>`:
+//!
+//! ```text
+//! hexwall/<node>/availability   online, or offline through the will
+//! hexwall/<node>/state          {"state":"ON","brightness":255,"effect":"Medbay"}
+//! hexwall/<node>/set            the same json, or just ON / OFF
+//! hexwall/<node>/effect/state   the transition effect
+//! hexwall/<node>/effect/set
+//! ```
+//!
+//! Nothing here talks to the broker. The wifi task publishes
+//! whatever comes back, which keeps this easy to test.
+
+use teensycore::math::{int_to_hex, itoa};
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+use crate::api::{append_json_name, json_value};
+use crate::control::*;
+use crate::strings::*;
+
+/// Home Assistant publishes "online" here whenever it starts,
+/// which is the cue to announce the wall again
+pub const STATUS_TOPIC: &[u8] = b"homeassistant/status";
+const DISCOVERY_PREFIX: &[u8] = b"homeassistant";
+const DEVICE_NAME: &[u8] = b"Hex Wall";
+
+/// A message for the broker. Don't forget to drop it.
+#[derive(Copy, Clone)]
+pub struct Outgoing {
+    pub topic: Str,
+    pub payload: Str,
+    pub retain: bool,
+}
+
+impl Outgoing {
+    pub fn drop(&mut self) {
+        self.topic.drop();
+        self.payload.drop();
+    }
+}
+
+/// What Home Assistant was last told.
+#[derive(Copy, Clone, PartialEq)]
+struct Snapshot {
+    power: bool,
+    brightness: u8,
+    shader: Option<&'static [u8]>,
+    effect: Option<&'static [u8]>,
+}
+
+impl Snapshot {
+    fn current() -> Self {
+        let status = get_status();
+        return Snapshot {
+            power: crate::get_power(),
+            brightness: crate::get_brightness(),
+            shader: status.shader,
+            effect: status.effect,
+        };
+    }
+}
+
+pub struct HomeAssistant {
+    node_id: Str,
+    base_topic: Str,
+    published: Option<Snapshot>,
+}
+
+impl HomeAssistant {
+    /// The node id comes from the mac address, so several walls
+    /// can share a broker.
+    pub fn new(mac: Option<[u8; 6]>) -> Self {
+        let mut node_id = Str::with_content(b"hexwall");
+        match mac {
+            None => {},
+            Some(mac) => {
+                node_id.append(b"_");
+                for byte in mac {
+                    for digit in [byte >> 4, byte & 0xF] {
+                        node_id.append(&[int_to_hex(digit).to_ascii_lowercase()]);
+                    }
+                }
+            },
+        }
+
+        let mut base_topic = Str::with_content(b"hexwall/");
+        base_topic.join(&node_id);
+
+        return HomeAssistant {
+            node_id: node_id,
+            base_topic: base_topic,
+            published: None,
+        };
+    }
+
+    /// Also used as the mqtt client id.
+    pub fn node_id(&self) -> &Str {
+        return &self.node_id;
+    }
+
+    /// Where the will says the wall went offline. Don't forget
+    /// to drop it.
+    pub fn availability_topic(&self) -> Str {
+        return self.topic(b"/availability");
+    }
+
+    /// Every topic filter to subscribe to once connected. Don't
+    /// forget to drop each entry and free the vector.
+    pub fn subscriptions(&self) -> Vector<Str> {
+        let mut result = Vector::new();
+        result.push(self.topic(b"/set"));
+        result.push(self.topic(b"/effect/set"));
+        result.push(Str::with_content(STATUS_TOPIC));
+        return result;
+    }
+
+    /// The discovery configs, followed by the wall coming online
+    /// and its state. Don't forget to drop each entry and free
+    /// the vector.
+    pub fn announce(&mut self) -> Vector<Outgoing> {
+        let mut result = Vector::new();
+        result.push(Outgoing {
+            topic: self.discovery_topic(b"light", b"light"),
+            payload: self.light_config(),
+            retain: true,
+        });
+        result.push(Outgoing {
+            topic: self.discovery_topic(b"select", b"effect"),
+            payload: self.select_config(),
+            retain: true,
+        });
+        result.push(Outgoing {
+            topic: self.availability_topic(),
+            payload: Str::with_content(b"online"),
+            retain: true,
+        });
+
+        self.published = None;
+        let mut state = self.state_changes();
+        for message in state.into_iter() {
+            result.push(message);
+        }
+        state.free();
+        return result;
+    }
+
+    /// Carry out a message from one of the subscriptions. Returns
+    /// anything that should be published in response.
+    pub fn handle_message(&mut self, topic: &Str, payload: &Str) -> Vector<Outgoing> {
+        if equals(topic, STATUS_TOPIC) {
+            if equals_ignore_case(payload, b"online") {
+                return self.announce();
+            }
+        } else if self.is_topic(topic, b"/set") {
+            handle_light_command(payload);
+        } else if self.is_topic(topic, b"/effect/set") {
+            match find_effect_name(payload) {
+                None => {},
+                Some(name) => queue_command(ControlCommand::ForceEffect(name)),
+            }
+        }
+        return Vector::new();
+    }
+
+    /// The state, if it changed since it was last published.
+    pub fn state_changes(&mut self) -> Vector<Outgoing> {
+        let mut result = Vector::new();
+        let current = Snapshot::current();
+        let previous = self.published;
+        if previous == Some(current) {
+            return result;
+        }
+
+        let mut state = Str::new();
+        state.append(b"{\"state\":");
+        state.append(match current.power {
+            true => b"\"ON\"",
+            false => b"\"OFF\"",
+        });
+        state.append(b",\"color_mode\":\"brightness\",\"brightness\":");
+        let mut brightness = itoa(current.brightness as u64);
+        state.join(&brightness);
+        brightness.drop();
+        if current.shader.is_some() {
+            state.append(b",\"effect\":");
+            append_json_name(&mut state, current.shader);
+        }
+        state.append(b"}");
+        result.push(Outgoing { topic: self.topic(b"/state"), payload: state, retain: true });
+
+        match current.effect {
+            Some(effect) if previous.map(|previous| previous.effect) != Some(current.effect) => {
+                result.push(Outgoing {
+                    topic: self.topic(b"/effect/state"),
+                    payload: Str::with_content(effect),
+                    retain: true,
+                });
+            },
+            _ => {},
+        }
+
+        self.published = Some(current);
+        return result;
+    }
+
+    pub fn drop(&mut self) {
+        self.node_id.drop();
+        self.base_topic.drop();
+    }
+
+    fn topic(&self, suffix: &[u8]) -> Str {
+        let mut result = Str::from_str(&self.base_topic);
+        result.append(suffix);
+        return result;
+    }
+
+    fn is_topic(&self, topic: &Str, suffix: &[u8]) -> bool {
+        let mut expected = self.topic(suffix);
+        let result = *topic == expected;
+        expected.drop();
+        return result;
+    }
+
+    fn discovery_topic(&self, component: &[u8], object_id: &[u8]) -> Str {
+        let mut result = Str::with_content(DISCOVERY_PREFIX);
+        result.append(b"/");
+        result.append(component);
+        result.append(b"/");
+        result.join(&self.node_id);
+        result.append(b"/");
+        result.append(object_id);
+        result.append(b"/config");
+        return result;
+    }
+
+    fn light_config(&self) -> Str {
+        let mut result = Str::new();
+        result.append(b"{\"name\":null,\"schema\":\"json\"");
+        self.append_common(&mut result, b"light", b"/set", b"/state");
+        result.append(b",\"brightness\":true,\"supported_color_modes\":[\"brightness\"],\"effect\":true,\"effect_list\":");
+        append_names(&mut result, get_shader_names());
+        self.append_device(&mut result);
+        return result;
+    }
+
+    fn select_config(&self) -> Str {
+        let mut result = Str::new();
+        result.append(b"{\"name\":\"Effect\",\"icon\":\"mdi:transition\"");
+        self.append_common(&mut result, b"effect", b"/effect/set", b"/effect/state");
+        result.append(b",\"options\":");
+        append_names(&mut result, get_effect_names());
+        self.append_device(&mut result);
+        return result;
+    }
+
+    fn append_common(&self, target: &mut Str, object_id: &[u8], command: &[u8], state: &[u8]) {
+        target.append(b",\"unique_id\":\"");
+        target.join(&self.node_id);
+        target.append(b"_");
+        target.append(object_id);
+        target.append(b"\"");
+        for (key, suffix) in [(b"command_topic" as &[u8], command), (b"state_topic", state), (b"availability_topic", b"/availability")] {
+            target.append(b",\"");
+            target.append(key);
+            target.append(b"\":\"");
+            target.join(&self.base_topic);
+            target.append(suffix);
+            target.append(b"\"");
+        }
+    }
+
+    fn append_device(&self, target: &mut Str) {
+        target.append(b",\"device\":{\"identifiers\":[\"");
+        target.join(&self.node_id);
+        target.append(b"\"],\"name\":\"");
+        target.append(DEVICE_NAME);
+        target.append(b"\",\"model\":\"");
+        target.append(DEVICE_NAME);
+        target.append(b"\"}}");
+    }
+}
+
+fn append_names(target: &mut Str, names: Vector<&'static [u8]>) {
+    target.append(b"[");
+    for (idx, name) in names.into_iter().enumerate() {
+        if idx > 0 {
+            target.append(b",");
+        }
+        append_json_name(target, Some(name));
+    }
+    target.append(b"]");
+}
+
+/// Switch, dim or change the shader, from either the json
+/// schema or a bare ON / OFF.
+fn handle_light_command(payload: &Str) {
+    if equals_ignore_case(payload, b"ON") || equals_ignore_case(payload, b"OFF") {
+        crate::set_power(equals_ignore_case(payload, b"ON"));
+        return;
+    }
+
+    match json_value(payload, b"state") {
+        None => {},
+        Some(mut state) => {
+            crate::set_power(equals_ignore_case(&state, b"ON"));
+            state.drop();
+        },
+    }
+
+    match json_value(payload, b"brightness") {
+        None => {},
+        Some(mut brightness) => {
+            match parse_decimal(&brightness) {
+                Some(value) => crate::set_brightness(value.min(255) as u8),
+                None => {},
+            }
+            brightness.drop();
+        },
+    }
+
+    match json_value(payload, b"effect") {
+        None => {},
+        Some(mut effect) => {
+            match find_shader_name(&effect) {
+                None => {},
+                Some(name) => queue_command(ControlCommand::ForceShader(name)),
+            }
+            effect.drop();
+        },
+    }
+}
+
+#[cfg(test)]
+pub mod test_home_assistant {
+    use super::*;
+    use std::prelude::rust_2021::*;
+    use teensycore::*;
+    use crate::pixel_engine::golden::RENDER_LOCK;
+
+    fn text(content: &Str) -> String {
+        return String::from_utf8(chars(content).collect()).unwrap();
+    }
+
+    fn messages(mut outgoing: Vector<Outgoing>) -> Vec<(String, String)> {
+        let mut result = Vec::new();
+        for mut message in outgoing.into_iter() {
+            assert!(message.retain);
+            result.push((text(&message.topic), text(&message.payload)));
+            message.drop();
+        }
+        outgoing.free();
+        return result;
+    }
+
+    #[test]
+    fn test_discovery() {
+        // The pixel task registers names and changes the brightness too
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        register_shader(b"Medbay");
+        register_effect(b"Randomized");
+        set_current_shader(b"Medbay");
+        set_current_effect(b"Randomized");
+        crate::set_power(true);
+        crate::set_brightness(200);
+
+        let mut home_assistant = HomeAssistant::new(Some([0xa4, 0x2b, 0xb0, 0x01, 0xd2, 0xff]));
+        assert!(*home_assistant.node_id() == str!(b"hexwall_a42bb001d2ff"));
+        assert!(home_assistant.availability_topic() == str!(b"hexwall/hexwall_a42bb001d2ff/availability"));
+        assert!(*HomeAssistant::new(None).node_id() == str!(b"hexwall"));
+
+        let announced = messages(home_assistant.announce());
+        assert_eq!(announced.len(), 5);
+        let (topic, light) = &announced[0];
+        assert_eq!(topic, "homeassistant/light/hexwall_a42bb001d2ff/light/config");
+        assert!(light.starts_with("{\"name\":null,\"schema\":\"json\",\"unique_id\":\"hexwall_a42bb001d2ff_light\",\"command_topic\":\"hexwall/hexwall_a42bb001d2ff/set\",\"state_topic\":\"hexwall/hexwall_a42bb001d2ff/state\",\"availability_topic\":\"hexwall/hexwall_a42bb001d2ff/availability\","));
+        assert!(light.contains("\"effect_list\":[") && light.contains("\"Medbay\""));
+        assert!(light.ends_with(",\"device\":{\"identifiers\":[\"hexwall_a42bb001d2ff\"],\"name\":\"Hex Wall\",\"model\":\"Hex Wall\"}}"));
+
+        let (topic, select) = &announced[1];
+        assert_eq!(topic, "homeassistant/select/hexwall_a42bb001d2ff/effect/config");
+        assert!(select.contains("\"command_topic\":\"hexwall/hexwall_a42bb001d2ff/effect/set\""));
+        assert!(select.contains("\"options\":[") && select.contains("\"Randomized\""));
+
+        assert_eq!(announced[2], ("hexwall/hexwall_a42bb001d2ff/availability".to_string(), "online".to_string()));
+        assert_eq!(announced[3], ("hexwall/hexwall_a42bb001d2ff/state".to_string(), "{\"state\":\"ON\",\"color_mode\":\"brightness\",\"brightness\":200,\"effect\":\"Medbay\"}".to_string()));
+        assert_eq!(announced[4], ("hexwall/hexwall_a42bb001d2ff/effect/state".to_string(), "Randomized".to_string()));
+
+        // Only changes are published
+        assert!(messages(home_assistant.state_changes()).is_empty());
+
+        let mut subscriptions = home_assistant.subscriptions();
+        let topics: Vec<String> = subscriptions.into_iter().map(|topic| text(&topic)).collect();
+        assert_eq!(topics, ["hexwall/hexwall_a42bb001d2ff/set", "hexwall/hexwall_a42bb001d2ff/effect/set", "homeassistant/status"]);
+        subscriptions.free();
+
+        // Home Assistant restarted
+        assert_eq!(messages(home_assistant.handle_message(&str!(STATUS_TOPIC), &str!(b"offline"))).len(), 0);
+        assert_eq!(messages(home_assistant.handle_message(&str!(STATUS_TOPIC), &str!(b"online"))).len(), 5);
+        home_assistant.drop();
+        crate::set_brightness(crate::DEFAULT_BRIGHTNESS);
+    }
+
+    #[test]
+    fn test_commands() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        register_shader(b"Medbay");
+        register_effect(b"Randomized");
+        set_current_effect(b"Randomized");
+        while next_command().is_some() {}
+        crate::set_power(true);
+
+        let mut home_assistant = HomeAssistant::new(None);
+        messages(home_assistant.announce());
+        let set = str!(b"hexwall/hexwall/set");
+
+        messages(home_assistant.handle_message(&set, &str!(b"{\"state\":\"OFF\"}")));
+        assert!(!crate::get_power());
+        let changes = messages(home_assistant.state_changes());
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].1.starts_with("{\"state\":\"OFF\","));
+
+        messages(home_assistant.handle_message(&set, &str!(b"{\"state\":\"ON\",\"brightness\":64,\"effect\":\"medbay\"}")));
+        assert!(crate::get_power());
+        assert_eq!(crate::get_brightness(), 64);
+        assert!(next_command() == Some(ControlCommand::ForceShader(b"Medbay")));
+
+        messages(home_assistant.handle_message(&set, &str!(b"OFF")));
+        assert!(!crate::get_power());
+        messages(home_assistant.handle_message(&set, &str!(b"{\"effect\":\"Nope\"}")));
+        messages(home_assistant.handle_message(&str!(b"hexwall/hexwall/effect/set"), &str!(b"Randomized")));
+        assert!(next_command() == Some(ControlCommand::ForceEffect(b"Randomized")));
+        assert!(next_command().is_none());
+
+        // Commands for other walls are ignored
+        messages(home_assistant.handle_message(&str!(b"hexwall/other/set"), &str!(b"ON")));
+        assert!(!crate::get_power());
+
+        home_assistant.drop();
+        crate::set_power(true);
+        crate::set_brightness(crate::DEFAULT_BRIGHTNESS);
+    }
+}
@@ -0,0 +1,323 @@
+//! Encoding and decoding MQTT 3.1.1 packets. Only what the
+//! wall needs is here: it publishes and subscribes at QoS 0,
+//! so it never has to acknowledge anything or send anything
+//! twice.
+
+use teensycore::system::str::*;
+use crate::strings::*;
+
+// Fixed header packet types, already shifted into place
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+// Connect flags
+const CLEAN_SESSION: u8 = 0x02;
+const WILL_FLAG: u8 = 0x04;
+const WILL_RETAIN: u8 = 0x20;
+const PASSWORD_FLAG: u8 = 0x40;
+const USERNAME_FLAG: u8 = 0x80;
+
+const RETAIN: u8 = 0x01;
+
+/// The remaining length takes at most four bytes
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+/// A message the broker publishes when the wall goes away
+/// without saying goodbye.
+#[derive(Copy, Clone)]
+pub struct Will<'a> {
+    pub topic: &'a Str,
+    pub payload: &'a Str,
+    pub retain: bool,
+}
+
+pub enum Packet {
+    /// The answer to CONNECT. Zero means accepted.
+    ConnAck { session_present: bool, code: u8 },
+    Publish { topic: Str, payload: Str, retain: bool },
+    SubAck { packet_id: u16 },
+    PingResp,
+    /// Anything else the broker may send, by type
+    Other(u8),
+}
+
+impl Packet {
+    pub fn drop(&mut self) {
+        match self {
+            Packet::Publish { topic, payload, .. } => {
+                topic.drop();
+                payload.drop();
+            },
+            _ => {},
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PacketError {
+    /// The remaining length is longer than four bytes
+    BadLength,
+    /// The packet doesn't hold what its type says it should
+    Malformed,
+}
+
+/// Start a clean session. The keepalive is in seconds.
+pub fn connect_packet(client_id: &Str, keepalive_s: u16, username: Option<&Str>, password: Option<&Str>, will: Option<Will>) -> Str {
+    let mut body = Str::new();
+    put_bytes(&mut body, b"MQTT");
+    // Protocol level 4 is 3.1.1
+    body.append(&[4]);
+
+    let mut flags = CLEAN_SESSION;
+    match will {
+        None => {},
+        Some(will) => {
+            flags |= WILL_FLAG;
+            if will.retain {
+                flags |= WILL_RETAIN;
+            }
+        },
+    }
+    if username.is_some() {
+        flags |= USERNAME_FLAG;
+        if password.is_some() {
+            flags |= PASSWORD_FLAG;
+        }
+    }
+    body.append(&[flags]);
+    body.append(&keepalive_s.to_be_bytes());
+
+    put_str(&mut body, client_id);
+    match will {
+        None => {},
+        Some(will) => {
+            put_str(&mut body, will.topic);
+            put_str(&mut body, will.payload);
+        },
+    }
+    match (username, password) {
+        (Some(username), password) => {
+            put_str(&mut body, username);
+            match password {
+                None => {},
+                Some(password) => put_str(&mut body, password),
+            }
+        },
+        _ => {},
+    }
+
+    return finish(CONNECT, body);
+}
+
+/// Publish at QoS 0.
+pub fn publish_packet(topic: &Str, payload: &Str, retain: bool) -> Str {
+    let mut body = Str::new();
+    put_str(&mut body, topic);
+    body.join(payload);
+
+    let header = match retain {
+        true => PUBLISH | RETAIN,
+        false => PUBLISH,
+    };
+    return finish(header, body);
+}
+
+/// Subscribe to a single topic filter at QoS 0.
+pub fn subscribe_packet(packet_id: u16, filter: &Str) -> Str {
+    let mut body = Str::new();
+    body.append(&packet_id.to_be_bytes());
+    put_str(&mut body, filter);
+    body.append(&[0]);
+    return finish(SUBSCRIBE, body);
+}
+
+pub fn ping_packet() -> Str {
+    return finish(PINGREQ, Str::new());
+}
+
+pub fn disconnect_packet() -> Str {
+    return finish(DISCONNECT, Str::new());
+}
+
+/// Take the next packet out of the buffer. Returns None until
+/// the whole packet has arrived, in which case the buffer is
+/// left alone.
+pub fn parse_packet(buf: &mut Str) -> Option<Result<Packet, PacketError>> {
+    let header = byte_at(buf, 0)?;
+
+    let mut length: usize = 0;
+    let mut offset = 1;
+    loop {
+        let byte = byte_at(buf, offset)?;
+        length += ((byte & 0x7F) as usize) << (7 * (offset - 1));
+        offset += 1;
+        if byte & 0x80 == 0 {
+            break;
+        } else if offset > 4 {
+            buf.clear();
+            return Some(Err(PacketError::BadLength));
+        }
+    }
+
+    if buf.len() < offset + length {
+        return None;
+    }
+
+    let mut body = substring(buf, offset, length);
+    consume(buf, offset + length);
+    let result = decode(header, &body);
+    body.drop();
+    return Some(result);
+}
+
+fn decode(header: u8, body: &Str) -> Result<Packet, PacketError> {
+    return match header & 0xF0 {
+        CONNACK if body.len() == 2 => Ok(Packet::ConnAck {
+            session_present: byte_at(body, 0) == Some(1),
+            code: byte_at(body, 1).unwrap(),
+        }),
+        PUBLISH => {
+            let topic_length = read_u16(body, 0).ok_or(PacketError::Malformed)? as usize;
+            // QoS 1 and 2 come with a packet id in front of the payload
+            let qos = (header >> 1) & 0x03;
+            let payload_start = 2 + topic_length + if qos > 0 { 2 } else { 0 };
+            if payload_start > body.len() || qos == 3 {
+                return Err(PacketError::Malformed);
+            }
+
+            Ok(Packet::Publish {
+                topic: substring(body, 2, topic_length),
+                payload: substring(body, payload_start, body.len() - payload_start),
+                retain: header & RETAIN != 0,
+            })
+        },
+        SUBACK => Ok(Packet::SubAck {
+            packet_id: read_u16(body, 0).ok_or(PacketError::Malformed)?,
+        }),
+        PINGRESP => Ok(Packet::PingResp),
+        CONNACK => Err(PacketError::Malformed),
+        other => Ok(Packet::Other(other)),
+    };
+}
+
+fn read_u16(body: &Str, offset: usize) -> Option<u16> {
+    return Some(u16::from_be_bytes([byte_at(body, offset)?, byte_at(body, offset + 1)?]));
+}
+
+/// A string with its length in front
+fn put_str(target: &mut Str, content: &Str) {
+    target.append(&(content.len() as u16).to_be_bytes());
+    target.join(content);
+}
+
+fn put_bytes(target: &mut Str, content: &[u8]) {
+    target.append(&(content.len() as u16).to_be_bytes());
+    target.append(content);
+}
+
+/// Put the fixed header in front of the body, which is dropped.
+fn finish(header: u8, mut body: Str) -> Str {
+    let mut result = Str::new();
+    result.append(&[header]);
+
+    let mut length = body.len().min(MAX_REMAINING_LENGTH);
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        result.append(&[byte]);
+        if length == 0 {
+            break;
+        }
+    }
+
+    result.join(&body);
+    body.drop();
+    return result;
+}
+
+#[cfg(test)]
+pub mod test_packet {
+    use super::*;
+    use teensycore::*;
+    use std::prelude::rust_2021::*;
+
+    fn bytes(content: &Str) -> Vec<u8> {
+        return chars(content).collect();
+    }
+
+    #[test]
+    fn test_connect_packet() {
+        let packet = connect_packet(&str!(b"hexwall"), 60, None, None, None);
+        assert_eq!(bytes(&packet), b"\x10\x13\x00\x04MQTT\x04\x02\x00\x3c\x00\x07hexwall");
+
+        let topic = str!(b"hex/status");
+        let payload = str!(b"offline");
+        let will = Will { topic: &topic, payload: &payload, retain: true };
+        let packet = connect_packet(&str!(b"hw"), 30, Some(&str!(b"me")), Some(&str!(b"pw")), Some(will));
+        assert_eq!(bytes(&packet), b"\x10\x2b\x00\x04MQTT\x04\xe6\x00\x1e\x00\x02hw\x00\x0ahex/status\x00\x07offline\x00\x02me\x00\x02pw");
+    }
+
+    #[test]
+    fn test_other_packets() {
+        assert_eq!(bytes(&publish_packet(&str!(b"a/b"), &str!(b"on"), true)), b"\x31\x07\x00\x03a/bon");
+        assert_eq!(bytes(&subscribe_packet(7, &str!(b"a/#"))), b"\x82\x08\x00\x07\x00\x03a/#\x00");
+        assert_eq!(bytes(&ping_packet()), b"\xc0\x00");
+        assert_eq!(bytes(&disconnect_packet()), b"\xe0\x00");
+
+        // Long payloads need more length bytes
+        let payload = Str::with_content(&[b'x'; 200]);
+        let packet = bytes(&publish_packet(&str!(b"t"), &payload, false));
+        assert_eq!(&packet[0 .. 6], b"\x30\xcb\x01\x00\x01t");
+        assert_eq!(packet.len(), 206);
+    }
+
+    #[test]
+    fn test_parse_packets() {
+        let mut buf = Str::with_content(b"\x20\x02\x00\x00\x90\x03\x00\x07\x00\xd0\x00\x31\x09\x00\x05a/setON\x32\x08\x00\x01t\x00\x05OFF\xd0");
+        assert!(matches!(parse_packet(&mut buf), Some(Ok(Packet::ConnAck { session_present: false, code: 0 }))));
+        assert!(matches!(parse_packet(&mut buf), Some(Ok(Packet::SubAck { packet_id: 7 }))));
+        assert!(matches!(parse_packet(&mut buf), Some(Ok(Packet::PingResp))));
+
+        match parse_packet(&mut buf) {
+            Some(Ok(Packet::Publish { topic, payload, retain })) => {
+                assert!(topic == str!(b"a/set"));
+                assert!(payload == str!(b"ON"));
+                assert!(retain);
+            },
+            _ => std::panic!("expected a publish"),
+        }
+
+        // QoS 1 has a packet id before the payload
+        match parse_packet(&mut buf) {
+            Some(Ok(Packet::Publish { topic, payload, retain })) => {
+                assert!(topic == str!(b"t"));
+                assert!(payload == str!(b"OFF"));
+                assert!(!retain);
+            },
+            _ => std::panic!("expected a publish"),
+        }
+
+        // Waits for the rest
+        assert!(parse_packet(&mut buf).is_none());
+        buf.append(b"\x00");
+        assert!(matches!(parse_packet(&mut buf), Some(Ok(Packet::PingResp))));
+        assert_eq!(buf.len(), 0);
+
+        let mut buf = Str::with_content(b"\x30\x01\x00\x20\x01\x00");
+        assert!(matches!(parse_packet(&mut buf), Some(Err(PacketError::Malformed))));
+        assert!(matches!(parse_packet(&mut buf), Some(Err(PacketError::Malformed))));
+
+        let mut buf = Str::with_content(b"\x30\xff\xff\xff\xff\x01");
+        assert!(matches!(parse_packet(&mut buf), Some(Err(PacketError::BadLength))));
+        assert_eq!(buf.len(), 0);
+    }
+}
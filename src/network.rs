@@ -0,0 +1,89 @@
+//! How the wall gets its address. Normally the network hands
+//! one out over dhcp, but the address, gateway, netmask and
+//! name server can be fixed instead, which keeps the api in
+//! the same place.
+
+use teensycore::math::itoa;
+use teensycore::system::str::*;
+use crate::strings::*;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Ipv4(pub [u8; 4]);
+
+impl Ipv4 {
+    /// Parse dotted decimal, like `192.168.1.50`.
+    pub fn parse(content: &Str) -> Option<Ipv4> {
+        let mut parts = split(content, b'.');
+        let mut result = [0u8; 4];
+        let mut valid = parts.size() == 4 && byte_at(content, content.len().wrapping_sub(1)) != Some(b'.');
+
+        for (idx, part) in parts.into_iter().enumerate() {
+            match parse_decimal(&part) {
+                Some(octet) if valid && idx < 4 && octet <= 255 => result[idx] = octet as u8,
+                _ => valid = false,
+            }
+        }
+
+        for mut part in parts.into_iter() {
+            part.drop();
+        }
+        parts.free();
+
+        return match valid {
+            true => Some(Ipv4(result)),
+            false => None,
+        };
+    }
+
+    /// Don't forget to drop the result.
+    pub fn to_str(&self) -> Str {
+        let mut result = Str::new();
+        for (idx, octet) in self.0.iter().enumerate() {
+            if idx > 0 {
+                result.append(b".");
+            }
+            let mut digits = itoa(*octet as u64);
+            result.join(&digits);
+            digits.drop();
+        }
+        return result;
+    }
+
+    /// The module reports this while it has no address.
+    pub fn is_unspecified(&self) -> bool {
+        return self.0 == [0, 0, 0, 0];
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum NetworkConfig {
+    Dhcp,
+    Static {
+        ip: Ipv4,
+        gateway: Ipv4,
+        netmask: Ipv4,
+        /// Without one, the module falls back to its built in
+        /// name servers
+        dns: Option<Ipv4>,
+    },
+}
+
+#[cfg(test)]
+pub mod test_network {
+    use super::*;
+    use teensycore::*;
+
+    #[test]
+    fn test_parse_ipv4() {
+        assert_eq!(Ipv4::parse(&str!(b"192.168.1.50")), Some(Ipv4([192, 168, 1, 50])));
+        assert_eq!(Ipv4::parse(&str!(b"0.0.0.0")), Some(Ipv4([0, 0, 0, 0])));
+        assert_eq!(Ipv4::parse(&str!(b"192.168.1.256")), None);
+        assert_eq!(Ipv4::parse(&str!(b"192.168.1")), None);
+        assert_eq!(Ipv4::parse(&str!(b"192.168.1.50.")), None);
+        assert_eq!(Ipv4::parse(&str!(b"192.168..50")), None);
+        assert_eq!(Ipv4::parse(&str!(b"a.b.c.d")), None);
+        assert_eq!(Ipv4::parse(&str!(b"")), None);
+
+        assert!(Ipv4([10, 0, 0, 7]).to_str() == str!(b"10.0.0.7"));
+    }
+}
@@ -2,5 +2,9 @@ pub mod color;
 pub mod context;
 pub mod effect;
 pub mod math;
+pub mod render;
 pub mod shader;
-pub mod shader_config;
\ No newline at end of file
+pub mod shader_config;
+
+#[cfg(test)]
+pub mod golden;
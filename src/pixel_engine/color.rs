@@ -100,6 +100,15 @@ impl Color {
             (self.b as u32); 
     }
 
+    /// Dim the color. A brightness of 255 leaves it unchanged.
+    pub fn scale(&self, brightness: u8) -> Self {
+        return Color {
+            r: ((self.r as u32 * brightness as u32) / 255) as u8,
+            g: ((self.g as u32 * brightness as u32) / 255) as u8,
+            b: ((self.b as u32 * brightness as u32) / 255) as u8,
+        };
+    }
+
     pub fn blank() -> Self {
         return Color {
             r: 255,
@@ -112,50 +121,47 @@ impl Color {
 #[cfg(test)]
 pub mod test_colors {
     use crate::pixel_engine::math::interpolate;
-    use std::println;
     use super::*;
 
     #[test]
     fn test_hsv() {
-        // assert_eq!(hsv(39.0, 1.0, 1.0).g, 165);
-
-        // let (h, s, v) = rgb_to_hsv(0,0,128);
-        // assert_eq!(h, 240.0);
-        // assert_eq!(s, 1.0);
-        // assert_eq!(v, 0.5019608);
-        // assert_eq!(hsv(h, s, v).b, 128);
-        // assert_eq!(hsv(0.0).r, 255);
+        assert_eq!(hsv(39.0, 1.0, 1.0).g, 165);
+
+        let (h, s, v) = rgb_to_hsv(0,0,128);
+        assert_eq!(h, 240.0);
+        assert_eq!(s, 1.0);
+        assert_eq!(v, 0.5019608);
+        assert_eq!(hsv(h, s, v).b, 128);
+        assert_eq!(hsv(0.0, 1.0, 1.0).r, 255);
     }
 
     #[test]
     fn test_interpolated_hsv() {
+        let (h, s, v) = rgb_to_hsv(148, 0, 133);
+        assert!(abs(h - 306.08) < 0.01);
+        assert_eq!(s, 1.0);
+        assert!(abs(v - 0.58) < 0.01);
+
+        // Interpolating across the hue wheel should never produce
+        // a hue outside of the valid range.
+        for time in 0 .. 100 {
+            let start = (255, 0, 0);
+            let end = (50, 0, 255);
+            let r = interpolate(start.0, end.0, time, 100);
+            let g = interpolate(start.1, end.1, time, 100);
+            let b = interpolate(start.2, end.2, time, 100);
+            let (h, _, _) = rgb_to_hsv(r as u8, g as u8, b as u8);
+            assert!(h >= 0.0 && h <= 360.0);
+        }
+    }
 
+    #[test]
+    fn test_scale() {
+        let color = rgb(255, 128, 10);
+        assert_eq!(color.scale(255).as_hex(), color.as_hex());
+        assert_eq!(color.scale(0).as_hex(), 0);
 
-        // // Interpolate
-        // for time in 0 .. 100 {
-        //     let start = (255, 0, 0);
-        //     let end = (50, 0, 255);
-        //     let r = interpolate(start.0, end.0, time, 100);
-        //     let g = interpolate(start.1, end.1, time, 100);
-        //     let b = interpolate(start.2, end.2, time, 100);
-        //     let (h, s, v) = rgb_to_hsv(r as u8, g as u8, b as u8);
-
-        //     println!("rgb({}, {}, {})  hsv({}, {}, {})", r, g, b, h, s, v);
-        // }
-
-        // for time in 0 .. 100 {
-        //     let start = (50, 0, 255);
-        //     let end = (255, 0, 0);
-        //     let r = interpolate(start.0, end.0, time, 100);
-        //     let g = interpolate(start.1, end.1, time, 100);
-        //     let b = interpolate(start.2, end.2, time, 100);
-        //     let (h, s, v) = rgb_to_hsv(r as u8, g as u8, b as u8);
-
-        //     println!("rgb({}, {}, {})  hsv({}, {}, {})", r, g, b, h, s, v);
-        // }
-
-        std::println!("hi");
-        let (h, s, v) = rgb_to_hsv(148, 0, 133);
-        // assert_eq!(h, 306.0);
+        let half = color.scale(128);
+        assert_eq!((half.r, half.g, half.b), (128, 64, 5));
     }
-}
\ No newline at end of file
+}
@@ -163,16 +163,77 @@ impl Effect {
 pub mod test_effects {
     use super::*;
 
+    fn context_with_offset(offset: uNano) -> Context {
+        let mut ctx = Context::empty();
+        ctx.initialized = true;
+        ctx.offset = offset;
+        return ctx;
+    }
+
     #[test]
     fn test_effects() {
-        // let fx = Effect::new(b"Sample")
-        //     .randomize(|node_id, total_nodes| {
-        //         return rand() % 100;
-        //     })
-        //     .transition_to_without_delay(100, 500)
-        //     .transition_to(0, 500)
-        //     .build();
+        let mut fx = Effect::new(b"Sample")
+            .transition_to(100, 1000)
+            .transition_to(0, 1000)
+            .build();
+
+        assert_eq!(fx.total_time, 2000);
+
+        let mut ctx = context_with_offset(0);
+        assert_eq!(fx.process(&mut ctx, 0).0, 0);
+        assert_eq!(fx.process(&mut ctx, 500).0, 50);
+        assert_eq!(fx.process(&mut ctx, 1250).0, 75);
+        assert_eq!(fx.process(&mut ctx, 2500).0, 50);
+
+        // The offset shifts the node along the timeline
+        let mut ctx = context_with_offset(500);
+        assert_eq!(fx.process(&mut ctx, 1000).0, 50);
+    }
 
+    #[test]
+    fn test_effect_hold() {
+        let mut fx = Effect::new(b"Hold")
+            .transition_to(100, 1000)
+            .transition_to_and_hold(0, 1000)
+            .build();
+
+        // Without an offset, a held node behaves like a normal transition
+        let mut ctx = context_with_offset(0);
+        assert_eq!(fx.process(&mut ctx, 1250).0, 75);
+        assert_eq!(fx.process(&mut ctx, 1999).0, 1);
+
+        // With an offset, the held node is stretched by that
+        // offset, so it moves more slowly towards its target
+        let mut ctx = context_with_offset(500);
+        assert_eq!(fx.process(&mut ctx, 500).0, 100);
+        assert_eq!(fx.process(&mut ctx, 1000).0, 67);
+        assert_eq!(fx.process(&mut ctx, 1499).0, 34);
+        assert_eq!(fx.process(&mut ctx, 1500).0, 0);
+    }
 
+    #[test]
+    fn test_effect_initializer() {
+        let mut fx = Effect::new(b"Init")
+            .with_initializer(|fx, ctx| {
+                let mut next_ctx = ctx.clone();
+                fx.regs[0] += 1;
+                next_ctx.offset = ctx.node_id * 100;
+                return next_ctx;
+            })
+            .transition_to(100, 1000)
+            .build();
+
+        let mut ctx = Context::empty();
+        ctx.node_id = 2;
+
+        let (time, next_ctx) = fx.process(&mut ctx, 0);
+        assert_eq!(time, 20);
+        assert!(next_ctx.initialized);
+        assert_eq!(fx.regs[0], 1);
+
+        // Once initialized, the initializer is not invoked again
+        let mut ctx = next_ctx;
+        fx.process(&mut ctx, 0);
+        assert_eq!(fx.regs[0], 1);
     }
-}
\ No newline at end of file
+}
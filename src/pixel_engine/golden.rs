@@ -0,0 +1,190 @@
+//! Golden-frame regression tests.
+//!
+//! Every entry in `initialize_shaders()` is rendered through
+//! every entry in `initialize_effects()` for a fixed number of
+//! frames, using a fixed random seed and a fixed frame clock.
+//! The resulting colors are compared against the files stored
+//! in `golden/` at the root of the repository.
+//!
+//! When a change to the output is intentional, regenerate the
+//! files and review the diff like any other change:
+//!
+//! ```text
+//! UPDATE_GOLDEN=1 ./test.sh
+//! ```
+
+use std::prelude::rust_2021::*;
+use std::{format, fs, println};
+use teensycore::clock::uNano;
+use teensycore::system::vector::*;
+use crate::effects::*;
+use crate::shaders::*;
+use crate::pixel_engine::context::*;
+use crate::pixel_engine::effect::*;
+use crate::pixel_engine::math::seed_rand;
+use crate::pixel_engine::render::*;
+use crate::pixel_engine::shader::*;
+
+const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/golden");
+const SEED: u64 = 1337;
+const FRAMES: uNano = 16;
+const FRAME_MS: uNano = 525;
+const NODES: usize = crate::HEX_UNITS;
+
+/// How many mismatched nodes to print for each combination
+/// before summarizing the rest.
+const MAX_REPORTED_NODES: usize = 12;
+
+/// The random number generator is global, so renders are
+/// serialized to keep parallel tests from interleaving. Any
+/// other test which renders shaders must hold this as well.
+pub static RENDER_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+fn name_of(name: &'static [u8]) -> &'static str {
+    return core::str::from_utf8(name).unwrap();
+}
+
+fn golden_path(shader: &Shader, effect: &Effect) -> String {
+    return format!("{}/{}__{}.txt", GOLDEN_DIR, name_of(shader.name), name_of(effect.name));
+}
+
+/// Render a shader and effect combination into the textual
+/// golden representation. Each frame is a single line with
+/// the hex color of every node.
+pub fn render_golden(shader: &Shader, effect: &Effect) -> String {
+    let mut shader = *shader;
+    let mut effect = *effect;
+    let mut contexts = [Context::empty(); NODES];
+
+    for node_id in 0 .. NODES {
+        contexts[node_id].node_id = node_id as uNano;
+        contexts[node_id].total_nodes = NODES as uNano;
+    }
+
+    let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    seed_rand(SEED);
+
+    let mut result = String::new();
+    result.push_str(&format!("# {} x {}\n", name_of(shader.name), name_of(effect.name)));
+    result.push_str(&format!("# seed {}, {} frames, {}ms per frame, {} nodes\n", SEED, FRAMES, FRAME_MS, NODES));
+
+    for frame in 0 .. FRAMES {
+        result.push_str(&format!("{:03}", frame));
+        for node_id in 0 .. NODES {
+            let (color, next_context) = render_node(&mut shader, &mut effect, &contexts[node_id], frame * FRAME_MS);
+            contexts[node_id] = next_context;
+            result.push_str(&format!(" {:06X}", color.as_hex()));
+        }
+        result.push('\n');
+    }
+
+    return result;
+}
+
+/// Compare the rendered output with the golden file and
+/// return a human readable description of every difference.
+fn diff_golden(label: &str, expected: &str, actual: &str) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+
+    let mut report = format!("{} differs from its golden file\n", label);
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    let mut reported = 0;
+    let mut total = 0;
+
+    for idx in 0 .. expected_lines.len().max(actual_lines.len()) {
+        let expected_line = expected_lines.get(idx).copied().unwrap_or("");
+        let actual_line = actual_lines.get(idx).copied().unwrap_or("");
+        if expected_line == actual_line {
+            continue;
+        }
+
+        // Header lines are reported verbatim
+        if expected_line.starts_with('#') || actual_line.starts_with('#') {
+            report.push_str(&format!("  line {}: expected `{}`, got `{}`\n", idx + 1, expected_line, actual_line));
+            continue;
+        }
+
+        let expected_cols: Vec<&str> = expected_line.split(' ').collect();
+        let actual_cols: Vec<&str> = actual_line.split(' ').collect();
+        let frame = actual_cols.first().or(expected_cols.first()).copied().unwrap_or("?");
+
+        for col in 1 .. expected_cols.len().max(actual_cols.len()) {
+            let expected_color = expected_cols.get(col).copied().unwrap_or("------");
+            let actual_color = actual_cols.get(col).copied().unwrap_or("------");
+            if expected_color != actual_color {
+                total += 1;
+                if reported < MAX_REPORTED_NODES {
+                    reported += 1;
+                    report.push_str(&format!("  frame {} node {:02}: expected #{} got #{}\n", frame, col - 1, expected_color, actual_color));
+                }
+            }
+        }
+    }
+
+    if total > reported {
+        report.push_str(&format!("  ... and {} more differing nodes\n", total - reported));
+    }
+
+    return Some(report);
+}
+
+#[test]
+fn test_golden_frames() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let shaders = initialize_shaders();
+    let effects = initialize_effects();
+    let mut failures = Vec::new();
+
+    if update {
+        fs::create_dir_all(GOLDEN_DIR).unwrap();
+    }
+
+    for shader in shaders.into_iter() {
+        for effect in effects.into_iter() {
+            let path = golden_path(&shader, &effect);
+            let label = format!("{} x {}", name_of(shader.name), name_of(effect.name));
+            let actual = render_golden(&shader, &effect);
+
+            if update {
+                fs::write(&path, &actual).unwrap();
+                continue;
+            }
+
+            match fs::read_to_string(&path) {
+                Err(_) => {
+                    failures.push(format!("{} has no golden file at {}\n", label, path));
+                },
+                Ok(expected) => {
+                    match diff_golden(&label, &expected, &actual) {
+                        None => {},
+                        Some(report) => failures.push(report),
+                    }
+                }
+            }
+        }
+    }
+
+    if failures.len() > 0 {
+        for failure in failures.iter() {
+            println!("{}", failure);
+        }
+        std::panic!("{} golden frame combinations changed. Re-run with UPDATE_GOLDEN=1 if this is intentional.", failures.len());
+    }
+}
+
+#[test]
+fn test_golden_render_is_deterministic() {
+    let shader = initialize_shaders().get(0).unwrap();
+    let effect = initialize_effects().get(0).unwrap();
+    assert_eq!(render_golden(&shader, &effect), render_golden(&shader, &effect));
+}
+
+#[test]
+fn test_golden_diff_reports_nodes() {
+    let report = diff_golden("A x B", "# A x B\n000 000000 FFFFFF\n", "# A x B\n000 000000 FF0000\n").unwrap();
+    assert!(report.contains("frame 000 node 01: expected #FFFFFF got #FF0000"));
+    assert!(diff_golden("A x B", "000 000000\n", "000 000000\n").is_none());
+}
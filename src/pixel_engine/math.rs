@@ -1,6 +1,11 @@
 use teensycore::math::*;
 use teensycore::clock::uNano;
 
+// XORSHIFT+ registers used by the pixel engine. These mirror the
+// ones inside teensycore, but they can be fully re-seeded which
+// is what makes rendering reproducible.
+static mut XORSHIFT_REGS: [u64; 2] = [0xFAE0, 0xFFAA_FFDC];
+
 pub fn interpolate(from: u32, to: u32, current_time: uNano, duration: uNano) -> u32 {
     let x0 = 0f64;
     let y0 = min(from, to) as f64;
@@ -14,4 +19,44 @@ pub fn interpolate(from: u32, to: u32, current_time: uNano, duration: uNano) ->
     } else {
         return from + delta;
     }
-}
\ No newline at end of file
+}
+
+/// Returns the next pseudo-random number used by shaders
+/// and effects.
+pub fn rand() -> u64 {
+    unsafe {
+        let mut t = XORSHIFT_REGS[0];
+        let s = XORSHIFT_REGS[1];
+        XORSHIFT_REGS[0] = s;
+        t ^= t << 23;
+        t ^= t >> 18;
+        t ^= s ^ (s >> 5);
+        XORSHIFT_REGS[1] = t;
+        return t.wrapping_add(s);
+    }
+}
+
+/// Seed the pixel engine random number generator. Unlike
+/// the teensycore implementation, this replaces the entire
+/// generator state so the same seed always yields the same
+/// sequence. The kernel generator is seeded as well, so
+/// anything relying on it (like `Vector::shuffle`) benefits.
+pub fn seed_rand(seed: u64) {
+    // Expand the seed with splitmix64 so that small
+    // seeds still produce well-mixed registers.
+    let mut state = seed;
+    let mut regs = [0u64; 2];
+    for reg in regs.iter_mut() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        *reg = z ^ (z >> 31);
+    }
+
+    unsafe {
+        XORSHIFT_REGS = regs;
+    }
+
+    teensycore::math::seed_rand(seed);
+}
@@ -0,0 +1,18 @@
+use teensycore::clock::uNano;
+use crate::pixel_engine::color::*;
+use crate::pixel_engine::context::*;
+use crate::pixel_engine::effect::*;
+use crate::pixel_engine::shader::*;
+
+/// Compute the color of a single hexagon node.
+/// 
+/// The effect decides how far along the shader the node
+/// should be (as a percentage from 0 - 100) and the shader
+/// turns that into a color. The updated context is returned
+/// so the caller can commit it for the next frame.
+pub fn render_node(shader: &mut Shader, effect: &mut Effect, ctx: &Context, elapsed_ms: uNano) -> (Color, Context) {
+    let mut node_ctx = *ctx;
+    let (effect_time, next_context) = effect.process(&mut node_ctx, elapsed_ms);
+    let time_t = ((effect_time as f64 / 100.0) * shader.total_time as f64) as uNano;
+    return (shader.get_color(time_t), next_context);
+}
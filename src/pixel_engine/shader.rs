@@ -146,25 +146,58 @@ impl Shader {
 
 #[cfg(test)]
 pub mod test_shaders {
-    
     use super::*;
-    use teensycore::*;
-    use teensycore::system::str::*;
 
     #[test]
     fn test_shader() {
-        // let mut shader = Shader::new(b"Sample")
-        //     .with_color(rgb(255, 0, 0))
-        //     .transition_to(rgb(0,255,0), 500)
-        //     .transition_to(rgb(255,0,0), 500)
-        //     .build();
-
-        // let color = shader.get_color(250);
-        // assert_eq!(color.r, 128);
-        // assert_eq!(color.g, 127);
-        // assert_eq!(color.b, 0);
-
-        // let color2 = shader.get_color(503);
-        // assert_eq!(color2.r, 1);
+        let mut shader = Shader::new(b"Sample")
+            .with_color(rgb(255, 0, 0))
+            .transition_to(rgb(0,255,0), 500)
+            .transition_to(rgb(255,0,0), 500)
+            .build();
+
+        assert_eq!(shader.total_segments, 2);
+        assert_eq!(shader.total_time, 1000);
+
+        // rgb(128, 127, 0) is brightened to full value
+        let color = shader.get_color(250);
+        assert_eq!(color.r, 255);
+        assert_eq!(color.g, 253);
+        assert_eq!(color.b, 0);
+
+        let color2 = shader.get_color(503);
+        assert_eq!(color2.r, 1);
+        assert_eq!(color2.g, 255);
+
+        // Time wraps around the total shader time
+        let color3 = shader.get_color(1250);
+        assert_eq!(color3.as_hex(), color.as_hex());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_shader_clamps_saturation() {
+        // Greys have no saturation, but the shader forces
+        // at least 70% saturation at full brightness.
+        let mut shader = Shader::new(b"Grey")
+            .with_color(rgb(255, 255, 255))
+            .transition_to(rgb(128, 128, 128), 500)
+            .build();
+
+        let color = shader.get_color(250);
+        assert_eq!(color.r, 255);
+        assert_eq!(color.g, 76);
+        assert_eq!(color.b, 76);
+    }
+
+    #[test]
+    fn test_sealed_shader() {
+        let mut shader = Shader::new(b"Sealed")
+            .with_color(rgb(255, 0, 0))
+            .transition_to(rgb(0, 0, 255), 500)
+            .build();
+
+        shader.transition_to(rgb(0, 255, 0), 500);
+        assert_eq!(shader.total_segments, 1);
+        assert_eq!(shader.total_time, 500);
+    }
+}
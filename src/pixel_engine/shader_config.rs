@@ -1,8 +1,8 @@
 
-use teensycore::math::rand;
 use teensycore::system::str::*;
 use teensycore::system::vector::*;
 use teensycore::clock::uNano;
+use crate::hal::RandomSource;
 
 /// A ShaderConfig defines the configuration for a particular
 /// shader.
@@ -38,7 +38,30 @@ impl ShaderConfigList {
         self.configs.push(config);
     }
 
-    pub fn get_shader(&self, date: uNano) -> Str {
+    /// Returns true if both lists hold the same rules in the
+    /// same order.
+    pub fn matches(&self, other: &ShaderConfigList) -> bool {
+        if self.size() != other.size() {
+            return false;
+        }
+
+        return self.configs.into_iter().zip(other.configs.into_iter()).all(|(a, b)| {
+            a.time_range_start == b.time_range_start
+                && a.time_range_end == b.time_range_end
+                && a.probability == b.probability
+                && a.shader == b.shader
+        });
+    }
+
+    /// Release the list along with the shader names.
+    pub fn drop(&mut self) {
+        for mut config in self.configs.into_iter() {
+            config.shader.drop();
+        }
+        self.configs.free();
+    }
+
+    pub fn get_shader<R: RandomSource>(&self, date: uNano, random: &mut R) -> Str {
         let mut total_probabilities = 0;
         let mut candidates = Vector::new();
 
@@ -59,7 +82,7 @@ impl ShaderConfigList {
         let mut shuffled = candidates.shuffle();
         candidates.free();
 
-        let target = rand() % total_probabilities;
+        let target = random.rand() % total_probabilities;
         let mut accumulator = 0;
         for candidate in shuffled.into_iter() {
             accumulator += candidate.probability;
@@ -1,6 +1,5 @@
 use teensycore::*;
 use teensycore::clock::*;
-use teensycore::math::rand;
 use teensycore::system::str::Str;
 use teensycore::system::str::StringOps;
 use teensycore::system::vector::Array;
@@ -12,14 +11,23 @@ use crate::shaders::*;
 use crate::effects::*;
 use crate::pixel_engine::color::*;
 use crate::pixel_engine::math::interpolate;
+use crate::pixel_engine::render::*;
 use crate::pixel_engine::shader::*;
 use crate::pixel_engine::effect::*;
 use crate::pixel_engine::context::*;
-use crate::drivers::ws2812::*;
+use crate::hal::*;
+use crate::control::{self, ControlCommand, StreamFrame};
+use crate::thermal::*;
 
-const LEDS_PER_UNIT: usize = 3;
-const LEDS: usize = crate::HEX_UNITS * LEDS_PER_UNIT;
+pub const LEDS_PER_UNIT: usize = 3;
+pub const LEDS: usize = crate::HEX_UNITS * LEDS_PER_UNIT;
 const TRANSITION_TIME: uNano = 1000 * crate::WORLD_MUTIPLIER; // ms
+const MIN_WARPED_DELAY: uNano = 5 * S_TO_NANO;
+/// Frames from lighting software are held this long, which is
+/// how long E1.31 waits before calling the stream lost
+pub const STREAM_TIMEOUT: uNano = 2500 * MS_TO_NANO;
+/// How long it takes to fade back to the schedule afterwards
+const STREAM_FADE_TIME: uNano = 2000; // ms
 
 enum PixelState {
     Loading,
@@ -27,7 +35,7 @@ enum PixelState {
     MainSequence,
 }
 
-pub struct PixelTask {
+pub struct PixelTask<L: LedSink, C: Clock, R: RandomSource> {
     state: PixelState,
     shader: Option<Shader>,
     next_shader: Option<Shader>,
@@ -35,7 +43,11 @@ pub struct PixelTask {
     contexts: [Context; crate::HEX_UNITS],
     effect: Option<Effect>,
     effects: Vector<Effect>,
-    driver: WS2812Driver<LEDS>,
+    // Used instead of a random effect on the next transition
+    forced_effect: Option<Effect>,
+    driver: L,
+    clock: C,
+    random: R,
     target: uNano,
     day_target: uNano,
 
@@ -46,14 +58,16 @@ pub struct PixelTask {
     // Randomize every couple hours
     randomize_target: uNano,
     ready: bool,
+    // Whether a time warp was active last we checked
+    warped: bool,
     color_buffer: [Color; crate::HEX_UNITS],
     transition_start: uNano,
     transition_offset: uNano,
     cycle_offset: uNano,
 }
 
-impl PixelTask {
-    pub fn new() -> Self {
+impl<L: LedSink, C: Clock, R: RandomSource> PixelTask<L, C, R> {
+    pub fn new(driver: L, clock: C, random: R) -> Self {
 
         return PixelTask {
             state: PixelState::Loading,
@@ -66,20 +80,21 @@ impl PixelTask {
             cycle_offset: 0,
             cycles: 0,
             ready: false,
+            warped: false,
             shader: None,
             effect: None,
+            forced_effect: None,
             next_shader: None,
             shaders: initialize_shaders(),
             effects: initialize_effects(),
-            driver: WS2812Driver::<LEDS>::new(
-                18, // pin
-            ),
+            driver: driver,
+            clock: clock,
+            random: random,
             color_buffer: [Color::blank(); crate::HEX_UNITS],
             contexts: [Context::empty(); crate::HEX_UNITS],
         };   
     }
 
-    #[allow(dead_code)]
     fn find_effect(&self, name: &'static [u8]) -> Option<Effect> {
         for effect in self.effects.into_iter() {
             if effect.name == name {
@@ -117,12 +132,12 @@ impl PixelTask {
 
     // Evaluate which shader to select based on
     // world information.
-    fn get_next_shader(&self) -> Shader {
+    fn get_next_shader(&mut self) -> Shader {
         // return self.find_shader(&str!(b"Ghibli")).unwrap();
 
         // If we have WIFI access, use the shader configs downloaded from the internet
         if crate::USE_WIFI {
-            let appropriate_shader = get_shader_configs().get_shader(crate::get_world_time());
+            let appropriate_shader = get_shader_configs().get_shader(crate::get_world_time(&self.clock), &mut self.random);
             match self.find_shader(&appropriate_shader) {
                 None => return self.shaders.get(0).unwrap(),
                 Some(shader) => { 
@@ -134,7 +149,7 @@ impl PixelTask {
             }
         } else {
             // Otherwise, there is no wifi. Return any random shader.
-            let idx = rand() % self.shaders.size() as u64;
+            let idx = self.random.rand() % self.shaders.size() as u64;
             let next_shader = self.shaders.get(idx as usize).unwrap();
             if next_shader.wifi_only || next_shader.disabled {
                 return self.get_next_shader();
@@ -145,7 +160,7 @@ impl PixelTask {
     }
 
     // Returns a random effect
-    fn get_next_effect(&self, shader: &Shader) -> Effect {
+    fn get_next_effect(&mut self, shader: &Shader) -> Effect {
         // if shader.total_segments < 3 {
         //     return self.find_effect(b"Randomized").unwrap();
         // } else {
@@ -153,7 +168,7 @@ impl PixelTask {
         // }
 
         // Select an appropriate effect to match the shader
-        let idx = rand() % self.effects.size() as u64;
+        let idx = self.random.rand() % self.effects.size() as u64;
         let next_effect = self.effects.get(idx as usize).unwrap();
         if next_effect.disabled ||  next_effect.max_color_segments.unwrap_or(usize::MAX) < shader.total_segments || next_effect.min_hex_units.unwrap_or(0) > crate::HEX_UNITS {
             return self.get_next_effect(shader);
@@ -173,13 +188,27 @@ impl PixelTask {
         }
 
         // Set the next day processing target
-        self.day_target = nanos() + (S_TO_NANO * 60 * 30);
+        self.day_target = self.clock.nanos() + (S_TO_NANO * 60 * 30);
 
         // Select a shader
         self.shader = self.find_shader(&str!(b"Medbay"));
         
         // Select an effect
         self.effect = Some(self.find_effect(b"Randomized").unwrap());
+
+        // Let remote interfaces know what there is to pick from
+        for shader in self.shaders.into_iter() {
+            if !shader.disabled {
+                control::register_shader(shader.name);
+            }
+        }
+        for effect in self.effects.into_iter() {
+            if !effect.disabled {
+                control::register_effect(effect.name);
+            }
+        }
+        control::set_current_shader(self.shader.unwrap().name);
+        control::set_current_effect(self.effect.unwrap().name);
     }
 
     pub fn transition_to(&mut self, next_shader: Shader) {
@@ -192,17 +221,61 @@ impl PixelTask {
         }
 
         // Randomize the next effect
-        self.effect = Some(self.get_next_effect(&next_shader));
+        let effect = match self.forced_effect.take() {
+            Some(effect) => effect,
+            None => self.get_next_effect(&next_shader),
+        };
+        self.effect = Some(effect);
+        control::set_current_shader(next_shader.name);
+        control::set_current_effect(effect.name);
 
         // Set the transition start time
-        self.transition_start = nanos();
+        self.transition_start = self.clock.nanos();
         self.transition_offset = 0;
         self.state = PixelState::Transitioning;
     }
 
     pub fn randomize(&mut self) {
-        self.randomize_target = nanos() + get_tranasition_delay();
-        self.transition_to(self.get_next_shader());
+        // Warped time moves faster, so the schedule has to be
+        // re-evaluated more often to keep up with it.
+        let delay = match crate::get_time_warp(&self.clock) {
+            None => get_tranasition_delay(),
+            Some(warp) => (get_tranasition_delay() / warp.multiplier).max(MIN_WARPED_DELAY),
+        };
+
+        self.randomize_target = self.clock.nanos() + delay;
+        let next_shader = self.get_next_shader();
+        self.transition_to(next_shader);
+    }
+
+    /// Carry out any commands queued by remote interfaces.
+    fn process_commands(&mut self) {
+        while let Some(command) = control::next_command() {
+            match command {
+                ControlCommand::ForceShader(name) => {
+                    match self.shaders.into_iter().find(|shader| shader.name == name) {
+                        None => {},
+                        Some(shader) => {
+                            // Hold it for a full transition delay
+                            self.randomize_target = self.clock.nanos() + get_tranasition_delay();
+                            self.transition_to(shader);
+                        }
+                    }
+                },
+                ControlCommand::ForceEffect(name) => {
+                    match (self.find_effect(name), self.next_shader.or(self.shader)) {
+                        (Some(effect), Some(shader)) => {
+                            self.forced_effect = Some(effect);
+                            self.transition_to(shader);
+                        },
+                        _ => {},
+                    }
+                },
+                ControlCommand::Randomize => {
+                    self.randomize();
+                },
+            }
+        }
     }
 
     /* 
@@ -210,7 +283,7 @@ impl PixelTask {
         overflows.
      */
     pub fn overflow_watch(&mut self) {
-        let now = nanos();
+        let now = self.clock.nanos();
         if 
             self.transition_offset > 0 && 
             self.transition_start > 0 && 
@@ -224,12 +297,23 @@ impl PixelTask {
     }
 
     pub fn system_loop(&mut self) {
-        let time = nanos() - self.transition_offset;
+        self.process_commands();
+
+        let time = self.clock.nanos() - self.transition_offset;
         let cycle_time = (time - self.cycle_offset) / teensycore::MS_TO_NANO;
         let elapsed_ms = time / teensycore::MS_TO_NANO;
+        let derating = control::get_status().thermal;
+        // Switched off, the schedule keeps going in the dark
+        let brightness = match crate::get_power() {
+            true => derating.brightness(crate::get_brightness()),
+            false => 0,
+        };
         let mut should_cycle = false;
 
         if time > self.target {
+            // Whatever the schedule shows, before lighting software has its say
+            let mut scheduled = [Color::blank(); crate::HEX_UNITS];
+            let mut rendered = true;
             let shader = self.shader.as_mut().unwrap();
             let effect = self.effect.as_mut().unwrap();
 
@@ -241,29 +325,19 @@ impl PixelTask {
                         self.state = PixelState::MainSequence;
                         self.shader = self.next_shader;
                         self.transition_offset = self.transition_start;
+                        rendered = false;
                     } else {
                         // We will interpolate from the snapshot of the last known colors
                         // into the computed effect of the next color. And once
                         // we've iterated the correct amount of time, we will
                         // swap next_shader with shader.
                         for node_id in 0 .. crate::HEX_UNITS {
-                            let mut ctx = self.contexts[node_id];
                             let next_shader = self.next_shader.as_mut().unwrap();
                             let transition_time_elapsed = (time - self.transition_start) / MS_TO_NANO;
-                            let (effect_time, next_context) = effect.process(&mut ctx, transition_time_elapsed);
-                            let time_t = ((effect_time as f64 / 100.0) * next_shader.total_time as f64) as uNano;
-                            let next_color = next_shader.get_color(time_t);
+                            let (next_color, next_context) = render_node(next_shader, effect, &self.contexts[node_id], transition_time_elapsed);
                             self.contexts[node_id] = next_context;
                             
-                            let color = rgb(
-                                interpolate(self.color_buffer[node_id].r as u32, next_color.r as u32, transition_time_elapsed, TRANSITION_TIME) as u8,
-                                interpolate(self.color_buffer[node_id].g as u32, next_color.g as u32, transition_time_elapsed, TRANSITION_TIME) as u8,
-                                interpolate(self.color_buffer[node_id].b as u32, next_color.b as u32, transition_time_elapsed, TRANSITION_TIME) as u8,
-                            ).as_hex();
-
-                            for pixel_id in 0 .. LEDS_PER_UNIT {
-                                self.driver.set_color(node_id * LEDS_PER_UNIT + pixel_id, color);
-                            }
+                            scheduled[node_id] = blend(self.color_buffer[node_id], next_color, transition_time_elapsed, TRANSITION_TIME);
                         }
                     }
                 },
@@ -273,7 +347,7 @@ impl PixelTask {
 
                     if cycle_time > effect.total_time {
                         self.cycles += 1;
-                        self.cycle_offset = nanos() - self.transition_offset;
+                        self.cycle_offset = self.clock.nanos() - self.transition_offset;
 
                         // If cycle mode, then advance to the next color theme
                         if crate::CYCLE_MODE && self.cycles % 3 == 0 {
@@ -283,19 +357,12 @@ impl PixelTask {
 
                     // For each hexagon node
                     for node_id in 0 .. crate::HEX_UNITS {
-                        let mut ctx = self.contexts[node_id];
-                        let (effect_time, next_context) = effect.process(&mut ctx, elapsed_ms);
-                        let time_t = (( effect_time as f64 / 100.0) * shader.total_time as f64) as uNano;
-                        self.color_buffer[node_id] = shader.get_color(time_t);
-                        let color = self.color_buffer[node_id].as_hex();
+                        let (node_color, next_context) = render_node(shader, effect, &self.contexts[node_id], elapsed_ms);
+                        self.color_buffer[node_id] = node_color;
+                        scheduled[node_id] = node_color;
 
                         // Commit any updates to context that we should be registering
                         self.contexts[node_id] = next_context;
-
-                        // Render the color for each unit in this node
-                        for pixel_id in 0 .. LEDS_PER_UNIT {
-                            self.driver.set_color(node_id * LEDS_PER_UNIT + pixel_id, color);
-                        }
                     }
                 },
             }
@@ -303,23 +370,47 @@ impl PixelTask {
 
             match self.state {
                 PixelState::MainSequence => {
-                    // day_target is only valid if we can sync world_clock with the wifi
-                    // which doesn't happen if WIFI is disabled.
-                    if crate::USE_WIFI && nanos() > self.day_target {
+                    let warped = crate::get_time_warp(&self.clock).is_some();
+
+                    // A time warp starting or reverting changes the
+                    // world time, so pick whatever the schedule says now.
+                    if warped != self.warped {
+                        self.warped = warped;
+                        self.randomize();
+                    } else if crate::USE_WIFI && self.clock.nanos() > self.day_target {
+                        // day_target is only valid if we can sync world_clock with the wifi
+                        // which doesn't happen if WIFI is disabled.
                         // Check if we need to recalculate transition
-                        let datetime = DateTime::now();
+                        let datetime = DateTime::now(&self.clock);
                         if self.day_processed != datetime.days && datetime.hour >= 6 {
                             self.day_processed = datetime.days;
                             self.randomize();
                         }
-                        self.day_target = nanos() + S_TO_NANO;// (S_TO_NANO * 60 * 30);
-                    } else if nanos() > self.randomize_target {
+                        self.day_target = self.clock.nanos() + S_TO_NANO;// (S_TO_NANO * 60 * 30);
+                    } else if self.clock.nanos() > self.randomize_target {
                         self.randomize();
                     }
                 },
                 _ => {},
             }
 
+            // The frame which ends a transition renders nothing, so the
+            // leds keep what they had
+            if rendered {
+                let stream = control::get_stream_frame();
+                let now = self.clock.nanos();
+                for node_id in 0 .. crate::HEX_UNITS {
+                    for pixel_id in 0 .. LEDS_PER_UNIT {
+                        let led = node_id * LEDS_PER_UNIT + pixel_id;
+                        // Too hot for the schedule or lighting software
+                        let color = match derating.state {
+                            ThermalState::Critical => safe_color(pixel_id),
+                            _ => stream_color(stream, led, scheduled[node_id], now),
+                        };
+                        self.driver.set_color(led, color.scale(brightness).as_hex());
+                    }
+                }
+            }
 
             self.driver.flush();
         }
@@ -339,4 +430,135 @@ impl PixelTask {
         }
     }
 
-}
\ No newline at end of file
+}
+
+fn blend(from: Color, to: Color, elapsed: uNano, duration: uNano) -> Color {
+    return rgb(
+        interpolate(from.r as u32, to.r as u32, elapsed, duration) as u8,
+        interpolate(from.g as u32, to.g as u32, elapsed, duration) as u8,
+        interpolate(from.b as u32, to.b as u32, elapsed, duration) as u8,
+    );
+}
+
+/// Lighting software takes over while its frames are fresh.
+/// Once they stop, the wall fades from the last one back into
+/// the schedule.
+fn stream_color(stream: Option<StreamFrame>, led: usize, scheduled: Color, now: uNano) -> Color {
+    let frame = match stream {
+        None => return scheduled,
+        Some(frame) => frame,
+    };
+
+    let hold_until = match frame.terminated {
+        true => frame.received_at,
+        false => frame.received_at + STREAM_TIMEOUT,
+    };
+    if now <= hold_until {
+        return frame.colors[led];
+    }
+
+    let fading_ms = (now - hold_until) / MS_TO_NANO;
+    if fading_ms >= STREAM_FADE_TIME {
+        return scheduled;
+    }
+    return blend(frame.colors[led], scheduled, fading_ms, STREAM_FADE_TIME);
+}
+
+#[cfg(test)]
+pub mod test_pixel_task {
+    use super::*;
+    use crate::hal::mock::*;
+    use crate::pixel_engine::golden::RENDER_LOCK;
+
+    #[test]
+    fn test_pixel_task_renders() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let clock = MockClock::new();
+        let leds = MockLeds::new(LEDS);
+        let mut task = PixelTask::new(leds.clone(), &clock, MockRandom::new(7));
+
+        task.init();
+        assert!(leds.initialized());
+
+        // Loading sequence
+        clock.advance(100 * MS_TO_NANO);
+        task.system_loop();
+        assert_eq!(leds.frames(), 1);
+        assert!(leds.colors().iter().all(|color| *color != 0));
+
+        // Every pixel in a unit shares the same color
+        let colors = leds.colors();
+        for node_id in 0 .. crate::HEX_UNITS {
+            let base = node_id * LEDS_PER_UNIT;
+            assert_eq!(colors[base], colors[base + 1]);
+            assert_eq!(colors[base], colors[base + 2]);
+        }
+
+        // Once ready, the task transitions into a new shader
+        task.ready();
+        for _ in 0 .. 10 {
+            clock.advance(250 * MS_TO_NANO);
+            task.system_loop();
+        }
+        assert_eq!(leds.frames(), 11);
+    }
+
+    #[test]
+    fn test_pixel_task_derates() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let clock = MockClock::new();
+        let leds = MockLeds::new(LEDS);
+        let mut task = PixelTask::new(leds.clone(), &clock, MockRandom::new(7));
+        task.init();
+
+        // The same moment every time, so only the derating changes
+        clock.advance(100 * MS_TO_NANO);
+        let mut frame = |derating: Derating| {
+            control::record_thermal_event(ThermalEvent {
+                at: clock.now(),
+                temperature: Some(5000),
+                derating: derating,
+            });
+            task.system_loop();
+            return leds.colors();
+        };
+
+        let full = frame(Derating::none());
+        let dimmed = frame(Derating { state: ThermalState::Warning, scale: 128 });
+        let safe = frame(Derating { state: ThermalState::Critical, scale: 64 });
+        control::clear_thermal_events();
+
+        let total = |colors: &[u32]| colors.iter().map(|color| {
+            (color >> 16 & 0xFF) + (color >> 8 & 0xFF) + (color & 0xFF)
+        }).sum::<u32>();
+        assert!(total(&full) > 0);
+        assert!(total(&dimmed) * 10 < total(&full) * 6);
+
+        let lit = rgb(255, 96, 0).scale(SAFE_BRIGHTNESS).as_hex();
+        for node_id in 0 .. crate::HEX_UNITS {
+            let base = node_id * LEDS_PER_UNIT;
+            assert_eq!(&safe[base .. base + LEDS_PER_UNIT], &[lit, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn test_stream_color() {
+        let scheduled = rgb(0, 0, 200);
+        let mut frame = StreamFrame {
+            colors: [rgb(200, 0, 0); LEDS],
+            received_at: S_TO_NANO,
+            terminated: false,
+        };
+        let at = |ms: uNano| S_TO_NANO + ms * MS_TO_NANO;
+
+        assert_eq!(stream_color(None, 0, scheduled, at(0)).as_hex(), scheduled.as_hex());
+        assert_eq!(stream_color(Some(frame), 0, scheduled, at(2500)).as_hex(), rgb(200, 0, 0).as_hex());
+        // Halfway back to the schedule
+        assert_eq!(stream_color(Some(frame), 0, scheduled, at(3500)).as_hex(), rgb(100, 0, 100).as_hex());
+        assert_eq!(stream_color(Some(frame), 0, scheduled, at(4500)).as_hex(), scheduled.as_hex());
+
+        // A sender which says goodbye doesn't have to wait for the timeout
+        frame.terminated = true;
+        assert_eq!(stream_color(Some(frame), 0, scheduled, at(1000)).as_hex(), rgb(100, 0, 100).as_hex());
+    }
+}
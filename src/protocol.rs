@@ -0,0 +1,515 @@
+//! A binary protocol for driving the wall from a program on
+//! a host machine. It shares the serial port with the console:
+//! text is never sent with the sync byte, so anything starting
+//! with one is a frame.
+//!
+//! Frame layout:
+//!
+//! | Offset | Size | Field                                 |
+//! |--------|------|---------------------------------------|
+//! | 0      | 2    | sync (0xA5 0x5A)                      |
+//! | 2      | 1    | kind (command, ack or nack)           |
+//! | 3      | 1    | sequence number                       |
+//! | 4      | 2    | payload length                        |
+//! | 6      | n    | payload                               |
+//! | 6 + n  | 4    | crc32 of bytes 2 to 5 and the payload |
+//!
+//! Numbers are little endian. The host sends commands, each
+//! payload being an encoded `SystemCommand` followed by any data
+//! it carries, and the wall answers every one with an ack or a
+//! nack of the same sequence number. An ack carries a status byte
+//! and then what the command returned. A nack means the command
+//! didn't arrive intact and carries the reason.
+//!
+//! The host sends the same sequence number again when it gets a
+//! nack or nothing at all, and the next number for a new command.
+//! A command which is received twice in a row is only carried
+//! out once, and the second copy is answered with the first ack.
+
+use teensycore::*;
+use teensycore::clock::uNano;
+use teensycore::system::str::*;
+use crate::console;
+use crate::control::*;
+use crate::crc::*;
+use crate::hal::*;
+use crate::models::*;
+use crate::pixel_engine::color::*;
+use crate::pixel_task::{LEDS, STREAM_TIMEOUT};
+use crate::strings::chars;
+use crate::wifi_task::supervisor::WifiState;
+
+pub const SYNC: [u8; 2] = [0xA5, 0x5A];
+pub const HEADER_SIZE: usize = 6;
+pub const CRC_SIZE: usize = 4;
+/// Enough for a command and a color for every led
+pub const MAX_PAYLOAD: usize = 512;
+const MAX_FRAME: usize = HEADER_SIZE + MAX_PAYLOAD + CRC_SIZE;
+/// A frame which stops halfway is dropped after this long
+pub const FRAME_TIMEOUT: uNano = 500 * MS_TO_NANO;
+
+pub const KIND_COMMAND: u8 = 1;
+pub const KIND_ACK: u8 = 2;
+pub const KIND_NACK: u8 = 3;
+
+/// The first byte of an ack
+pub const STATUS_OK: u8 = 0;
+/// The command was understood but failed. The message follows.
+pub const STATUS_ERROR: u8 = 1;
+
+/// Returns the telemetry block described by `telemetry`
+pub const CMD_TELEMETRY: [u8; 4] = *b"TELE";
+/// Colors for leds from the first argument on, three bytes each.
+/// A second argument of 1 shows the frame.
+pub const CMD_FRAME: [u8; 4] = *b"FRAM";
+/// Fade back to the schedule
+pub const CMD_FRAME_END: [u8; 4] = *b"FEND";
+pub const TELEMETRY_VERSION: u8 = 1;
+
+/// Why a frame was answered with a nack. This is the payload.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum NackReason {
+    /// The frame was damaged on the way
+    BadCrc = 1,
+    /// The payload is longer than `MAX_PAYLOAD`
+    TooLong = 2,
+    /// The payload isn't a command
+    Malformed = 3,
+    /// The command's own checksum doesn't match
+    BadChecksum = 4,
+}
+
+pub struct Frame<'a> {
+    pub kind: u8,
+    pub seq: u8,
+    pub payload: &'a [u8],
+}
+
+/// Write a whole frame. The payload can be given in pieces.
+pub fn write_frame<O: ByteSink>(out: &mut O, kind: u8, seq: u8, payload: &[&[u8]]) {
+    let length = payload.iter().map(|part| part.len()).sum::<usize>() as u16;
+    let length = length.to_le_bytes();
+    let header = [kind, seq, length[0], length[1]];
+
+    let mut crc = crc32_update(0xFFFF_FFFF, &header);
+    for part in payload {
+        crc = crc32_update(crc, part);
+    }
+
+    out.write(&SYNC);
+    out.write(&header);
+    for part in payload {
+        out.write(part);
+    }
+    out.write(&(!crc).to_le_bytes());
+}
+
+pub enum ReadResult {
+    /// More bytes are needed
+    Pending,
+    /// `frame` has the frame until `reset` is called
+    Complete,
+    /// The frame was dropped, and should be answered with a
+    /// nack of this sequence number
+    Corrupt(u8, NackReason),
+}
+
+/// Puts frames back together one byte at a time.
+pub struct FrameReader {
+    buf: [u8; MAX_FRAME],
+    len: usize,
+    started_at: uNano,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        return FrameReader {
+            buf: [0; MAX_FRAME],
+            len: 0,
+            started_at: 0,
+        };
+    }
+
+    /// Returns true while a frame is partly read. Until then,
+    /// only the sync byte has to be handed over.
+    pub fn busy(&self) -> bool {
+        return self.len > 0;
+    }
+
+    pub fn push(&mut self, byte: u8, now: uNano) -> ReadResult {
+        if self.len > 0 && now.saturating_sub(self.started_at) > FRAME_TIMEOUT {
+            self.reset();
+        }
+
+        if self.len < SYNC.len() && byte != SYNC[self.len] {
+            self.reset();
+            return ReadResult::Pending;
+        }
+
+        if self.len == 0 {
+            self.started_at = now;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        if self.len < HEADER_SIZE {
+            return ReadResult::Pending;
+        }
+
+        let seq = self.buf[3];
+        let payload_len = self.payload_len();
+        if payload_len > MAX_PAYLOAD {
+            self.reset();
+            return ReadResult::Corrupt(seq, NackReason::TooLong);
+        } else if self.len < HEADER_SIZE + payload_len + CRC_SIZE {
+            return ReadResult::Pending;
+        }
+
+        let end = HEADER_SIZE + payload_len;
+        let mut crc = [0; CRC_SIZE];
+        crc.copy_from_slice(&self.buf[end .. end + CRC_SIZE]);
+        if crc32(&self.buf[SYNC.len() .. end]) != u32::from_le_bytes(crc) {
+            self.reset();
+            return ReadResult::Corrupt(seq, NackReason::BadCrc);
+        }
+        return ReadResult::Complete;
+    }
+
+    pub fn frame(&self) -> Frame<'_> {
+        return Frame {
+            kind: self.buf[2],
+            seq: self.buf[3],
+            payload: &self.buf[HEADER_SIZE .. HEADER_SIZE + self.payload_len()],
+        };
+    }
+
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+
+    fn payload_len(&self) -> usize {
+        return u16::from_le_bytes([self.buf[4], self.buf[5]]) as usize;
+    }
+}
+
+/// Carries out the commands which arrive in frames.
+pub struct CommandServer {
+    last_seq: Option<u8>,
+    /// The ack for the last command, in case it was lost
+    last_ack: Str,
+    /// Colors uploaded so far
+    colors: [Color; LEDS],
+}
+
+impl CommandServer {
+    pub fn new() -> Self {
+        return CommandServer {
+            last_seq: None,
+            last_ack: Str::new(),
+            colors: [rgb(0, 0, 0); LEDS],
+        };
+    }
+
+    /// Answer a frame which arrived intact.
+    pub fn handle<C: Clock, O: ByteSink>(&mut self, frame: &Frame, clock: &C, out: &mut O) {
+        if frame.kind != KIND_COMMAND {
+            self.reject(frame.seq, NackReason::Malformed, out);
+            return;
+        } else if self.last_seq == Some(frame.seq) {
+            out.write_str(&self.last_ack);
+            return;
+        }
+
+        let command = match SystemCommand::decode(frame.payload) {
+            None => {
+                self.reject(frame.seq, NackReason::Malformed, out);
+                return;
+            },
+            Some(command) => command,
+        };
+        if command.checksum != command.compute_checksum() {
+            self.reject(frame.seq, NackReason::BadChecksum, out);
+            return;
+        }
+
+        let mut body = Str::new();
+        let result = self.execute(&command, &frame.payload[COMMAND_SIZE ..], clock, &mut body);
+        let status = match result {
+            Ok(()) => STATUS_OK,
+            Err(message) => {
+                body.clear();
+                body.append(message);
+                STATUS_ERROR
+            }
+        };
+
+        let mut content = [0u8; MAX_PAYLOAD];
+        let mut length = 0;
+        for char in chars(&body).take(MAX_PAYLOAD - 1) {
+            content[length] = char;
+            length += 1;
+        }
+        body.drop();
+
+        self.last_ack.clear();
+        write_frame(&mut self.last_ack, KIND_ACK, frame.seq, &[&[status], &content[0 .. length]]);
+        self.last_seq = Some(frame.seq);
+        out.write_str(&self.last_ack);
+    }
+
+    /// Answer a frame which didn't make it.
+    pub fn reject<O: ByteSink>(&mut self, seq: u8, reason: NackReason, out: &mut O) {
+        write_frame(out, KIND_NACK, seq, &[&[reason as u8]]);
+    }
+
+    fn execute<C: Clock>(&mut self, command: &SystemCommand, data: &[u8], clock: &C, out: &mut Str) -> Result<(), &'static [u8]> {
+        match command.command {
+            CMD_TELEMETRY => {
+                out.append(&telemetry(clock));
+            },
+            CMD_FRAME => {
+                let first = match command.arg(0) {
+                    Some(first) if first >= 0 && (first as usize) < LEDS => first as usize,
+                    _ => {
+                        return Err(b"the first led is past the end of the wall");
+                    }
+                };
+                if data.len() % 3 != 0 || first + data.len() / 3 > LEDS {
+                    return Err(b"colors must be three bytes each and fit on the wall");
+                }
+
+                for (idx, color) in data.chunks(3).enumerate() {
+                    self.colors[first + idx] = rgb(color[0], color[1], color[2]);
+                }
+                if command.arg(1) == Some(1) {
+                    set_stream_frame(self.colors, clock.nanos());
+                }
+            },
+            CMD_FRAME_END => {
+                end_stream(clock.nanos());
+            },
+            _ => {
+                return console::execute(command, clock, out);
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Bytes in a telemetry block
+pub const TELEMETRY_SIZE: usize = 22;
+
+/// What the wall is doing, for programs to read:
+///
+/// | Offset | Size | Field                                     |
+/// |--------|------|-------------------------------------------|
+/// | 0      | 1    | version                                   |
+/// | 1      | 1    | power (0 or 1)                            |
+/// | 2      | 1    | brightness                                |
+/// | 3      | 1    | wifi state, 0xFF when wifi is disabled    |
+/// | 4      | 4    | temperature in hundredths of a degree C   |
+/// | 8      | 4    | uptime in seconds                         |
+/// | 12     | 4    | world time in epoch seconds               |
+/// | 16     | 2    | shader number, -1 if none                 |
+/// | 18     | 2    | effect number, -1 if none                 |
+/// | 20     | 1    | signal strength in dBm                    |
+/// | 21     | 1    | 1 while streamed frames are shown         |
+///
+/// Unknown temperatures are `UNSET_ARG` and an unknown signal
+/// strength is 0.
+pub fn telemetry<C: Clock>(clock: &C) -> [u8; TELEMETRY_SIZE] {
+    let status = get_status();
+    let mut result = [0; TELEMETRY_SIZE];
+    result[0] = TELEMETRY_VERSION;
+    result[1] = crate::get_power() as u8;
+    result[2] = crate::get_brightness();
+    result[3] = match status.wifi {
+        None => 0xFF,
+        Some(wifi) => wifi_state_number(wifi.state),
+    };
+    result[4 .. 8].copy_from_slice(&status.temperature.unwrap_or(UNSET_ARG).to_le_bytes());
+    result[8 .. 12].copy_from_slice(&((clock.nanos() / S_TO_NANO) as u32).to_le_bytes());
+    result[12 .. 16].copy_from_slice(&(crate::get_world_time(clock) as u32).to_le_bytes());
+
+    let shader = status.shader.and_then(|name| get_shader_names().into_iter().position(|other| other == name));
+    let effect = status.effect.and_then(|name| get_effect_names().into_iter().position(|other| other == name));
+    result[16 .. 18].copy_from_slice(&shader.map_or(-1, |idx| idx as i16).to_le_bytes());
+    result[18 .. 20].copy_from_slice(&effect.map_or(-1, |idx| idx as i16).to_le_bytes());
+
+    let rssi = status.wifi.and_then(|wifi| wifi.rssi).unwrap_or(0);
+    result[20] = rssi.clamp(i8::MIN as i32, 0) as i8 as u8;
+    let now = clock.nanos();
+    let streaming = get_stream_frame().map_or(false, |frame| !frame.terminated && now.saturating_sub(frame.received_at) < STREAM_TIMEOUT);
+    result[21] = streaming as u8;
+    return result;
+}
+
+fn wifi_state_number(state: WifiState) -> u8 {
+    return match state {
+        WifiState::Resetting => 0,
+        WifiState::Joining => 1,
+        WifiState::Connected => 2,
+        WifiState::GotIp => 3,
+        WifiState::Fetching => 4,
+        WifiState::Idle => 5,
+        WifiState::Failed => 6,
+    };
+}
+
+#[cfg(test)]
+pub mod test_protocol {
+    use super::*;
+    use std::prelude::rust_2021::*;
+    use crate::console::*;
+    use crate::hal::mock::*;
+    use crate::pixel_engine::golden::RENDER_LOCK;
+
+    /// A command frame the way a host sends it
+    pub fn command_frame(seq: u8, command: [u8; 4], args: &[i32], data: &[u8]) -> Vec<u8> {
+        let mut system_command = SystemCommand::with_command(command);
+        system_command.args[0 .. args.len()].copy_from_slice(args);
+        system_command.seal();
+
+        let mut payload = system_command.encode().to_vec();
+        payload.extend_from_slice(data);
+        return frame_bytes(KIND_COMMAND, seq, &payload);
+    }
+
+    /// The kind, sequence number and payload of every frame
+    pub fn read_frames(bytes: &[u8]) -> Vec<(u8, u8, Vec<u8>)> {
+        let mut reader = FrameReader::new();
+        let mut result = Vec::new();
+        for byte in bytes {
+            match reader.push(*byte, 0) {
+                ReadResult::Complete => {
+                    let frame = reader.frame();
+                    result.push((frame.kind, frame.seq, frame.payload.to_vec()));
+                    reader.reset();
+                },
+                ReadResult::Corrupt(..) => std::panic!("corrupt reply"),
+                ReadResult::Pending => {},
+            }
+        }
+        return result;
+    }
+
+    fn frame_bytes(kind: u8, seq: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Str::new();
+        write_frame(&mut frame, kind, seq, &[payload]);
+        let result = chars(&frame).collect();
+        frame.drop();
+        return result;
+    }
+
+    #[test]
+    fn test_frame_reader() {
+        let frame = command_frame(7, *b"STAT", &[], b"xyz");
+        let frames = read_frames(&frame);
+        assert_eq!(frames.len(), 1);
+        assert_eq!((frames[0].0, frames[0].1), (KIND_COMMAND, 7));
+        assert_eq!(&frames[0].2[COMMAND_SIZE ..], b"xyz");
+
+        let mut reader = FrameReader::new();
+        fn push_all(reader: &mut FrameReader, bytes: &[u8], now: uNano) -> ReadResult {
+            let mut last = ReadResult::Pending;
+            for byte in bytes {
+                last = reader.push(*byte, now);
+            }
+            return last;
+        }
+
+        // Damage is noticed, and the reader starts over
+        let mut damaged = frame.clone();
+        damaged[10] ^= 0x40;
+        assert!(matches!(push_all(&mut reader, &damaged, 0), ReadResult::Corrupt(7, NackReason::BadCrc)));
+        assert!(matches!(push_all(&mut reader, &frame, 0), ReadResult::Complete));
+        reader.reset();
+
+        let mut too_long = frame.clone();
+        too_long[5] = 0x10;
+        assert!(matches!(push_all(&mut reader, &too_long[0 .. HEADER_SIZE], 0), ReadResult::Corrupt(7, NackReason::TooLong)));
+        assert!(!reader.busy());
+
+        // A frame which stalls is dropped
+        push_all(&mut reader, &frame[0 .. 20], 0);
+        assert!(reader.busy());
+        assert!(matches!(push_all(&mut reader, &frame, FRAME_TIMEOUT + 1), ReadResult::Complete));
+        reader.reset();
+
+        // The second sync byte has to follow the first
+        assert!(matches!(push_all(&mut reader, &[SYNC[0], b'h'], 0), ReadResult::Pending));
+        assert!(!reader.busy());
+    }
+
+    #[test]
+    fn test_command_server() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let clock = MockClock::new();
+        let mut serial = MockSerial::new();
+        let handle = serial.clone();
+        let mut console = ConsoleTask::new(&mut serial, &clock);
+        let mut send = |frame: &[u8]| {
+            handle.receive(frame);
+            console.system_loop();
+            return read_frames(&handle.take_written_bytes());
+        };
+
+        // Commands from the console work the same
+        crate::set_brightness(10);
+        let replies = send(&command_frame(1, CMD_BRIGHTNESS, &[64], &[]));
+        assert_eq!(replies, vec![(KIND_ACK, 1, b"\x00brightness set\r\n".to_vec())]);
+        assert_eq!(crate::get_brightness(), 64);
+
+        // A retransmission gets the same answer without running again
+        crate::set_brightness(10);
+        assert_eq!(send(&command_frame(1, CMD_BRIGHTNESS, &[64], &[])), replies);
+        assert_eq!(crate::get_brightness(), 10);
+
+        let replies = send(&command_frame(2, CMD_BRIGHTNESS, &[256], &[]));
+        assert_eq!(replies, vec![(KIND_ACK, 2, b"\x01brightness must be 0 - 255".to_vec())]);
+
+        // Damaged frames and commands are turned away
+        let mut damaged = command_frame(3, CMD_BRIGHTNESS, &[64], &[]);
+        damaged[8] ^= 1;
+        assert_eq!(send(&damaged), vec![(KIND_NACK, 3, vec![NackReason::BadCrc as u8])]);
+        let unsealed = frame_bytes(KIND_COMMAND, 3, &SystemCommand::with_command(CMD_STATUS).encode());
+        assert_eq!(send(&unsealed), vec![(KIND_NACK, 3, vec![NackReason::BadChecksum as u8])]);
+        assert_eq!(send(&frame_bytes(KIND_COMMAND, 3, b"STAT")), vec![(KIND_NACK, 3, vec![NackReason::Malformed as u8])]);
+        assert_eq!(send(&frame_bytes(KIND_ACK, 3, &[])), vec![(KIND_NACK, 3, vec![NackReason::Malformed as u8])]);
+        assert_eq!(crate::get_brightness(), 10);
+
+        // Text and frames can be mixed
+        handle.receive(b"status\r");
+        let replies = send(&command_frame(3, CMD_TELEMETRY, &[], &[]));
+        assert_eq!(replies.len(), 1);
+        let telemetry = &replies[0].2[1 ..];
+        assert_eq!(telemetry.len(), TELEMETRY_SIZE);
+        assert_eq!(telemetry[0], TELEMETRY_VERSION);
+        assert_eq!(telemetry[2], 10);
+
+        // Frames are uploaded in pieces and shown at the end
+        clear_stream();
+        let colors: Vec<u8> = (0 .. LEDS * 3).map(|idx| idx as u8).collect();
+        send(&command_frame(4, CMD_FRAME, &[0, 0], &colors[0 .. 30]));
+        assert!(get_stream_frame().is_none());
+        let replies = send(&command_frame(5, CMD_FRAME, &[10, 1], &colors[30 ..]));
+        assert_eq!(replies[0].2, vec![STATUS_OK]);
+        let frame = get_stream_frame().unwrap();
+        assert_eq!(frame.colors[0].as_hex(), rgb(0, 1, 2).as_hex());
+        assert_eq!(frame.colors[LEDS - 1].as_hex(), rgb(colors[LEDS * 3 - 3], colors[LEDS * 3 - 2], colors[LEDS * 3 - 1]).as_hex());
+        assert_eq!(send(&command_frame(6, CMD_TELEMETRY, &[], &[]))[0].2[1 + 21], 1);
+        assert_eq!(send(&command_frame(7, CMD_FRAME, &[LEDS as i32 - 1, 1], &[1, 2, 3, 4, 5, 6])), vec![(KIND_ACK, 7, b"\x01colors must be three bytes each and fit on the wall".to_vec())]);
+        send(&command_frame(8, CMD_FRAME_END, &[], &[]));
+        assert!(get_stream_frame().unwrap().terminated);
+        clear_stream();
+
+        // Long answers are cut to fit in a frame
+        let mut help = MockSerial::new();
+        execute(&SystemCommand::with_command(CMD_HELP), &clock, &mut help).unwrap();
+        let mut expected = vec![STATUS_OK];
+        expected.extend_from_slice(&help.take_written_bytes()[0 .. MAX_PAYLOAD - 1]);
+        assert_eq!(send(&command_frame(9, CMD_HELP, &[], &[])), vec![(KIND_ACK, 9, expected)]);
+
+        crate::set_brightness(crate::DEFAULT_BRIGHTNESS);
+    }
+}
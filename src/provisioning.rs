@@ -0,0 +1,164 @@
+//! WiFi provisioning. When the wall does not know which
+//! network to join, the ESP8266 hosts an open access point
+//! and serves a small setup page. The page lists the networks
+//! found by a scan and saves whichever one is picked.
+
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+use crate::drivers::esp8266::parser::*;
+use crate::http::models::IncomingRequest;
+use crate::strings::*;
+
+/// The name of the access point hosted during setup.
+pub const SETUP_SSID: &[u8] = b"Hexwall-Setup";
+pub const SETUP_PORT: u32 = 80;
+pub const SETUP_CHANNEL: u8 = 5;
+
+// Keep the page under the 2048 byte CIPSEND limit
+const MAX_NETWORKS: usize = 16;
+const MAX_SSID_LENGTH: usize = 32;
+const MAX_PASSWORD_LENGTH: usize = 64;
+
+/// Extract the network names from the response to `AT+CWLAP`,
+/// strongest first. Hidden and duplicate networks are skipped.
+/// Don't forget to drop each entry and free the vector.
+pub fn parse_network_list(buf: &Str) -> Vector<Str> {
+    let mut result: Vector<Str> = Vector::new();
+    let mut access_points = parse_access_points(buf);
+
+    while result.size() < MAX_NETWORKS {
+        let mut strongest: Option<AccessPoint> = None;
+        for access_point in access_points.into_iter() {
+            let listed = result.into_iter().any(|ssid| ssid == access_point.ssid);
+            if access_point.ssid.len() == 0 || listed {
+                continue;
+            }
+
+            match strongest {
+                Some(other) if other.rssi >= access_point.rssi => {},
+                _ => strongest = Some(access_point),
+            }
+        }
+
+        match strongest {
+            None => break,
+            Some(access_point) => result.push(Str::from_str(&access_point.ssid)),
+        }
+    }
+
+    for mut access_point in access_points.into_iter() {
+        access_point.drop();
+    }
+    access_points.free();
+    return result;
+}
+
+/// Append content to the target, escaping anything that has
+/// special meaning in html.
+fn append_escaped(target: &mut Str, content: &Str) {
+    for char in chars(content) {
+        match char {
+            b'<' => target.append(b"&lt;"),
+            b'>' => target.append(b"&gt;"),
+            b'&' => target.append(b"&amp;"),
+            b'"' => target.append(b"&quot;"),
+            b'\'' => target.append(b"&#39;"),
+            _ => target.append(&[char]),
+        };
+    }
+}
+
+/// The setup page, with a form for picking a network.
+pub fn render_setup_page(networks: &Vector<Str>) -> Str {
+    let mut result = Str::new();
+    result.append(b"<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\"><title>Hexwall Setup</title></head><body>");
+    result.append(b"<h1>Hexwall Setup</h1><form method=\"POST\" action=\"/save\">");
+    result.append(b"<p><label>Network<br><input name=\"ssid\" list=\"networks\" maxlength=\"32\" required></label></p><datalist id=\"networks\">");
+
+    for network in networks.into_iter() {
+        result.append(b"<option value=\"");
+        append_escaped(&mut result, &network);
+        result.append(b"\">");
+    }
+
+    result.append(b"</datalist><p><label>Password<br><input name=\"password\" type=\"password\" maxlength=\"64\"></label></p>");
+    result.append(b"<p><button>Save</button></p></form><p><a href=\"/scan\">Scan again</a></p></body></html>");
+    return result;
+}
+
+/// The page shown once the credentials have been saved.
+pub fn render_saved_page(ssid: &Str) -> Str {
+    let mut result = Str::new();
+    result.append(b"<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\"><title>Hexwall Setup</title></head><body>");
+    result.append(b"<h1>Saved</h1><p>The wall will now join ");
+    append_escaped(&mut result, ssid);
+    result.append(b". This network is going away.</p></body></html>");
+    return result;
+}
+
+/// Read the chosen credentials out of a submitted setup form.
+/// Don't forget to drop both.
+pub fn parse_credentials(request: &IncomingRequest) -> Option<(Str, Str)> {
+    let ssid = request.param(b"ssid")?;
+    let pwd = request.param(b"password").unwrap_or(Str::new());
+
+    if ssid.len() == 0 || ssid.len() > MAX_SSID_LENGTH || pwd.len() > MAX_PASSWORD_LENGTH {
+        return None;
+    }
+
+    return Some((Str::from_str(&ssid), Str::from_str(&pwd)));
+}
+
+#[cfg(test)]
+pub mod test_provisioning {
+    use super::*;
+    use teensycore::*;
+    use crate::http::parser::parse_request;
+
+    fn parse(raw: &[u8]) -> IncomingRequest {
+        return parse_request(0, &Str::with_content(raw)).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_parse_network_list() {
+        let buf = str!(b"AT+CWLAP\r\r\n+CWLAP:(3,\"Home & Garden\",-52,\"aa:bb:cc:dd:ee:ff\",1,-22,0)\r\n+CWLAP:(0,\"\",-70,\"aa:bb:cc:dd:ee:00\",6,-22,0)\r\n+CWLAP:(4,\"Neighbor\",-80,\"aa:bb:cc:dd:ee:01\",11,-22,0)\r\n+CWLAP:(3,\"Home & Garden\",-60,\"aa:bb:cc:dd:ee:02\",6,-22,0)\r\n\r\nOK\r\n");
+        let networks = parse_network_list(&buf);
+        assert_eq!(networks.size(), 2);
+        assert!(networks.get(0).unwrap() == str!(b"Home & Garden"));
+        assert!(networks.get(1).unwrap() == str!(b"Neighbor"));
+    }
+
+    #[test]
+    fn test_parse_network_list_by_signal() {
+        let buf = str!(b"+CWLAP:(3,\"Far\",-85,\"aa:bb:cc:dd:ee:ff\",1,-22,0)\r\n+CWLAP:(3,\"Near\",-40,\"aa:bb:cc:dd:ee:00\",6,-22,0)\r\n+CWLAP:(3,\"Middle\",-60,\"aa:bb:cc:dd:ee:01\",11,-22,0)\r\n\r\nOK\r\n");
+        let networks = parse_network_list(&buf);
+        assert_eq!(networks.size(), 3);
+        assert!(networks.get(0).unwrap() == str!(b"Near"));
+        assert!(networks.get(1).unwrap() == str!(b"Middle"));
+        assert!(networks.get(2).unwrap() == str!(b"Far"));
+    }
+
+    #[test]
+    fn test_render_setup_page() {
+        let mut networks = Vector::new();
+        networks.push(str!(b"<Evil \"Net\">"));
+        let page = render_setup_page(&networks);
+        assert!(find(&page, b"<option value=\"&lt;Evil &quot;Net&quot;&gt;\">", 0).is_some());
+        assert!(page.len() < 1024);
+
+    }
+
+    #[test]
+    fn test_parse_credentials() {
+        let request = parse(b"POST /save HTTP/1.1\r\nHost: 192.168.4.1\r\nContent-Length: 33\r\n\r\nssid=My+Net%21&password=p%26ss%3D");
+        let (ssid, pwd) = parse_credentials(&request).unwrap();
+        assert!(ssid == str!(b"My Net!"));
+        assert!(pwd == str!(b"p&ss="));
+
+        let get = parse(b"GET / HTTP/1.1\r\n\r\n");
+        assert!(parse_credentials(&get).is_none());
+
+        let empty = parse(b"POST /save HTTP/1.1\r\nContent-Length: 14\r\n\r\nssid=&password");
+        assert!(parse_credentials(&empty).is_none());
+    }
+}
@@ -0,0 +1,1110 @@
+//! Persistent settings.
+//!
+//! Settings are kept in flash as a log of records. Each record
+//! is a complete snapshot of every setting, encoded as a list of
+//! key/value entries, and protected by a CRC. New records are
+//! appended after the previous one. When a sector fills up, the
+//! next sector in line is erased and the log continues there, so
+//! every sector wears at the same rate.
+//!
+//! On boot, the newest record with a valid checksum wins. If a
+//! record is damaged (for example power was lost halfway through
+//! writing it) the previous one is used instead, and if nothing
+//! can be read the compiled-in defaults stay in place.
+//!
+//! Record layout:
+//!
+//! | Offset | Size | Field                                 |
+//! |--------|------|---------------------------------------|
+//! | 0      | 2    | magic                                 |
+//! | 2      | 1    | format version                        |
+//! | 3      | 1    | reserved (0xFF)                       |
+//! | 4      | 4    | sequence number                       |
+//! | 8      | 2    | payload length                        |
+//! | 10     | 2    | reserved (0xFFFF)                     |
+//! | 12     | 4    | crc32 of bytes 0-11 and the payload   |
+//! | 16     | n    | payload                               |
+//!
+//! Each payload entry is a one byte key, a two byte length and
+//! then the value. Unknown keys are skipped and missing keys keep
+//! their default, so adding a setting does not need a new format
+//! version. The most recently saved wifi network has keys of its
+//! own, which is all older firmware knows about, and any others
+//! follow in a list.
+
+use teensycore::*;
+use teensycore::clock::uNano;
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+use crate::*;
+use crate::crc::*;
+use crate::dmx::*;
+use crate::hal::*;
+use crate::network::*;
+use crate::pixel_engine::shader_config::*;
+use crate::thermal::ThermalConfig;
+use crate::strings::chars;
+
+/// The newest record format this firmware understands.
+pub const SETTINGS_VERSION: u8 = 1;
+
+const RECORD_MAGIC: u16 = 0x5348;
+const HEADER_SIZE: usize = 16;
+pub const MAX_RECORD_SIZE: usize = 4096;
+// Records are checked a piece at a time, so only the record
+// being loaded or saved needs a full size buffer.
+const CHUNK_SIZE: usize = 64;
+
+// How long the settings must stay unchanged before they are
+// written. This keeps a burst of changes from wearing the flash.
+const SAVE_DELAY: uNano = 5 * S_TO_NANO;
+const RETRY_DELAY: uNano = 60 * S_TO_NANO;
+
+const KEY_UTC_OFFSET: u8 = 1;
+const KEY_TRANSITION_DELAY: u8 = 2;
+const KEY_BRIGHTNESS: u8 = 3;
+const KEY_WIFI_SSID: u8 = 4;
+const KEY_WIFI_PASSWORD: u8 = 5;
+const KEY_SHADER_CONFIGS: u8 = 6;
+const KEY_CONFIG_URL: u8 = 7;
+const KEY_WIFI_NETWORKS: u8 = 8;
+const KEY_NETWORK_CONFIG: u8 = 9;
+const KEY_MQTT_URL: u8 = 10;
+const KEY_STREAM_CONFIG: u8 = 11;
+const KEY_THERMAL_CONFIG: u8 = 12;
+
+/// A snapshot of everything which survives a power cycle.
+pub struct Settings {
+    pub utc_offset: uNano,
+    pub transition_delay: uNano,
+    pub brightness: u8,
+    /// (ssid, password), the most recently saved first
+    pub wifi_networks: Vector<(Str, Str)>,
+    pub config_url: Option<Str>,
+    pub network_config: NetworkConfig,
+    pub mqtt_url: Option<Str>,
+    pub stream_config: Option<StreamConfig>,
+    pub thermal_config: ThermalConfig,
+    pub shader_configs: ShaderConfigList,
+}
+
+impl Settings {
+    pub fn defaults() -> Self {
+        return Settings {
+            utc_offset: DEFAULT_UTC_OFFSET,
+            transition_delay: DEFAULT_TRANSITION_DELAY_NANOS,
+            brightness: DEFAULT_BRIGHTNESS,
+            wifi_networks: Vector::new(),
+            config_url: None,
+            network_config: NetworkConfig::Dhcp,
+            mqtt_url: None,
+            stream_config: None,
+            thermal_config: ThermalConfig::default(),
+            shader_configs: ShaderConfigList::new(),
+        };
+    }
+
+    /// Take a snapshot of the settings currently in use.
+    pub fn current() -> Self {
+        return Settings {
+            utc_offset: get_utc_offset(),
+            transition_delay: get_tranasition_delay(),
+            brightness: get_brightness(),
+            wifi_networks: {
+                let mut networks = Vector::new();
+                for (ssid, pwd) in get_wifi_networks().into_iter() {
+                    networks.push((Str::from_str(&ssid), Str::from_str(&pwd)));
+                }
+                networks
+            },
+            config_url: get_config_url().map(|url| Str::from_str(&url)),
+            network_config: get_network_config(),
+            mqtt_url: get_mqtt_url().map(|url| Str::from_str(&url)),
+            stream_config: get_stream_config(),
+            thermal_config: get_thermal_config(),
+            shader_configs: ShaderConfigList {
+                configs: get_shader_configs().configs.clone(),
+            },
+        };
+    }
+
+    /// Make these the settings currently in use.
+    pub fn apply(&self) {
+        set_utc_offset(self.utc_offset);
+        set_transition_delay(self.transition_delay);
+        set_brightness(self.brightness);
+
+        // Saved oldest first, so the order is kept
+        clear_wifi_credentials();
+        let mut oldest_first = self.wifi_networks.reverse();
+        for (ssid, pwd) in oldest_first.into_iter() {
+            set_wifi_credentials(&ssid, &pwd);
+        }
+        oldest_first.free();
+
+        match self.config_url {
+            None => clear_config_url(),
+            Some(url) => set_config_url(&url),
+        }
+        set_network_config(self.network_config);
+        match self.mqtt_url {
+            None => clear_mqtt_url(),
+            Some(url) => set_mqtt_url(&url),
+        }
+        set_stream_config(self.stream_config);
+        set_thermal_config(self.thermal_config);
+
+        if self.shader_configs.size() > 0 {
+            set_shader_configs(self.shader_configs);
+        }
+    }
+
+    /// Release the memory held by this snapshot. The shader
+    /// names are shared with whoever the configs were applied
+    /// to, so they are left alone.
+    pub fn drop(&mut self) {
+        for (mut ssid, mut pwd) in self.wifi_networks.into_iter() {
+            ssid.drop();
+            pwd.drop();
+        }
+        self.wifi_networks.free();
+        match self.config_url.as_mut() {
+            None => {},
+            Some(url) => url.drop(),
+        }
+        self.config_url = None;
+        match self.mqtt_url.as_mut() {
+            None => {},
+            Some(url) => url.drop(),
+        }
+        self.mqtt_url = None;
+        self.shader_configs.configs.free();
+    }
+
+    /// Encode the settings into `buf` and return how many bytes
+    /// were used. Shader configs are left out if they do not
+    /// fit, since they can always be downloaded again.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut writer = RecordWriter::new(buf);
+
+        let mark = writer.begin_entry(KEY_UTC_OFFSET);
+        writer.put_u64(self.utc_offset as u64);
+        writer.end_entry(mark);
+
+        let mark = writer.begin_entry(KEY_TRANSITION_DELAY);
+        writer.put_u64(self.transition_delay as u64);
+        writer.end_entry(mark);
+
+        let mark = writer.begin_entry(KEY_BRIGHTNESS);
+        writer.put_u8(self.brightness);
+        writer.end_entry(mark);
+
+        match self.wifi_networks.get(0) {
+            None => {},
+            Some((ssid, pwd)) => {
+                let mark = writer.begin_entry(KEY_WIFI_SSID);
+                writer.put_str(&ssid);
+                writer.end_entry(mark);
+
+                let mark = writer.begin_entry(KEY_WIFI_PASSWORD);
+                writer.put_str(&pwd);
+                writer.end_entry(mark);
+            }
+        }
+
+        if self.wifi_networks.size() > 1 {
+            let mark = writer.begin_entry(KEY_WIFI_NETWORKS);
+            writer.put_u8((self.wifi_networks.size() - 1) as u8);
+            for (ssid, pwd) in self.wifi_networks.into_iter().skip(1) {
+                writer.put_u8(ssid.len() as u8);
+                writer.put_str(&ssid);
+                writer.put_u8(pwd.len() as u8);
+                writer.put_str(&pwd);
+            }
+            writer.end_entry(mark);
+        }
+
+        match self.config_url {
+            None => {},
+            Some(url) => {
+                let mark = writer.begin_entry(KEY_CONFIG_URL);
+                writer.put_str(&url);
+                writer.end_entry(mark);
+            }
+        }
+
+        // Dhcp is the default, so it needs no entry
+        match self.network_config {
+            NetworkConfig::Dhcp => {},
+            NetworkConfig::Static { ip, gateway, netmask, dns } => {
+                let mark = writer.begin_entry(KEY_NETWORK_CONFIG);
+                writer.put_bytes(&ip.0);
+                writer.put_bytes(&gateway.0);
+                writer.put_bytes(&netmask.0);
+                match dns {
+                    None => {},
+                    Some(dns) => writer.put_bytes(&dns.0),
+                }
+                writer.end_entry(mark);
+            }
+        }
+
+        match self.mqtt_url {
+            None => {},
+            Some(url) => {
+                let mark = writer.begin_entry(KEY_MQTT_URL);
+                writer.put_str(&url);
+                writer.end_entry(mark);
+            }
+        }
+
+        match self.stream_config {
+            None => {},
+            Some(config) => {
+                let mark = writer.begin_entry(KEY_STREAM_CONFIG);
+                writer.put_u16(config.universe);
+                writer.put_u16(config.start_channel);
+                writer.put_u8(match config.mapping {
+                    DmxMapping::Units => 0,
+                    DmxMapping::Leds => 1,
+                });
+                writer.put_u8(match config.protocol {
+                    StreamProtocol::Dmx => 0,
+                    StreamProtocol::Ddp => 1,
+                });
+                writer.end_entry(mark);
+            }
+        }
+
+        let mark = writer.begin_entry(KEY_THERMAL_CONFIG);
+        writer.put_u16(self.thermal_config.warning as u16);
+        writer.put_u16(self.thermal_config.critical as u16);
+        writer.put_u16(self.thermal_config.hysteresis as u16);
+        writer.end_entry(mark);
+
+        if self.shader_configs.size() > 0 {
+            let mark = writer.begin_entry(KEY_SHADER_CONFIGS);
+            writer.put_u16(self.shader_configs.size() as u16);
+            for config in self.shader_configs.configs.into_iter() {
+                writer.put_u64(config.time_range_start as u64);
+                writer.put_u64(config.time_range_end as u64);
+                writer.put_u8(config.probability as u8);
+                writer.put_u8(config.shader.len() as u8);
+                writer.put_str(&config.shader);
+            }
+            writer.end_entry(mark);
+        }
+
+        return writer.len;
+    }
+
+    /// Decode a payload produced by `encode`. Anything missing
+    /// or malformed keeps its default value.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let mut result = Settings::defaults();
+        let mut reader = RecordReader::new(bytes);
+        let mut ssid = None;
+        let mut pwd = None;
+        let mut others = Vector::new();
+
+        loop {
+            let (key, value) = match (reader.u8(), reader.u16()) {
+                (Some(key), Some(len)) => match reader.bytes(len as usize) {
+                    Some(value) => (key, value),
+                    None => break,
+                },
+                _ => break,
+            };
+
+            let mut entry = RecordReader::new(value);
+            match key {
+                KEY_UTC_OFFSET => {
+                    result.utc_offset = entry.u64().map_or(result.utc_offset, |val| val as uNano);
+                },
+                KEY_TRANSITION_DELAY => {
+                    result.transition_delay = entry.u64().map_or(result.transition_delay, |val| val as uNano);
+                },
+                KEY_BRIGHTNESS => {
+                    result.brightness = entry.u8().unwrap_or(result.brightness);
+                },
+                KEY_WIFI_SSID => {
+                    ssid = Some(value);
+                },
+                KEY_WIFI_PASSWORD => {
+                    pwd = Some(value);
+                },
+                KEY_CONFIG_URL => {
+                    result.config_url = Some(Str::with_content(value));
+                },
+                KEY_MQTT_URL => {
+                    result.mqtt_url = Some(Str::with_content(value));
+                },
+                KEY_WIFI_NETWORKS => {
+                    let count = entry.u8().unwrap_or(0);
+                    for _ in 0 .. count {
+                        let other_ssid = entry.u8().and_then(|len| entry.bytes(len as usize));
+                        let other_pwd = entry.u8().and_then(|len| entry.bytes(len as usize));
+                        match (other_ssid, other_pwd) {
+                            (Some(other_ssid), Some(other_pwd)) => others.push((other_ssid, other_pwd)),
+                            _ => break,
+                        }
+                    }
+                },
+                KEY_NETWORK_CONFIG => {
+                    match (entry.ipv4(), entry.ipv4(), entry.ipv4()) {
+                        (Some(ip), Some(gateway), Some(netmask)) => {
+                            result.network_config = NetworkConfig::Static {
+                                ip: ip,
+                                gateway: gateway,
+                                netmask: netmask,
+                                dns: entry.ipv4(),
+                            };
+                        },
+                        _ => {},
+                    }
+                },
+                KEY_STREAM_CONFIG => {
+                    let universe = entry.u16();
+                    let start_channel = entry.u16();
+                    let mapping = match entry.u8() {
+                        Some(0) => Some(DmxMapping::Units),
+                        Some(1) => Some(DmxMapping::Leds),
+                        _ => None,
+                    };
+                    // Added after the others, so it may be missing
+                    let protocol = match entry.u8() {
+                        None | Some(0) => Some(StreamProtocol::Dmx),
+                        Some(1) => Some(StreamProtocol::Ddp),
+                        _ => None,
+                    };
+                    match (universe, start_channel, mapping, protocol) {
+                        (Some(universe), Some(start_channel), Some(mapping), Some(protocol)) => {
+                            let config = StreamConfig {
+                                protocol: protocol,
+                                universe: universe,
+                                start_channel: start_channel,
+                                mapping: mapping,
+                            };
+                            if config.is_valid() {
+                                result.stream_config = Some(config);
+                            }
+                        },
+                        _ => {},
+                    }
+                },
+                KEY_THERMAL_CONFIG => {
+                    match (entry.u16(), entry.u16(), entry.u16()) {
+                        (Some(warning), Some(critical), Some(hysteresis)) => {
+                            let config = ThermalConfig {
+                                warning: warning as i32,
+                                critical: critical as i32,
+                                hysteresis: hysteresis as i32,
+                            };
+                            if config.is_valid() {
+                                result.thermal_config = config;
+                            }
+                        },
+                        _ => {},
+                    }
+                },
+                KEY_SHADER_CONFIGS => {
+                    let count = entry.u16().unwrap_or(0);
+                    for _ in 0 .. count {
+                        match (entry.u64(), entry.u64(), entry.u8(), entry.u8()) {
+                            (Some(start), Some(end), Some(probability), Some(name_len)) => {
+                                match entry.bytes(name_len as usize) {
+                                    None => break,
+                                    Some(name) => {
+                                        result.shader_configs.add_config(ShaderConfig {
+                                            time_range_start: start as uNano,
+                                            time_range_end: end as uNano,
+                                            shader: Str::with_content(name),
+                                            probability: probability as u64,
+                                        });
+                                    }
+                                }
+                            },
+                            _ => break,
+                        }
+                    }
+                },
+                _ => {
+                    // Written by a newer firmware, skip it.
+                }
+            }
+        }
+
+        match (ssid, pwd) {
+            (Some(ssid), Some(pwd)) => {
+                result.wifi_networks.push((Str::with_content(ssid), Str::with_content(pwd)));
+                for (other_ssid, other_pwd) in others.into_iter() {
+                    if result.wifi_networks.size() < MAX_WIFI_NETWORKS {
+                        result.wifi_networks.push((Str::with_content(other_ssid), Str::with_content(other_pwd)));
+                    }
+                }
+            },
+            _ => {},
+        }
+        others.free();
+
+        return result;
+    }
+}
+
+/// Appends little-endian values to a buffer, refusing to go
+/// past the end of it.
+struct RecordWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'a> RecordWriter<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        return RecordWriter {
+            buf: buf,
+            len: 0,
+            overflow: false,
+        };
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        if self.len + bytes.len() > self.buf.len() {
+            self.overflow = true;
+            return;
+        }
+        self.buf[self.len .. self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn put_u8(&mut self, val: u8) {
+        self.put_bytes(&[val]);
+    }
+
+    fn put_u16(&mut self, val: u16) {
+        self.put_bytes(&val.to_le_bytes());
+    }
+
+    fn put_u64(&mut self, val: u64) {
+        self.put_bytes(&val.to_le_bytes());
+    }
+
+    fn put_str(&mut self, val: &Str) {
+        for byte in chars(val) {
+            self.put_u8(byte);
+        }
+    }
+
+    /// Start a new entry. The length is filled in by `end_entry`.
+    fn begin_entry(&mut self, key: u8) -> usize {
+        let mark = self.len;
+        self.overflow = false;
+        self.put_u8(key);
+        self.put_u16(0);
+        return mark;
+    }
+
+    /// Finish an entry, or remove it entirely if it did not fit.
+    fn end_entry(&mut self, mark: usize) {
+        if self.overflow {
+            self.len = mark;
+            self.overflow = false;
+            debug_str(b"setting did not fit and was not saved");
+            return;
+        }
+
+        let value_len = (self.len - mark - 3) as u16;
+        self.buf[mark + 1 .. mark + 3].copy_from_slice(&value_len.to_le_bytes());
+    }
+}
+
+/// Reads little-endian values from a buffer.
+struct RecordReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        return RecordReader {
+            bytes: bytes,
+            pos: 0,
+        };
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.pos + len > self.bytes.len() {
+            return None;
+        }
+        let result = &self.bytes[self.pos .. self.pos + len];
+        self.pos += len;
+        return Some(result);
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        return self.bytes(1).map(|bytes| bytes[0]);
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        return self.bytes(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    fn ipv4(&mut self) -> Option<Ipv4> {
+        return self.bytes(4).map(|bytes| Ipv4([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        return self.bytes(8).map(|bytes| {
+            let mut val = [0u8; 8];
+            val.copy_from_slice(bytes);
+            return u64::from_le_bytes(val);
+        });
+    }
+}
+
+#[derive(Copy, Clone)]
+struct RecordHeader {
+    version: u8,
+    sequence: u32,
+    length: usize,
+    crc: u32,
+}
+
+impl RecordHeader {
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0 .. 2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        buf[2] = self.version;
+        buf[3] = 0xFF;
+        buf[4 .. 8].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[8 .. 10].copy_from_slice(&(self.length as u16).to_le_bytes());
+        buf[10] = 0xFF;
+        buf[11] = 0xFF;
+        buf[12 .. 16].copy_from_slice(&self.crc.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if u16::from_le_bytes([buf[0], buf[1]]) != RECORD_MAGIC {
+            return None;
+        }
+
+        return Some(RecordHeader {
+            version: buf[2],
+            sequence: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            length: u16::from_le_bytes([buf[8], buf[9]]) as usize,
+            crc: u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]),
+        });
+    }
+}
+
+fn record_crc(header: &[u8], payload: &[u8]) -> u32 {
+    return !crc32_update(crc32_update(0xFFFF_FFFF, &header[0 .. 12]), payload);
+}
+
+/// The crc of a record which is still in storage.
+fn stored_record_crc<S: Storage>(storage: &mut S, address: usize, header: &[u8], length: usize) -> u32 {
+    let mut crc = crc32_update(0xFFFF_FFFF, &header[0 .. 12]);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut done = 0;
+    while done < length {
+        let count = CHUNK_SIZE.min(length - done);
+        storage.read(address + done, &mut chunk[0 .. count]);
+        crc = crc32_update(crc, &chunk[0 .. count]);
+        done += count;
+    }
+    return !crc;
+}
+
+/// Whether storage holds exactly `bytes` at `address`.
+fn stored_equals<S: Storage>(storage: &mut S, address: usize, bytes: &[u8]) -> bool {
+    let mut chunk = [0u8; CHUNK_SIZE];
+    for (idx, expected) in bytes.chunks(CHUNK_SIZE).enumerate() {
+        let actual = &mut chunk[0 .. expected.len()];
+        storage.read(address + idx * CHUNK_SIZE, actual);
+        if actual != expected {
+            return false;
+        }
+    }
+    return true;
+}
+
+// Records start on a word boundary
+fn record_size(length: usize) -> usize {
+    return (HEADER_SIZE + length + 3) & !3;
+}
+
+/// Reads and writes settings records on a storage device.
+pub struct SettingsStore<S: Storage> {
+    storage: S,
+    sequence: u32,
+    sector: usize,
+    offset: usize,
+    // The record being loaded or saved. The stack is too small
+    // for it, so the caller hands over a buffer which lives for
+    // good.
+    record: &'static mut [u8; MAX_RECORD_SIZE],
+}
+
+impl<S: Storage> SettingsStore<S> {
+    pub fn new(storage: S, record: &'static mut [u8; MAX_RECORD_SIZE]) -> Self {
+        return SettingsStore {
+            storage: storage,
+            sequence: 0,
+            sector: 0,
+            offset: 0,
+            record: record,
+        };
+    }
+
+    fn capacity(&self) -> usize {
+        return self.storage.sector_size().min(MAX_RECORD_SIZE);
+    }
+
+    /// Walk the records in a sector. Calls `visit` with the
+    /// header and location of every intact record, and returns
+    /// the offset at which free space begins.
+    fn scan_sector<F: FnMut(RecordHeader, usize)>(&mut self, sector: usize, mut visit: F) -> usize {
+        let sector_size = self.storage.sector_size();
+        let base = sector * sector_size;
+        let mut offset = 0;
+        let mut header_bytes = [0u8; HEADER_SIZE];
+
+        while offset + HEADER_SIZE <= sector_size {
+            self.storage.read(base + offset, &mut header_bytes);
+
+            // Erased flash means the end of the log
+            if header_bytes.iter().all(|byte| *byte == 0xFF) {
+                return offset;
+            }
+
+            let header = match RecordHeader::decode(&header_bytes) {
+                Some(header) if offset + record_size(header.length) <= sector_size && header.length <= self.capacity() - HEADER_SIZE => header,
+                // Garbage, nothing after this can be trusted
+                _ => return sector_size,
+            };
+
+            if stored_record_crc(&mut self.storage, base + offset + HEADER_SIZE, &header_bytes, header.length) == header.crc {
+                visit(header, offset);
+            }
+
+            offset += record_size(header.length);
+        }
+
+        return sector_size;
+    }
+
+    /// Find the newest readable record and return the settings
+    /// stored in it. This also works out where the next record
+    /// will be written, so it should be called before `save`.
+    pub fn load(&mut self) -> Option<Settings> {
+        let mut newest: Option<(RecordHeader, usize, usize)> = None;
+        let mut free_offsets = [0usize; 64];
+        let sectors = self.storage.sectors().min(free_offsets.len());
+
+        for sector in 0 .. sectors {
+            free_offsets[sector] = self.scan_sector(sector, |header, offset| {
+                if header.version > SETTINGS_VERSION {
+                    // Written by a newer firmware, which may have
+                    // changed what the entries mean.
+                    return;
+                }
+
+                let is_newer = match newest {
+                    None => true,
+                    Some((best, _, _)) => header.sequence > best.sequence,
+                };
+
+                if is_newer {
+                    newest = Some((header, sector, offset));
+                }
+            });
+        }
+
+        match newest {
+            None => {
+                self.sequence = 0;
+                self.sector = 0;
+                self.offset = free_offsets[0];
+                debug_str(b"no stored settings, using defaults");
+                return None;
+            },
+            Some((header, sector, offset)) => {
+                self.sequence = header.sequence;
+                self.sector = sector;
+                self.offset = free_offsets[sector];
+
+                let payload = &mut self.record[0 .. header.length];
+                self.storage.read(sector * self.storage.sector_size() + offset + HEADER_SIZE, payload);
+                debug_u64(header.sequence as u64, b"restored settings record");
+                return Some(Settings::decode(payload));
+            }
+        }
+    }
+
+    /// Append a new record. Returns false if it could not be
+    /// written anywhere.
+    pub fn save(&mut self, settings: &Settings) -> bool {
+        let sector_size = self.storage.sector_size();
+        let capacity = self.capacity();
+        let record = &mut self.record;
+        record.fill(0xFF);
+
+        let length = settings.encode(&mut record[HEADER_SIZE .. capacity]);
+        let mut header = RecordHeader {
+            version: SETTINGS_VERSION,
+            sequence: self.sequence.wrapping_add(1),
+            length: length,
+            crc: 0,
+        };
+        header.encode(&mut record[0 .. HEADER_SIZE]);
+        header.crc = record_crc(&record[0 .. HEADER_SIZE], &record[HEADER_SIZE .. HEADER_SIZE + length]);
+        header.encode(&mut record[0 .. HEADER_SIZE]);
+
+        let size = record_size(length);
+
+        // Every sector gets one chance before giving up
+        for _ in 0 .. self.storage.sectors() {
+            if self.offset + size > sector_size {
+                self.sector = (self.sector + 1) % self.storage.sectors();
+                self.offset = 0;
+                if !self.storage.erase(self.sector) {
+                    self.offset = sector_size;
+                    continue;
+                }
+            }
+
+            let address = self.sector * sector_size + self.offset;
+            let record = &self.record[0 .. size];
+            if self.storage.write(address, record) && stored_equals(&mut self.storage, address, record) {
+                self.offset += size;
+                self.sequence = header.sequence;
+                return true;
+            }
+
+            // Don't write after a damaged record
+            debug_u64(self.sector as u64, b"settings write failed in sector");
+            self.offset = sector_size;
+        }
+
+        return false;
+    }
+}
+
+/// Restores settings at boot and saves them whenever they
+/// change.
+pub struct SettingsTask<S: Storage, C: Clock> {
+    store: SettingsStore<S>,
+    clock: C,
+    saved_generation: u32,
+    pending_generation: u32,
+    save_target: uNano,
+}
+
+impl<S: Storage, C: Clock> SettingsTask<S, C> {
+    pub fn new(store: SettingsStore<S>, clock: C) -> Self {
+        return SettingsTask {
+            store: store,
+            clock: clock,
+            saved_generation: 0,
+            pending_generation: 0,
+            save_target: 0,
+        };
+    }
+
+    /// Restore the last saved settings. This should run before
+    /// any other task is initialized.
+    pub fn init(&mut self) {
+        match self.store.load() {
+            None => {},
+            Some(mut settings) => {
+                settings.apply();
+                settings.drop();
+            }
+        }
+
+        self.saved_generation = get_settings_generation();
+        self.pending_generation = self.saved_generation;
+    }
+
+    pub fn system_loop(&mut self) {
+        let generation = get_settings_generation();
+        if generation == self.saved_generation {
+            return;
+        }
+
+        let now = self.clock.nanos();
+        if generation != self.pending_generation {
+            // Wait for things to settle down
+            self.pending_generation = generation;
+            self.save_target = now + SAVE_DELAY;
+        } else if now > self.save_target {
+            let mut settings = Settings::current();
+            if self.store.save(&settings) {
+                self.saved_generation = generation;
+            } else {
+                self.save_target = now + RETRY_DELAY;
+            }
+            settings.drop();
+        }
+    }
+
+    /// Save any changes right away, like before a reboot.
+    pub fn flush(&mut self) {
+        let generation = get_settings_generation();
+        if generation == self.saved_generation {
+            return;
+        }
+
+        let mut settings = Settings::current();
+        if self.store.save(&settings) {
+            self.saved_generation = generation;
+            self.pending_generation = generation;
+        }
+        settings.drop();
+    }
+}
+
+#[cfg(test)]
+pub mod test_settings {
+    use super::*;
+    use std::prelude::rust_2021::*;
+    use crate::hal::mock::*;
+
+    const SECTOR_SIZE: usize = 512;
+    const SECTORS: usize = 4;
+
+    // Every store keeps its own buffer, so tests can run side by side
+    fn record_buffer() -> &'static mut [u8; MAX_RECORD_SIZE] {
+        return Box::leak(Box::new([0u8; MAX_RECORD_SIZE]));
+    }
+
+    fn example(brightness: u8) -> Settings {
+        let mut result = Settings::defaults();
+        result.utc_offset = 7;
+        result.transition_delay = 42 * S_TO_NANO;
+        result.brightness = brightness;
+        result.wifi_networks.push((str!(b"Hexnet"), str!(b"hunter2")));
+        result.wifi_networks.push((str!(b"Office"), str!(b"s3cret")));
+        result.config_url = Some(str!(b"http://hexwall.example/config"));
+        result.network_config = NetworkConfig::Static {
+            ip: Ipv4([192, 168, 1, 50]),
+            gateway: Ipv4([192, 168, 1, 1]),
+            netmask: Ipv4([255, 255, 255, 0]),
+            dns: Some(Ipv4([1, 1, 1, 1])),
+        };
+        result.mqtt_url = Some(str!(b"mqtt://wall:pw@broker.local"));
+        result.stream_config = Some(StreamConfig {
+            protocol: StreamProtocol::Ddp,
+            universe: 2,
+            start_channel: 301,
+            mapping: DmxMapping::Units,
+        });
+        result.thermal_config = ThermalConfig {
+            warning: 4000,
+            critical: 5500,
+            hysteresis: 250,
+        };
+        result.shader_configs.add_config(ShaderConfig {
+            time_range_start: 1641024000,
+            time_range_end: 7952371200,
+            shader: str!(b"Starfleet"),
+            probability: 6,
+        });
+        result.shader_configs.add_config(ShaderConfig {
+            time_range_start: 1649314800,
+            time_range_end: 1649397600,
+            shader: str!(b"Birthday"),
+            probability: 255,
+        });
+        return result;
+    }
+
+    fn assert_example(settings: &Settings, brightness: u8) {
+        assert_eq!(settings.utc_offset, 7);
+        assert_eq!(settings.transition_delay, 42 * S_TO_NANO);
+        assert_eq!(settings.brightness, brightness);
+
+        assert_eq!(settings.wifi_networks.size(), 2);
+        let (ssid, pwd) = settings.wifi_networks.get(0).unwrap();
+        assert!(ssid == str!(b"Hexnet"));
+        assert!(pwd == str!(b"hunter2"));
+        let (ssid, pwd) = settings.wifi_networks.get(1).unwrap();
+        assert!(ssid == str!(b"Office"));
+        assert!(pwd == str!(b"s3cret"));
+        assert!(settings.config_url.unwrap() == str!(b"http://hexwall.example/config"));
+        assert_eq!(settings.network_config, NetworkConfig::Static {
+            ip: Ipv4([192, 168, 1, 50]),
+            gateway: Ipv4([192, 168, 1, 1]),
+            netmask: Ipv4([255, 255, 255, 0]),
+            dns: Some(Ipv4([1, 1, 1, 1])),
+        });
+        assert!(settings.mqtt_url.unwrap() == str!(b"mqtt://wall:pw@broker.local"));
+        assert_eq!(settings.stream_config, Some(StreamConfig {
+            protocol: StreamProtocol::Ddp,
+            universe: 2,
+            start_channel: 301,
+            mapping: DmxMapping::Units,
+        }));
+        assert_eq!(settings.thermal_config, ThermalConfig {
+            warning: 4000,
+            critical: 5500,
+            hysteresis: 250,
+        });
+
+        assert_eq!(settings.shader_configs.size(), 2);
+        let birthday = settings.shader_configs.configs.get(1).unwrap();
+        assert_eq!(birthday.time_range_start, 1649314800);
+        assert_eq!(birthday.time_range_end, 1649397600);
+        assert_eq!(birthday.probability, 255);
+        assert!(birthday.shader == str!(b"Birthday"));
+    }
+
+    #[test]
+    fn test_settings_encoding() {
+        let mut buf = [0u8; 256];
+        let len = example(128).encode(&mut buf);
+        assert_example(&Settings::decode(&buf[0 .. len]), 128);
+
+        // Unknown keys are skipped
+        let mut extended = [0u8; 256];
+        extended[0 .. 5].copy_from_slice(&[200, 2, 0, 0xAB, 0xCD]);
+        extended[5 .. 5 + len].copy_from_slice(&buf[0 .. len]);
+        assert_example(&Settings::decode(&extended[0 .. len + 5]), 128);
+
+        // Truncated payloads keep whatever was read
+        let settings = Settings::decode(&buf[0 .. 12]);
+        assert_eq!(settings.utc_offset, 7);
+        assert_eq!(settings.transition_delay, DEFAULT_TRANSITION_DELAY_NANOS);
+    }
+
+    #[test]
+    fn test_settings_skip_what_does_not_fit() {
+        let mut buf = [0u8; 64];
+        let len = example(1).encode(&mut buf);
+        let settings = Settings::decode(&buf[0 .. len]);
+        assert_eq!(settings.brightness, 1);
+        assert_eq!(settings.wifi_networks.size(), 2);
+        assert!(settings.config_url.is_none());
+        assert_eq!(settings.network_config, NetworkConfig::Dhcp);
+        assert!(settings.mqtt_url.is_none());
+        assert_eq!(settings.shader_configs.size(), 0);
+    }
+
+    #[test]
+    fn test_settings_store_round_trip() {
+        let flash = MockFlash::new(SECTOR_SIZE, SECTORS);
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert!(store.load().is_none());
+        assert!(store.save(&example(10)));
+        assert!(store.save(&example(20)));
+
+        // As if the device rebooted
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert_example(&store.load().unwrap(), 20);
+        assert!(store.save(&example(30)));
+
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert_example(&store.load().unwrap(), 30);
+    }
+
+    #[test]
+    fn test_settings_store_falls_back_on_corruption() {
+        let flash = MockFlash::new(SECTOR_SIZE, SECTORS);
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        store.load();
+        assert!(store.save(&example(10)));
+        assert!(store.save(&example(20)));
+
+        // Damage the payload of the newest record
+        let first_size = record_size(example(10).encode(&mut [0u8; 256]));
+        flash.corrupt(first_size + HEADER_SIZE + 4);
+
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert_example(&store.load().unwrap(), 10);
+
+        // And the older one too
+        flash.corrupt(HEADER_SIZE + 4);
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert!(store.load().is_none());
+
+        // Writing still works afterwards
+        assert!(store.save(&example(40)));
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert_example(&store.load().unwrap(), 40);
+    }
+
+    #[test]
+    fn test_settings_store_survives_power_loss() {
+        let flash = MockFlash::new(SECTOR_SIZE, SECTORS);
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        store.load();
+        assert!(store.save(&example(10)));
+
+        flash.cut_power_after(30);
+        store.save(&example(20));
+        flash.restore_power();
+
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert_example(&store.load().unwrap(), 10);
+        assert!(store.save(&example(30)));
+
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert_example(&store.load().unwrap(), 30);
+    }
+
+    #[test]
+    fn test_settings_store_ignores_newer_versions() {
+        let flash = MockFlash::new(SECTOR_SIZE, SECTORS);
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        store.load();
+        assert!(store.save(&example(10)));
+
+        // Hand craft a record from the future
+        let mut record = [0xFFu8; 64];
+        let mut header = RecordHeader { version: SETTINGS_VERSION + 1, sequence: 99, length: 4, crc: 0 };
+        record[HEADER_SIZE .. HEADER_SIZE + 4].copy_from_slice(&[KEY_BRIGHTNESS, 1, 0, 99]);
+        header.encode(&mut record);
+        header.crc = record_crc(&record, &record[HEADER_SIZE .. HEADER_SIZE + 4]);
+        header.encode(&mut record);
+
+        let mut storage = flash.clone();
+        storage.write(SECTOR_SIZE, &record[0 .. record_size(4)]);
+
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert_example(&store.load().unwrap(), 10);
+    }
+
+    #[test]
+    fn test_settings_store_wear_leveling() {
+        let flash = MockFlash::new(SECTOR_SIZE, SECTORS);
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        store.load();
+
+        for brightness in 0 .. 200 {
+            assert!(store.save(&example(brightness)));
+        }
+
+        let counts = flash.erase_counts();
+        let most = *counts.iter().max().unwrap();
+        let least = *counts.iter().min().unwrap();
+        assert!(least > 0);
+        assert!(most - least <= 1);
+
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert_example(&store.load().unwrap(), 199);
+    }
+
+    #[test]
+    fn test_settings_store_write_errors() {
+        let flash = MockFlash::new(SECTOR_SIZE, SECTORS);
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        store.load();
+
+        flash.fail_writes(true);
+        assert!(!store.save(&example(10)));
+
+        flash.fail_writes(false);
+        assert!(store.save(&example(20)));
+        let mut store = SettingsStore::new(flash.clone(), record_buffer());
+        assert_example(&store.load().unwrap(), 20);
+    }
+}
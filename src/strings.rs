@@ -0,0 +1,225 @@
+//! Helpers for working with kernel strings.
+//!
+//! The `Str` iterator in teensycore keeps reading past the end
+//! of a string once it spans more than one block, which means
+//! `into_iter()` and `split()` pick up garbage on anything longer
+//! than 32 bytes. These helpers walk the string by index instead
+//! and should be used for any data that comes off the wire.
+
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+
+#[derive(Clone)]
+pub struct StrChars<'a> {
+    content: &'a Str,
+    index: usize,
+}
+
+impl<'a> Iterator for StrChars<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.index >= self.content.len() {
+            return None;
+        }
+
+        let result = self.content.char_at(self.index);
+        self.index += 1;
+        return result;
+    }
+}
+
+/// Iterate over every byte of the string.
+pub fn chars(content: &Str) -> StrChars<'_> {
+    return StrChars {
+        content: content,
+        index: 0,
+    };
+}
+
+/// Split the string into a vector of other strings delimited
+/// by the target character. Don't forget to drop each entry
+/// and free the vector.
+pub fn split(content: &Str, target: u8) -> Vector<Str> {
+    let mut result = Vector::new();
+    let mut temp = Str::new();
+
+    for char in chars(content) {
+        if char == target {
+            result.push(Str::from_str(&temp));
+            temp.clear();
+        } else {
+            temp.append(&[char]);
+        }
+    }
+
+    if temp.len() > 0 {
+        result.push(Str::from_str(&temp));
+    }
+
+    temp.drop();
+    return result;
+}
+
+/// Returns the byte at the index, or None past the end. Use
+/// this instead of `Str::char_at`, which does not check the
+/// upper bound.
+pub fn byte_at(content: &Str, index: usize) -> Option<u8> {
+    if index >= content.len() {
+        return None;
+    }
+    return content.char_at(index);
+}
+
+/// Returns the position of the first occurrence of `target`
+/// at or after `from`.
+pub fn find(content: &Str, target: &[u8], from: usize) -> Option<usize> {
+    if target.len() == 0 || content.len() < target.len() {
+        return None;
+    }
+
+    for start in from ..= content.len() - target.len() {
+        let mut matched = true;
+        for idx in 0 .. target.len() {
+            if content.char_at(start + idx) != Some(target[idx]) {
+                matched = false;
+                break;
+            }
+        }
+
+        if matched {
+            return Some(start);
+        }
+    }
+
+    return None;
+}
+
+/// Returns a copy of `count` bytes starting at `start`. Unlike
+/// `Str::slice`, an empty range yields an empty string. Don't
+/// forget to drop the result.
+pub fn substring(content: &Str, start: usize, count: usize) -> Str {
+    let mut result = Str::new();
+    let end = (start + count).min(content.len());
+    for idx in start .. end {
+        result.append(&[content.char_at(idx).unwrap()]);
+    }
+    return result;
+}
+
+/// Remove the first `count` bytes from the string.
+pub fn consume(content: &mut Str, count: usize) {
+    let mut rest = substring(content, count, content.len());
+    content.clear();
+    content.join(&rest);
+    rest.drop();
+}
+
+/// Remove `count` bytes starting at `start`, keeping whatever
+/// comes before and after.
+pub fn remove(content: &mut Str, start: usize, count: usize) {
+    let mut rest = substring(content, start + count, content.len());
+    let mut result = substring(content, 0, start);
+    result.join(&rest);
+    content.clear();
+    content.join(&result);
+    result.drop();
+    rest.drop();
+}
+
+/// Returns true if the string holds exactly these bytes.
+pub fn equals(content: &Str, target: &[u8]) -> bool {
+    return content.len() == target.len() && chars(content).zip(target.iter()).all(|(a, b)| a == *b);
+}
+
+/// Returns true if the string begins with these bytes.
+pub fn starts_with(content: &Str, prefix: &[u8]) -> bool {
+    return content.len() >= prefix.len() && chars(content).zip(prefix.iter()).all(|(a, b)| a == *b);
+}
+
+/// Like `equals`, but ascii letters match either case.
+pub fn equals_ignore_case(content: &Str, target: &[u8]) -> bool {
+    return content.len() == target.len() && chars(content).zip(target.iter()).all(|(a, b)| a.eq_ignore_ascii_case(b));
+}
+
+/// Parse an unsigned decimal number. Unlike `atoi`, anything
+/// other than digits (or too many of them) is rejected.
+pub fn parse_decimal(content: &Str) -> Option<u64> {
+    if content.len() == 0 || content.len() > 19 {
+        return None;
+    }
+
+    let mut result: u64 = 0;
+    for char in chars(content) {
+        if char < b'0' || char > b'9' {
+            return None;
+        }
+        result = result * 10 + (char - b'0') as u64;
+    }
+    return Some(result);
+}
+
+#[cfg(test)]
+pub mod test_strings {
+    use super::*;
+    use teensycore::*;
+
+    #[test]
+    fn test_split_long_strings() {
+        let content = str!(b"rule;1641024000;7952371200;RetroFuturistic;6\nrule;1641024000;7952371200;Starfleet;4");
+        let lines = split(&content, b'\n');
+        assert_eq!(lines.size(), 2);
+        assert_eq!(lines.get(0).unwrap().len(), 44);
+        assert_eq!(lines.get(1).unwrap().len(), 38);
+
+        let paths = split(&lines.get(0).unwrap(), b';');
+        assert_eq!(paths.size(), 5);
+        assert!(paths.get(3).unwrap() == str!(b"RetroFuturistic"));
+        assert!(paths.get(4).unwrap() == str!(b"6"));
+    }
+
+    #[test]
+    fn test_chars() {
+        let content = str!(b"0123456789012345678901234567890123456789");
+        assert_eq!(chars(&content).count(), 40);
+        assert_eq!(chars(&content).last(), Some(b'9'));
+    }
+
+    #[test]
+    fn test_find_and_consume() {
+        let mut content = str!(b"0,CONNECT\r\n+IPD,0,5:hello+IPD,0,2:hi");
+        assert_eq!(find(&content, b"+IPD,", 0), Some(11));
+        assert_eq!(find(&content, b"+IPD,", 12), Some(25));
+        assert_eq!(find(&content, b"nope", 0), None);
+        assert!(substring(&content, 20, 5) == str!(b"hello"));
+        assert_eq!(substring(&content, 100, 5).len(), 0);
+
+        consume(&mut content, 25);
+        assert!(content == str!(b"+IPD,0,2:hi"));
+        consume(&mut content, 100);
+        assert_eq!(content.len(), 0);
+    }
+
+    #[test]
+    fn test_equals() {
+        let content = str!(b"Content-Length");
+        assert!(equals(&content, b"Content-Length"));
+        assert!(!equals(&content, b"content-length"));
+        assert!(!equals(&content, b"Content-Len"));
+        assert!(equals_ignore_case(&content, b"content-length"));
+        assert!(!equals_ignore_case(&content, b"content-lengths"));
+        assert!(starts_with(&content, b"Content-"));
+        assert!(starts_with(&content, b""));
+        assert!(!starts_with(&content, b"Content-Length: "));
+    }
+
+    #[test]
+    fn test_parse_decimal() {
+        assert_eq!(parse_decimal(&str!(b"0")), Some(0));
+        assert_eq!(parse_decimal(&str!(b"1646690021")), Some(1646690021));
+        assert_eq!(parse_decimal(&str!(b"")), None);
+        assert_eq!(parse_decimal(&str!(b"-1")), None);
+        assert_eq!(parse_decimal(&str!(b"12a")), None);
+        assert_eq!(parse_decimal(&str!(b"99999999999999999999")), None);
+    }
+}
@@ -0,0 +1,286 @@
+//! Thermal protection. Past the warning threshold the wall
+//! is dimmed a step at a time, down to a quarter of its
+//! brightness just below the critical threshold. Past critical
+//! it only shows a dim safe pattern. Temperatures are hundredths
+//! of a degree celsius.
+//!
+//! Brightness only comes back once the temperature has dropped
+//! by the hysteresis, so a wall which hovers around a threshold
+//! doesn't flicker between two levels.
+
+use teensycore::clock::uNano;
+use crate::pixel_engine::color::*;
+
+pub const DEFAULT_WARNING: i32 = 4500;
+pub const DEFAULT_CRITICAL: i32 = 6000;
+pub const DEFAULT_HYSTERESIS: i32 = 300;
+/// The hottest the sensor can read
+pub const MAX_THRESHOLD: i32 = 12500;
+pub const MAX_HYSTERESIS: i32 = 1000;
+
+/// How many steps the brightness is lowered in between the
+/// warning and the critical threshold
+const DERATE_STEPS: i32 = 8;
+/// What is left of the brightness at the last step
+const MIN_SCALE: u8 = 64;
+/// Full brightness, as a scale
+pub const FULL_SCALE: u8 = 255;
+/// The safe pattern is never brighter than this
+pub const SAFE_BRIGHTNESS: u8 = 24;
+
+/// When to start dimming and when to give up on the schedule.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ThermalConfig {
+    pub warning: i32,
+    pub critical: i32,
+    /// How far the temperature has to drop again before the
+    /// wall brightens
+    pub hysteresis: i32,
+}
+
+impl ThermalConfig {
+    pub const fn default() -> Self {
+        return ThermalConfig {
+            warning: DEFAULT_WARNING,
+            critical: DEFAULT_CRITICAL,
+            hysteresis: DEFAULT_HYSTERESIS,
+        };
+    }
+
+    pub fn is_valid(&self) -> bool {
+        return self.warning > 0
+            && self.warning < self.critical
+            && self.critical <= MAX_THRESHOLD
+            && self.hysteresis >= 0
+            && self.hysteresis <= MAX_HYSTERESIS;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ThermalState {
+    Normal,
+    /// Dimmed
+    Warning,
+    /// Showing the safe pattern
+    Critical,
+}
+
+impl ThermalState {
+    pub fn name(&self) -> &'static [u8] {
+        return match self {
+            ThermalState::Normal => b"normal",
+            ThermalState::Warning => b"warning",
+            ThermalState::Critical => b"critical",
+        };
+    }
+}
+
+/// How much the wall is held back because of the heat.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Derating {
+    pub state: ThermalState,
+    /// Applied on top of the brightness, where 255 leaves it as is
+    pub scale: u8,
+}
+
+impl Derating {
+    pub const fn none() -> Self {
+        return Derating {
+            state: ThermalState::Normal,
+            scale: FULL_SCALE,
+        };
+    }
+
+    /// The brightness to show the schedule at.
+    pub fn brightness(&self, brightness: u8) -> u8 {
+        return match self.state {
+            ThermalState::Critical => brightness.min(SAFE_BRIGHTNESS),
+            _ => (brightness as u32 * self.scale as u32 / 255) as u8,
+        };
+    }
+}
+
+/// A change in derating, kept so it can be looked at later.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ThermalEvent {
+    /// Uptime when it happened
+    pub at: uNano,
+    /// The reading which caused it, or None if the sensors
+    /// stopped answering
+    pub temperature: Option<i32>,
+    pub derating: Derating,
+}
+
+/// Works out the derating from one reading after another.
+pub struct ThermalGuard {
+    derating: Derating,
+    /// Whether there ever was a reading
+    measured: bool,
+}
+
+impl ThermalGuard {
+    pub fn new() -> Self {
+        return ThermalGuard {
+            derating: Derating::none(),
+            measured: false,
+        };
+    }
+
+    pub fn derating(&self) -> Derating {
+        return self.derating;
+    }
+
+    /// Take a new reading into account. Returns the derating
+    /// if it changed.
+    pub fn update(&mut self, config: &ThermalConfig, temperature: i32) -> Option<Derating> {
+        self.measured = true;
+        let critical = match self.derating.state {
+            ThermalState::Critical => temperature > config.critical - config.hysteresis,
+            _ => temperature >= config.critical,
+        };
+
+        // Dim as soon as it gets hotter, but only brighten once it
+        // is a hysteresis cooler than the level it is at
+        let hotter = scale_at(config, temperature);
+        let cooler = scale_at(config, temperature + config.hysteresis);
+        let scale = if critical {
+            MIN_SCALE
+        } else if hotter < self.derating.scale {
+            hotter
+        } else {
+            cooler.max(self.derating.scale)
+        };
+
+        let state = if critical {
+            ThermalState::Critical
+        } else if scale < FULL_SCALE {
+            ThermalState::Warning
+        } else {
+            ThermalState::Normal
+        };
+
+        return self.change(Derating {
+            state: state,
+            scale: scale,
+        });
+    }
+
+    /// The sensors stopped answering. The wall may have heated
+    /// up since, so it is dimmed as far as it goes short of the
+    /// safe pattern until there is a reading again. A wall which
+    /// never had a reading is left alone. Returns the derating
+    /// if it changed.
+    pub fn lost(&mut self) -> Option<Derating> {
+        if !self.measured || self.derating.state == ThermalState::Critical {
+            return None;
+        }
+
+        return self.change(Derating {
+            state: ThermalState::Warning,
+            scale: MIN_SCALE,
+        });
+    }
+
+    fn change(&mut self, derating: Derating) -> Option<Derating> {
+        if derating == self.derating {
+            return None;
+        }
+        self.derating = derating;
+        return Some(derating);
+    }
+}
+
+/// The scale for a temperature, on the way up.
+fn scale_at(config: &ThermalConfig, temperature: i32) -> u8 {
+    if temperature <= config.warning {
+        return FULL_SCALE;
+    }
+
+    let span = config.critical - config.warning;
+    let over = (temperature - config.warning).min(span);
+    // Anything past the warning is at least one step down
+    let step = (over * DERATE_STEPS + span - 1) / span;
+    return (FULL_SCALE as i32 - step * (FULL_SCALE - MIN_SCALE) as i32 / DERATE_STEPS) as u8;
+}
+
+/// What a led shows while it is too hot for the schedule. Only
+/// one led of every unit is lit, in a dim amber.
+pub fn safe_color(pixel_id: usize) -> Color {
+    return match pixel_id {
+        0 => rgb(255, 96, 0),
+        _ => rgb(0, 0, 0),
+    };
+}
+
+#[cfg(test)]
+pub mod test_thermal {
+    use super::*;
+
+    #[test]
+    fn test_thermal_config() {
+        assert!(ThermalConfig::default().is_valid());
+        assert!(!ThermalConfig { warning: 6000, critical: 4500, hysteresis: 300 }.is_valid());
+        assert!(!ThermalConfig { warning: 4500, critical: 13000, hysteresis: 300 }.is_valid());
+        assert!(!ThermalConfig { warning: 4500, critical: 6000, hysteresis: 2000 }.is_valid());
+        assert!(!ThermalConfig { warning: 0, critical: 6000, hysteresis: 300 }.is_valid());
+    }
+
+    #[test]
+    fn test_thermal_derating() {
+        let config = ThermalConfig::default();
+        let mut guard = ThermalGuard::new();
+
+        assert_eq!(guard.update(&config, 2500), None);
+        assert_eq!(guard.update(&config, 4500), None);
+
+        // Just past the warning is one step down
+        assert_eq!(guard.update(&config, 4501), Some(Derating { state: ThermalState::Warning, scale: 232 }));
+        assert_eq!(guard.update(&config, 5250), Some(Derating { state: ThermalState::Warning, scale: 160 }));
+        assert_eq!(guard.update(&config, 5999), Some(Derating { state: ThermalState::Warning, scale: 64 }));
+        assert_eq!(guard.update(&config, 6000), Some(Derating { state: ThermalState::Critical, scale: 64 }));
+
+        // Still critical until it cools by the hysteresis
+        assert_eq!(guard.update(&config, 5800), None);
+        assert_eq!(guard.update(&config, 5700), Some(Derating { state: ThermalState::Warning, scale: 64 }));
+
+        // Brightening waits for the hysteresis as well
+        assert_eq!(guard.update(&config, 5650), None);
+        assert_eq!(guard.update(&config, 5100), Some(Derating { state: ThermalState::Warning, scale: 136 }));
+        assert_eq!(guard.update(&config, 5300), None);
+        assert_eq!(guard.update(&config, 4300), Some(Derating { state: ThermalState::Warning, scale: 232 }));
+        assert_eq!(guard.update(&config, 4200), Some(Derating::none()));
+        assert_eq!(guard.derating(), Derating::none());
+
+        // A cold start on a hot wall goes straight to critical
+        let mut guard = ThermalGuard::new();
+        assert_eq!(guard.update(&config, 7000).unwrap().state, ThermalState::Critical);
+    }
+
+    #[test]
+    fn test_thermal_lost_reading() {
+        let config = ThermalConfig::default();
+        let mut guard = ThermalGuard::new();
+
+        // Nothing to go by yet
+        assert_eq!(guard.lost(), None);
+        assert_eq!(guard.update(&config, 2500), None);
+
+        // Dimmed until the sensor is back
+        assert_eq!(guard.lost(), Some(Derating { state: ThermalState::Warning, scale: 64 }));
+        assert_eq!(guard.lost(), None);
+        assert_eq!(guard.update(&config, 2500), Some(Derating::none()));
+
+        // The safe pattern stays
+        assert_eq!(guard.update(&config, 7000).unwrap().state, ThermalState::Critical);
+        assert_eq!(guard.lost(), None);
+        assert_eq!(guard.derating().state, ThermalState::Critical);
+    }
+
+    #[test]
+    fn test_thermal_brightness() {
+        assert_eq!(Derating::none().brightness(200), 200);
+        assert_eq!(Derating { state: ThermalState::Warning, scale: 128 }.brightness(200), 100);
+        assert_eq!(Derating { state: ThermalState::Critical, scale: 64 }.brightness(200), SAFE_BRIGHTNESS);
+        assert_eq!(Derating { state: ThermalState::Critical, scale: 64 }.brightness(10), 10);
+    }
+}
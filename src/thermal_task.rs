@@ -1,41 +1,82 @@
 use teensycore::*;
 use teensycore::clock::*;
-use teensycore::debug::*;
-use teensycore::math::seed_rand;
+use teensycore::system::vector::*;
 
-use crate::drivers::max31820::Max31820Driver;
+use crate::drivers::max31820::{Max31820Driver, CONVERT_ALL_TIME};
+use crate::hal::*;
+use crate::control::{self, SensorReading, MAX_SENSORS};
+use crate::thermal::*;
 
 /// This task is responsible for sampling the ambiant temperature
-/// and keeping the system informed of changes.
+/// and keeping the system informed of changes. The first few
+/// samples seed the prng, and after that the wall is dimmed
+/// whenever it runs hot.
 const SAMPLES: usize = match crate::CYCLE_MODE {
     true => 1,
     false => 8,
 };
 
-pub struct ThermalTask {
-    driver: Max31820Driver,
+/// How often the temperature is checked once the prng is seeded
+const MONITOR_INTERVAL: uNano = 5 * S_TO_NANO;
+/// Looking for sensors which don't answer stalls the leds, so
+/// the interval doubles with every miss, up to this
+const MAX_MONITOR_INTERVAL: uNano = 5 * 60 * S_TO_NANO;
+
+pub struct ThermalTask<G: Gpio, C: Clock, R: RandomSource> {
+    driver: Max31820Driver<G, C>,
+    clock: C,
+    random: R,
     next_event: uNano,
     count: usize,
-    samples: [Option<u16>; SAMPLES],
+    samples: [Option<i32>; SAMPLES],
+    /// The ROM codes found on the bus. Empty when the search
+    /// failed, in which case the only sensor is read without one.
+    sensors: Vector<u64>,
+    /// When the conversion in progress is done, if there is one
+    converted_at: Option<uNano>,
+    /// How many times in a row nothing answered on the bus
+    misses: u32,
+    guard: ThermalGuard,
     pub loaded: bool,
 }
 
-impl ThermalTask {
-    pub fn new(driver: Max31820Driver) -> Self {
+impl<G: Gpio, C: Clock, R: RandomSource> ThermalTask<G, C, R> {
+    pub fn new(driver: Max31820Driver<G, C>, clock: C, random: R) -> Self {
         return ThermalTask {
             driver: driver,
+            clock: clock,
+            random: random,
             next_event: 0,
             count: 0,
             loaded: false,
             samples: [None; SAMPLES],
+            sensors: Vector::new(),
+            converted_at: None,
+            misses: 0,
+            guard: ThermalGuard::new(),
         };
     }
 
     pub fn init(&mut self) {
-        match self.driver.read_temperature() {
+        match self.driver.bus().search() {
+            Err(_) => {},
+            Ok(mut roms) => {
+                for rom in roms.into_iter() {
+                    if self.sensors.size() < MAX_SENSORS {
+                        self.sensors.push(rom);
+                    }
+                }
+                debug_u64(roms.size() as u64, b"temperature sensors");
+                roms.free();
+            }
+        }
+
+        let sample = self.sample();
+        self.protect(sample);
+        match sample {
             None => {},
             Some(sample) => {
-                seed_rand(sample as u64);
+                self.random.seed(sample as u32 as u64);
 
                 // If we are using wifi, this is the only sampling we need.
                 if crate::USE_WIFI {
@@ -46,33 +87,275 @@ impl ThermalTask {
     }
 
     pub fn system_loop(&mut self) {
-        let time = nanos();
-        if !crate::USE_WIFI && !self.loaded && time > self.next_event {
-            if self.count < SAMPLES {
-                self.samples[self.count] = self.driver.read_temperature();
-                self.count += 1;
-            } else if self.count == SAMPLES {
-                let mut prng_seed = 1337;
-                let primes = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97];
-    
-                for i in 0 .. self.samples.len() {
-                    if self.samples[i].is_some() {
-                        prng_seed += self.samples[i].unwrap() as u64 * primes[i];
+        let time = self.clock.nanos();
+        if !crate::USE_WIFI && !self.loaded {
+            if time > self.next_event {
+                self.seed();
+                self.next_event = time + MS_TO_NANO * 7 * 85 * crate::WORLD_MUTIPLIER;
+            }
+            return;
+        }
+
+        // Conversions take long enough to stall the leds, so
+        // start one and come back for the readings
+        match self.converted_at {
+            None => {
+                if time > self.next_event {
+                    self.next_event = time + MONITOR_INTERVAL;
+                    match self.driver.start_convert_all() {
+                        Ok(()) => {
+                            self.misses = 0;
+                            self.converted_at = Some(time + CONVERT_ALL_TIME);
+                        },
+                        Err(_) => {
+                            self.misses += 1;
+                            self.next_event = time + monitor_interval(self.misses);
+                            let sample = self.collect(false);
+                            self.protect(sample);
+                        }
                     }
                 }
-                
-                // Reach into the dregs of what we have available and utilize
-                // the bits of entropy associated with timing delays aggregated
-                // up to this point. (NOTE: This actually makes a significant difference)
-                prng_seed *= nanos() as u64;
-
-                debug_u64(prng_seed, b"prng seed");
-                seed_rand(prng_seed);
-    
-                self.loaded = true;
-                self.count += 1;
+            },
+            Some(converted_at) => {
+                if time > converted_at {
+                    self.converted_at = None;
+                    let sample = self.collect(true);
+                    self.protect(sample);
+                }
+            }
+        }
+    }
+
+    fn seed(&mut self) {
+        if self.count < SAMPLES {
+            let sample = self.sample();
+            self.protect(sample);
+            self.samples[self.count] = sample;
+            self.count += 1;
+        } else if self.count == SAMPLES {
+            let mut prng_seed = 1337;
+            let primes = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97];
+
+            for i in 0 .. self.samples.len() {
+                if self.samples[i].is_some() {
+                    prng_seed += self.samples[i].unwrap() as u32 as u64 * primes[i];
+                }
             }
-            self.next_event = time + MS_TO_NANO * 7 * 85 * crate::WORLD_MUTIPLIER;
+            
+            // Reach into the dregs of what we have available and utilize
+            // the bits of entropy associated with timing delays aggregated
+            // up to this point. (NOTE: This actually makes a significant difference)
+            prng_seed = prng_seed.wrapping_mul(self.clock.nanos() as u64);
+
+            debug_u64(prng_seed, b"prng seed");
+            self.random.seed(prng_seed);
+
+            self.loaded = true;
+            self.count += 1;
         }
     }
-}
\ No newline at end of file
+
+    /// Read every sensor and publish what they say. Returns the
+    /// hottest reading, in hundredths of a degree celsius.
+    fn sample(&mut self) -> Option<i32> {
+        let converted = self.driver.convert_all().is_ok();
+        return self.collect(converted);
+    }
+
+    /// Read and publish what every sensor measured in the last
+    /// conversion. Returns the hottest reading.
+    fn collect(&mut self, converted: bool) -> Option<i32> {
+        if self.sensors.size() == 0 {
+            let result = match converted {
+                false => None,
+                true => self.driver.read_converted(None).ok(),
+            };
+            control::set_temperature(result);
+            return result;
+        }
+
+        let mut readings = [None; MAX_SENSORS];
+        for (idx, rom) in self.sensors.into_iter().enumerate() {
+            let temperature = match converted {
+                false => None,
+                true => self.driver.read_converted(Some(rom)).ok(),
+            };
+            readings[idx] = Some(SensorReading {
+                rom: rom,
+                temperature: temperature,
+            });
+        }
+        control::set_sensor_readings(readings);
+
+        let result = readings.iter().filter_map(|reading| reading.and_then(|reading| reading.temperature)).max();
+        control::set_temperature(result);
+        return result;
+    }
+
+    /// Dim the wall if it is running hot, or if the sensors
+    /// stopped answering.
+    fn protect(&mut self, sample: Option<i32>) {
+        let change = match sample {
+            None => self.guard.lost(),
+            Some(temperature) => self.guard.update(&crate::get_thermal_config(), temperature),
+        };
+
+        match change {
+            None => {},
+            Some(derating) => {
+                match derating.state {
+                    ThermalState::Normal => debug_str(b"thermal derating lifted"),
+                    ThermalState::Warning => debug_u64(derating.scale as u64, b"thermal warning, brightness scaled to"),
+                    ThermalState::Critical => debug_str(b"thermal critical, showing the safe pattern"),
+                }
+                match sample {
+                    None => debug_str(b"because the temperature can't be read"),
+                    Some(temperature) => debug_u64(temperature.max(0) as u64, b"at hundredths of a degree"),
+                }
+                control::record_thermal_event(ThermalEvent {
+                    at: self.clock.nanos(),
+                    temperature: sample,
+                    derating: derating,
+                });
+            }
+        }
+    }
+}
+
+/// How long to wait before looking for the sensors again
+/// after this many misses in a row.
+fn monitor_interval(misses: u32) -> uNano {
+    let mut interval = MONITOR_INTERVAL;
+    for _ in 0 .. misses {
+        interval = (interval * 2).min(MAX_MONITOR_INTERVAL);
+    }
+    return interval;
+}
+
+#[cfg(test)]
+pub mod test_thermal_task {
+    use super::*;
+    use std::prelude::rust_2021::*;
+    use crate::drivers::one_wire::{OneWireBus, RESET_ATTEMPTS};
+    use crate::drivers::one_wire::simulator::BusSimulator;
+    use crate::drivers::one_wire::test_one_wire::rom;
+    use crate::hal::mock::*;
+    use crate::pixel_engine::golden::RENDER_LOCK;
+
+    #[test]
+    fn test_thermal_loads_without_sensor() {
+        // Nothing pulls the bus low, so every read fails
+        let clock = MockClock::new().with_auto_advance(MICRO_TO_NANO);
+        let driver = Max31820Driver::new(OneWireBus::new(10, MockGpio::new(), &clock));
+        let mut task = ThermalTask::new(driver, &clock, MockRandom::new(1));
+
+        task.init();
+        for _ in 0 .. SAMPLES + 1 {
+            clock.advance(S_TO_NANO);
+            task.system_loop();
+        }
+
+        assert!(task.loaded);
+    }
+
+    #[test]
+    fn test_thermal_derates_when_hot() {
+        // The derating and the readings are shown to everyone
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let clock = MockClock::new().with_auto_advance(MICRO_TO_NANO);
+        let wire = BusSimulator::new(&clock, 10);
+        let sensor = rom(0x28, 7);
+        wire.add_sensor(sensor, 0x0190);
+        let driver = Max31820Driver::new(OneWireBus::new(10, wire.clone(), &clock));
+        let mut task = ThermalTask::new(driver, &clock, MockRandom::new(1));
+        control::clear_thermal_events();
+
+        let wait = |task: &mut ThermalTask<BusSimulator, &MockClock, MockRandom>, nanos: uNano| {
+            let until = clock.now() + nanos;
+            while clock.now() < until {
+                clock.advance(100 * MS_TO_NANO);
+                task.system_loop();
+            }
+        };
+
+        task.init();
+        wait(&mut task, 10 * S_TO_NANO);
+        assert!(task.loaded);
+        assert_eq!(control::get_status().temperature, Some(2500));
+        assert_eq!(control::get_status().thermal, Derating::none());
+
+        // Monitoring goes on after the prng is seeded
+        wire.set_temperature(sensor, 0x0340);
+        wait(&mut task, MONITOR_INTERVAL + CONVERT_ALL_TIME);
+        assert_eq!(control::get_status().temperature, Some(5200));
+        let derating = control::get_status().thermal;
+        assert_eq!(derating.state, ThermalState::Warning);
+        assert!(derating.scale < FULL_SCALE);
+
+        wire.set_temperature(sensor, 0x0410);
+        wait(&mut task, MONITOR_INTERVAL + CONVERT_ALL_TIME);
+        assert_eq!(control::get_status().thermal.state, ThermalState::Critical);
+
+        wire.set_temperature(sensor, 0x0190);
+        wait(&mut task, MONITOR_INTERVAL + CONVERT_ALL_TIME);
+        assert_eq!(control::get_status().thermal, Derating::none());
+
+        // Every change was logged
+        let events = control::get_thermal_events();
+        let states: Vec<ThermalState> = events.iter().flatten().map(|event| event.derating.state).collect();
+        assert_eq!(states, [ThermalState::Warning, ThermalState::Critical, ThermalState::Normal]);
+        assert_eq!(events[1].unwrap().temperature, Some(6500));
+        assert!(wire.timing_errors().is_empty(), "{:?}", wire.timing_errors());
+
+        control::clear_thermal_events();
+        control::set_sensor_readings([None; MAX_SENSORS]);
+    }
+
+    #[test]
+    fn test_thermal_lost_sensor() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let clock = MockClock::new().with_auto_advance(MICRO_TO_NANO);
+        let wire = BusSimulator::new(&clock, 10);
+        let sensor = rom(0x28, 7);
+        wire.add_sensor(sensor, 0x0190);
+        let driver = Max31820Driver::new(OneWireBus::new(10, wire.clone(), &clock));
+        let mut task = ThermalTask::new(driver, &clock, MockRandom::new(1));
+        control::clear_thermal_events();
+
+        let wait = |task: &mut ThermalTask<BusSimulator, &MockClock, MockRandom>, nanos: uNano| {
+            let until = clock.now() + nanos;
+            while clock.now() < until {
+                clock.advance(100 * MS_TO_NANO);
+                task.system_loop();
+            }
+        };
+
+        task.init();
+        wait(&mut task, 10 * S_TO_NANO);
+        assert_eq!(control::get_status().temperature, Some(2500));
+
+        // The old reading goes away and the wall is dimmed
+        wire.remove_sensor(sensor);
+        wait(&mut task, MONITOR_INTERVAL + CONVERT_ALL_TIME);
+        assert_eq!(control::get_status().temperature, None);
+        assert_eq!(control::get_status().sensors[0].unwrap().temperature, None);
+        let derating = control::get_status().thermal;
+        assert_eq!(derating.state, ThermalState::Warning);
+        assert!(derating.scale < FULL_SCALE);
+        let events = control::get_thermal_events();
+        assert_eq!(events[0].unwrap().temperature, None);
+
+        // Nobody answering doesn't mean a search every 5 seconds
+        let resets = wire.resets();
+        wait(&mut task, 60 * S_TO_NANO);
+        assert!(wire.resets() - resets < 4 * RESET_ATTEMPTS, "{} resets", wire.resets() - resets);
+
+        wire.add_sensor(sensor, 0x0190);
+        wait(&mut task, MAX_MONITOR_INTERVAL + CONVERT_ALL_TIME);
+        assert_eq!(control::get_status().temperature, Some(2500));
+        assert_eq!(control::get_status().thermal, Derating::none());
+
+        control::clear_thermal_events();
+        control::set_sensor_readings([None; MAX_SENSORS]);
+    }
+}
@@ -0,0 +1,190 @@
+use teensycore::clock::uNano;
+use teensycore::S_TO_NANO;
+
+/// Errors bigger than this are fixed by jumping straight to
+/// the new time.
+pub const STEP_THRESHOLD_NS: uNano = 16 * S_TO_NANO;
+/// Smaller errors are slewed out by running world time up to
+/// this many parts per million faster or slower.
+pub const MAX_SLEW_PPM: i128 = 5000;
+/// Drift is only estimated from samples at least this far
+/// apart, since time sources only have a resolution of one
+/// second.
+pub const DRIFT_MIN_INTERVAL_NS: uNano = 60 * 60 * S_TO_NANO;
+/// Drift estimates beyond this (in parts per billion) are
+/// assumed to come from a bad sample.
+pub const MAX_DRIFT_PPB: i128 = 500_000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SyncResult {
+    /// World time jumped to the new time
+    Stepped,
+    /// World time is being nudged towards the new time. Holds
+    /// the error in nanoseconds, positive if world time was
+    /// behind.
+    Slewing(i128),
+}
+
+/// A WorldClock turns uptime into world time. It follows
+/// samples from a time source without ever jumping for small
+/// errors, so schedules don't skip or repeat. Instead, world
+/// time runs slightly faster or slower until the error is
+/// gone.
+///
+/// The local oscillator is never quite on frequency either.
+/// Comparing samples which are far apart tells how far off it
+/// is, and world time is corrected for that between samples.
+#[derive(Copy, Clone)]
+pub struct WorldClock {
+    /// Uptime (in nanoseconds) at the last sample
+    base_local_ns: uNano,
+    /// World time (in nanoseconds) at the last sample
+    base_world_ns: uNano,
+    /// How much faster world time runs than uptime, in parts
+    /// per billion
+    pub drift_ppb: i128,
+    /// Error from the last sample which is still being slewed
+    /// out
+    slew_ns: i128,
+    /// The first sample since world time last jumped, which
+    /// drift is measured against
+    anchor: Option<(uNano, uNano)>,
+}
+
+impl WorldClock {
+    pub const fn new() -> Self {
+        return WorldClock {
+            base_local_ns: 0,
+            base_world_ns: 0,
+            drift_ppb: 0,
+            slew_ns: 0,
+            anchor: None,
+        };
+    }
+
+    /// Returns world time (in nanoseconds since the unix epoch)
+    pub fn world_ns(&self, now_ns: uNano) -> uNano {
+        let elapsed = now_ns.saturating_sub(self.base_local_ns) as i128;
+        let drift = elapsed * self.drift_ppb / 1_000_000_000;
+        let max_slew = elapsed * MAX_SLEW_PPM / 1_000_000;
+        let slew = self.slew_ns.max(-max_slew).min(max_slew);
+        return (self.base_world_ns as i128 + elapsed + drift + slew).max(0) as uNano;
+    }
+
+    /// Jump straight to this world time.
+    pub fn set(&mut self, now_ns: uNano, world_ns: uNano) {
+        self.base_local_ns = now_ns;
+        self.base_world_ns = world_ns;
+        self.slew_ns = 0;
+        self.anchor = Some((now_ns, world_ns));
+    }
+
+    /// Follow a sample from a time source. The first sample
+    /// and big errors jump, anything else is slewed.
+    pub fn sync(&mut self, now_ns: uNano, world_ns: uNano) -> SyncResult {
+        let current = self.world_ns(now_ns);
+        let error = world_ns as i128 - current as i128;
+        let (anchor_local, anchor_world) = match self.anchor {
+            Some(anchor) if error.unsigned_abs() <= STEP_THRESHOLD_NS => anchor,
+            _ => {
+                self.set(now_ns, world_ns);
+                return SyncResult::Stepped;
+            }
+        };
+
+        let local_elapsed = now_ns.saturating_sub(anchor_local);
+        if local_elapsed >= DRIFT_MIN_INTERVAL_NS {
+            let world_elapsed = world_ns as i128 - anchor_world as i128;
+            let drift = (world_elapsed - local_elapsed as i128) * 1_000_000_000 / local_elapsed as i128;
+            if drift.abs() <= MAX_DRIFT_PPB {
+                self.drift_ppb = drift;
+            }
+        }
+
+        // Carry on from where world time is now, so the new
+        // drift doesn't cause a jump either
+        self.base_local_ns = now_ns;
+        self.base_world_ns = current;
+        self.slew_ns = error;
+        return SyncResult::Slewing(error);
+    }
+
+    /// Returns true once world time came from a time source.
+    pub fn synced(&self) -> bool {
+        return self.anchor.is_some();
+    }
+}
+
+#[cfg(test)]
+pub mod test_time_sync {
+    use super::*;
+    use teensycore::MS_TO_NANO;
+
+    const EPOCH_NS: uNano = 1646690021 * S_TO_NANO;
+
+    #[test]
+    fn test_world_clock_steps() {
+        let mut clock = WorldClock::new();
+        assert!(!clock.synced());
+        assert_eq!(clock.world_ns(5 * S_TO_NANO), 5 * S_TO_NANO);
+
+        assert_eq!(clock.sync(10 * S_TO_NANO, EPOCH_NS), SyncResult::Stepped);
+        assert!(clock.synced());
+        assert_eq!(clock.world_ns(12 * S_TO_NANO), EPOCH_NS + 2 * S_TO_NANO);
+
+        // Too far off to slew
+        assert_eq!(clock.sync(20 * S_TO_NANO, EPOCH_NS + 60 * S_TO_NANO), SyncResult::Stepped);
+        assert_eq!(clock.world_ns(20 * S_TO_NANO), EPOCH_NS + 60 * S_TO_NANO);
+    }
+
+    #[test]
+    fn test_world_clock_slews() {
+        let mut clock = WorldClock::new();
+        clock.sync(0, EPOCH_NS);
+
+        // Two seconds behind, which takes 400 seconds to make up
+        let now = 100 * S_TO_NANO;
+        assert_eq!(clock.sync(now, EPOCH_NS + 102 * S_TO_NANO), SyncResult::Slewing(2 * S_TO_NANO as i128));
+        assert_eq!(clock.world_ns(now), EPOCH_NS + 100 * S_TO_NANO);
+        assert_eq!(clock.world_ns(now + 100 * S_TO_NANO), EPOCH_NS + 200 * S_TO_NANO + 500 * MS_TO_NANO);
+        assert_eq!(clock.world_ns(now + 400 * S_TO_NANO), EPOCH_NS + 502 * S_TO_NANO);
+        assert_eq!(clock.world_ns(now + 1000 * S_TO_NANO), EPOCH_NS + 1102 * S_TO_NANO);
+
+        // Slewing backwards never goes back in time
+        let mut clock = WorldClock::new();
+        clock.sync(0, EPOCH_NS);
+        clock.sync(100 * S_TO_NANO, EPOCH_NS + 90 * S_TO_NANO);
+        let mut previous = 0;
+        for second in 100 .. 3000 {
+            let world = clock.world_ns(second * S_TO_NANO);
+            assert!(world > previous);
+            previous = world;
+        }
+        assert_eq!(clock.world_ns(3000 * S_TO_NANO), EPOCH_NS + 2990 * S_TO_NANO);
+    }
+
+    #[test]
+    fn test_world_clock_estimates_drift() {
+        // The local clock runs 50ppm slow
+        let local = |world_s: uNano| world_s * S_TO_NANO * 999_950 / 1_000_000;
+        let mut clock = WorldClock::new();
+        clock.sync(local(0), EPOCH_NS);
+
+        // Not enough time has passed to tell
+        clock.sync(local(600), EPOCH_NS + 600 * S_TO_NANO);
+        assert_eq!(clock.drift_ppb, 0);
+
+        clock.sync(local(7200), EPOCH_NS + 7200 * S_TO_NANO);
+        assert!((clock.drift_ppb - 50_002).abs() < 10);
+
+        // With the drift known, world time keeps up on its own
+        // once the remaining error is slewed out
+        clock.sync(local(14400), EPOCH_NS + 14400 * S_TO_NANO);
+        let world = clock.world_ns(local(21600));
+        assert!(world.abs_diff(EPOCH_NS + 21600 * S_TO_NANO) < MS_TO_NANO);
+
+        // A bad sample doesn't ruin the estimate
+        clock.sync(local(28800), EPOCH_NS + 28815 * S_TO_NANO);
+        assert!((clock.drift_ppb - 50_002).abs() < 1000);
+    }
+}
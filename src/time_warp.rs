@@ -0,0 +1,100 @@
+use teensycore::clock::uNano;
+use teensycore::{MS_TO_NANO, S_TO_NANO};
+
+/// How long a time warp lasts if no duration is given.
+pub const DEFAULT_TIME_WARP_S: uNano = 10 * 60;
+/// The longest a time warp is allowed to last before the
+/// wall goes back to real time.
+pub const MAX_TIME_WARP_S: uNano = 4 * 60 * 60;
+/// The fastest world time is allowed to run.
+pub const MAX_TIME_WARP_MULTIPLIER: uNano = 24 * 60 * 60;
+
+/// A TimeWarp replaces the world clock for a limited amount
+/// of real time. World time starts at `start_s` and then runs
+/// `multiplier` times faster than real time. This makes it
+/// possible to preview a full day of schedule transitions in
+/// a few minutes.
+///
+/// Once `duration_ns` of real time has passed, the warp is
+/// expired and world time reverts to the real clock.
+#[derive(Copy, Clone)]
+pub struct TimeWarp {
+    /// World time (in unix epoch seconds) when the warp began
+    pub start_s: uNano,
+    /// World seconds which pass for every real second
+    pub multiplier: uNano,
+    /// Uptime (in nanoseconds) when the warp began
+    pub started_ns: uNano,
+    /// How long (in nanoseconds of real time) the warp lasts
+    pub duration_ns: uNano,
+}
+
+impl TimeWarp {
+    pub fn new(start_s: uNano, multiplier: uNano, started_ns: uNano, duration_s: uNano) -> Self {
+        let duration_s = match duration_s {
+            0 => DEFAULT_TIME_WARP_S,
+            duration => duration.min(MAX_TIME_WARP_S),
+        };
+
+        return TimeWarp {
+            start_s: start_s,
+            multiplier: multiplier.max(1).min(MAX_TIME_WARP_MULTIPLIER),
+            started_ns: started_ns,
+            duration_ns: duration_s * S_TO_NANO,
+        };
+    }
+
+    /// Returns the warped world time (in unix epoch seconds)
+    pub fn world_time(&self, now_ns: uNano) -> uNano {
+        let elapsed_ms = now_ns.saturating_sub(self.started_ns) / MS_TO_NANO;
+        return self.start_s + (elapsed_ms * self.multiplier) / 1000;
+    }
+
+    pub fn expired(&self, now_ns: uNano) -> bool {
+        return now_ns.saturating_sub(self.started_ns) >= self.duration_ns;
+    }
+
+    /// Seconds of real time until the warp reverts
+    pub fn remaining_s(&self, now_ns: uNano) -> uNano {
+        let elapsed = now_ns.saturating_sub(self.started_ns);
+        return self.duration_ns.saturating_sub(elapsed) / S_TO_NANO;
+    }
+}
+
+#[cfg(test)]
+pub mod test_time_warp {
+    use super::*;
+
+    #[test]
+    fn test_time_warp_runs_faster() {
+        // One world hour per real second
+        let warp = TimeWarp::new(1000, 3600, 5 * S_TO_NANO, 60);
+        assert_eq!(warp.world_time(5 * S_TO_NANO), 1000);
+        assert_eq!(warp.world_time(6 * S_TO_NANO), 1000 + 3600);
+        assert_eq!(warp.world_time(5 * S_TO_NANO + 500 * MS_TO_NANO), 1000 + 1800);
+
+        // A day goes by in 24 seconds
+        assert_eq!(warp.world_time(29 * S_TO_NANO), 1000 + 86400);
+    }
+
+    #[test]
+    fn test_time_warp_expires() {
+        let warp = TimeWarp::new(0, 1, 10 * S_TO_NANO, 60);
+        assert!(!warp.expired(10 * S_TO_NANO));
+        assert_eq!(warp.remaining_s(40 * S_TO_NANO), 30);
+        assert!(!warp.expired(69 * S_TO_NANO));
+        assert!(warp.expired(70 * S_TO_NANO));
+        assert_eq!(warp.remaining_s(100 * S_TO_NANO), 0);
+    }
+
+    #[test]
+    fn test_time_warp_limits() {
+        let warp = TimeWarp::new(0, 0, 0, 0);
+        assert_eq!(warp.multiplier, 1);
+        assert_eq!(warp.duration_ns, DEFAULT_TIME_WARP_S * S_TO_NANO);
+
+        let warp = TimeWarp::new(0, uNano::MAX, 0, uNano::MAX);
+        assert_eq!(warp.multiplier, MAX_TIME_WARP_MULTIPLIER);
+        assert_eq!(warp.duration_ns, MAX_TIME_WARP_S * S_TO_NANO);
+    }
+}
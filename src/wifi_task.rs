@@ -1,57 +1,199 @@
 use crate::*;
-use crate::http::models::HttpRequest;
-use crate::http::parser::*;
+use crate::api::*;
+use crate::hal::*;
+use crate::http::client::*;
+use crate::http::models::*;
+use crate::http::server::*;
 use crate::pixel_engine::shader_config::ShaderConfig;
 use crate::pixel_engine::shader_config::ShaderConfigList;
 use teensycore::*;
 use teensycore::clock::uNano;
-use teensycore::gate::*;
-use teensycore::math::seed_rand;
 use teensycore::system::str::*;
 use teensycore::system::vector::*;
-use teensycore::serio::*;
 use teensycore::phys::pins::*;
 use teensycore::math::atoi;
 use crate::drivers::esp8266::*;
+use crate::drivers::esp8266::parser::*;
+use crate::drivers::esp8266::queue::*;
+use crate::dmx::*;
+use crate::dmx::ddp::DdpReceiver;
+use crate::mqtt::client::*;
+use crate::mqtt::home_assistant::*;
+use crate::mqtt::packet::Will;
+use crate::network::*;
+use crate::provisioning::*;
+use crate::date_time::parse_date;
+use crate::strings::split;
+
+pub mod supervisor;
+use supervisor::*;
 
-const DEVICE: SerioDevice = SerioDevice::Default;
 const RST_PIN: usize = 2;
 const EN_PIN: usize = 3;
+// Joining a network and scanning for them take a while
+const JOIN_TIMEOUT: uNano = 20 * S_TO_NANO;
+const SCAN_TIMEOUT: uNano = 10 * S_TO_NANO;
+// How long the whole config download may take
+const DOWNLOAD_TIMEOUT: uNano = 10 * S_TO_NANO;
+// How often the config is downloaded again
+const REFRESH_INTERVAL: uNano = 15 * 60 * S_TO_NANO;
+// Failed downloads are retried after a delay which doubles
+// with every failure in a row, up to the maximum
+const RETRY_MIN: uNano = 10 * S_TO_NANO;
+const RETRY_MAX: uNano = 15 * 60 * S_TO_NANO;
+// The connections used to download the config and to talk to
+// the mqtt broker. The server gets the other three.
+const CONFIG_LINK: u8 = 4;
+const MQTT_LINK: u8 = 3;
+const SERVER_CONNECTIONS: u8 = 3;
+// While lighting software may stream to the wall, some of the
+// server's links listen for it instead
+const ARTNET_LINK: u8 = 1;
+const E131_LINK: u8 = 2;
+const DDP_LINK: u8 = 1;
+// How long the broker may go without hearing from the wall
+const MQTT_KEEPALIVE_S: u16 = 60;
+// Time servers for the module's sntp client
+const SNTP_SERVERS: [&[u8]; 2] = [b"pool.ntp.org", b"time.google.com"];
+// How often world time is checked against sntp, and how soon
+// to ask again if the module doesn't have the time yet
+const SNTP_INTERVAL: uNano = 60 * 60 * S_TO_NANO;
+const SNTP_RETRY: uNano = 30 * S_TO_NANO;
+const SNTP_TIMEOUT: uNano = 2 * S_TO_NANO;
+// Time from config downloads is only used while sntp hasn't
+// worked for this long
+const SNTP_STALE: uNano = 3 * SNTP_INTERVAL;
+// The module reports 1970 until it has reached a server
+const MIN_VALID_TIME_S: uNano = 1577836800;
+// Holding this button at boot enters provisioning mode
+const SETUP_BUTTON_PIN: usize = 4;
 
-static mut INITIALIZED: bool = false;
-static mut INITIALIZE_TIMEOUT: uNano = 0;
-
-static mut OK: Option<Str> = None;
-static mut READY: Option<Str> = None;
-static mut ERROR: Option<Str> = None;
-static mut FAIL: Option<Str> = None;
-static mut SEND_OK: Option<Str> = None;
-static mut BAD_REQUEST: Option<Str> = None;
+/// A saved network to try joining
+#[derive(Copy, Clone)]
+struct JoinCandidate {
+    ssid: Str,
+    pwd: Str,
+    /// None if the last scan didn't find it
+    rssi: Option<i32>,
+}
 
-// Buffers to hold the content and header, so we don't have
-// to recreate them all the time.
-static mut CONTENT: Option<Str> = None;
-static mut HEADER: Option<Str> = None;
+/// What the wifi task is up to. Most of the work happens in
+/// the callbacks of the commands it sends, which also decide
+/// what to send next.
+#[derive(Copy, Clone, PartialEq)]
+enum WifiStep {
+    Reset,
+    /// Waiting for the module to start up
+    Boot,
+    /// Working through the commands which join the network,
+    /// start the server or host the setup access point
+    Setup,
+    /// Serving requests, and downloading the config every so
+    /// often
+    Serve,
+    /// Lost the network, waiting before joining again
+    Reconnect,
+    /// Waiting for the last setup page to go out
+    ApFinish,
+}
 
-pub struct WifiTask {
+pub struct WifiTask<S: SerialPort, C: Clock, G: Gpio, R: RandomSource> {
     pub ready: bool,
+    serial: S,
+    clock: C,
+    gpio: G,
+    random: R,
+    step: WifiStep,
+    settle_target: uNano,
+    queue: CommandQueue<Self>,
+    supervisor: Supervisor,
+
+    // Saved networks to join, best first
+    candidates: Vector<JoinCandidate>,
+    next_candidate: usize,
+
+    // Provisioning state
+    provisioning: bool,
+    networks: Vector<Str>,
+    scan_link: Option<u8>,
+
+    // Requests made to the server
+    requests: RequestReader,
+
+    // Config downloads
+    client: HttpClient,
+    etag: Option<Str>,
+    last_modified: Option<Str>,
+    refresh_target: uNano,
+    download_failures: u32,
+
+    // Time sync
+    sntp: bool,
+    sntp_target: uNano,
+    sntp_synced_at: Option<uNano>,
+
+    // Home automation
+    mqtt: MqttClient,
+    home_assistant: Option<HomeAssistant>,
+    /// The broker url the client was connected with
+    mqtt_url: Option<Str>,
+    mqtt_target: uNano,
+    mqtt_failures: u32,
+
+    // Frames from lighting software
+    /// What the listeners were opened for when the server started
+    streaming: Option<StreamProtocol>,
+    artnet: DmxReceiver,
+    e131: DmxReceiver,
+    ddp: DdpReceiver,
 }
 
-impl WifiTask {
+impl<S: SerialPort, C: Clock, G: Gpio, R: RandomSource> WifiTask<S, C, G, R> {
 
-    pub fn new() -> Self {
+    pub fn new(serial: S, clock: C, gpio: G, random: R) -> Self {
         return WifiTask {
             ready: false,
+            serial: serial,
+            clock: clock,
+            gpio: gpio,
+            random: random,
+            step: WifiStep::Reset,
+            settle_target: 0,
+            queue: CommandQueue::new(),
+            supervisor: Supervisor::new(),
+            candidates: Vector::new(),
+            next_candidate: 0,
+            provisioning: false,
+            networks: Vector::new(),
+            scan_link: None,
+            requests: RequestReader::new(),
+            client: HttpClient::new(Some(CONFIG_LINK)),
+            etag: None,
+            last_modified: None,
+            refresh_target: 0,
+            download_failures: 0,
+            sntp: false,
+            sntp_target: 0,
+            sntp_synced_at: None,
+            mqtt: MqttClient::new(MQTT_LINK),
+            home_assistant: None,
+            mqtt_url: None,
+            mqtt_target: 0,
+            mqtt_failures: 0,
+            streaming: None,
+            artnet: DmxReceiver::new(DmxProtocol::ArtNet),
+            e131: DmxReceiver::new(DmxProtocol::E131),
+            ddp: DdpReceiver::new(),
         };
     }
 
     pub fn init(&mut self) {
-        serial_init(DEVICE);
-        serial_baud(DEVICE, 115200);
+        self.serial.init();
+        self.serial.set_baud(115200);
 
         // Restart
-        pin_mode(RST_PIN, Mode::Output);
-        pin_pad_config(RST_PIN, PadConfig { 
+        self.gpio.mode(RST_PIN, Mode::Output);
+        self.gpio.pad_config(RST_PIN, PadConfig { 
             hysterisis: false, 
             resistance: PullUpDown::PullDown100k, 
             pull_keep: PullKeep::Pull, 
@@ -62,8 +204,8 @@ impl WifiTask {
             fast_slew_rate: false 
         });
 
-        pin_mode(EN_PIN, Mode::Output);
-        pin_pad_config(EN_PIN, PadConfig { 
+        self.gpio.mode(EN_PIN, Mode::Output);
+        self.gpio.pad_config(EN_PIN, PadConfig { 
             hysterisis: false, 
             resistance: PullUpDown::PullDown100k, 
             pull_keep: PullKeep::Pull, 
@@ -74,238 +216,1978 @@ impl WifiTask {
             fast_slew_rate: false 
         });
 
-        // If we don't cache these, it uses up a lot of memory re-creating them
-        // in the main loop. Which just seems silly.
-        unsafe {
-            OK = Some(str!(b"OK"));
-            READY = Some(str!(b"ready"));
-            ERROR = Some(str!(b"ERROR"));
-            FAIL = Some(str!(b"FAIL"));
-            SEND_OK = Some(str!(b"SEND OK"));
-            BAD_REQUEST = Some(str!(b"Bad Request"));
-            HEADER = Some(Str::new());
-            CONTENT = Some(Str::new());
-            INITIALIZE_TIMEOUT = nanos() + S_TO_NANO * 30;
+        self.gpio.mode(SETUP_BUTTON_PIN, Mode::Input);
+        self.gpio.pad_config(SETUP_BUTTON_PIN, PadConfig { 
+            hysterisis: true, 
+            resistance: PullUpDown::PullUp47k, 
+            pull_keep: PullKeep::Pull, 
+            pull_keep_en: true, 
+            open_drain: false, 
+            speed: PinSpeed::Low50MHz, 
+            drive_strength: DriveStrength::Disabled, 
+            fast_slew_rate: false 
+        });
+
+        // Without credentials, there is nothing to join
+        let button_held = self.gpio.read(SETUP_BUTTON_PIN) == 0;
+        if button_held || get_wifi_credentials().is_none() {
+            self.provisioning = true;
         }
     }
 
+    /// Host the setup access point until new credentials
+    /// are saved.
+    pub fn start_provisioning(&mut self) {
+        self.provisioning = true;
+        self.ready = false;
+        self.step = WifiStep::Reset;
+    }
+
+    pub fn provisioning(&self) -> bool {
+        return self.provisioning;
+    }
+
+    pub fn stats(&self) -> WifiStats {
+        return self.supervisor.stats();
+    }
+
     pub fn system_loop(&mut self) {
-        if unsafe { INITIALIZED } {
-            self.ready = true;
-        }
-
-        gate_open!()
-            .once(|| {
-                unsafe { INITIALIZED = false; }
-                pin_out(EN_PIN, Power::Low);
-                teensycore::wait_ns(5 * teensycore::MS_TO_NANO);
-                pin_out(EN_PIN, Power::High);
-                pin_out(RST_PIN, Power::Low);
-                teensycore::wait_ns(5 * teensycore::MS_TO_NANO);
-                pin_out(RST_PIN, Power::High);
-                teensycore::wait_ns(5 * teensycore::MS_TO_NANO);
-                pin_mode(RST_PIN, Mode::Input);
-                debug::debug_str(b"reset");
-                esp8266_version(DEVICE);
-            })
-            .when(ready, || {
-                esp8266_configure_echo(DEVICE, true);
-            })
-            .when(ok, || {
-                esp8266_wifi_mode(DEVICE, WifiMode::Client);
-            })
-            .when(ok, || {
-                esp8266_auto_connect(DEVICE, false);
-            })
-            .when(ok, || {
-                let mut ssid = str!(b"B&M");
-                let mut pwd = str!(b"password_here");
-
-                esp8266_connect_to_wifi(DEVICE, &ssid, &pwd);
-
-                // Free up memory
-                ssid.drop();
-                pwd.drop();
-            })
-            .when(ok, || {
-                esp8266_multiple_connections(DEVICE, false);
-            })
-            .when(ok, || {
-                let mut ip = str!(b"52.27.143.19");
-                esp8266_open_tcp(DEVICE, &ip, 80, None);
-                ip.drop();
-            })
-            .when(ok, || {
-                // Generate the http request
-                let mut request = HttpRequest {
-                    method: str!(b"GET"),
-                    request_path: str!(b"/hexwall"),
-                    host: str!(b"amazon.com"),
-                    headers: None,
-                    content: None,
-                };
-
-                // Send request
-                esp8266_write(DEVICE, &request.to_str(), None);
-                request.drop();
-            })
-            .when(send_ok, || { })
-            .when_nano(1500 * MS_TO_NANO,  || { })
-            .when(|gate| {
-                // Check for bad request
-                let buf = serial_read(DEVICE);
-                match unsafe { &BAD_REQUEST } {
-                    None => {
-                        return false;
-                    },
-                    Some(target) => {
-                        if buf.contains(&target) {
-                            gate.reset();
-                            buf.clear();
-                            return false;
-                        }
+        if control::take_wifi_reconnect() && self.step != WifiStep::Reset {
+            debug_str(b"reconnect requested");
+            self.restart();
+        }
+
+        self.run_queue();
+
+        match self.step {
+            WifiStep::Reset => {
+                self.ready = false;
+                self.sntp = false;
+                self.supervisor.enter(WifiState::Resetting, self.clock.nanos());
+                self.queue.clear();
+                self.client.cancel();
+                self.mqtt.cancel();
+                self.clear_candidates();
+                for link in 0 .. MAX_LINKS {
+                    self.requests.reset(link as u8);
+                }
+
+                // Whatever was half received before the reset
+                // would be mistaken for the start of what comes
+                // after it
+                self.serial.read().clear();
+                self.reset_module();
+                let mut command = AtCommand::new();
+                esp8266_version(&mut command);
+                self.queue.push(command, ignore_response);
+                self.step = WifiStep::Boot;
+            },
+            WifiStep::Boot | WifiStep::Setup => {},
+            WifiStep::Serve => {
+                let now = self.clock.nanos();
+                // A failed download is tried again later, while
+                // the server keeps going
+                if self.client.busy() {
+                    match self.finish_download() {
+                        None => {},
+                        Some(true) => {
+                            self.ready = true;
+                            self.supervisor.enter(WifiState::Idle, now);
+                        },
+                        Some(false) => {
+                            self.supervisor.enter(WifiState::Failed, now);
+                        },
                     }
+                } else if !self.provisioning && now >= self.refresh_target && !self.start_download() {
+                    self.supervisor.enter(WifiState::Failed, now);
+                }
+
+                if self.sntp && now > self.sntp_target {
+                    // Ask again later if the module never answers
+                    self.sntp_target = now + SNTP_RETRY;
+                    let mut command = AtCommand::new().with_timeout(SNTP_TIMEOUT);
+                    esp8266_sntp_time(&mut command);
+                    self.queue.push(command, Self::time_received);
+                }
+
+                if !self.provisioning {
+                    self.serve_mqtt(now);
+                }
+
+                self.serve_request();
+
+                // Anything else in the queue will show whether the
+                // module still answers
+                if !self.queue.busy() && self.supervisor.probe_due(now) {
+                    let mut command = AtCommand::new();
+                    esp8266_at(&mut command);
+                    self.queue.push(command, Self::probed);
+                }
+            },
+            WifiStep::Reconnect => {
+                if self.supervisor.reconnect_due(self.clock.nanos()) {
+                    self.step = WifiStep::Reset;
+                }
+            },
+            WifiStep::ApFinish => {
+                if self.clock.nanos() > self.settle_target {
+                    self.step = WifiStep::Reset;
+                }
+            },
+        }
+
+        self.run_queue();
+    }
+
+    /// Send whatever commands are due and act on everything
+    /// the module said since the last time.
+    fn run_queue(&mut self) {
+        while let Some(event) = self.queue.poll(&mut self.serial, &self.clock) {
+            match &event {
+                QueueEvent::Done(_, response) if response.result == AtResult::Timeout => {},
+                _ => self.supervisor.heard(self.clock.nanos()),
+            }
+
+            match event {
+                QueueEvent::Done(callback, mut response) => {
+                    callback(self, &response);
+                    response.drop();
+                },
+                QueueEvent::Event(mut event) => {
+                    self.handle_event(&event);
+                    event.drop();
                 }
-                
-                // Parse response here
-                let shaders = parse_config(buf);
-                if shaders.size() > 0 {
-                    buf.clear();
-                    set_shader_configs(shaders);
-                    return true;
+            }
+        }
+    }
+
+    /// Deal with something the module reported by itself.
+    fn handle_event(&mut self, event: &AtEvent) {
+        if self.client.handle_event(event) || self.mqtt.handle_event(event) {
+            return;
+        }
+
+        match event {
+            AtEvent::Ready if self.step == WifiStep::Boot => {
+                // Whatever was sent before is lost
+                self.queue.clear();
+                let mut command = AtCommand::new();
+                esp8266_configure_echo(&mut command, true);
+                self.queue.push(command, Self::echo_configured);
+                self.step = WifiStep::Setup;
+            },
+            AtEvent::Ready if self.step != WifiStep::Reset => {
+                // The module restarted by itself
+                debug_str(b"module restarted");
+                self.restart();
+            },
+            AtEvent::WifiConnected if self.supervisor.state() == WifiState::Joining => {
+                self.supervisor.enter(WifiState::Connected, self.clock.nanos());
+            },
+            AtEvent::GotIp if self.supervisor.state() == WifiState::Connected => {
+                self.supervisor.enter(WifiState::GotIp, self.clock.nanos());
+            },
+            AtEvent::Disconnected if self.step == WifiStep::Serve && !self.provisioning => {
+                let now = self.clock.nanos();
+                let delay = backoff_delay(&mut self.random, self.supervisor.reconnect_attempts() + 1);
+                debug_str(b"network lost");
+                self.supervisor.disconnected(now, delay);
+                self.step = WifiStep::Reconnect;
+            },
+            AtEvent::Ipd { link: Some(link), data, .. } if self.stream_link(*link) => {
+                self.receive_stream(*link, data);
+            },
+            AtEvent::Ipd { link: Some(link), data, .. } => {
+                self.requests.receive(*link, data);
+            },
+            AtEvent::Closed(Some(link)) => {
+                self.requests.reset(*link);
+            },
+            _ => {},
+        }
+    }
+
+    /// Start over after a command failed.
+    fn restart(&mut self) {
+        self.step = WifiStep::Reset;
+    }
+
+    fn echo_configured(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.restart();
+            return;
+        }
+
+        let mut command = AtCommand::new();
+        if self.provisioning {
+            // Stay a station as well, so networks can be scanned
+            esp8266_wifi_mode(&mut command, WifiMode::All);
+        } else {
+            esp8266_wifi_mode(&mut command, WifiMode::Client);
+        }
+        self.queue.push(command, Self::mode_configured);
+    }
+
+    fn mode_configured(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.restart();
+            return;
+        }
+
+        let mut command = AtCommand::new();
+        if self.provisioning {
+            let mut ssid = Str::with_content(SETUP_SSID);
+            let mut pwd = Str::new();
+            esp8266_configure_ap(&mut command, &ssid, &pwd, SETUP_CHANNEL);
+            ssid.drop();
+            pwd.drop();
+            self.queue.push(command, Self::ap_configured);
+        } else {
+            esp8266_auto_connect(&mut command, false);
+            self.queue.push(command, Self::auto_connect_configured);
+        }
+    }
+
+    fn auto_connect_configured(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.restart();
+            return;
+        }
+
+        if get_wifi_credentials().is_none() {
+            // The credentials were cleared in the meantime
+            self.start_provisioning();
+            return;
+        }
+
+        // The address has to be settled before joining
+        let mut command = AtCommand::new();
+        match get_network_config() {
+            NetworkConfig::Dhcp => {
+                esp8266_dhcp_mode(&mut command, WifiMode::Client, true);
+            },
+            NetworkConfig::Static { ip, gateway, netmask, .. } => {
+                esp8266_station_ip(&mut command, ip, gateway, netmask);
+            },
+        }
+        self.queue.push(command, Self::address_configured);
+    }
+
+    fn address_configured(&mut self, response: &AtResponse) {
+        // The module keeps whatever it had before, which is
+        // better than not joining at all
+        if !response.ok() {
+            debug_str(b"could not configure the address");
+        }
+
+        let mut command = AtCommand::new();
+        match get_network_config() {
+            NetworkConfig::Dhcp => esp8266_dns(&mut command, None),
+            NetworkConfig::Static { dns, .. } => esp8266_dns(&mut command, dns),
+        }
+        self.queue.push(command, Self::dns_configured);
+    }
+
+    fn dns_configured(&mut self, response: &AtResponse) {
+        // Older firmware doesn't know about this, and its own
+        // name servers work too
+        if !response.ok() {
+            debug_str(b"could not configure dns");
+        }
+
+        // See which of the saved networks are around
+        let mut command = AtCommand::new().with_timeout(SCAN_TIMEOUT);
+        esp8266_list_wifi(&mut command);
+        self.queue.push(command, Self::known_networks_scanned);
+        self.supervisor.enter(WifiState::Joining, self.clock.nanos());
+    }
+
+    /// Line up the saved networks to join. The ones the scan
+    /// found come first, strongest first, followed by the rest
+    /// in case the scan missed them.
+    fn known_networks_scanned(&mut self, response: &AtResponse) {
+        let mut access_points = match response.ok() {
+            true => parse_access_points(&response.text),
+            false => Vector::new(),
+        };
+
+        self.clear_candidates();
+        let mut found: Vector<JoinCandidate> = Vector::new();
+        for (ssid, pwd) in get_wifi_networks().into_iter() {
+            let mut rssi = None;
+            for access_point in access_points.into_iter() {
+                if access_point.ssid == ssid && rssi.map_or(true, |rssi| access_point.rssi > rssi) {
+                    rssi = Some(access_point.rssi);
+                }
+            }
+
+            found.push(JoinCandidate {
+                ssid: Str::from_str(&ssid),
+                pwd: Str::from_str(&pwd),
+                rssi: rssi,
+            });
+        }
+
+        let mut taken = [false; MAX_WIFI_NETWORKS];
+        loop {
+            let mut best: Option<(usize, i32)> = None;
+            for (idx, candidate) in found.into_iter().enumerate() {
+                match (candidate.rssi, best) {
+                    (None, _) => {},
+                    _ if taken[idx] => {},
+                    (Some(rssi), Some((_, best_rssi))) if rssi <= best_rssi => {},
+                    (Some(rssi), _) => best = Some((idx, rssi)),
+                }
+            }
+
+            match best {
+                None => break,
+                Some((idx, _)) => {
+                    taken[idx] = true;
+                    self.candidates.push(found.get(idx).unwrap());
+                },
+            }
+        }
+
+        for (idx, candidate) in found.into_iter().enumerate() {
+            if !taken[idx] {
+                self.candidates.push(candidate);
+            }
+        }
+        found.free();
+
+        for mut access_point in access_points.into_iter() {
+            access_point.drop();
+        }
+        access_points.free();
+
+        self.join_next();
+    }
+
+    /// Try joining the next network in line.
+    fn join_next(&mut self) {
+        match self.candidates.get(self.next_candidate) {
+            Some(candidate) => {
+                self.next_candidate += 1;
+                let mut command = AtCommand::new().with_timeout(JOIN_TIMEOUT);
+                esp8266_connect_to_wifi(&mut command, &candidate.ssid, &candidate.pwd);
+                self.queue.push(command, Self::joined);
+                self.supervisor.enter(WifiState::Joining, self.clock.nanos());
+            },
+            None if self.candidates.size() == 0 => {
+                // The credentials were cleared in the meantime
+                self.start_provisioning();
+            },
+            None => {
+                // None of them worked, so start over
+                self.restart();
+            },
+        }
+    }
+
+    fn clear_candidates(&mut self) {
+        for mut candidate in self.candidates.into_iter() {
+            candidate.ssid.drop();
+            candidate.pwd.drop();
+        }
+        self.candidates.free();
+        self.candidates = Vector::new();
+        self.next_candidate = 0;
+    }
+
+    fn joined(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.join_next();
+            return;
+        }
+
+        let joined = self.candidates.get(self.next_candidate - 1);
+        self.supervisor.set_rssi(joined.and_then(|candidate| candidate.rssi));
+        self.clear_candidates();
+        self.supervisor.enter(WifiState::GotIp, self.clock.nanos());
+
+        let mut command = AtCommand::new();
+        esp8266_read_ip(&mut command);
+        self.queue.push(command, Self::addresses_read);
+    }
+
+    /// The answer to `AT+CIFSR`, which is only for the status.
+    fn addresses_read(&mut self, response: &AtResponse) {
+        if response.ok() {
+            let addresses = parse_addresses(&response.text);
+            self.supervisor.set_addresses(addresses.station_ip, addresses.station_mac);
+        }
+
+        // The config download shares the module with the server
+        let mut command = AtCommand::new();
+        esp8266_multiple_connections(&mut command, true);
+        self.queue.push(command, Self::mux_configured);
+    }
+
+    /// Start the server. The config is downloaded once it is
+    /// up, so the api works even if the download doesn't.
+    fn mux_configured(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.restart();
+            return;
+        }
+
+        self.streaming = get_stream_config().map(|config| config.protocol);
+        let connections = match self.streaming {
+            None => SERVER_CONNECTIONS,
+            Some(StreamProtocol::Dmx) => SERVER_CONNECTIONS - 2,
+            Some(StreamProtocol::Ddp) => SERVER_CONNECTIONS - 1,
+        };
+        let mut command = AtCommand::new();
+        esp8266_server_max_connections(&mut command, connections);
+        self.queue.push(command, Self::server_limited);
+    }
+
+    fn server_limited(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.restart();
+            return;
+        }
+
+        let mut command = AtCommand::new();
+        esp8266_create_server(&mut command, API_PORT);
+        self.queue.push(command, Self::server_started);
+    }
+
+    fn server_started(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.restart();
+            return;
+        }
+
+        let mut command = AtCommand::new();
+        esp8266_sntp_config(&mut command, &SNTP_SERVERS);
+        self.queue.push(command, Self::sntp_configured);
+    }
+
+    fn sntp_configured(&mut self, response: &AtResponse) {
+        // Older firmware doesn't know about sntp, in which
+        // case the time comes from config downloads only
+        self.sntp = response.ok();
+
+        // The module needs a moment to reach the servers
+        let now = self.clock.nanos();
+        self.sntp_target = now + SNTP_RETRY;
+        self.supervisor.enter(WifiState::Idle, now);
+        self.step = WifiStep::Serve;
+
+        // Download the config right away
+        self.refresh_target = now;
+
+        match self.streaming {
+            None => {},
+            Some(StreamProtocol::Dmx) => {
+                let mut command = AtCommand::new();
+                esp8266_listen_udp(&mut command, ARTNET_LINK, self.artnet.port());
+                self.queue.push(command, Self::stream_listening);
+
+                let mut command = AtCommand::new();
+                esp8266_listen_udp(&mut command, E131_LINK, self.e131.port());
+                self.queue.push(command, Self::stream_listening);
+            },
+            Some(StreamProtocol::Ddp) => {
+                let mut command = AtCommand::new();
+                esp8266_listen_udp(&mut command, DDP_LINK, self.ddp.port());
+                self.queue.push(command, Self::stream_listening);
+            },
+        }
+    }
+
+    fn stream_listening(&mut self, response: &AtResponse) {
+        // The api works without it, so carry on regardless
+        if !response.ok() {
+            debug_str(b"failed to listen for lighting software");
+        }
+    }
+
+    /// Whether lighting software talks to the wall on the link.
+    fn stream_link(&self, link: u8) -> bool {
+        return match self.streaming {
+            None => false,
+            Some(StreamProtocol::Dmx) => link == ARTNET_LINK || link == E131_LINK,
+            Some(StreamProtocol::Ddp) => link == DDP_LINK,
+        };
+    }
+
+    /// Hand a frame from lighting software to the pixel task.
+    fn receive_stream(&mut self, link: u8, datagram: &Str) {
+        // The universe and channels can change without a restart,
+        // but the protocol can't
+        let config = match get_stream_config() {
+            Some(config) if Some(config.protocol) == self.streaming => config,
+            _ => return,
+        };
+
+        let update = match (config.protocol, link) {
+            (StreamProtocol::Ddp, _) => self.ddp.receive(datagram, config.mapping),
+            (StreamProtocol::Dmx, ARTNET_LINK) => self.artnet.receive(datagram, &config),
+            (StreamProtocol::Dmx, _) => self.e131.receive(datagram, &config),
+        };
+        match update {
+            None => {},
+            Some(StreamUpdate::Frame(colors)) => control::set_stream_frame(colors, self.clock.nanos()),
+            Some(StreamUpdate::Terminated) => control::end_stream(self.clock.nanos()),
+        }
+    }
+
+    fn ap_configured(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.restart();
+            return;
+        }
+
+        // The server requires multiple connections
+        let mut command = AtCommand::new();
+        esp8266_multiple_connections(&mut command, true);
+        self.queue.push(command, Self::ap_mux_configured);
+    }
+
+    fn ap_mux_configured(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.restart();
+            return;
+        }
+
+        let mut command = AtCommand::new();
+        esp8266_create_server(&mut command, SETUP_PORT);
+        self.queue.push(command, Self::ap_server_started);
+    }
+
+    fn ap_server_started(&mut self, response: &AtResponse) {
+        if !response.ok() {
+            self.restart();
+            return;
+        }
+
+        self.scan_networks();
+        self.supervisor.enter(WifiState::Idle, self.clock.nanos());
+        self.step = WifiStep::Serve;
+    }
+
+    fn scan_networks(&mut self) {
+        let mut command = AtCommand::new().with_timeout(SCAN_TIMEOUT);
+        esp8266_list_wifi(&mut command);
+        self.queue.push(command, Self::networks_scanned);
+    }
+
+    fn networks_scanned(&mut self, response: &AtResponse) {
+        if response.ok() {
+            for mut network in self.networks.into_iter() {
+                network.drop();
+            }
+            self.networks.free();
+            self.networks = parse_network_list(&response.text);
+            debug_u64(self.networks.size() as u64, b"networks found");
+        }
+
+        // Someone asked for the scan, so show them the results
+        match self.scan_link.take() {
+            None => {},
+            Some(link) => self.respond_setup_page(link),
+        }
+    }
+
+    /// The answer to a liveness probe. Errors still mean the
+    /// module is alive.
+    fn probed(&mut self, response: &AtResponse) {
+        if self.supervisor.probe_answered(response.result != AtResult::Timeout) {
+            debug_str(b"module stopped answering");
+            self.restart();
+        }
+    }
+
+    /// The answer to `AT+CIPSNTPTIME?`
+    fn time_received(&mut self, response: &AtResponse) {
+        let now = self.clock.nanos();
+        let mut date = response.line(b"+CIPSNTPTIME:").unwrap_or(Str::new());
+        let time = parse_date(&date);
+        date.drop();
+
+        match time {
+            Some(time_s) if response.ok() && time_s >= MIN_VALID_TIME_S => {
+                // Only whole seconds are reported
+                sync_world_time(&self.clock, time_s * S_TO_NANO + S_TO_NANO / 2);
+                self.sntp_synced_at = Some(now);
+                self.sntp_target = now + SNTP_INTERVAL;
+            },
+            _ => {
+                self.sntp_target = now + SNTP_RETRY;
+            }
+        }
+    }
+
+    /// Answer the next request which has fully arrived.
+    fn serve_request(&mut self) {
+        match self.requests.next_request() {
+            None => {},
+            Some((link, Err(status))) => {
+                let mut response = ServerResponse::text(status, status.line());
+                self.respond(link, &response);
+                response.drop();
+            },
+            Some((link, Ok(mut request))) => {
+                if self.provisioning {
+                    self.handle_setup_request(&request);
                 } else {
-                    gate.reset();
-                    buf.clear();
-                    return false;
+                    let mut response = dispatch(&api_routes(), &mut self.clock, &request);
+                    self.respond(link, &response);
+                    response.drop();
                 }
-            }, || {
-                unsafe { INITIALIZED = true };
-            })
-            .when(|_gate| {
+                request.drop();
+            }
+        }
+    }
+
+    /// Keep the connection to the mqtt broker going, and tell
+    /// Home Assistant about anything that changed.
+    fn serve_mqtt(&mut self, now: uNano) {
+        let url = get_mqtt_url();
+        let url_changed = match (url, self.mqtt_url) {
+            (Some(url), Some(used)) => url != used,
+            (None, None) => false,
+            _ => true,
+        };
+
+        if url_changed && self.mqtt.busy() {
+            self.mqtt.disconnect(&mut self.queue);
+            self.mqtt_failures = 0;
+            self.mqtt_target = now;
+        }
+
+        if !self.mqtt.busy() {
+            if url.is_some() && now > self.mqtt_target {
+                self.start_mqtt();
+            }
+            return;
+        }
+
+        while let Some(event) = self.mqtt.poll(&mut self.queue, &self.clock) {
+            match event {
+                MqttEvent::Connected => {
+                    self.mqtt_failures = 0;
+                    self.mqtt_connected();
+                },
+                MqttEvent::Message { mut topic, mut payload } => {
+                    let replies = match self.home_assistant.as_mut() {
+                        None => Vector::new(),
+                        Some(home_assistant) => home_assistant.handle_message(&topic, &payload),
+                    };
+                    self.publish_all(replies);
+                    topic.drop();
+                    payload.drop();
+                },
+                MqttEvent::Disconnected(_) => {
+                    debug_str(b"mqtt disconnected");
+                    self.mqtt_failures += 1;
+                    self.mqtt_target = now + backoff_delay(&mut self.random, self.mqtt_failures);
+                },
+            }
+        }
+
+        if self.mqtt.connected() {
+            let changes = match self.home_assistant.as_mut() {
+                None => Vector::new(),
+                Some(home_assistant) => home_assistant.state_changes(),
+            };
+            self.publish_all(changes);
+        }
+    }
+
+    /// Connect to the configured broker. The will tells Home
+    /// Assistant the wall is gone if it drops off the network.
+    fn start_mqtt(&mut self) {
+        match self.mqtt_url.as_mut() {
+            None => {},
+            Some(url) => url.drop(),
+        }
+        self.mqtt_url = get_mqtt_url().map(|url| Str::from_str(&url));
+
+        let mut broker = match self.mqtt_url.as_ref().and_then(Broker::parse) {
+            Some(broker) => broker,
+            None => {
+                debug_str(b"mqtt url invalid");
+                self.mqtt_failures += 1;
+                self.mqtt_target = self.clock.nanos() + backoff_delay(&mut self.random, self.mqtt_failures);
+                return;
+            }
+        };
+
+        // The mac address may not have been known last time
+        match self.home_assistant.as_mut() {
+            None => {},
+            Some(home_assistant) => home_assistant.drop(),
+        }
+        let home_assistant = HomeAssistant::new(self.supervisor.stats().mac);
+        let mut topic = home_assistant.availability_topic();
+        let mut payload = Str::with_content(b"offline");
+        let will = Will { topic: &topic, payload: &payload, retain: true };
+        self.mqtt.connect(&mut self.queue, &self.clock, &broker, home_assistant.node_id(), MQTT_KEEPALIVE_S, Some(will));
+        self.home_assistant = Some(home_assistant);
+
+        topic.drop();
+        payload.drop();
+        broker.drop();
+    }
+
+    /// Subscribe to commands and announce the wall.
+    fn mqtt_connected(&mut self) {
+        let (mut subscriptions, announcement) = match self.home_assistant.as_mut() {
+            None => return,
+            Some(home_assistant) => (home_assistant.subscriptions(), home_assistant.announce()),
+        };
+
+        for mut filter in subscriptions.into_iter() {
+            self.mqtt.subscribe(&mut self.queue, &self.clock, &filter);
+            filter.drop();
+        }
+        subscriptions.free();
+        self.publish_all(announcement);
+    }
+
+    fn publish_all(&mut self, mut messages: Vector<Outgoing>) {
+        for mut message in messages.into_iter() {
+            if !self.mqtt.publish(&mut self.queue, &self.clock, &message.topic, &message.payload, message.retain) {
+                debug_str(b"mqtt message not sent");
+            }
+            message.drop();
+        }
+        messages.free();
+    }
+
+    /// Ask the server for the config. What was learned from the
+    /// last download goes along, so the server can answer 304
+    /// if nothing changed.
+    fn start_download(&mut self) -> bool {
+        let mut url = match get_config_url() {
+            None => Str::with_content(DEFAULT_CONFIG_URL),
+            Some(url) => Str::from_str(&url),
+        };
+        let started = self.client.get(&mut self.queue, &self.clock, &url, DOWNLOAD_TIMEOUT).is_ok();
+        url.drop();
+
+        if !started {
+            debug_str(b"config url invalid");
+            self.download_failed();
+            return false;
+        }
+
+        self.supervisor.enter(WifiState::Fetching, self.clock.nanos());
+
+        match self.etag {
+            None => {},
+            Some(etag) => self.client.add_header(b"If-None-Match", &etag),
+        }
+        match self.last_modified {
+            None => {},
+            Some(date) => self.client.add_header(b"If-Modified-Since", &date),
+        }
+        return true;
+    }
+
+    /// Check on the config download. Returns whether it worked
+    /// once it is over, and schedules the next one.
+    fn finish_download(&mut self) -> Option<bool> {
+        let success = match self.client.poll(&mut self.queue, &self.clock) {
+            ClientResult::Pending => return None,
+            ClientResult::Done(mut response) => {
+                let success = self.accept_config(&response);
+                response.drop();
+                success
+            },
+            ClientResult::Failed(_) => {
+                debug_str(b"config download failed");
+                false
+            }
+        };
+
+        if success {
+            self.download_failures = 0;
+            self.refresh_target = self.clock.nanos() + REFRESH_INTERVAL;
+        } else {
+            self.download_failed();
+        }
+        return Some(success);
+    }
+
+    /// Use the time from a config download, unless sntp is
+    /// working. The config's own time line is more precise than
+    /// the Date header, which only has whole seconds.
+    fn sync_http_time(&mut self, config_time_ms: Option<uNano>, response: &HttpResponse) {
+        match self.sntp_synced_at {
+            Some(synced_at) if self.clock.nanos() < synced_at + SNTP_STALE => return,
+            _ => {},
+        }
+
+        let time_ns = match config_time_ms {
+            Some(time_ms) => Some(time_ms * MS_TO_NANO),
+            None => response.header(b"Date").and_then(|date| parse_date(&date)).map(|time_s| time_s * S_TO_NANO + S_TO_NANO / 2),
+        };
+
+        match time_ns {
+            None => {},
+            Some(time_ns) => {
+                sync_world_time(&self.clock, time_ns);
+            }
+        }
+    }
+
+    fn download_failed(&mut self) {
+        self.download_failures += 1;
+        self.supervisor.download_failed();
+        self.refresh_target = self.clock.nanos() + backoff_delay(&mut self.random, self.download_failures);
+    }
+
+    /// Use the response to a config download. The new config
+    /// only replaces the current one if all of it could be
+    /// read.
+    fn accept_config(&mut self, response: &HttpResponse) -> bool {
+        if response.status == 304 {
+            // Nothing changed since the last download
+            self.sync_http_time(None, response);
+            return get_shader_configs().size() > 0;
+        }
+
+        match parse_config(response) {
+            Ok(update) if update.shaders.size() > 0 => {
+                self.sync_http_time(update.world_time_ms, response);
+                remember_header(&mut self.etag, response.header(b"ETag"));
+                remember_header(&mut self.last_modified, response.header(b"Last-Modified"));
+                self.apply_config(update);
+                return true;
+            },
+            Ok(mut update) => {
+                debug_str(b"config was empty");
+                update.shaders.drop();
                 return false;
-            }, || {
-                
-            })
-            .sealed()
-            .compile();
+            },
+            Err(status) => {
+                debug_u64(status as u64, b"config request failed");
+                return false;
+            }
+        }
+    }
+
+    fn apply_config(&mut self, mut update: ConfigUpdate) {
+        match update.world_time_ms {
+            None => {},
+            Some(time_ms) => {
+                self.random.seed((time_ms / 1000) as u64);
+            }
+        }
+
+        // Only touch settings which changed, so the flash
+        // isn't rewritten on every download
+        match update.transition_delay {
+            Some(delay) if delay != get_tranasition_delay() => set_transition_delay(delay),
+            _ => {},
+        }
+
+        if update.shaders.matches(get_shader_configs()) {
+            update.shaders.drop();
+        } else {
+            set_shader_configs(update.shaders);
+            update.shaders.configs.free();
+        }
+    }
+
+    /// Route a request made to the setup page.
+    fn handle_setup_request(&mut self, request: &IncomingRequest) {
+        if request.method == HttpMethod::Get && request.path_is(b"/scan") {
+            // Answer once the scan completes
+            self.scan_link = Some(request.link);
+            self.scan_networks();
+            return;
+        }
+
+        let routes = [
+            Route { method: HttpMethod::Get, path: b"/", handler: Self::setup_page },
+            Route { method: HttpMethod::Post, path: b"/save", handler: Self::save_credentials },
+        ];
+
+        let mut response = dispatch(&routes, self, request);
+        self.respond(request.link, &response);
+        response.drop();
+    }
+
+    fn setup_page(&mut self, _request: &IncomingRequest) -> ServerResponse {
+        return ServerResponse::new(HttpStatus::Ok, b"text/html", render_setup_page(&self.networks));
+    }
+
+    fn save_credentials(&mut self, request: &IncomingRequest) -> ServerResponse {
+        match parse_credentials(request) {
+            Some((mut ssid, mut pwd)) => {
+                set_wifi_credentials(&ssid, &pwd);
+                let page = render_saved_page(&ssid);
+                ssid.drop();
+                pwd.drop();
+
+                // Give the page a moment to go out before
+                // the access point disappears.
+                self.provisioning = false;
+                self.settle_target = self.clock.nanos() + 2 * S_TO_NANO;
+                self.step = WifiStep::ApFinish;
+                return ServerResponse::new(HttpStatus::Ok, b"text/html", page);
+            },
+            None => {
+                return ServerResponse::new(HttpStatus::BadRequest, b"text/html", render_setup_page(&self.networks));
+            }
+        }
+    }
+
+    fn respond_setup_page(&mut self, link: u8) {
+        let mut response = ServerResponse::new(HttpStatus::Ok, b"text/html", render_setup_page(&self.networks));
+        self.respond(link, &response);
+        response.drop();
+    }
+
+    fn respond(&mut self, link: u8, response: &ServerResponse) {
+        self.queue.push(AtCommand::send(Some(link), http_response(response)), ignore_response);
+        let mut command = AtCommand::new();
+        esp8266_close_tcp(&mut command, Some(link));
+        self.queue.push(command, ignore_response);
+        self.requests.reset(link);
+    }
+
+    /// Power cycle the ESP8266 using the enable and reset pins.
+    fn reset_module(&mut self) {
+        self.gpio.mode(RST_PIN, Mode::Output);
+        self.gpio.write(EN_PIN, Power::Low);
+        self.clock.wait_ns(5 * teensycore::MS_TO_NANO);
+        self.gpio.write(EN_PIN, Power::High);
+        self.gpio.write(RST_PIN, Power::Low);
+        self.clock.wait_ns(5 * teensycore::MS_TO_NANO);
+        self.gpio.write(RST_PIN, Power::High);
+        self.clock.wait_ns(5 * teensycore::MS_TO_NANO);
+        self.gpio.mode(RST_PIN, Mode::Input);
+        debug_str(b"reset");
     }
 }
 
-fn parse_config(serial_content: &Str) -> ShaderConfigList {
-    // Parse the http headers
+impl<S: SerialPort, C: Clock, G: Gpio, R: RandomSource> HttpContext for WifiTask<S, C, G, R> {
+    fn http_client(&mut self) -> &mut HttpClient {
+        return &mut self.client;
+    }
+
+    fn command_queue(&mut self) -> &mut CommandQueue<Self> {
+        return &mut self.queue;
+    }
+}
+
+impl<S: SerialPort, C: Clock, G: Gpio, R: RandomSource> MqttContext for WifiTask<S, C, G, R> {
+    fn mqtt_client(&mut self) -> &mut MqttClient {
+        return &mut self.mqtt;
+    }
+
+    fn command_queue(&mut self) -> &mut CommandQueue<Self> {
+        return &mut self.queue;
+    }
+}
+
+/// Everything a config download can change. Nothing is
+/// applied until the whole response has been read.
+struct ConfigUpdate {
+    /// Unix epoch in milliseconds
+    world_time_ms: Option<uNano>,
+    transition_delay: Option<uNano>,
+    shaders: ShaderConfigList,
+}
+
+/// Read the config out of the response to the config request.
+/// Only a 200 response is accepted, anything else returns its
+/// status.
+fn parse_config(response: &HttpResponse) -> Result<ConfigUpdate, u16> {
+    if response.status != 200 {
+        return Err(response.status);
+    }
+
     let mut time_cmd = str!(b"time");
     let mut rule_cmd = str!(b"rule");
     let mut delay_cmd = str!(b"delay");
+    let mut update = ConfigUpdate {
+        world_time_ms: None,
+        transition_delay: None,
+        shaders: ShaderConfigList::new(),
+    };
 
-    let mut header = Str::new();
-    let mut content = Str::new();
-    let mut configs = Vector::new();
-
-    if parse_http_request(serial_content, &mut header, &mut content) {
-        let mut lines = content.split(b'\n');
-        for line in lines.into_iter() {
-            let mut paths = line.split(b';');
-            match paths.get(0) {
-                None => {},
-                Some(command) => {
-
-                    if command.contains(&time_cmd) && paths.size() > 1 {
-                        // Parse world time
-                        let epoch = (atoi(&paths.get(1).unwrap()) / 1000) as uNano;
-                        set_world_time(epoch);
-                        seed_rand(epoch as u64);
-                    } else if command.contains(&delay_cmd) && paths.size() > 1 {
-                        // Parse transition delay (global setting)
-                        let delay = atoi(&paths.get(1).unwrap()) as uNano;
-                        set_transition_delay(delay);
-                    } else if command.contains(&rule_cmd) && paths.size() > 3 {
-                        // Parse the shader rule entries
-                        let config = ShaderConfig { 
-                            time_range_start: atoi(&paths.get(1).unwrap()) as uNano, 
-                            time_range_end: atoi(&paths.get(2).unwrap()) as uNano, 
-                            shader: paths.get(3).unwrap(), 
-                            probability: atoi(&paths.get(4).unwrap()) as u64,
-                        };
-
-                        configs.push(config);
-                    }
+    let mut lines = split(&response.body, b'\n');
+    for mut line in lines.into_iter() {
+        let mut paths = split(&line, b';');
+        match paths.get(0) {
+            None => {},
+            Some(command) => {
+
+                if command.contains(&time_cmd) && paths.size() > 1 {
+                    // Parse world time
+                    update.world_time_ms = Some(atoi(&paths.get(1).unwrap()) as uNano);
+                } else if command.contains(&delay_cmd) && paths.size() > 1 {
+                    // Parse transition delay (global setting)
+                    update.transition_delay = Some(atoi(&paths.get(1).unwrap()) as uNano);
+                } else if command.contains(&rule_cmd) && paths.size() > 4 {
+                    // Parse the shader rule entries
+                    let config = ShaderConfig { 
+                        time_range_start: atoi(&paths.get(1).unwrap()) as uNano, 
+                        time_range_end: atoi(&paths.get(2).unwrap()) as uNano, 
+                        shader: Str::from_str(&paths.get(3).unwrap()), 
+                        probability: atoi(&paths.get(4).unwrap()) as u64,
+                    };
+
+                    update.shaders.add_config(config);
                 }
             }
-            paths.free();
+        }
 
+        for mut path in paths.into_iter() {
+            path.drop();
         }
-        lines.free();
+        paths.free();
+        line.drop();
     }
+    lines.free();
 
     time_cmd.drop();
     delay_cmd.drop();
     rule_cmd.drop();
-    header.drop();
-    content.drop();
 
-    return ShaderConfigList {
-        configs: configs,
+    return Ok(update);
+}
+
+/// Keep a copy of a header from the last download.
+fn remember_header(slot: &mut Option<Str>, value: Option<Str>) {
+    match slot.as_mut() {
+        None => {},
+        Some(previous) => previous.drop(),
     }
+    *slot = value.map(|value| Str::from_str(&value));
 }
 
-fn ready(gate: &mut Gate) -> bool { return rx_contains(gate, unsafe { &READY }, true); }
-fn ok(gate: &mut Gate) -> bool { return rx_contains(gate, unsafe { &OK }, true); }
-fn send_ok(gate: &mut Gate) -> bool { return rx_contains(gate, unsafe { &SEND_OK }, false); }
-fn rx_contains(gate: &mut Gate, cond: &Option<Str>, clear: bool) -> bool {
-    match unsafe { &ERROR } {
-        // I know you think this logic is wrong, but it's not
-        None => {
-            return false;
-        },
-        Some(target) => {
-            if serial_read(DEVICE).contains(&target) {
-                gate.reset();
-                serial_read(DEVICE).clear();
-                return false;
-            }
+/// How long to wait before trying again after this many
+/// failures in a row. The delay doubles each time, and a random
+/// part of up to half of it keeps many walls from retrying in
+/// lockstep after an outage.
+fn backoff_delay<R: RandomSource>(random: &mut R, failures: u32) -> uNano {
+    let mut delay = RETRY_MIN;
+    for _ in 1 .. failures {
+        delay = (delay * 2).min(RETRY_MAX);
+    }
+
+    let spread = (delay / 2 / MS_TO_NANO) as u64;
+    let jitter = (random.rand() % (spread + 1)) as uNano * MS_TO_NANO;
+    return delay / 2 + jitter;
+}
+
+#[cfg(test)]
+pub mod test_wifi_task {
+    use super::*;
+    use crate::drivers::esp8266::emulator::*;
+    use crate::hal::mock::*;
+    use std::prelude::rust_2021::*;
+
+    // The credentials are global, so tests which depend
+    // on them take turns.
+    static CREDENTIALS_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    const CONFIG: &[u8] = b"time;1646690021723\nrule;1641024000;7952371200;Medbay;6\nrule;1641024000;7952371200;Rainbow;3\n";
+
+    fn respond(task: &mut WifiTask<MockSerial, &MockClock, MockGpio, MockRandom>, serial: &MockSerial, response: &[u8]) {
+        serial.receive(response);
+        task.system_loop();
+    }
+
+    #[test]
+    fn test_wifi_downloads_config() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let gpio = MockGpio::new();
+        let mut task = WifiTask::new(serial.clone(), &clock, gpio.clone(), MockRandom::new(1));
+
+        // World time is global, so start from scratch
+        set_world_time(&clock, 0);
+        task.init();
+        assert!(serial.initialized());
+        assert_eq!(serial.baud(), 115200);
+
+        // The module is power cycled and asked for its version
+        task.system_loop();
+        assert_eq!(gpio.output(EN_PIN), Some(true));
+        assert_eq!(gpio.output(RST_PIN), Some(true));
+        assert_eq!(serial.take_written(), "AT+GMR\r\n");
+
+        respond(&mut task, &serial, b"ready\r\n");
+        assert_eq!(serial.take_written(), "ATE1\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CWMODE=1\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CWAUTOCONN=0\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CWDHCP=1,1\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPDNS=0\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CWLAP\r\n");
+        respond(&mut task, &serial, b"+CWLAP:(3,\"Hexnet\",-61,\"aa:bb:cc:dd:ee:ff\",6,-22,0)\r\n\r\nOK\r\n");
+        assert!(serial.take_written().starts_with("AT+CWJAP=\"Hexnet\",\"hunter2\""));
+        respond(&mut task, &serial, b"WIFI CONNECTED\r\nWIFI GOT IP\r\nOK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIFSR\r\n");
+        respond(&mut task, &serial, b"+CIFSR:STAIP,\"192.168.1.50\"\r\n+CIFSR:STAMAC,\"a4:2b:b0:c1:d2:ff\"\r\n\r\nOK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPMUX=1\r\n");
+
+        // The api is served as soon as the wall has an address
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPSERVERMAXCONN=3\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPSERVER=1,80\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPSNTPCFG=1,0,\"pool.ntp.org\",\"time.google.com\"\r\n");
+
+        // Then the config is downloaded
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPSTART=4,\"TCP\",\"52.27.143.19\",80\r\n");
+        respond(&mut task, &serial, b"4,CONNECT\r\nOK\r\n");
+        assert!(serial.take_written().starts_with("AT+CIPSEND=4,"));
+
+        // The request goes out once the module asks for it
+        respond(&mut task, &serial, b"OK\r\n> ");
+        assert!(serial.take_written().starts_with("GET /hexwall HTTP/1.1"));
+        respond(&mut task, &serial, b"Recv 90 bytes\r\n\r\nSEND OK\r\n");
+        assert!(!task.ready);
+
+        // Deliver the response
+        serial.receive(&ipd(&config_response(b"", CONFIG)));
+        clock.advance(2 * S_TO_NANO);
+        task.system_loop();
+
+        assert!(task.ready);
+        assert_eq!(get_shader_configs().size(), 2);
+        assert_eq!(get_world_time(&clock), 1646690021);
+        assert_eq!(serial.take_written(), "AT+CIPCLOSE=4\r\n");
+        respond(&mut task, &serial, b"4,CLOSED\r\nERROR\r\n");
+        assert!(task.ready);
+
+        serial.receive(b"0,CONNECT\r\n");
+        task.system_loop();
+        let response = serve(&mut task, &serial, 0, b"GET /api/status HTTP/1.1\r\nHost: hexwall\r\n\r\n");
+        assert!(response.starts_with("AT+CIPSEND=0,"));
+        assert!(response.contains("HTTP/1.1 200 OK\r\nContent-Type: application/json"));
+        assert!(response.contains("\"world_time\":1646690021"));
+        assert!(response.ends_with("AT+CIPCLOSE=0\r\n"));
+
+        assert!(serve(&mut task, &serial, 1, b"POST /api/brightness?value=300 HTTP/1.1\r\n\r\n").contains("HTTP/1.1 400 Bad Request"));
+
+        // The setup page is gone once provisioned
+        assert!(serve(&mut task, &serial, 2, b"GET / HTTP/1.1\r\n\r\n").contains("HTTP/1.1 404 Not Found"));
+    }
+
+    /// Walk through joining the network and starting the server
+    /// up to the config request. `sntp` is how the module answers
+    /// the sntp config.
+    fn request_config(task: &mut WifiTask<MockSerial, &MockClock, MockGpio, MockRandom>, serial: &MockSerial, sntp: &[u8]) {
+        task.system_loop();
+        for response in [b"ready\r\n" as &[u8], b"OK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n", b"WIFI GOT IP\r\nOK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n", sntp] {
+            respond(task, serial, response);
         }
+        assert!(serial.take_written().ends_with("AT+CIPSTART=4,\"TCP\",\"52.27.143.19\",80\r\n"));
+        fetch_config(task, serial);
     }
 
-    match unsafe { &FAIL } {
-        // I know you think this logic is wrong, but it's not
-        None => {
-            return false;
-        },
-        Some(target) => {
-            if serial_read(DEVICE).contains(&target) {
-                gate.reset();
-                serial_read(DEVICE).clear();
-                return false;
-            }
+    /// Play the module's part in sending the config request.
+    fn fetch_config(task: &mut WifiTask<MockSerial, &MockClock, MockGpio, MockRandom>, serial: &MockSerial) {
+        for response in [b"4,CONNECT\r\nOK\r\n" as &[u8], b"OK\r\n> ", b"SEND OK\r\n"] {
+            respond(task, serial, response);
         }
+        assert!(serial.take_written().contains("GET /hexwall HTTP/1.1"));
     }
 
-    match unsafe { &BAD_REQUEST } {
-        None => {
-            return false;
-        },
-        Some(target) => {
-            if serial_read(DEVICE).contains(&target) {
-                gate.reset();
-                serial_read(DEVICE).clear();
-                return false;
-            }
+    /// A 200 response with the given extra headers.
+    fn config_response(headers: &[u8], config: &[u8]) -> Vec<u8> {
+        let mut response = b"HTTP/1.1 200 OK\r\n".to_vec();
+        response.extend_from_slice(headers);
+        response.extend_from_slice(b"Content-Length: ");
+        response.extend_from_slice(config.len().to_string().as_bytes());
+        response.extend_from_slice(b"\r\n\r\n");
+        response.extend_from_slice(config);
+        return response;
+    }
+
+    /// Wrap a payload the way it arrives on the config connection.
+    fn ipd(payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(b"\r\n+IPD,4,");
+        packet.extend_from_slice(payload.len().to_string().as_bytes());
+        packet.extend_from_slice(b":");
+        packet.extend_from_slice(payload);
+        return packet;
+    }
+
+    #[test]
+    fn test_wifi_config_responses() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut task = WifiTask::new(serial.clone(), &clock, MockGpio::new(), MockRandom::new(1));
+        task.init();
+
+        // An error page is not a config, even if it looks like one
+        request_config(&mut task, &serial, b"OK\r\n");
+        let mut page = b"HTTP/1.1 404 Not Found\r\nContent-Length: ".to_vec();
+        page.extend_from_slice(CONFIG.len().to_string().as_bytes());
+        page.extend_from_slice(b"\r\n\r\n");
+        page.extend_from_slice(CONFIG);
+        serial.receive(&ipd(&page));
+        clock.advance(2 * S_TO_NANO);
+        task.system_loop();
+        assert!(!task.ready);
+        assert_eq!(serial.take_written(), "AT+CIPCLOSE=4\r\n");
+        respond(&mut task, &serial, b"4,CLOSED\r\nOK\r\n");
+        assert!(task.stats().state == WifiState::Failed);
+
+        // The api works without a config
+        assert!(serve(&mut task, &serial, 0, b"GET /api/status HTTP/1.1\r\n\r\n").contains("HTTP/1.1 200 OK"));
+
+        // It tries again after a while, without restarting
+        task.system_loop();
+        assert_eq!(serial.take_written(), "");
+        clock.advance(RETRY_MIN);
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+CIPSTART=4,\"TCP\",\"52.27.143.19\",80\r\n");
+
+        // A chunked config which arrives in pieces
+        fetch_config(&mut task, &serial);
+        serial.receive(&ipd(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"));
+        serial.receive(&ipd(b"5c\r\ntime;1646690021723\nrule;1641024000;7952371200;Medbay;6\nrule;1641024000;7952371200;Rai"));
+        clock.advance(2 * S_TO_NANO);
+        task.system_loop();
+        task.system_loop();
+        task.system_loop();
+        assert!(!task.ready);
+        assert_eq!(serial.take_written(), "");
+
+        serial.receive(&ipd(b"nbow;3\n\r\n0\r\n\r\n"));
+        task.system_loop();
+        assert!(task.ready);
+        assert_eq!(get_shader_configs().size(), 2);
+    }
+
+    #[test]
+    fn test_wifi_config_url() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+        set_config_url(&str!(b"http://config.example:8080/wall?id=3"));
+
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut task = WifiTask::new(serial.clone(), &clock, MockGpio::new(), MockRandom::new(1));
+        task.init();
+        task.system_loop();
+        for response in [b"ready\r\n" as &[u8], b"OK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n", b"WIFI GOT IP\r\nOK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n", b"OK\r\n"] {
+            respond(&mut task, &serial, response);
         }
+        serial.take_written();
+
+        // The host name is resolved first
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPDOMAIN=\"config.example\"\r\n");
+        respond(&mut task, &serial, b"+CIPDOMAIN:\"10.0.0.7\"\r\nOK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPSTART=4,\"TCP\",\"10.0.0.7\",8080\r\n");
+        respond(&mut task, &serial, b"4,CONNECT\r\nOK\r\n");
+        respond(&mut task, &serial, b"OK\r\n> ");
+        assert!(serial.take_written().contains("GET /wall?id=3 HTTP/1.1\r\nHost: config.example:8080\r\n"));
+
+        // Nothing comes back, so it gives up and tries again later
+        clear_config_url();
+        respond(&mut task, &serial, b"SEND OK\r\n");
+        clock.advance(DOWNLOAD_TIMEOUT + 1);
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+CIPCLOSE=4\r\n");
+        respond(&mut task, &serial, b"4,CLOSED\r\nOK\r\n");
+        clock.advance(RETRY_MIN);
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+CIPSTART=4,\"TCP\",\"52.27.143.19\",80\r\n");
     }
 
-    match cond {
-        None => {
-            return false;
-        },
-        Some(target) => {
-            if serial_read(DEVICE).contains(&target) {
-                if clear {
-                    serial_read(DEVICE).clear();
-                }
-                return true;
-            } else {
-                return false;
-            }
+    #[test]
+    fn test_wifi_refreshes_config() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut task = WifiTask::new(serial.clone(), &clock, MockGpio::new(), MockRandom::new(1));
+        set_world_time(&clock, 0);
+        task.init();
+
+        // This module has no sntp, so time comes from the downloads
+        request_config(&mut task, &serial, b"ERROR\r\n");
+        serial.receive(&ipd(&config_response(b"ETag: \"v1\"\r\nLast-Modified: Tue, 08 Mar 2022 21:53:41 GMT\r\n", CONFIG)));
+        task.system_loop();
+        respond(&mut task, &serial, b"4,CLOSED\r\nERROR\r\n");
+        assert!(task.ready);
+        serial.take_written();
+
+        // Nothing happens until the refresh is due, other than
+        // checking the module is still there
+        clock.advance(REFRESH_INTERVAL / 2);
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT\r\n");
+        respond(&mut task, &serial, b"AT\r\n\r\nOK\r\n");
+        assert_eq!(serial.take_written(), "");
+
+        // Unchanged configs are not downloaded again
+        clock.advance(REFRESH_INTERVAL);
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+CIPSTART=4,\"TCP\",\"52.27.143.19\",80\r\n");
+        respond(&mut task, &serial, b"4,CONNECT\r\nOK\r\n");
+        respond(&mut task, &serial, b"OK\r\n> ");
+        let sent = serial.take_written();
+        assert!(sent.contains("If-None-Match: \"v1\"\r\n"));
+        assert!(sent.contains("If-Modified-Since: Tue, 08 Mar 2022 21:53:41 GMT\r\n"));
+        respond(&mut task, &serial, b"SEND OK\r\n");
+        serial.receive(&ipd(b"HTTP/1.1 304 Not Modified\r\nDate: Tue, 08 Mar 2022 21:53:41 GMT\r\n\r\n"));
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+CIPCLOSE=4\r\n");
+        assert_eq!(get_shader_configs().size(), 2);
+        assert_eq!(get_world_time(&clock), 1646776421);
+
+        // The server picks up where it left off
+        respond(&mut task, &serial, b"4,CLOSED\r\nOK\r\n");
+        assert!(serve(&mut task, &serial, 0, b"GET /api/status HTTP/1.1\r\n\r\n").contains("HTTP/1.1 200 OK"));
+
+        // A new config replaces the old one
+        clock.advance(REFRESH_INTERVAL + S_TO_NANO);
+        task.system_loop();
+        respond(&mut task, &serial, b"4,CONNECT\r\nOK\r\n");
+        respond(&mut task, &serial, b"OK\r\n> ");
+        respond(&mut task, &serial, b"SEND OK\r\n");
+        serial.receive(&ipd(&config_response(b"ETag: \"v2\"\r\n", b"time;1646690021723\nrule;1641024000;7952371200;Medbay;255\n")));
+        task.system_loop();
+        assert_eq!(get_shader_configs().size(), 1);
+        assert!(task.ready);
+        serial.take_written();
+
+        // Failures back off before trying again
+        clock.advance(REFRESH_INTERVAL + S_TO_NANO);
+        task.system_loop();
+        assert!(serial.take_written().starts_with("AT+CIPSTART=4,"));
+        respond(&mut task, &serial, b"ERROR\r\n");
+        task.system_loop();
+        assert_eq!(serial.take_written(), "");
+        clock.advance(RETRY_MIN);
+        task.system_loop();
+        assert!(serial.take_written().starts_with("AT+CIPSTART=4,"));
+        assert!(task.ready);
+        assert_eq!(get_shader_configs().size(), 1);
+    }
+
+    #[test]
+    fn test_wifi_sntp() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut task = WifiTask::new(serial.clone(), &clock, MockGpio::new(), MockRandom::new(1));
+        set_world_time(&clock, 0);
+        task.init();
+        request_config(&mut task, &serial, b"OK\r\n");
+        serial.receive(&ipd(&config_response(b"", CONFIG)));
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+CIPCLOSE=4\r\n");
+        respond(&mut task, &serial, b"4,CLOSED\r\nERROR\r\n");
+        assert_eq!(get_world_time(&clock), 1646690021);
+
+        // The module hasn't reached a server yet
+        clock.advance(SNTP_RETRY + S_TO_NANO);
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+CIPSNTPTIME?\r\n");
+        respond(&mut task, &serial, b"+CIPSNTPTIME:Thu Jan 01 00:00:03 1970\r\nOK\r\n");
+        task.system_loop();
+        assert_eq!(serial.take_written(), "");
+
+        // A request which arrives alongside the answer is served too
+        clock.advance(SNTP_RETRY + S_TO_NANO);
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+CIPSNTPTIME?\r\n");
+        serial.receive(b"AT+CIPSNTPTIME?\r\n+CIPSNTPTIME:Tue Mar 08 21:53:41 2022\r\nOK\r\n");
+        let response = serve(&mut task, &serial, 0, b"GET /api/status HTTP/1.1\r\n\r\n");
+        assert_eq!(get_world_time(&clock), 1646776421);
+        assert!(response.contains("HTTP/1.1 200 OK"));
+        assert!(response.contains("\"world_time\":1646776421"));
+
+        // While sntp works, the time from downloads is ignored
+        clock.advance(SNTP_INTERVAL);
+        task.system_loop();
+        assert!(serial.take_written().starts_with("AT+CIPSTART=4,"));
+        respond(&mut task, &serial, b"4,CONNECT\r\nOK\r\n");
+        respond(&mut task, &serial, b"OK\r\n> ");
+        respond(&mut task, &serial, b"SEND OK\r\n");
+        serial.receive(&ipd(b"HTTP/1.1 304 Not Modified\r\nDate: Tue, 08 Mar 2022 20:00:00 GMT\r\n\r\n"));
+        task.system_loop();
+        assert!(get_world_time(&clock) >= 1646776421 + 3600);
+        assert!(serial.take_written().ends_with("AT+CIPCLOSE=4\r\n"));
+
+        // Small corrections are slewed in
+        respond(&mut task, &serial, b"4,CLOSED\r\nOK\r\n");
+        clock.advance(S_TO_NANO);
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+CIPSNTPTIME?\r\n");
+        respond(&mut task, &serial, b"+CIPSNTPTIME:Tue Mar 08 22:53:45 2022\r\nOK\r\n");
+        let world_time = get_world_time(&clock);
+        assert!(world_time >= 1646776421 + 3600 && world_time < 1646776421 + 3604);
+        clock.advance(1000 * S_TO_NANO);
+        assert_eq!(get_world_time(&clock), 1646776421 + 3604 + 1000);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let mut random = MockRandom::new(7);
+        for _ in 0 .. 20 {
+            let first = backoff_delay(&mut random, 1);
+            assert!(first >= RETRY_MIN / 2 && first <= RETRY_MIN);
+            let third = backoff_delay(&mut random, 3);
+            assert!(third >= 2 * RETRY_MIN && third <= 4 * RETRY_MIN);
+            let capped = backoff_delay(&mut random, 40);
+            assert!(capped >= RETRY_MAX / 2 && capped <= RETRY_MAX);
+        }
+
+        // The delays are spread out
+        let mut delays: Vec<uNano> = (0 .. 10).map(|_| backoff_delay(&mut random, 2)).collect();
+        delays.dedup();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn test_wifi_restarts_on_error() {
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut task = WifiTask::new(serial.clone(), &clock, MockGpio::new(), MockRandom::new(1));
+
+        task.init();
+        task.system_loop();
+        respond(&mut task, &serial, b"ready\r\n");
+        serial.take_written();
+
+        // An error sends the module back through a reset
+        respond(&mut task, &serial, b"ERROR\r\n");
+        assert_eq!(serial.take_written(), "AT+GMR\r\n");
+        assert!(!task.ready);
+    }
+
+    /// Let the task run against the emulator for a while, with
+    /// the clock moving along a little every time.
+    fn run(task: &mut WifiTask<EspEmulator, &MockClock, MockGpio, MockRandom>, clock: &MockClock, loops: usize) {
+        for _ in 0 .. loops {
+            task.system_loop();
+            clock.advance(10 * MS_TO_NANO);
+        }
+    }
+
+    #[test]
+    fn test_wifi_emulated_download() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let _render = crate::pixel_engine::golden::RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+
+        let clock = MockClock::new();
+        let gpio = MockGpio::new();
+        let esp = EspEmulator::new().with_reset_pin(gpio.clone(), RST_PIN);
+        esp.add_network("Hexnet", "hunter2", -52, 6);
+        esp.serve_file("http://52.27.143.19/hexwall", "config.txt");
+
+        // Small frames which trickle in
+        esp.set_frame_size(100);
+        esp.set_chunk_size(Some(37));
+
+        let mut task = WifiTask::new(esp.clone(), &clock, gpio, MockRandom::new(1));
+        set_world_time(&clock, 0);
+        task.init();
+        run(&mut task, &clock, 300);
+
+        assert!(task.ready);
+        assert_eq!(esp.boots(), 1);
+        assert_eq!(esp.joined(), Some(String::from("Hexnet")));
+        assert_eq!(esp.server_port(), Some(80));
+        assert_eq!(get_shader_configs().size(), 125);
+        assert!(get_world_time(&clock) >= 1646690021);
+
+        esp.request(0, b"GET /api/status HTTP/1.1\r\nHost: hexwall\r\n\r\n");
+        run(&mut task, &clock, 50);
+        let response = esp.take_sent(0);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\"world_time\":16466900"));
+        assert!(!esp.is_open(0));
+    }
+
+    #[test]
+    fn test_wifi_emulated_failures() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let _render = crate::pixel_engine::golden::RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+
+        let clock = MockClock::new();
+        let gpio = MockGpio::new();
+        let esp = EspEmulator::new().with_reset_pin(gpio.clone(), RST_PIN);
+        esp.serve_body("http://52.27.143.19/hexwall", CONFIG);
+        let mut task = WifiTask::new(esp.clone(), &clock, gpio, MockRandom::new(1));
+        task.init();
+
+        // The connection can't be opened, which leaves the api
+        // running
+        esp.once("AT+CIPSTART", Reply::text(b"ERROR\r\n"));
+        run(&mut task, &clock, 50);
+        assert!(!task.ready);
+        assert!(task.stats().state == WifiState::Failed);
+        assert_eq!(esp.server_port(), Some(80));
+        assert_eq!(esp.boots(), 1);
+
+        // The server goes away halfway through the response
+        esp.truncate_responses(Some(60));
+        clock.advance(RETRY_MAX);
+        run(&mut task, &clock, 50);
+        assert!(!task.ready);
+        assert_eq!(esp.boots(), 1);
+        assert!(esp.commands().iter().any(|command| command.starts_with("AT+CIPSEND=4,")));
+
+        // The module restarts and never answers the join
+        esp.truncate_responses(None);
+        esp.once("AT+CWJAP", Reply::Silent);
+        esp.inject(b"\r\nready\r\n");
+        run(&mut task, &clock, 50);
+        assert!(!task.ready);
+        assert_eq!(esp.boots(), 2);
+
+        // Until it does, along with some noise
+        esp.inject(b"\r\n\xff\xfe+IPD,garbage\r\n");
+        clock.advance(JOIN_TIMEOUT);
+        run(&mut task, &clock, 50);
+        assert!(task.ready);
+        assert_eq!(esp.boots(), 3);
+    }
+
+    #[test]
+    fn test_wifi_emulated_supervision() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let _render = crate::pixel_engine::golden::RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+
+        let clock = MockClock::new();
+        let gpio = MockGpio::new();
+        let esp = EspEmulator::new().with_reset_pin(gpio.clone(), RST_PIN);
+        esp.serve_body("http://52.27.143.19/hexwall", CONFIG);
+
+        // Without sntp, the module is quiet between downloads
+        esp.on("AT+CIPSNTPCFG", Reply::text(b"\r\nERROR\r\n"));
+        let mut task = WifiTask::new(esp.clone(), &clock, gpio, MockRandom::new(1));
+        task.init();
+        run(&mut task, &clock, 50);
+        assert!(task.ready);
+        assert!(task.stats().state == WifiState::Idle);
+        assert_eq!(task.stats().resets, 1);
+
+        // The access point goes away, and is joined again after
+        // a while
+        esp.drop_wifi();
+        run(&mut task, &clock, 50);
+        assert!(task.stats().state == WifiState::Failed);
+        assert_eq!(task.stats().disconnects, 1);
+        assert_eq!(esp.boots(), 1);
+        clock.advance(RETRY_MIN);
+        run(&mut task, &clock, 50);
+        assert!(task.stats().state == WifiState::Idle);
+        assert_eq!(esp.boots(), 2);
+        assert_eq!(esp.joined(), Some(String::from("Hexnet")));
+
+        // The module is checked on once it has been quiet
+        esp.take_commands();
+        clock.advance(PROBE_INTERVAL);
+        run(&mut task, &clock, 5);
+        assert_eq!(esp.take_commands(), vec![String::from("AT")]);
+
+        // It hangs, so it is power cycled after two probes
+        esp.on("", Reply::Silent);
+        clock.advance(PROBE_INTERVAL);
+        run(&mut task, &clock, 5);
+        clock.advance(2 * S_TO_NANO);
+        run(&mut task, &clock, 5);
+        assert_eq!(task.stats().probe_failures, 1);
+        assert_eq!(esp.boots(), 2);
+
+        clock.advance(PROBE_INTERVAL);
+        run(&mut task, &clock, 5);
+        esp.clear_rules();
+        clock.advance(2 * S_TO_NANO);
+        run(&mut task, &clock, 50);
+        assert_eq!(task.stats().probe_failures, 2);
+        assert_eq!(esp.boots(), 3);
+        assert!(task.stats().state == WifiState::Idle);
+
+        // Restarting by itself is noticed too
+        esp.inject(b"\r\nready\r\n");
+        run(&mut task, &clock, 50);
+        assert_eq!(esp.boots(), 4);
+        assert_eq!(task.stats().resets, 4);
+        assert!(task.ready);
+    }
+
+    #[test]
+    fn test_wifi_emulated_networks() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let _render = crate::pixel_engine::golden::RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Attic"), &str!(b"pw0"));
+        set_wifi_credentials(&str!(b"Office"), &str!(b"pw1"));
+        set_wifi_credentials(&str!(b"Home"), &str!(b"pw2"));
+
+        let clock = MockClock::new();
+        let gpio = MockGpio::new();
+        let esp = EspEmulator::new().with_reset_pin(gpio.clone(), RST_PIN);
+        esp.add_network("Home", "pw2", -80, 1);
+        esp.add_network("Office", "pw1", -40, 6);
+        esp.add_network("Cafe", "", -30, 11);
+        esp.serve_body("http://52.27.143.19/hexwall", CONFIG);
+
+        // The strongest saved network wins over the newest
+        let mut task = WifiTask::new(esp.clone(), &clock, gpio, MockRandom::new(1));
+        task.init();
+        run(&mut task, &clock, 50);
+        assert!(task.ready);
+        assert_eq!(esp.joined(), Some(String::from("Office")));
+        assert_eq!(task.stats().rssi, Some(-40));
+        assert!(esp.commands().contains(&String::from("AT+CWLAP")));
+
+        // When it refuses, the next one in line is tried
+        esp.once("AT+CWJAP=\"Office\"", Reply::text(b"+CWJAP:3\r\n\r\nFAIL\r\n"));
+        esp.take_commands();
+        esp.inject(b"\r\nready\r\n");
+        run(&mut task, &clock, 50);
+        assert!(task.ready);
+        assert_eq!(esp.joined(), Some(String::from("Home")));
+        assert_eq!(task.stats().rssi, Some(-80));
+
+        // The one the scan didn't find comes last
+        let joins: Vec<String> = esp.take_commands().into_iter().filter(|command| command.starts_with("AT+CWJAP")).collect();
+        assert_eq!(joins, vec![String::from("AT+CWJAP=\"Office\",\"pw1\""), String::from("AT+CWJAP=\"Home\",\"pw2\"")]);
+        esp.once("AT+CWJAP", Reply::text(b"+CWJAP:3\r\n\r\nFAIL\r\n"));
+        esp.once("AT+CWJAP", Reply::text(b"+CWJAP:3\r\n\r\nFAIL\r\n"));
+        esp.inject(b"\r\nready\r\n");
+        run(&mut task, &clock, 50);
+        let joins: Vec<String> = esp.take_commands().into_iter().filter(|command| command.starts_with("AT+CWJAP")).collect();
+        assert_eq!(joins[2], "AT+CWJAP=\"Attic\",\"pw0\"");
+        clear_wifi_credentials();
+    }
+
+    #[test]
+    fn test_wifi_emulated_static_address() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let _render = crate::pixel_engine::golden::RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+        set_network_config(NetworkConfig::Static {
+            ip: Ipv4([192, 168, 1, 77]),
+            gateway: Ipv4([192, 168, 1, 1]),
+            netmask: Ipv4([255, 255, 255, 0]),
+            dns: Some(Ipv4([1, 1, 1, 1])),
+        });
+
+        let clock = MockClock::new();
+        let gpio = MockGpio::new();
+        let esp = EspEmulator::new().with_reset_pin(gpio.clone(), RST_PIN);
+        esp.serve_body("http://52.27.143.19/hexwall", CONFIG);
+        let mut task = WifiTask::new(esp.clone(), &clock, gpio, MockRandom::new(1));
+        task.init();
+        run(&mut task, &clock, 50);
+        assert!(task.ready);
+
+        let commands = esp.take_commands();
+        assert!(commands.contains(&String::from("AT+CIPSTA=\"192.168.1.77\",\"192.168.1.1\",\"255.255.255.0\"")));
+        assert!(commands.contains(&String::from("AT+CIPDNS=1,\"1.1.1.1\"")));
+        assert!(!commands.iter().any(|command| command.starts_with("AT+CWDHCP")));
+        assert_eq!(task.stats().ip, Some(Ipv4([192, 168, 1, 77])));
+        assert_eq!(task.stats().mac, Some([0xa4, 0x2b, 0xb0, 0xc1, 0xd2, 0xff]));
+
+        // Going back to dhcp turns it on again
+        set_network_config(NetworkConfig::Dhcp);
+        esp.inject(b"\r\nready\r\n");
+        run(&mut task, &clock, 50);
+        assert!(task.ready);
+        let commands = esp.take_commands();
+        assert!(commands.contains(&String::from("AT+CWDHCP=1,1")));
+        assert!(commands.contains(&String::from("AT+CIPDNS=0")));
+        assert_eq!(task.stats().ip, Some(Ipv4([192, 168, 1, 50])));
+
+        // Old firmware without CIPDNS still joins
+        esp.on("AT+CIPDNS", Reply::text(b"\r\nERROR\r\n"));
+        esp.inject(b"\r\nready\r\n");
+        run(&mut task, &clock, 50);
+        assert!(task.ready);
+        assert_eq!(esp.joined(), Some(String::from("Hexnet")));
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        return haystack.windows(needle.len()).any(|window| window == needle);
+    }
+
+    #[test]
+    fn test_wifi_emulated_mqtt() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let _render = crate::pixel_engine::golden::RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+        set_mqtt_url(&str!(b"mqtt://wall:pw@broker.local"));
+        set_power(true);
+
+        let clock = MockClock::new();
+        let gpio = MockGpio::new();
+        let esp = EspEmulator::new().with_reset_pin(gpio.clone(), RST_PIN);
+        esp.serve_body("http://52.27.143.19/hexwall", CONFIG);
+        esp.listen("broker.local", 1883);
+        let mut task = WifiTask::new(esp.clone(), &clock, gpio, MockRandom::new(1));
+        task.init();
+        run(&mut task, &clock, 60);
+        assert!(task.ready);
+
+        let hello = esp.take_sent_bytes(MQTT_LINK);
+        assert!(hello.starts_with(b"\x10"));
+        assert!(contains(&hello, b"hexwall_a42bb0c1d2ff"));
+        assert!(contains(&hello, b"hexwall/hexwall_a42bb0c1d2ff/availability\x00\x07offline"));
+
+        // The broker accepts, and the wall subscribes and announces itself
+        esp.request(MQTT_LINK, b"\x20\x02\x00\x00");
+        run(&mut task, &clock, 60);
+        let sent = esp.take_sent_bytes(MQTT_LINK);
+        assert!(contains(&sent, b"\x82\x25\x00\x01\x00\x20hexwall/hexwall_a42bb0c1d2ff/set\x00"));
+        assert!(contains(&sent, b"homeassistant/light/hexwall_a42bb0c1d2ff/light/config{"));
+        assert!(contains(&sent, b"hexwall/hexwall_a42bb0c1d2ff/availabilityonline"));
+        assert!(contains(&sent, b"hexwall/hexwall_a42bb0c1d2ff/state{\"state\":\"ON\""));
+
+        // Home Assistant switches the wall off
+        let mut command = crate::mqtt::packet::publish_packet(&str!(b"hexwall/hexwall_a42bb0c1d2ff/set"), &str!(b"{\"state\":\"OFF\"}"), false);
+        esp.request(MQTT_LINK, &crate::strings::chars(&command).collect::<Vec<u8>>());
+        command.drop();
+        run(&mut task, &clock, 60);
+        assert!(!get_power());
+        assert!(contains(&esp.take_sent_bytes(MQTT_LINK), b"hexwall/hexwall_a42bb0c1d2ff/state{\"state\":\"OFF\""));
+        assert!(esp.take_sent(0).is_empty());
+
+        // The broker goes away, and the wall comes back later
+        esp.drop_connection(MQTT_LINK);
+        run(&mut task, &clock, 10);
+        assert!(!esp.is_open(MQTT_LINK));
+        clock.advance(RETRY_MAX);
+        run(&mut task, &clock, 60);
+        assert!(esp.take_sent_bytes(MQTT_LINK).starts_with(b"\x10"));
+
+        // Without a url, the wall leaves
+        esp.request(MQTT_LINK, b"\x20\x02\x00\x00");
+        run(&mut task, &clock, 60);
+        esp.take_sent_bytes(MQTT_LINK);
+        clear_mqtt_url();
+        run(&mut task, &clock, 60);
+        assert_eq!(esp.take_sent_bytes(MQTT_LINK), b"\xe0\x00");
+        assert!(!esp.is_open(MQTT_LINK));
+        set_power(true);
+    }
+
+    #[test]
+    fn test_wifi_emulated_stream() {
+        use crate::dmx::e131::test_e131::e131_packet;
+        use crate::dmx::test_dmx::artnet_packet;
+        use crate::pixel_engine::color::rgb;
+
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let _render = crate::pixel_engine::golden::RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+        set_stream_config(Some(StreamConfig { protocol: StreamProtocol::Dmx, universe: 1, start_channel: 4, mapping: DmxMapping::Units }));
+
+        let clock = MockClock::new();
+        let gpio = MockGpio::new();
+        let esp = EspEmulator::new().with_reset_pin(gpio.clone(), RST_PIN);
+        esp.serve_body("http://52.27.143.19/hexwall", CONFIG);
+        let mut task = WifiTask::new(esp.clone(), &clock, gpio, MockRandom::new(1));
+        task.init();
+        run(&mut task, &clock, 60);
+        assert!(task.ready);
+
+        // Two of the server's links listen for lighting software
+        let commands = esp.commands();
+        assert!(commands.contains(&String::from("AT+CIPSERVERMAXCONN=1")));
+        assert!(commands.contains(&String::from("AT+CIPSTART=1,\"UDP\",\"0.0.0.0\",6454,6454,2")));
+        assert!(commands.contains(&String::from("AT+CIPSTART=2,\"UDP\",\"0.0.0.0\",5568,5568,2")));
+
+        esp.request(E131_LINK, &e131_packet(1, 10, 0, &[0, 0, 0, 255, 0, 0, 0, 0, 255]));
+        run(&mut task, &clock, 5);
+        let frame = control::get_stream_frame().unwrap();
+        assert_eq!(frame.colors[0].as_hex(), rgb(255, 0, 0).as_hex());
+        assert_eq!(frame.colors[crate::pixel_task::LEDS_PER_UNIT].as_hex(), rgb(0, 0, 255).as_hex());
+        assert!(!frame.terminated);
+
+        // Other universes are ignored
+        esp.request(ARTNET_LINK, &artnet_packet(2, 1, &[0, 0, 0, 0, 255, 0]));
+        run(&mut task, &clock, 5);
+        assert_eq!(control::get_stream_frame().unwrap().colors[0].as_hex(), rgb(255, 0, 0).as_hex());
+        esp.request(ARTNET_LINK, &artnet_packet(1, 1, &[0, 0, 0, 0, 255, 0]));
+        run(&mut task, &clock, 5);
+        assert_eq!(control::get_stream_frame().unwrap().colors[0].as_hex(), rgb(0, 255, 0).as_hex());
+
+        // The sender says goodbye
+        esp.request(E131_LINK, &e131_packet(1, 11, 0x40, &[]));
+        run(&mut task, &clock, 5);
+        assert!(control::get_stream_frame().unwrap().terminated);
+
+        set_stream_config(None);
+        assert!(control::get_stream_frame().is_none());
+    }
+
+    #[test]
+    fn test_wifi_emulated_ddp() {
+        use crate::dmx::ddp::test_ddp::ddp_packet;
+        use crate::pixel_engine::color::rgb;
+
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let _render = crate::pixel_engine::golden::RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+        set_wifi_credentials(&str!(b"Hexnet"), &str!(b"hunter2"));
+        set_stream_config(Some(StreamConfig { protocol: StreamProtocol::Ddp, universe: 0, start_channel: 1, mapping: DmxMapping::Leds }));
+
+        let clock = MockClock::new();
+        let gpio = MockGpio::new();
+        let esp = EspEmulator::new().with_reset_pin(gpio.clone(), RST_PIN);
+        esp.serve_body("http://52.27.143.19/hexwall", CONFIG);
+        let mut task = WifiTask::new(esp.clone(), &clock, gpio, MockRandom::new(1));
+        task.init();
+        run(&mut task, &clock, 60);
+        assert!(task.ready);
+
+        // DDP needs one link, so the server keeps two
+        let commands = esp.commands();
+        assert!(commands.contains(&String::from("AT+CIPSERVERMAXCONN=2")));
+        assert!(commands.contains(&String::from("AT+CIPSTART=1,\"UDP\",\"0.0.0.0\",4048,4048,2")));
+        assert!(!commands.iter().any(|command| command.contains("5568")));
+
+        esp.request(DDP_LINK, &ddp_packet(true, 3, &[0, 0, 255]));
+        run(&mut task, &clock, 5);
+        let frame = control::get_stream_frame().unwrap();
+        assert_eq!(frame.colors[0].as_hex(), rgb(0, 0, 0).as_hex());
+        assert_eq!(frame.colors[1].as_hex(), rgb(0, 0, 255).as_hex());
+
+        set_stream_config(None);
+    }
+
+    fn request(serial: &MockSerial, link: u8, content: &[u8]) {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(b"+IPD,");
+        packet.extend_from_slice(link.to_string().as_bytes());
+        packet.extend_from_slice(b",");
+        packet.extend_from_slice(content.len().to_string().as_bytes());
+        packet.extend_from_slice(b":");
+        packet.extend_from_slice(content);
+        serial.receive(&packet);
+    }
+
+    /// Deliver a request and play the module's part in sending
+    /// the response. Returns everything the task wrote.
+    fn serve(task: &mut WifiTask<MockSerial, &MockClock, MockGpio, MockRandom>, serial: &MockSerial, link: u8, content: &[u8]) -> String {
+        request(serial, link, content);
+        task.system_loop();
+        let mut written = serial.take_written();
+        if written.is_empty() {
+            return written;
+        }
+
+        respond(task, serial, b"OK\r\n> ");
+        respond(task, serial, b"\r\nRecv 100 bytes\r\n\r\nSEND OK\r\n");
+        written.push_str(&serial.take_written());
+        respond(task, serial, format!("{},CLOSED\r\n\r\nOK\r\n", link).as_bytes());
+        return written;
+    }
+
+    #[test]
+    fn test_wifi_provisioning() {
+        let _guard = CREDENTIALS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        clear_wifi_credentials();
+
+        let clock = MockClock::new();
+        let serial = MockSerial::new();
+        let mut task = WifiTask::new(serial.clone(), &clock, MockGpio::new(), MockRandom::new(1));
+
+        // Nothing to join, so the task sets up an access point
+        task.init();
+        assert!(task.provisioning());
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+GMR\r\n");
+        respond(&mut task, &serial, b"ready\r\n");
+        assert_eq!(serial.take_written(), "ATE1\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CWMODE=3\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CWSAP=\"Hexwall-Setup\",\"\",5,0\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPMUX=1\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CIPSERVER=1,80\r\n");
+        respond(&mut task, &serial, b"OK\r\n");
+        assert_eq!(serial.take_written(), "AT+CWLAP\r\n");
+        respond(&mut task, &serial, b"+CWLAP:(3,\"Home\",-52,\"aa:bb:cc:dd:ee:ff\",1,-22,0)\r\n+CWLAP:(3,\"Cafe\",-70,\"aa:bb:cc:dd:ee:00\",6,-22,0)\r\n\r\nOK\r\n");
+
+        // Browse to the setup page
+        serial.receive(b"0,CONNECT\r\n");
+        task.system_loop();
+        let response = serve(&mut task, &serial, 0, b"GET / HTTP/1.1\r\nHost: 192.168.4.1\r\n\r\n");
+        assert!(response.starts_with("AT+CIPSEND=0,"));
+        assert!(response.contains("HTTP/1.1 200 OK"));
+        assert!(response.contains("<option value=\"Home\">"));
+        assert!(response.contains("<option value=\"Cafe\">"));
+        assert!(response.ends_with("AT+CIPCLOSE=0\r\n"));
+
+        // Unknown pages
+        assert!(serve(&mut task, &serial, 1, b"GET /favicon.ico HTTP/1.1\r\n\r\n").contains("HTTP/1.1 404 Not Found"));
+
+        // The form arrives in two packets
+        let body = b"ssid=Home&password=correct+horse";
+        let mut headers = Vec::new();
+        headers.extend_from_slice(b"POST /save HTTP/1.1\r\nContent-Length: ");
+        headers.extend_from_slice(body.len().to_string().as_bytes());
+        headers.extend_from_slice(b"\r\n\r\n");
+        serial.receive(b"+IPD,2,");
+        serial.receive(headers.len().to_string().as_bytes());
+        serial.receive(b":");
+        serial.receive(&headers);
+        task.system_loop();
+        assert_eq!(serial.take_written(), "");
+        assert!(serve(&mut task, &serial, 2, body).contains("<h1>Saved</h1>"));
+
+        let (ssid, pwd) = get_wifi_credentials().unwrap();
+        assert!(ssid == str!(b"Home"));
+        assert!(pwd == str!(b"correct horse"));
+        assert!(!task.provisioning());
+
+        // Then the task joins the new network
+        clock.advance(3 * S_TO_NANO);
+        task.system_loop();
+        task.system_loop();
+        assert_eq!(serial.take_written(), "AT+GMR\r\n");
+        respond(&mut task, &serial, b"ready\r\n");
+        serial.take_written();
+        for _ in 0 .. 6 {
+            respond(&mut task, &serial, b"OK\r\n");
         }
+        assert_eq!(serial.take_written(), "AT+CWMODE=1\r\nAT+CWAUTOCONN=0\r\nAT+CWDHCP=1,1\r\nAT+CIPDNS=0\r\nAT+CWLAP\r\nAT+CWJAP=\"Home\",\"correct horse\"\r\n");
     }
-}
\ No newline at end of file
+}
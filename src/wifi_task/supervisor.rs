@@ -0,0 +1,247 @@
+//! Keeps track of how the connection to the network is doing.
+//! The wifi task reports every step it takes, and the
+//! supervisor decides when a lost connection should be tried
+//! again and when the module has stopped answering. What it
+//! knows is published through `control`, so the api can show
+//! it.
+
+use teensycore::*;
+use teensycore::clock::uNano;
+use crate::control::set_wifi_status;
+use crate::network::Ipv4;
+
+/// The module is asked whether it is still alive once it has
+/// been quiet for this long
+pub const PROBE_INTERVAL: uNano = 30 * S_TO_NANO;
+/// The module is restarted after this many probes in a row
+/// went unanswered
+const PROBE_LIMIT: u32 = 2;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WifiState {
+    /// Power cycling the module and waiting for it to start
+    Resetting,
+    Joining,
+    /// Associated with the access point, but without an address
+    Connected,
+    GotIp,
+    /// Downloading the config
+    Fetching,
+    /// Serving requests until the next download
+    Idle,
+    /// Something went wrong, waiting to try again
+    Failed,
+}
+
+impl WifiState {
+    pub fn name(&self) -> &'static [u8] {
+        return match self {
+            WifiState::Resetting => b"resetting",
+            WifiState::Joining => b"joining",
+            WifiState::Connected => b"connected",
+            WifiState::GotIp => b"got_ip",
+            WifiState::Fetching => b"fetching",
+            WifiState::Idle => b"idle",
+            WifiState::Failed => b"failed",
+        };
+    }
+}
+
+/// What the supervisor knows, for diagnostics. The counts
+/// start at boot.
+#[derive(Copy, Clone)]
+pub struct WifiStats {
+    pub state: WifiState,
+    /// When the current state was entered
+    pub since: uNano,
+    pub resets: u32,
+    /// How often the access point went away
+    pub disconnects: u32,
+    pub probe_failures: u32,
+    pub download_failures: u32,
+    /// Signal strength in dBm of the network joined last, if
+    /// the scan found it
+    pub rssi: Option<i32>,
+    /// The station's address, as the module reported it after
+    /// joining
+    pub ip: Option<Ipv4>,
+    pub mac: Option<[u8; 6]>,
+}
+
+pub struct Supervisor {
+    stats: WifiStats,
+    probe_target: uNano,
+    missed_probes: u32,
+    reconnect_target: uNano,
+    /// Reconnects since the module last got an address
+    reconnect_attempts: u32,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        return Supervisor {
+            stats: WifiStats {
+                state: WifiState::Resetting,
+                since: 0,
+                resets: 0,
+                disconnects: 0,
+                probe_failures: 0,
+                download_failures: 0,
+                rssi: None,
+                ip: None,
+                mac: None,
+            },
+            probe_target: 0,
+            missed_probes: 0,
+            reconnect_target: 0,
+            reconnect_attempts: 0,
+        };
+    }
+
+    pub fn state(&self) -> WifiState {
+        return self.stats.state;
+    }
+
+    pub fn stats(&self) -> WifiStats {
+        return self.stats;
+    }
+
+    pub fn enter(&mut self, state: WifiState, now: uNano) {
+        if state == self.stats.state && state != WifiState::Resetting {
+            return;
+        }
+
+        match state {
+            WifiState::Resetting => {
+                self.stats.resets += 1;
+                self.stats.rssi = None;
+                self.stats.ip = None;
+                self.heard(now);
+            },
+            WifiState::GotIp => {
+                self.reconnect_attempts = 0;
+            },
+            _ => {},
+        }
+
+        self.stats.state = state;
+        self.stats.since = now;
+        self.publish();
+    }
+
+    pub fn download_failed(&mut self) {
+        self.stats.download_failures += 1;
+        self.publish();
+    }
+
+    pub fn set_rssi(&mut self, rssi: Option<i32>) {
+        self.stats.rssi = rssi;
+        self.publish();
+    }
+
+    pub fn set_addresses(&mut self, ip: Option<Ipv4>, mac: Option<[u8; 6]>) {
+        self.stats.ip = ip;
+        self.stats.mac = mac;
+        self.publish();
+    }
+
+    /// The access point went away. Try again once the delay is
+    /// over.
+    pub fn disconnected(&mut self, now: uNano, delay: uNano) {
+        self.stats.disconnects += 1;
+        self.reconnect_attempts += 1;
+        self.reconnect_target = now + delay;
+        self.enter(WifiState::Failed, now);
+    }
+
+    /// How many reconnects in a row failed to get an address.
+    pub fn reconnect_attempts(&self) -> u32 {
+        return self.reconnect_attempts;
+    }
+
+    pub fn reconnect_due(&self, now: uNano) -> bool {
+        return now > self.reconnect_target;
+    }
+
+    /// The module said something, so it is still alive.
+    pub fn heard(&mut self, now: uNano) {
+        self.missed_probes = 0;
+        self.probe_target = now + PROBE_INTERVAL;
+    }
+
+    /// Whether it is time to check on the module. Calling this
+    /// schedules the next probe.
+    pub fn probe_due(&mut self, now: uNano) -> bool {
+        if now <= self.probe_target {
+            return false;
+        }
+        self.probe_target = now + PROBE_INTERVAL;
+        return true;
+    }
+
+    /// Returns whether the module should be given up on.
+    pub fn probe_answered(&mut self, answered: bool) -> bool {
+        if answered {
+            self.missed_probes = 0;
+            return false;
+        }
+
+        self.stats.probe_failures += 1;
+        self.missed_probes += 1;
+        self.publish();
+        return self.missed_probes >= PROBE_LIMIT;
+    }
+
+    fn publish(&self) {
+        set_wifi_status(Some(self.stats));
+    }
+}
+
+#[cfg(test)]
+pub mod test_supervisor {
+    use super::*;
+
+    #[test]
+    fn test_supervisor_probes() {
+        let mut supervisor = Supervisor::new();
+        supervisor.enter(WifiState::Resetting, 0);
+        supervisor.heard(10);
+        assert!(!supervisor.probe_due(10 + PROBE_INTERVAL));
+        assert!(supervisor.probe_due(11 + PROBE_INTERVAL));
+        assert!(!supervisor.probe_due(12 + PROBE_INTERVAL));
+
+        // One miss is forgiven, two in a row are not
+        assert!(!supervisor.probe_answered(false));
+        assert!(!supervisor.probe_answered(true));
+        assert!(!supervisor.probe_answered(false));
+        assert!(supervisor.probe_answered(false));
+        assert_eq!(supervisor.stats().probe_failures, 3);
+
+        // Hearing from the module puts the next probe off
+        supervisor.heard(20 + PROBE_INTERVAL);
+        assert!(!supervisor.probe_due(12 + 2 * PROBE_INTERVAL));
+        assert!(supervisor.probe_due(21 + 2 * PROBE_INTERVAL));
+        assert!(!supervisor.probe_answered(false));
+
+        supervisor.enter(WifiState::Resetting, 40);
+        supervisor.enter(WifiState::Resetting, 50);
+        assert_eq!(supervisor.stats().resets, 3);
+        assert_eq!(supervisor.stats().since, 50);
+    }
+
+    #[test]
+    fn test_supervisor_reconnects() {
+        let mut supervisor = Supervisor::new();
+        supervisor.disconnected(100, 5 * S_TO_NANO);
+        assert_eq!(supervisor.state(), WifiState::Failed);
+        assert!(!supervisor.reconnect_due(100 + 5 * S_TO_NANO));
+        assert!(supervisor.reconnect_due(101 + 5 * S_TO_NANO));
+
+        supervisor.disconnected(200, S_TO_NANO);
+        assert_eq!(supervisor.reconnect_attempts(), 2);
+        assert_eq!(supervisor.stats().disconnects, 2);
+
+        supervisor.enter(WifiState::GotIp, 300);
+        assert_eq!(supervisor.reconnect_attempts(), 0);
+    }
+}
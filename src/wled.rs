@@ -0,0 +1,407 @@
+//! Enough of the WLED JSON api for WLED apps and Home
+//! Assistant's WLED integration to control the wall. The wall
+//! is a single segment whose effects are the shaders and whose
+//! palettes are the transition effects.
+//!
+//! ```text
+//! GET  /json          state, info, effects and palettes
+//! GET  /json/si       state and info
+//! GET  /json/state
+//! GET  /json/info
+//! GET  /json/eff
+//! GET  /json/pal
+//! POST /json/state    {"on":true,"bri":128,"seg":[{"fx":2,"pal":1}]}
+//! ```
+//!
+//! Realtime pixels come in over DDP, see `dmx::ddp`.
+
+use teensycore::*;
+use teensycore::clock::uNano;
+use teensycore::math::{int_to_hex, itoa};
+use teensycore::system::str::*;
+use teensycore::system::vector::*;
+use crate::api::{append_json_name, json_first, json_value, JSON_CONTENT_TYPE};
+use crate::control::*;
+use crate::hal::Clock;
+use crate::http::models::*;
+use crate::pixel_task::{LEDS, STREAM_TIMEOUT};
+use crate::strings::*;
+
+/// The WLED release whose api this follows. Apps check it to
+/// decide what to ask for.
+const WLED_VERSION: &[u8] = b"0.14.0";
+const WLED_BUILD: &[u8] = b"2310130";
+const DEVICE_NAME: &[u8] = b"Hex Wall";
+/// Sent back for a body which isn't a json object
+const ERROR_INVALID_JSON: &[u8] = b"{\"error\":9}";
+
+pub fn show_all<C: Clock>(clock: &mut C, _request: &IncomingRequest) -> ServerResponse {
+    let mut body = Str::new();
+    body.append(b"{\"state\":");
+    append_state(&mut body, clock);
+    body.append(b",\"info\":");
+    append_info(&mut body, clock);
+    body.append(b",\"effects\":");
+    append_names(&mut body, get_shader_names());
+    body.append(b",\"palettes\":");
+    append_names(&mut body, get_effect_names());
+    body.append(b"}");
+    return json(HttpStatus::Ok, body);
+}
+
+pub fn show_state_info<C: Clock>(clock: &mut C, _request: &IncomingRequest) -> ServerResponse {
+    let mut body = Str::new();
+    body.append(b"{\"state\":");
+    append_state(&mut body, clock);
+    body.append(b",\"info\":");
+    append_info(&mut body, clock);
+    body.append(b"}");
+    return json(HttpStatus::Ok, body);
+}
+
+pub fn show_state<C: Clock>(clock: &mut C, _request: &IncomingRequest) -> ServerResponse {
+    let mut body = Str::new();
+    append_state(&mut body, clock);
+    return json(HttpStatus::Ok, body);
+}
+
+pub fn show_info<C: Clock>(clock: &mut C, _request: &IncomingRequest) -> ServerResponse {
+    let mut body = Str::new();
+    append_info(&mut body, clock);
+    return json(HttpStatus::Ok, body);
+}
+
+pub fn show_effects<C: Clock>(_clock: &mut C, _request: &IncomingRequest) -> ServerResponse {
+    let mut body = Str::new();
+    append_names(&mut body, get_shader_names());
+    return json(HttpStatus::Ok, body);
+}
+
+pub fn show_palettes<C: Clock>(_clock: &mut C, _request: &IncomingRequest) -> ServerResponse {
+    let mut body = Str::new();
+    append_names(&mut body, get_effect_names());
+    return json(HttpStatus::Ok, body);
+}
+
+/// Change the state. Like WLED, the answer is just a success
+/// unless the body asks for the new state with `"v":true`.
+pub fn update_state<C: Clock>(clock: &mut C, request: &IncomingRequest) -> ServerResponse {
+    if !starts_with(&request.body, b"{") {
+        return json(HttpStatus::BadRequest, Str::with_content(ERROR_INVALID_JSON));
+    }
+
+    apply_state(&request.body, clock.nanos());
+
+    let mut body = Str::new();
+    if json_flag(&request.body, b"v") == Some(true) {
+        append_state(&mut body, clock);
+    } else {
+        body.append(b"{\"success\":true}");
+    }
+    return json(HttpStatus::Ok, body);
+}
+
+fn apply_state(body: &Str, now: uNano) {
+    match json_value(body, b"on") {
+        None => {},
+        Some(mut on) => {
+            if equals(&on, b"t") {
+                crate::set_power(!crate::get_power());
+            } else if equals(&on, b"true") || equals(&on, b"false") {
+                crate::set_power(equals(&on, b"true"));
+            }
+            on.drop();
+        },
+    }
+
+    // WLED switches off rather than going dark
+    match json_number(body, b"bri") {
+        None => {},
+        Some(0) => crate::set_power(false),
+        Some(brightness) => crate::set_brightness(brightness.min(255) as u8),
+    }
+
+    // The first segment is the only one
+    match first_segment(body) {
+        None => {},
+        Some(mut segment) => {
+            match json_number(&segment, b"fx").and_then(|idx| get_shader_names().get(idx as usize)) {
+                None => {},
+                Some(name) => queue_command(ControlCommand::ForceShader(name)),
+            }
+            match json_number(&segment, b"pal").and_then(|idx| get_effect_names().get(idx as usize)) {
+                None => {},
+                Some(name) => queue_command(ControlCommand::ForceEffect(name)),
+            }
+            segment.drop();
+        },
+    }
+
+    // Leave realtime mode and fade back to the schedule
+    if json_flag(body, b"live") == Some(false) {
+        end_stream(now);
+    }
+}
+
+/// WLED takes a list of segments, or a single one on its own.
+fn first_segment(body: &Str) -> Option<Str> {
+    let mut segments = json_value(body, b"seg")?;
+    if byte_at(&segments, 0) != Some(b'[') {
+        return Some(segments);
+    }
+    let segment = json_first(&segments);
+    segments.drop();
+    return segment;
+}
+
+fn json_number(body: &Str, key: &[u8]) -> Option<u64> {
+    let mut value = json_value(body, key)?;
+    let result = parse_decimal(&value);
+    value.drop();
+    return result;
+}
+
+fn json_flag(body: &Str, key: &[u8]) -> Option<bool> {
+    let mut value = json_value(body, key)?;
+    let result = match (equals(&value, b"true"), equals(&value, b"false")) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    };
+    value.drop();
+    return result;
+}
+
+/// Whether lighting software is showing on the wall right now.
+fn live<C: Clock>(clock: &C) -> bool {
+    return match get_stream_frame() {
+        None => false,
+        Some(frame) => !frame.terminated && clock.nanos() <= frame.received_at + STREAM_TIMEOUT,
+    };
+}
+
+fn append_state<C: Clock>(target: &mut Str, clock: &C) {
+    let status = get_status();
+    let on = bool_text(crate::get_power());
+    let brightness = itoa(crate::get_brightness() as u64);
+
+    target.append(b"{\"on\":");
+    target.append(on);
+    target.append(b",\"bri\":");
+    target.join(&brightness);
+    target.append(b",\"transition\":10,\"ps\":-1,\"pl\":-1,\"nl\":{\"on\":false,\"dur\":60,\"mode\":1,\"tbri\":0,\"rem\":-1}");
+    target.append(b",\"udpn\":{\"send\":false,\"recv\":false},\"lor\":0,\"mainseg\":0,\"live\":");
+    target.append(bool_text(live(clock)));
+    target.append(b",\"seg\":[{\"id\":0,\"start\":0,\"stop\":");
+    target.join(&itoa(LEDS as u64));
+    target.append(b",\"len\":");
+    target.join(&itoa(LEDS as u64));
+    target.append(b",\"grp\":1,\"spc\":0,\"of\":0,\"on\":");
+    target.append(on);
+    target.append(b",\"frz\":false,\"bri\":");
+    target.join(&brightness);
+    target.append(b",\"cct\":127,\"col\":[[255,255,255],[0,0,0],[0,0,0]],\"fx\":");
+    target.join(&itoa(name_index(get_shader_names(), status.shader) as u64));
+    target.append(b",\"sx\":128,\"ix\":128,\"pal\":");
+    target.join(&itoa(name_index(get_effect_names(), status.effect) as u64));
+    target.append(b",\"sel\":true,\"rev\":false,\"mi\":false}]}");
+}
+
+fn append_info<C: Clock>(target: &mut Str, clock: &C) {
+    let status = get_status();
+    let (rssi, ip, mac) = match status.wifi {
+        None => (None, None, None),
+        Some(wifi) => (wifi.rssi, wifi.ip, wifi.mac),
+    };
+
+    target.append(b"{\"ver\":\"");
+    target.append(WLED_VERSION);
+    target.append(b"\",\"vid\":");
+    target.append(WLED_BUILD);
+    target.append(b",\"leds\":{\"count\":");
+    target.join(&itoa(LEDS as u64));
+    target.append(b",\"pwr\":0,\"fps\":0,\"maxpwr\":0,\"maxseg\":1,\"seglc\":[1],\"lc\":1,\"rgbw\":false,\"wv\":0,\"cct\":0}");
+    target.append(b",\"str\":false,\"name\":");
+    append_json_name(target, Some(DEVICE_NAME));
+    target.append(b",\"udpport\":0,\"live\":");
+    target.append(bool_text(live(clock)));
+    target.append(b",\"liveseg\":-1,\"lm\":\"\",\"lip\":\"\",\"ws\":-1,\"fxcount\":");
+    target.join(&itoa(get_shader_names().size() as u64));
+    target.append(b",\"palcount\":");
+    target.join(&itoa(get_effect_names().size() as u64));
+
+    // WLED turns the signal strength into a percentage
+    let rssi = rssi.unwrap_or(-100);
+    let signal = (2 * (rssi + 100)).clamp(0, 100);
+    target.append(b",\"wifi\":{\"bssid\":\"\",\"rssi\":");
+    if rssi < 0 {
+        target.append(b"-");
+    }
+    target.join(&itoa(rssi.unsigned_abs() as u64));
+    target.append(b",\"signal\":");
+    target.join(&itoa(signal as u64));
+    target.append(b",\"channel\":0}");
+
+    target.append(b",\"fs\":{\"u\":0,\"t\":0,\"pmt\":0},\"ndc\":0,\"arch\":\"teensy40\",\"core\":\"hexagon2\",\"freeheap\":0,\"uptime\":");
+    target.join(&itoa((clock.nanos() / S_TO_NANO) as u64));
+    target.append(b",\"opt\":0,\"brand\":\"WLED\",\"product\":\"Hexwall\",\"mac\":\"");
+    match mac {
+        None => {},
+        Some(mac) => {
+            for byte in mac {
+                target.append(&[int_to_hex(byte >> 4).to_ascii_lowercase(), int_to_hex(byte & 0xF).to_ascii_lowercase()]);
+            }
+        },
+    }
+    target.append(b"\",\"ip\":\"");
+    match ip {
+        None => {},
+        Some(ip) => {
+            let mut address = ip.to_str();
+            target.join(&address);
+            address.drop();
+        },
+    }
+    target.append(b"\"}");
+}
+
+fn append_names(target: &mut Str, names: Vector<&'static [u8]>) {
+    target.append(b"[");
+    for (idx, name) in names.into_iter().enumerate() {
+        if idx > 0 {
+            target.append(b",");
+        }
+        append_json_name(target, Some(name));
+    }
+    target.append(b"]");
+}
+
+/// Where the name is in the list, or 0 if it isn't there.
+fn name_index(names: Vector<&'static [u8]>, name: Option<&'static [u8]>) -> usize {
+    return match name {
+        None => 0,
+        Some(name) => names.into_iter().position(|candidate| candidate == name).unwrap_or(0),
+    };
+}
+
+fn bool_text(value: bool) -> &'static [u8] {
+    return match value {
+        true => b"true",
+        false => b"false",
+    };
+}
+
+fn json(status: HttpStatus, body: Str) -> ServerResponse {
+    return ServerResponse::new(status, JSON_CONTENT_TYPE, body);
+}
+
+#[cfg(test)]
+pub mod test_wled {
+    use super::*;
+    use std::prelude::rust_2021::*;
+    use crate::api::api_routes;
+    use crate::hal::mock::*;
+    use crate::http::parser::parse_request;
+    use crate::http::server::dispatch;
+    use crate::pixel_engine::golden::RENDER_LOCK;
+
+    fn call(clock: &MockClock, raw: &[u8]) -> (HttpStatus, String) {
+        let mut request = parse_request(0, &Str::with_content(raw)).unwrap().unwrap();
+        let mut clock = clock;
+        let mut response = dispatch(&api_routes(), &mut clock, &request);
+        let bytes: Vec<u8> = chars(&response.body).collect();
+        let result = (response.status, String::from_utf8(bytes).unwrap());
+        response.drop();
+        request.drop();
+        return result;
+    }
+
+    fn post(clock: &MockClock, body: &str) -> (HttpStatus, String) {
+        let raw = format!("POST /json/state HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        return call(clock, raw.as_bytes());
+    }
+
+    #[test]
+    fn test_wled_documents() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let clock = MockClock::new();
+        clock.set(75 * S_TO_NANO);
+        register_shader(b"Medbay");
+        register_effect(b"Randomized");
+        set_current_shader(b"Medbay");
+
+        let (status, state) = call(&clock, b"GET /json/state HTTP/1.1\r\n\r\n");
+        assert_eq!(status, HttpStatus::Ok);
+        assert!(state.starts_with("{\"on\":true,\"bri\":"));
+        let fx = get_shader_names().into_iter().position(|name| name == b"Medbay").unwrap();
+        assert!(state.contains(&format!(",\"fx\":{},", fx)));
+        assert!(state.contains(",\"live\":false,"));
+
+        let (_, info) = call(&clock, b"GET /json/info HTTP/1.1\r\n\r\n");
+        assert!(info.starts_with("{\"ver\":\"0.14.0\","));
+        assert!(info.contains(&format!("\"leds\":{{\"count\":{},", LEDS)));
+        assert!(info.contains(",\"uptime\":75,"));
+        assert!(info.contains(",\"ws\":-1,"));
+        assert!(info.ends_with("}"));
+
+        let (_, effects) = call(&clock, b"GET /json/eff HTTP/1.1\r\n\r\n");
+        assert!(effects.starts_with("[\"") && effects.contains("\"Medbay\""));
+        let (_, palettes) = call(&clock, b"GET /json/pal HTTP/1.1\r\n\r\n");
+        assert!(palettes.contains("\"Randomized\""));
+
+        let (_, all) = call(&clock, b"GET /json HTTP/1.1\r\n\r\n");
+        assert!(all.starts_with("{\"state\":{\"on\":"));
+        assert!(all.contains(",\"info\":{\"ver\":"));
+        assert!(all.contains(",\"effects\":[") && all.contains(",\"palettes\":["));
+        let (_, state_info) = call(&clock, b"GET /json/si HTTP/1.1\r\n\r\n");
+        assert!(state_info.starts_with("{\"state\":{") && !state_info.contains("\"effects\""));
+    }
+
+    #[test]
+    fn test_wled_commands() {
+        let _guard = RENDER_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let clock = MockClock::new();
+        register_shader(b"Medbay");
+        register_effect(b"Randomized");
+        while next_command().is_some() {}
+        crate::set_power(true);
+
+        assert_eq!(post(&clock, "{\"on\":false}"), (HttpStatus::Ok, String::from("{\"success\":true}")));
+        assert!(!crate::get_power());
+        post(&clock, "{\"on\":\"t\",\"bri\":64}");
+        assert!(crate::get_power());
+        assert_eq!(crate::get_brightness(), 64);
+        post(&clock, "{\"bri\":0}");
+        assert!(!crate::get_power());
+        assert_eq!(crate::get_brightness(), 64);
+
+        // Effects and palettes are picked by their place in the lists
+        let fx = get_shader_names().into_iter().position(|name| name == b"Medbay").unwrap();
+        let pal = get_effect_names().into_iter().position(|name| name == b"Randomized").unwrap();
+        let (_, state) = post(&clock, &format!("{{\"on\":true,\"v\":true,\"seg\":[{{\"id\":0,\"fx\":{},\"pal\":{}}}]}}", fx, pal));
+        assert!(state.starts_with("{\"on\":true,\"bri\":64,"));
+        assert!(next_command() == Some(ControlCommand::ForceShader(b"Medbay")));
+        assert!(next_command() == Some(ControlCommand::ForceEffect(b"Randomized")));
+        post(&clock, "{\"seg\":[{\"fx\":9999}]}");
+        assert!(next_command().is_none());
+        post(&clock, &format!("{{\"seg\":{{\"fx\":{}}}}}", fx));
+        assert!(next_command() == Some(ControlCommand::ForceShader(b"Medbay")));
+
+        // Only the top level switches the wall
+        post(&clock, "{\"seg\":[{\"id\":0,\"on\":false,\"bri\":0}]}");
+        assert!(crate::get_power());
+        assert_eq!(crate::get_brightness(), 64);
+
+        assert_eq!(post(&clock, "on=true").0, HttpStatus::BadRequest);
+        assert_eq!(call(&clock, b"POST /json HTTP/1.1\r\nContent-Length: 11\r\n\r\n{\"on\":true}").0, HttpStatus::Ok);
+
+        // Lighting software is sent away
+        set_stream_frame([crate::pixel_engine::color::rgb(1, 2, 3); LEDS], clock.nanos());
+        assert!(call(&clock, b"GET /json/state HTTP/1.1\r\n\r\n").1.contains(",\"live\":true,"));
+        post(&clock, "{\"live\":false}");
+        assert!(get_stream_frame().unwrap().terminated);
+        clear_stream();
+
+        crate::set_brightness(crate::DEFAULT_BRIGHTNESS);
+    }
+}